@@ -0,0 +1,144 @@
+// Packs the initial ramdisk.
+//
+// Everything below `initrd/` goes into a USTAR archive in OUT_DIR, which the kernel
+// embeds and unpacks into its root file system at boot. A small test program is
+// generated into `bin/hello` so there is always something to run in user mode.
+// Reference: https://www.gnu.org/software/tar/manual/html_node/Standard.html
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Where user programs are loaded, the start of user space.
+const USER_BASE: u64 = 0x0000_4000_0000_0000;
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let initrd_dir = manifest_dir.join("initrd");
+    println!("cargo:rerun-if-changed={}", initrd_dir.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut archive = Vec::new();
+    if initrd_dir.is_dir() {
+        add_directory(&mut archive, &initrd_dir, "").expect("failed to pack the initrd");
+    }
+    add_entry(&mut archive, "bin/", 0o755, None);
+    add_entry(&mut archive, "bin/hello", 0o755, Some(&hello_elf()));
+    // The archive ends with two zero blocks.
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    fs::write(out_dir.join("initrd.tar"), archive).expect("failed to write the initrd");
+}
+
+fn add_directory(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            add_entry(archive, &format!("{}/", name), 0o755, None);
+            add_directory(archive, &entry.path(), &format!("{}/", name))?;
+        } else {
+            add_entry(archive, &name, 0o644, Some(&fs::read(entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+// Writes `value` as a NUL terminated octal number filling `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+// Appends a header and, for files, the contents padded to whole blocks. Directory
+// names end with a slash.
+fn add_entry(archive: &mut Vec<u8>, name: &str, mode: u64, contents: Option<&[u8]>) {
+    assert!(name.len() <= 100, "initrd path too long: {}", name);
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+
+    let (size, kind) = match contents {
+        Some(data) => (data.len(), b'0'),
+        None => (0, b'5'),
+    };
+    octal(&mut header[100..108], mode);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size as u64);
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+
+    archive.extend_from_slice(&header);
+    if let Some(data) = contents {
+        archive.extend_from_slice(data);
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        archive.resize(archive.len() + padding, 0);
+    }
+}
+
+// A static executable that prints a greeting and exits with code 0:
+//
+//     mov eax, 1            ; write
+//     mov edi, 1            ; stdout
+//     lea rsi, [rip + msg]
+//     mov edx, len
+//     syscall
+//     mov eax, 2            ; exit
+//     xor edi, edi
+//     syscall
+//   msg:
+fn hello_elf() -> Vec<u8> {
+    const HEADER_SIZE: usize = 64;
+    const PROGRAM_HEADER_SIZE: usize = 56;
+    let message = b"Hello from user space!\n";
+
+    let mut code = vec![0xb8, 1, 0, 0, 0, 0xbf, 1, 0, 0, 0];
+    // The message follows the remaining 16 bytes of code after the `lea`.
+    code.extend_from_slice(&[0x48, 0x8d, 0x35, 16, 0, 0, 0]);
+    code.extend_from_slice(&[0xba, message.len() as u8, 0, 0, 0, 0x0f, 0x05]);
+    code.extend_from_slice(&[0xb8, 2, 0, 0, 0, 0x31, 0xff, 0x0f, 0x05]);
+    code.extend_from_slice(message);
+
+    let code_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let file_size = code_offset + code.len() as u64;
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&0x3eu16.to_le_bytes()); // EM_X86_64
+    elf.extend_from_slice(&1u32.to_le_bytes()); // EV_CURRENT
+    elf.extend_from_slice(&(USER_BASE + code_offset).to_le_bytes()); // e_entry
+    elf.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    elf.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+    elf.extend_from_slice(&[0; 6]); // no section headers
+
+    elf.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    elf.extend_from_slice(&5u32.to_le_bytes()); // PF_R | PF_X
+    elf.extend_from_slice(&0u64.to_le_bytes()); // p_offset
+    elf.extend_from_slice(&USER_BASE.to_le_bytes()); // p_vaddr
+    elf.extend_from_slice(&USER_BASE.to_le_bytes()); // p_paddr
+    elf.extend_from_slice(&file_size.to_le_bytes()); // p_filesz
+    elf.extend_from_slice(&file_size.to_le_bytes()); // p_memsz
+    elf.extend_from_slice(&0x1000u64.to_le_bytes()); // p_align
+
+    elf.extend_from_slice(&code);
+    elf
+}
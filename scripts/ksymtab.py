@@ -0,0 +1,161 @@
+#!/usr/bin/env python3
+"""Embeds the kernel symbol table into a linked MoonlightOS kernel.
+
+Reads the ELF symbol table of the kernel, keeps the function symbols, sorts them by
+address and writes them into the zero-filled `.ksymtab` section reserved by
+`src/symbols.rs`. The kernel is patched in place, so running the script twice on the
+same file is harmless.
+
+Usage: ksymtab.py <kernel-elf>
+"""
+
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+SECTION = b".ksymtab"
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QII")
+SHDR = struct.Struct("<IIQQQQIIQQ")
+SYM = struct.Struct("<IBBHQQ")
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# Escapes used by the legacy Rust mangling scheme.
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+def demangle(name):
+    """Demangles legacy Rust (`_ZN...E`) symbols, dropping the trailing hash.
+
+    Anything else (C symbols, v0 mangled names) is returned unchanged, v0 names are
+    handled by `demangle_v0`.
+    """
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, char in ESCAPES.items():
+            part = part.replace(escape, char)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        return part.replace("..", "::")
+
+    return "::".join(unescape(part) for part in parts)
+
+
+def demangle_v0(names):
+    """Demangles v0 Rust (`_R...`) symbols with `c++filt` when it is installed."""
+    mangled = [name for name in names if name.startswith("_R")]
+    cxxfilt = shutil.which("c++filt")
+    if not mangled or cxxfilt is None:
+        return {}
+
+    result = subprocess.run([cxxfilt], input="\n".join(mangled), capture_output=True, text=True)
+    if result.returncode != 0:
+        return {}
+
+    demangled = result.stdout.splitlines()
+    # Drop the crate disambiguators, `core[c1f1a4ba060b9bfa]::fmt` becomes `core::fmt`.
+    return {m: re.sub(r"\[[0-9a-f]+\]", "", d) for m, d in zip(mangled, demangled)}
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = [SHDR.unpack_from(elf, shoff + i * shentsize) for i in range(shnum)]
+    names = headers[shstrndx]
+
+    for header in headers:
+        start = names[4] + header[0]
+        yield elf[start:elf.index(b"\0", start)], header
+
+
+def symbols(elf, all_sections):
+    symtab = next(h for _, h in all_sections if h[1] == SHT_SYMTAB)
+    strtab = all_sections[symtab[6]][1]
+    offset, size, entsize = symtab[4], symtab[5], symtab[9]
+
+    for i in range(size // entsize):
+        st_name, st_info, _, st_shndx, st_value, st_size = SYM.unpack_from(elf, offset + i * entsize)
+        if st_info & 0xF != STT_FUNC or st_value == 0 or st_shndx == 0:
+            continue
+        start = strtab[4] + st_name
+        name = elf[start:elf.index(b"\0", start)].decode("utf-8", "replace")
+        yield st_value, st_size, demangle(name)
+
+
+def build(syms, capacity):
+    unique = {}
+    for address, size, name in syms:
+        unique.setdefault(address, (size, name))
+
+    v0 = demangle_v0([name for _, name in unique.values()])
+    ordered = sorted((address, (size, v0.get(name, name))) for address, (size, name) in unique.items())
+
+    strings = bytearray()
+    entries = bytearray()
+    for address, (size, name) in ordered:
+        encoded = name.encode("utf-8")[:0xFFFF]
+        entries += ENTRY.pack(address, min(size, 0xFFFFFFFF), len(strings))
+        strings += struct.pack("<H", len(encoded)) + encoded
+
+    strings_offset = HEADER.size + len(entries)
+    total = strings_offset + len(strings)
+    if total > capacity:
+        sys.exit(f"ksymtab: table needs {total} bytes but only {capacity} are reserved, "
+                 "increase KSYMTAB_SIZE in src/symbols.rs")
+
+    table = HEADER.pack(MAGIC, len(ordered), strings_offset, total) + entries + strings
+    return table + bytes(capacity - total), len(ordered)
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit(f"ksymtab: {path} is not an ELF64 file")
+
+    all_sections = list(sections(elf))
+    target = next((h for name, h in all_sections if name == SECTION), None)
+    if target is None:
+        sys.exit(f"ksymtab: {path} has no {SECTION.decode()} section")
+
+    offset, size = target[4], target[5]
+    table, count = build(symbols(elf, all_sections), size)
+    elf[offset:offset + size] = table
+
+    with open(path, "wb") as f:
+        f.write(elf)
+    print(f"ksymtab: embedded {count} symbols into {path}")
+
+
+if __name__ == "__main__":
+    main()
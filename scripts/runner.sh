@@ -0,0 +1,7 @@
+#!/bin/sh
+# Cargo runner for MoonlightOS: embeds the kernel symbol table into the freshly linked
+# kernel and then hands it over to `bootimage runner`.
+set -e
+
+python3 "$(dirname "$0")/ksymtab.py" "$1"
+exec bootimage runner "$@"
@@ -0,0 +1,121 @@
+// Finding ACPI tables.
+//
+// The firmware leaves the root system description pointer (RSDP) in the first KiB of
+// the extended BIOS data area or in the BIOS area between 0xE0000 and 0xFFFFF, on a 16
+// byte boundary. It points to the root table, the RSDT with 32 bit or (from ACPI 2.0)
+// the XSDT with 64 bit pointers to all other tables. The tables are in RAM, which the
+// bootloader maps completely, so they are read through the physical memory mapping.
+// Reference: https://wiki.osdev.org/RSDP
+// Reference: https://wiki.osdev.org/RSDT
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use crate::locks::mutex::Mutex;
+use crate::memory::phys_to_virt;
+use crate::println;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const HEADER_SIZE: usize = 36;
+/// Where the real mode segment of the extended BIOS data area is stored.
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe0000, 0x100000);
+
+/// The physical addresses of all tables the root table lists.
+static TABLES: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+
+// Reads physical memory through the physical memory mapping.
+unsafe fn physical(addr: u64, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Returns the RSDP found in `[start, end)`.
+fn scan(start: u64, end: u64) -> Option<&'static [u8]> {
+    (start..end).step_by(16).find_map(|addr| {
+        let rsdp = unsafe { physical(addr, 20) };
+        if &rsdp[..8] != RSDP_SIGNATURE || !checksum_ok(rsdp) {
+            return None;
+        }
+        // Version 2 and later extend the structure and add a checksum over all of it.
+        if rsdp[15] >= 2 {
+            let extended = unsafe { physical(addr, 36) };
+            return checksum_ok(extended).then_some(extended);
+        }
+        Some(rsdp)
+    })
+}
+
+fn find_rsdp() -> Option<&'static [u8]> {
+    let segment = u16::from_le_bytes(unsafe { physical(EBDA_POINTER, 2) }.try_into().unwrap());
+    let ebda = (segment as u64) << 4;
+    let in_ebda = match ebda {
+        0 => None,
+        _ => scan(ebda, ebda + 1024),
+    };
+    in_ebda.or_else(|| scan(BIOS_AREA.0, BIOS_AREA.1))
+}
+
+// Returns the whole table at `addr` if its checksum is right.
+fn table_at(addr: PhysAddr) -> Option<&'static [u8]> {
+    let header = unsafe { physical(addr.as_u64(), HEADER_SIZE) };
+    let length = u32_at(header, 4) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = unsafe { physical(addr.as_u64(), length) };
+    checksum_ok(table).then_some(table)
+}
+
+/// Finds the RSDP and records the tables of the root table.
+pub fn init() {
+    println!("[!] Reading ACPI tables");
+    let Some(rsdp) = find_rsdp() else {
+        return println!("    [-] No RSDP found");
+    };
+    // Prefer the XSDT when there is one.
+    let (root, entry_size) = match rsdp.len() {
+        36 if u64_at(rsdp, 24) != 0 => (u64_at(rsdp, 24), 8),
+        _ => (u32_at(rsdp, 16) as u64, 4),
+    };
+    let Some(root) = table_at(PhysAddr::new(root)) else {
+        return println!("    [-] Root table is corrupt");
+    };
+
+    let mut tables = Vec::new();
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        let addr = match entry_size {
+            8 => u64_at(entry, 0),
+            _ => u32_at(entry, 0) as u64,
+        };
+        if let Some(table) = table_at(PhysAddr::new(addr)) {
+            println!(
+                "    [+] {} at {:#x}",
+                core::str::from_utf8(&table[..4]).unwrap_or("????"),
+                addr
+            );
+            tables.push(PhysAddr::new(addr));
+        }
+    }
+    *TABLES.lock() = tables;
+    println!("    [+] Done");
+}
+
+/// Returns the table with the given signature, e.g. `b"MCFG"`, including its header.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let tables = TABLES.lock().clone();
+    tables
+        .into_iter()
+        .filter_map(table_at)
+        .find(|table| &table[..4] == signature)
+}
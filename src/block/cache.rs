@@ -0,0 +1,363 @@
+// The buffer cache.
+//
+// File systems read and write their devices through a `BufferCache`, which keeps
+// recently used blocks in memory. Writes only change the cached copy and mark it
+// dirty; dirty blocks reach the device when they are evicted, when the cache is synced
+// and every few seconds from a background thread. Once the cache is full the least
+// recently used block makes room. The blocks are cached per disk: the cache of a
+// partition is a window into the cache of its disk, so both always see the same data.
+// Reference: https://en.wikipedia.org/wiki/Page_cache
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError};
+use crate::locks::mutex::Mutex;
+use crate::println;
+use crate::process;
+use crate::time;
+
+/// Blocks kept per device.
+const DEFAULT_CAPACITY: usize = 256;
+const WRITEBACK_INTERVAL_MS: u64 = 5000;
+
+struct Buffer {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Value of the cache clock when the block was last used.
+    last_used: u64,
+}
+
+struct CacheState {
+    buffers: BTreeMap<u64, Buffer>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub cached: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// The blocks cached for a disk, by their block number on the disk.
+struct DiskBlocks {
+    disk: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    /// First block of `device` on the disk, 0 unless it is a partition.
+    start: u64,
+    blocks: Arc<DiskBlocks>,
+}
+
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<BufferCache> {
+        let blocks = DiskBlocks {
+            disk: device.clone(),
+            capacity: capacity.max(1),
+            state: Mutex::new(CacheState {
+                buffers: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        };
+        Arc::new(BufferCache {
+            device,
+            start: 0,
+            blocks: Arc::new(blocks),
+        })
+    }
+
+    /// A cache for `partition`, which starts at block `start` of the disk cached by
+    /// `disk`. It shares the blocks of that cache.
+    pub fn for_partition(
+        partition: Arc<dyn BlockDevice>,
+        start: u64,
+        disk: &BufferCache,
+    ) -> Arc<BufferCache> {
+        Arc::new(BufferCache {
+            device: partition,
+            start: disk.start + start,
+            blocks: disk.blocks.clone(),
+        })
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    // Returns the buffer for `lba`, reading it from the device if `load` is set and it is
+    // not cached. Without `load` a missing block starts out zeroed, for callers that are
+    // about to overwrite all of it.
+    fn buffer<'a>(
+        &self,
+        state: &'a mut CacheState,
+        lba: u64,
+        load: bool,
+    ) -> Result<&'a mut Buffer, BlockError> {
+        if lba >= self.device.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        let lba = self.start + lba;
+        state.clock += 1;
+        let clock = state.clock;
+
+        if state.buffers.contains_key(&lba) {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+            if state.buffers.len() >= self.blocks.capacity {
+                self.evict(state)?;
+            }
+            let mut data = vec![0; self.device.block_size()].into_boxed_slice();
+            if load {
+                self.blocks.disk.read_blocks(lba, &mut data)?;
+            }
+            state.buffers.insert(
+                lba,
+                Buffer {
+                    data,
+                    dirty: false,
+                    last_used: clock,
+                },
+            );
+        }
+
+        let buffer = state.buffers.get_mut(&lba).expect("buffer vanished");
+        buffer.last_used = clock;
+        Ok(buffer)
+    }
+
+    // Drops the least recently used block, writing it back first if it is dirty.
+    fn evict(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let (&lba, buffer) = match state
+            .buffers
+            .iter()
+            .min_by_key(|(_, buffer)| buffer.last_used)
+        {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        if buffer.dirty {
+            self.blocks.disk.write_blocks(lba, &buffer.data)?;
+        }
+        state.buffers.remove(&lba);
+        Ok(())
+    }
+
+    /// Runs `f` on the contents of block `lba`.
+    pub fn with_block<F, R>(&self, lba: u64, f: F) -> Result<R, BlockError>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let mut state = super::lock(&self.blocks.state);
+        Ok(f(&self.buffer(&mut state, lba, true)?.data))
+    }
+
+    /// Runs `f` on the contents of block `lba` and marks the block dirty.
+    pub fn with_block_mut<F, R>(&self, lba: u64, f: F) -> Result<R, BlockError>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        if self.device.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut state = super::lock(&self.blocks.state);
+        let buffer = self.buffer(&mut state, lba, true)?;
+        buffer.dirty = true;
+        Ok(f(&mut buffer.data))
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset` of the device.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let block_size = self.device.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let count = (block_size as usize - start).min(buf.len() - done);
+            self.with_block(position / block_size, |data| {
+                buf[done..done + count].copy_from_slice(&data[start..start + count])
+            })?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` starting at byte `offset` of the device.
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let block_size = self.device.block_size() as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let count = (block_size as usize - start).min(buf.len() - done);
+            self.with_block_mut(position / block_size, |data| {
+                data[start..start + count].copy_from_slice(&buf[done..done + count])
+            })?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty blocks of the disk back, in block order, and flushes it.
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut state = super::lock(&self.blocks.state);
+        let mut dirty = false;
+        for (&lba, buffer) in state.buffers.iter_mut().filter(|(_, buffer)| buffer.dirty) {
+            self.blocks.disk.write_blocks(lba, &buffer.data)?;
+            buffer.dirty = false;
+            dirty = true;
+        }
+        drop(state);
+        if dirty {
+            self.blocks.disk.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Forgets all cached blocks of the disk after writing back the dirty ones.
+    pub fn invalidate(&self) -> Result<(), BlockError> {
+        self.sync()?;
+        super::lock(&self.blocks.state).buffers.clear();
+        Ok(())
+    }
+
+    /// Statistics of the disk's cache, shared with its partitions.
+    pub fn stats(&self) -> CacheStats {
+        let state = super::lock(&self.blocks.state);
+        CacheStats {
+            cached: state.buffers.len(),
+            dirty: state.buffers.values().filter(|buffer| buffer.dirty).count(),
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+// The cache is itself a block device, so anything that takes a device can be given a
+// cached one.
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_request(self, lba, buf.len())?;
+        for (index, chunk) in buf.chunks_exact_mut(self.block_size()).enumerate() {
+            self.with_block(lba + index as u64, |data| chunk.copy_from_slice(data))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        super::check_request(self, lba, buf.len())?;
+        let mut state = super::lock(&self.blocks.state);
+        for (index, chunk) in buf.chunks_exact(self.block_size()).enumerate() {
+            // Whole blocks are overwritten, there is no need to read them first.
+            let buffer = self.buffer(&mut state, lba + index as u64, false)?;
+            buffer.data.copy_from_slice(chunk);
+            buffer.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()
+    }
+}
+
+static CACHES: Mutex<Vec<Arc<BufferCache>>> = Mutex::new(Vec::new());
+
+/// The shared cache of the registered device `name`, created on first use.
+pub fn get(name: &str) -> Option<Arc<BufferCache>> {
+    let device = super::get(name)?;
+    let disk = match device.partition_of() {
+        Some((disk, start)) => Some((get(disk)?, start)),
+        None => None,
+    };
+
+    let mut caches = super::lock(&CACHES);
+    if let Some(cache) = caches.iter().find(|cache| cache.name() == name) {
+        return Some(cache.clone());
+    }
+    let cache = match disk {
+        Some((disk, start)) => BufferCache::for_partition(device, start, &disk),
+        None => BufferCache::new(device, DEFAULT_CAPACITY),
+    };
+    caches.push(cache.clone());
+    Some(cache)
+}
+
+/// Writes back the dirty blocks of every cache.
+pub fn sync_all() -> Result<(), BlockError> {
+    let caches = super::lock(&CACHES).clone();
+    caches.iter().try_for_each(|cache| cache.sync())
+}
+
+fn writeback_thread() -> ! {
+    loop {
+        time::sleep(WRITEBACK_INTERVAL_MS);
+        if let Err(error) = sync_all() {
+            println!("[-] Buffer cache writeback failed: {}", error);
+        }
+    }
+}
+
+/// Starts the thread that periodically writes dirty blocks back.
+pub fn init() {
+    process::spawn_kernel_thread(writeback_thread);
+}
+
+#[cfg(test)]
+use super::ram::RamDisk;
+
+#[test_case]
+fn test_buffer_cache_writeback() {
+    let disk = RamDisk::new("ram0", 512, 16);
+    let cache = BufferCache::new(disk.clone(), 2);
+
+    cache.write(510, b"span").unwrap();
+    let mut raw = [0; 512];
+    disk.read_blocks(0, &mut raw).unwrap();
+    assert_eq!(&raw[510..], b"\0\0");
+
+    // Touching two more blocks evicts block 0, which has to be written back.
+    cache.with_block(5, |_| ()).unwrap();
+    cache.with_block(6, |_| ()).unwrap();
+    disk.read_blocks(0, &mut raw).unwrap();
+    assert_eq!(&raw[510..], b"sp");
+
+    let mut buf = [0; 4];
+    cache.read(510, &mut buf).unwrap();
+    assert_eq!(&buf, b"span");
+    cache.sync().unwrap();
+    assert_eq!(cache.stats().dirty, 0);
+    disk.read_blocks(1, &mut raw).unwrap();
+    assert_eq!(&raw[..2], b"an");
+}
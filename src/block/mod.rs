@@ -0,0 +1,211 @@
+// Block devices.
+//
+// Disk drivers implement `BlockDevice`, which transfers whole blocks addressed by their
+// logical block address, and register their disks here under a short name like `hda`.
+// The partitions of a disk are registered next to it as devices of their own. File
+// systems never talk to the hardware directly: they go through the buffer cache of a
+// device, which is a `BlockDevice` as well. Every device also gets a node in /dev that
+// reads and writes through that same cache.
+pub mod cache;
+pub mod partition;
+pub mod ram;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::fs::devfs::{self, Device};
+use crate::fs::{FileType, FsError};
+use crate::locks::mutex::{Mutex, MutexGuard};
+use crate::println;
+use crate::process::scheduler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request extends past the end of the device.
+    OutOfRange,
+    /// The buffer length is not a multiple of the block size.
+    InvalidBuffer,
+    ReadOnly,
+    /// The device reported an error or did not respond.
+    Io,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            BlockError::OutOfRange => "block out of range",
+            BlockError::InvalidBuffer => "buffer is not a multiple of the block size",
+            BlockError::ReadOnly => "device is read-only",
+            BlockError::Io => "input/output error",
+        };
+        f.write_str(message)
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// The name the device is registered under.
+    fn name(&self) -> &str;
+
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buf.len() / block_size()` blocks starting at block `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / block_size()` blocks starting at block `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far has reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// For a partition, the name of its disk and the block of the disk it starts at.
+    fn partition_of(&self) -> Option<(&str, u64)> {
+        None
+    }
+}
+
+/// Checks a request against the geometry of `device` and returns the number of blocks.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(BlockError::InvalidBuffer);
+    }
+    let count = (len / block_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Locks a mutex that may be held across I/O. Kernel threads are not preempted, so
+/// instead of spinning we let the current owner run until it is done.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        scheduler::relax();
+    }
+}
+
+/// Makes `device` available under its name, also as a node in /dev.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let node = DeviceNode {
+        name: String::from(device.name()),
+        size: device.block_count() * device.block_size() as u64,
+    };
+    if let Err(error) = devfs::register(device.name(), Arc::new(node)) {
+        println!("        [-] /dev/{}: {}", device.name(), error);
+    }
+    DEVICES.lock().push(device);
+}
+
+// The node of a block device in /dev. It goes through the buffer cache, so it sees the
+// same data as a file system mounted from the device.
+struct DeviceNode {
+    name: String,
+    size: u64,
+}
+
+impl DeviceNode {
+    // Cuts a transfer at the end of the device.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        (len as u64).min(self.size.saturating_sub(offset)) as usize
+    }
+}
+
+impl Device for DeviceNode {
+    fn kind(&self) -> FileType {
+        FileType::BlockDevice
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let count = self.clamp(offset, buf.len());
+        let cache = cache::get(&self.name).ok_or(FsError::NotFound)?;
+        cache.read(offset, &mut buf[..count])?;
+        Ok(count)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let count = self.clamp(offset, buf.len());
+        if count == 0 && !buf.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        let cache = cache::get(&self.name).ok_or(FsError::NotFound)?;
+        cache.write(offset, &buf[..count])?;
+        Ok(count)
+    }
+}
+
+/// Registers a whole disk and every partition found on it.
+pub fn add_disk(disk: Arc<dyn BlockDevice>) {
+    register(disk.clone());
+    match partition::scan(&disk) {
+        Ok(partitions) => {
+            for partition in partitions {
+                println!(
+                    "        [+] {}: {} blocks at {} ({})",
+                    partition.name(),
+                    partition.block_count(),
+                    partition.start(),
+                    partition.kind
+                );
+                register(partition);
+            }
+        }
+        Err(error) => println!(
+            "        [-] {}: cannot read partition table: {}",
+            disk.name(),
+            error
+        ),
+    }
+}
+
+/// All registered devices in registration order.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Starts writing dirty cached blocks back in the background.
+pub fn init() {
+    println!("[!] Starting buffer cache writeback");
+    cache::init();
+    println!("    [+] Done");
+}
+
+/// Formats a size in bytes for humans, e.g. `64 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    alloc::format!("{} {}", size, UNITS[unit])
+}
@@ -0,0 +1,406 @@
+// Partition tables.
+//
+// A disk either starts with a classic MBR, whose four primary entries may include an
+// extended partition holding a chain of logical ones, or with a protective MBR followed
+// by a GUID partition table in block 1. Each partition found becomes a block device of
+// its own, named after the disk with the partition number appended (`hda1`). Logical
+// MBR partitions are numbered from 5, like on Linux.
+// Reference: https://wiki.osdev.org/MBR_(x86)
+// Reference: https://wiki.osdev.org/GPT
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{BlockDevice, BlockError};
+
+/// Partition tables address 512 byte sectors.
+const SECTOR_SIZE: usize = 512;
+
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0f;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+/// First number given to logical partitions.
+const FIRST_LOGICAL: u32 = 5;
+/// Guards against loops in a corrupted chain of extended boot records.
+const MAX_LOGICAL: u32 = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Entries are at least this large; the partition name fills the rest.
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Larger entries are allowed but unheard of, this keeps the table read bounded.
+const GPT_MAX_ENTRY_SIZE: usize = 1024;
+const GPT_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID byte of an MBR entry.
+    Mbr(u8),
+    /// The partition type GUID of a GPT entry.
+    Gpt(Guid),
+}
+
+/// A GUID as stored on disk: the first three fields are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]])
+        )?;
+        b[8..10]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))?;
+        f.write_str("-")?;
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionKind::Mbr(id) => write!(f, "mbr {:#04x}", id),
+            PartitionKind::Gpt(guid) => write!(f, "gpt {}", guid),
+        }
+    }
+}
+
+/// A range of blocks of a disk.
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    /// First block on the disk.
+    start: u64,
+    count: u64,
+    pub number: u32,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_request(self, lba, buf.len())?;
+        self.disk.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_request(self, lba, buf.len())?;
+        self.disk.write_blocks(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn partition_of(&self) -> Option<(&str, u64)> {
+        Some((self.disk.name(), self.start))
+    }
+}
+
+struct Entry {
+    number: u32,
+    start: u64,
+    count: u64,
+    kind: PartitionKind,
+}
+
+fn read_sector(disk: &dyn BlockDevice, lba: u64) -> Result<[u8; SECTOR_SIZE], BlockError> {
+    let mut sector = [0; SECTOR_SIZE];
+    disk.read_blocks(lba, &mut sector)?;
+    Ok(sector)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The CRC-32 used by GPT (IEEE 802.3, reflected).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// The four entries of an MBR or EBR as (system ID, relative start, sector count).
+fn mbr_entries(sector: &[u8; SECTOR_SIZE]) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+    (0..4).map(move |index| {
+        let entry = &sector[MBR_TABLE_OFFSET + index * MBR_ENTRY_SIZE..];
+        (entry[4], u32_at(entry, 8) as u64, u32_at(entry, 12) as u64)
+    })
+}
+
+fn is_extended(id: u8) -> bool {
+    id == MBR_TYPE_EXTENDED_CHS || id == MBR_TYPE_EXTENDED_LBA
+}
+
+fn parse_mbr(disk: &dyn BlockDevice, mbr: &[u8; SECTOR_SIZE]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut extended = None;
+
+    for (index, (id, start, count)) in mbr_entries(mbr).enumerate() {
+        if id == MBR_TYPE_EMPTY || count == 0 {
+            continue;
+        }
+        if is_extended(id) {
+            extended = Some(start);
+            continue;
+        }
+        entries.push(Entry {
+            number: index as u32 + 1,
+            start,
+            count,
+            kind: PartitionKind::Mbr(id),
+        });
+    }
+
+    // Each extended boot record describes one logical partition, relative to itself, and
+    // links to the next record, relative to the start of the extended partition. A record
+    // that cannot be read ends the chain, the partitions found so far are still good.
+    if let Some(extended_start) = extended {
+        let mut ebr = extended_start;
+        for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+            let sector = match read_sector(disk, ebr) {
+                Ok(sector) if sector[510..] == MBR_SIGNATURE => sector,
+                _ => break,
+            };
+            let mut next = None;
+            for (id, start, count) in mbr_entries(&sector).take(2) {
+                if is_extended(id) {
+                    next = Some(extended_start + start);
+                } else if id != MBR_TYPE_EMPTY && count != 0 {
+                    entries.push(Entry {
+                        number,
+                        start: ebr + start,
+                        count,
+                        kind: PartitionKind::Mbr(id),
+                    });
+                }
+            }
+            match next {
+                Some(lba) => ebr = lba,
+                None => break,
+            }
+        }
+    }
+    entries
+}
+
+fn parse_gpt(disk: &dyn BlockDevice) -> Result<Option<Vec<Entry>>, BlockError> {
+    let mut header = read_sector(disk, 1)?;
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(92..=SECTOR_SIZE).contains(&header_size) {
+        return Ok(None);
+    }
+    // The checksum covers the header with the checksum field zeroed.
+    let stored = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != stored {
+        return Ok(None);
+    }
+
+    let table_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if !(GPT_MIN_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size)
+        || entry_count > GPT_MAX_ENTRIES
+    {
+        return Ok(None);
+    }
+
+    let table_size = entry_count * entry_size;
+    let mut table = vec![0; (table_size + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE];
+    disk.read_blocks(table_lba, &mut table)?;
+    if crc32(&table[..table_size]) != u32_at(&header, 88) {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    for (index, entry) in table[..table_size].chunks_exact(entry_size).enumerate() {
+        let guid: [u8; 16] = entry[..16].try_into().unwrap();
+        // An all-zero type marks an unused entry.
+        if guid == [0; 16] {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if last < first {
+            continue;
+        }
+        entries.push(Entry {
+            number: index as u32 + 1,
+            start: first,
+            count: last - first + 1,
+            kind: PartitionKind::Gpt(Guid(guid)),
+        });
+    }
+    Ok(Some(entries))
+}
+
+/// Reads the partition table of `disk`. Disks without one, and devices whose blocks
+/// are not 512 byte sectors, have no partitions.
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Result<Vec<Arc<Partition>>, BlockError> {
+    if disk.block_size() != SECTOR_SIZE || disk.block_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_sector(disk.as_ref(), 0)?;
+    if mbr[510..] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let protective = mbr_entries(&mbr).any(|(id, _, _)| id == MBR_TYPE_PROTECTIVE);
+    let entries = if protective {
+        parse_gpt(disk.as_ref())?.unwrap_or_default()
+    } else {
+        parse_mbr(disk.as_ref(), &mbr)
+    };
+
+    Ok(entries
+        .into_iter()
+        // Ignore entries that do not fit on the disk.
+        .filter(|entry| {
+            entry
+                .start
+                .checked_add(entry.count)
+                .map_or(false, |end| end <= disk.block_count())
+        })
+        .map(|entry| {
+            Arc::new(Partition {
+                name: format!("{}{}", disk.name(), entry.number),
+                disk: disk.clone(),
+                start: entry.start,
+                count: entry.count,
+                number: entry.number,
+                kind: entry.kind,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+fn put_mbr_entry(sector: &mut [u8], index: usize, id: u8, start: u32, count: u32) {
+    let entry = &mut sector[MBR_TABLE_OFFSET + index * MBR_ENTRY_SIZE..];
+    entry[4] = id;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+}
+
+#[test_case]
+fn test_mbr_partitions() {
+    use super::cache::BufferCache;
+    use super::ram::RamDisk;
+
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+    let ram = RamDisk::new("ram0", SECTOR_SIZE, 128);
+    let mut sector = [0; SECTOR_SIZE];
+    put_mbr_entry(&mut sector, 0, 0x83, 2, 30);
+    put_mbr_entry(&mut sector, 1, MBR_TYPE_EXTENDED_LBA, 32, 96);
+    sector[510..].copy_from_slice(&MBR_SIGNATURE);
+    ram.write_blocks(0, &sector).unwrap();
+
+    // One logical partition in the extended one, right after its boot record.
+    let mut ebr = [0; SECTOR_SIZE];
+    put_mbr_entry(&mut ebr, 0, 0x0c, 1, 40);
+    ebr[510..].copy_from_slice(&MBR_SIGNATURE);
+    ram.write_blocks(32, &ebr).unwrap();
+
+    let disk: Arc<dyn BlockDevice> = ram;
+    let partitions = scan(&disk).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].name(), "ram01");
+    assert_eq!(
+        (partitions[0].start(), partitions[0].block_count()),
+        (2, 30)
+    );
+    assert_eq!(partitions[1].number, 5);
+    assert_eq!(
+        (partitions[1].start(), partitions[1].block_count()),
+        (33, 40)
+    );
+    assert_eq!(partitions[1].kind, PartitionKind::Mbr(0x0c));
+
+    let mut block = [0xab; SECTOR_SIZE];
+    partitions[0].write_blocks(29, &block).unwrap();
+    assert_eq!(
+        partitions[0].write_blocks(30, &block),
+        Err(BlockError::OutOfRange)
+    );
+    disk.read_blocks(31, &mut block).unwrap();
+    assert_eq!(block[0], 0xab);
+
+    // The cache of a partition shares the blocks of the disk's cache.
+    let disk_cache = BufferCache::new(disk.clone(), 8);
+    let partition: Arc<dyn BlockDevice> = partitions[0].clone();
+    let partition_cache = BufferCache::for_partition(partition, 2, &disk_cache);
+    partition_cache.write(0, b"shared").unwrap();
+    let mut buf = [0; 6];
+    disk_cache.read(2 * SECTOR_SIZE as u64, &mut buf).unwrap();
+    assert_eq!(&buf, b"shared");
+    assert_eq!(disk_cache.stats().dirty, 1);
+}
+
+#[test_case]
+fn test_unreadable_extended_partition() {
+    use super::ram::RamDisk;
+
+    // The extended partition starts past the end of the disk, its boot record cannot be
+    // read.
+    let ram = RamDisk::new("ram0", SECTOR_SIZE, 64);
+    let mut sector = [0; SECTOR_SIZE];
+    put_mbr_entry(&mut sector, 0, 0x83, 2, 30);
+    put_mbr_entry(&mut sector, 1, MBR_TYPE_EXTENDED_LBA, 1000, 96);
+    sector[510..].copy_from_slice(&MBR_SIGNATURE);
+    ram.write_blocks(0, &sector).unwrap();
+
+    let disk: Arc<dyn BlockDevice> = ram;
+    let partitions = scan(&disk).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].name(), "ram01");
+}
@@ -0,0 +1,61 @@
+// A block device backed by kernel memory.
+//
+// Useful to put a file system image in memory and as a stand-in for a disk in tests.
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError};
+use crate::locks::mutex::Mutex;
+
+pub struct RamDisk {
+    name: String,
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `blocks` blocks.
+    pub fn new(name: &str, block_size: usize, blocks: u64) -> Arc<RamDisk> {
+        RamDisk::from_image(name, block_size, vec![0; block_size * blocks as usize])
+    }
+
+    /// Wraps an existing image, which is cut to whole blocks.
+    pub fn from_image(name: &str, block_size: usize, mut image: Vec<u8>) -> Arc<RamDisk> {
+        image.truncate(image.len() / block_size * block_size);
+        Arc::new(RamDisk {
+            name: String::from(name),
+            block_size,
+            data: Mutex::new(image),
+        })
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_request(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_request(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// The console: keyboard input waiting to be consumed and the screen.
+//
+// The keyboard interrupt handler only decodes scancodes and queues the characters here.
+// Whoever currently owns the console, the shell or a user program reading from stdin,
+// takes them out again outside of interrupt context.
+use crate::interrupts::interrupts::without_interrupts;
+use crate::locks::mutex::Mutex;
+use crate::process::scheduler;
+use crate::vga_buffer::WRITER;
+
+const INPUT_BUFFER_SIZE: usize = 256;
+
+struct InputQueue {
+    buffer: [char; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputQueue {
+    fn push(&mut self, c: char) {
+        // Drop keys typed while nobody is reading rather than overwrite older ones.
+        if self.len == INPUT_BUFFER_SIZE {
+            return;
+        }
+        self.buffer[(self.head + self.len) % INPUT_BUFFER_SIZE] = c;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.len == 0 {
+            return None;
+        }
+        let c = self.buffer[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
+static INPUT: Mutex<InputQueue> = Mutex::new(InputQueue {
+    buffer: ['\0'; INPUT_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+});
+
+/// Queues a character, called from the keyboard interrupt handler.
+pub fn push(c: char) {
+    INPUT.lock().push(c);
+}
+
+/// Takes the next queued character, if any.
+pub fn pop() -> Option<char> {
+    without_interrupts(|| INPUT.lock().pop())
+}
+
+/// Waits until a character is available and returns it, letting other threads run in
+/// the meantime.
+pub fn read_char() -> char {
+    loop {
+        if let Some(c) = pop() {
+            return c;
+        }
+        scheduler::relax();
+    }
+}
+
+/// Reads typed characters into `buf` with a minimal line discipline: echo what is typed
+/// and return after a newline. Returns the number of bytes read.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buf.len() {
+        let c = read_char();
+        if c == '\u{8}' {
+            if count > 0 {
+                count -= 1;
+                WRITER.lock().backspace();
+            }
+            continue;
+        }
+
+        let mut encoded = [0; 4];
+        let encoded = c.encode_utf8(&mut encoded).as_bytes();
+        if count + encoded.len() > buf.len() {
+            break;
+        }
+        buf[count..count + encoded.len()].copy_from_slice(encoded);
+        count += encoded.len();
+        WRITER.lock().write_char(c);
+
+        if c == '\n' {
+            break;
+        }
+    }
+    count
+}
+
+/// Prints `buf` on the screen, byte by byte if it is not valid UTF-8.
+pub fn write(buf: &[u8]) {
+    let mut writer = WRITER.lock();
+    match core::str::from_utf8(buf) {
+        Ok(s) => writer.write_string(s),
+        Err(_) => buf.iter().for_each(|b| match b {
+            b'\n' => writer.new_line(),
+            _ => writer.write_byte(*b),
+        }),
+    }
+}
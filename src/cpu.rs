@@ -0,0 +1,134 @@
+// Identification of the processor with CPUID.
+// Reference: https://www.felixcloutier.com/x86/cpuid
+// Reference: https://wiki.osdev.org/CPUID
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::instructions::cpuid;
+
+// Feature bits of leaf 1 in EDX and ECX and of leaf 0x8000_0001 in EDX, with the names
+// Linux uses for them.
+const FEATURES_EDX: [(u32, &str); 21] = [
+    (0, "fpu"),
+    (1, "vme"),
+    (2, "de"),
+    (3, "pse"),
+    (4, "tsc"),
+    (5, "msr"),
+    (6, "pae"),
+    (7, "mce"),
+    (8, "cx8"),
+    (9, "apic"),
+    (11, "sep"),
+    (12, "mtrr"),
+    (13, "pge"),
+    (14, "mca"),
+    (15, "cmov"),
+    (16, "pat"),
+    (19, "clflush"),
+    (23, "mmx"),
+    (24, "fxsr"),
+    (25, "sse"),
+    (26, "sse2"),
+];
+const FEATURES_ECX: [(u32, &str); 16] = [
+    (0, "pni"),
+    (1, "pclmulqdq"),
+    (9, "ssse3"),
+    (12, "fma"),
+    (13, "cx16"),
+    (19, "sse4_1"),
+    (20, "sse4_2"),
+    (21, "x2apic"),
+    (22, "movbe"),
+    (23, "popcnt"),
+    (25, "aes"),
+    (26, "xsave"),
+    (28, "avx"),
+    (29, "f16c"),
+    (30, "rdrand"),
+    (31, "hypervisor"),
+];
+const FEATURES_EXTENDED_EDX: [(u32, &str); 5] = [
+    (11, "syscall"),
+    (20, "nx"),
+    (26, "pdpe1gb"),
+    (27, "rdtscp"),
+    (29, "lm"),
+];
+
+pub struct CpuInfo {
+    pub vendor: String,
+    /// The brand string, empty if the processor has none.
+    pub model_name: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Vec<&'static str>,
+}
+
+fn ascii(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers
+        .iter()
+        .flat_map(|register| register.to_le_bytes())
+        .collect();
+    String::from_utf8_lossy(&bytes)
+        .trim_matches(|c| c == '\0' || c == ' ')
+        .into()
+}
+
+fn flags(register: u32, names: &[(u32, &'static str)], features: &mut Vec<&'static str>) {
+    features.extend(
+        names
+            .iter()
+            .filter(|(bit, _)| register & 1 << bit != 0)
+            .map(|(_, name)| *name),
+    );
+}
+
+/// Queries the processor the kernel runs on.
+pub fn info() -> CpuInfo {
+    let [max_leaf, ebx, ecx, edx] = cpuid(0, 0);
+    let vendor = ascii(&[ebx, edx, ecx]);
+
+    let [signature, _, ecx, edx] = if max_leaf >= 1 { cpuid(1, 0) } else { [0; 4] };
+    // The extended family and model only count for some base families.
+    let base_family = signature >> 8 & 0xf;
+    let family = match base_family {
+        0xf => base_family + (signature >> 20 & 0xff),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xf => (signature >> 4 & 0xf) | (signature >> 12 & 0xf0),
+        _ => signature >> 4 & 0xf,
+    };
+    let mut features = Vec::new();
+    flags(edx, &FEATURES_EDX, &mut features);
+    flags(ecx, &FEATURES_ECX, &mut features);
+
+    let max_extended = cpuid(0x8000_0000, 0)[0];
+    if max_extended >= 0x8000_0001 {
+        flags(
+            cpuid(0x8000_0001, 0)[3],
+            &FEATURES_EXTENDED_EDX,
+            &mut features,
+        );
+    }
+    let model_name = if max_extended >= 0x8000_0004 {
+        let registers: Vec<u32> = (0x8000_0002..=0x8000_0004)
+            .flat_map(|leaf| cpuid(leaf, 0))
+            .collect();
+        ascii(&registers)
+    } else {
+        String::new()
+    };
+
+    CpuInfo {
+        vendor,
+        model_name,
+        family,
+        model,
+        stepping: signature & 0xf,
+        features,
+    }
+}
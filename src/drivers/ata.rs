@@ -0,0 +1,502 @@
+// ATA and ATAPI drives on the legacy IDE controller, using programmed I/O.
+//
+// Each of the two channels has its own I/O ports and IRQ line and can hold a master and
+// a slave drive. Drives are found with IDENTIFY (IDENTIFY PACKET DEVICE for ATAPI),
+// hard disks are then read and written with LBA28 or LBA48 commands and optical drives
+// with SCSI packets. Only one command can be in flight per channel, so every transfer
+// takes the channel lock. Completion is signalled by the channel interrupt; while
+// interrupts are disabled (during boot) the status register is polled instead.
+// Reference: https://wiki.osdev.org/ATA_PIO_Mode
+// Reference: https://wiki.osdev.org/ATAPI
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::instructions::{inb, interrupts_enabled, inw, outb, outw};
+use crate::interrupts::interrupts::{without_interrupts, PICS};
+use crate::locks::mutex::Mutex;
+use crate::println;
+use crate::process::scheduler;
+use crate::time;
+
+// Register offsets from the I/O base of a channel.
+const REG_DATA: u16 = 0;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_PACKET: u8 = 0xa0;
+const COMMAND_IDENTIFY_PACKET: u8 = 0xa1;
+const COMMAND_FLUSH_CACHE: u8 = 0xe7;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xa8;
+
+/// LBA_MID and LBA_HIGH after a failed IDENTIFY on a packet device.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+
+const SECTOR_SIZE: usize = 512;
+const ATAPI_SECTOR_SIZE: usize = 2048;
+/// Largest number of sectors transferred by one command.
+const MAX_SECTORS_PER_COMMAND: u64 = 256;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How long to wait for an interrupt before falling back to polling.
+const IRQ_TIMEOUT_TICKS: u64 = 2 * time::TICKS_PER_SECOND;
+/// Status reads before a drive that never clears BSY is given up on.
+const POLL_LIMIT: u32 = 1_000_000;
+
+struct Channel {
+    base: u16,
+    control: u16,
+    irq: u8,
+    /// Set by the interrupt handler, cleared before every command.
+    interrupted: AtomicBool,
+    lock: Mutex<()>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel {
+        base: 0x1f0,
+        control: 0x3f6,
+        irq: 14,
+        interrupted: AtomicBool::new(false),
+        lock: Mutex::new(()),
+    },
+    Channel {
+        base: 0x170,
+        control: 0x376,
+        irq: 15,
+        interrupted: AtomicBool::new(false),
+        lock: Mutex::new(()),
+    },
+];
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { inb(self.base + register) }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { outb(self.base + register, value) }
+    }
+
+    /// Reads the alternate status register, which does not acknowledge an interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { inb(self.control) }
+    }
+
+    /// Gives the drive the 400ns it needs to put a valid status on the bus.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn read_words(&self, buf: &mut [u8]) {
+        for chunk in buf.chunks_exact_mut(2) {
+            let word = unsafe { inw(self.base + REG_DATA) };
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn write_words(&self, buf: &[u8]) {
+        for chunk in buf.chunks_exact(2) {
+            unsafe {
+                outw(
+                    self.base + REG_DATA,
+                    u16::from_le_bytes([chunk[0], chunk[1]]),
+                )
+            };
+        }
+    }
+
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(REG_DRIVE, bits | (slave as u8) << 4);
+        self.delay();
+    }
+
+    /// Polls until the drive is no longer busy and returns its status.
+    fn poll(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(BlockError::Io);
+                }
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Io)
+    }
+
+    /// Waits for the drive to finish the current step of a command.
+    fn wait(&self) -> Result<u8, BlockError> {
+        if interrupts_enabled() {
+            let deadline = time::ticks() + IRQ_TIMEOUT_TICKS;
+            while !self.interrupted.swap(false, Ordering::Acquire) && time::ticks() < deadline {
+                scheduler::relax();
+            }
+        }
+        self.poll()
+    }
+
+    /// Waits until the drive wants to transfer data.
+    fn wait_data(&self) -> Result<(), BlockError> {
+        if self.wait()? & STATUS_DRQ == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    // Sends an IDENTIFY command and reads the 256 word answer. Returns None if there is
+    // no drive, or the device signature if the drive aborts the command.
+    fn identify(&self, slave: bool, command: u8) -> Result<[u8; 512], Option<(u8, u8)>> {
+        self.select(slave, 0xa0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.write(REG_COMMAND, command);
+        self.delay();
+        if self.read(REG_STATUS) == 0 {
+            return Err(None);
+        }
+
+        let status = self.poll();
+        let signature = (self.read(REG_LBA_MID), self.read(REG_LBA_HIGH));
+        match status {
+            Ok(status) if status & STATUS_DRQ != 0 => {
+                let mut data = [0; 512];
+                self.read_words(&mut data);
+                // Reading the status register acknowledges the interrupt.
+                self.read(REG_STATUS);
+                self.interrupted.store(false, Ordering::Release);
+                Ok(data)
+            }
+            _ => Err(Some(signature)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DriveKind {
+    Ata,
+    Atapi,
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Channel,
+    slave: bool,
+    kind: DriveKind,
+    lba48: bool,
+    sectors: u64,
+    model: String,
+}
+
+fn identify_word(data: &[u8; 512], index: usize) -> u16 {
+    u16::from_le_bytes([data[index * 2], data[index * 2 + 1]])
+}
+
+// ATA strings store two characters per word with the first one in the high byte.
+fn identify_string(data: &[u8; 512], words: core::ops::Range<usize>) -> String {
+    let mut string = String::new();
+    for index in words {
+        let [low, high] = identify_word(data, index).to_le_bytes();
+        string.push(high as char);
+        string.push(low as char);
+    }
+    String::from(string.trim())
+}
+
+impl AtaDrive {
+    fn probe(name: String, channel: &'static Channel, slave: bool) -> Option<AtaDrive> {
+        let (kind, data) = match channel.identify(slave, COMMAND_IDENTIFY) {
+            Ok(data) => (DriveKind::Ata, data),
+            Err(Some(ATAPI_SIGNATURE)) => (
+                DriveKind::Atapi,
+                channel.identify(slave, COMMAND_IDENTIFY_PACKET).ok()?,
+            ),
+            Err(_) => return None,
+        };
+
+        let lba48 = identify_word(&data, 83) & (1 << 10) != 0;
+        let lba28_sectors =
+            identify_word(&data, 60) as u64 | (identify_word(&data, 61) as u64) << 16;
+        let lba48_sectors = (100..104).rev().fold(0, |sectors, index| {
+            sectors << 16 | identify_word(&data, index) as u64
+        });
+
+        let mut drive = AtaDrive {
+            name,
+            channel,
+            slave,
+            kind,
+            lba48,
+            sectors: if lba48 { lba48_sectors } else { lba28_sectors },
+            model: identify_string(&data, 27..47),
+        };
+        if kind == DriveKind::Atapi {
+            // There may be no disc in the drive, which leaves it empty.
+            drive.sectors = drive.read_capacity().unwrap_or(0);
+        }
+        Some(drive)
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn is_atapi(&self) -> bool {
+        self.kind == DriveKind::Atapi
+    }
+
+    // Programs the task file for a transfer of `count` sectors at `lba` and issues
+    // `command`, using the 48 bit registers if needed.
+    fn issue(
+        &self,
+        lba: u64,
+        count: u64,
+        lba28_command: u8,
+        lba48_command: u8,
+    ) -> Result<(), BlockError> {
+        let channel = self.channel;
+        channel.poll()?;
+        channel.interrupted.store(false, Ordering::Release);
+
+        if lba + count > LBA28_LIMIT {
+            if !self.lba48 {
+                return Err(BlockError::OutOfRange);
+            }
+            channel.select(self.slave, 0x40);
+            // The high bytes go in first, the registers work like a two entry FIFO.
+            channel.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(REG_LBA_LOW, (lba >> 24) as u8);
+            channel.write(REG_LBA_MID, (lba >> 32) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 40) as u8);
+            channel.write(REG_SECTOR_COUNT, count as u8);
+            channel.write(REG_LBA_LOW, lba as u8);
+            channel.write(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.write(REG_COMMAND, lba48_command);
+        } else {
+            channel.select(self.slave, 0xe0 | (lba >> 24) as u8 & 0x0f);
+            // A count of 0 means 256 sectors.
+            channel.write(REG_SECTOR_COUNT, count as u8);
+            channel.write(REG_LBA_LOW, lba as u8);
+            channel.write(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.write(REG_COMMAND, lba28_command);
+        }
+        Ok(())
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = buf.len() as u64 / SECTOR_SIZE as u64;
+        self.issue(lba, count, COMMAND_READ_SECTORS, COMMAND_READ_SECTORS_EXT)?;
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.channel.wait_data()?;
+            self.channel.read_words(sector);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = buf.len() as u64 / SECTOR_SIZE as u64;
+        self.issue(lba, count, COMMAND_WRITE_SECTORS, COMMAND_WRITE_SECTORS_EXT)?;
+        for sector in buf.chunks_exact(SECTOR_SIZE) {
+            // The drive asks for the first sector without raising an interrupt.
+            if self.channel.poll()? & STATUS_DRQ == 0 {
+                return Err(BlockError::Io);
+            }
+            self.channel.interrupted.store(false, Ordering::Release);
+            self.channel.write_words(sector);
+            self.channel.wait()?;
+        }
+        Ok(())
+    }
+
+    // Sends a SCSI command to a packet device and reads up to `buf.len()` bytes of
+    // response. Returns the number of bytes received.
+    fn packet(&self, command: &[u8; 12], buf: &mut [u8]) -> Result<usize, BlockError> {
+        let channel = self.channel;
+        channel.select(self.slave, 0xa0);
+        channel.poll()?;
+        channel.interrupted.store(false, Ordering::Release);
+
+        // PIO mode, with the largest chunk the drive may send per interrupt.
+        let limit = buf.len().min(0xfffe) as u16;
+        channel.write(REG_FEATURES, 0);
+        channel.write(REG_LBA_MID, limit as u8);
+        channel.write(REG_LBA_HIGH, (limit >> 8) as u8);
+        channel.write(REG_COMMAND, COMMAND_PACKET);
+        if channel.poll()? & STATUS_DRQ == 0 {
+            return Err(BlockError::Io);
+        }
+        channel.write_words(command);
+
+        let mut received = 0;
+        loop {
+            if channel.wait()? & STATUS_DRQ == 0 {
+                return Ok(received);
+            }
+            let size =
+                channel.read(REG_LBA_MID) as usize | (channel.read(REG_LBA_HIGH) as usize) << 8;
+            let end = received + size;
+            if end > buf.len() {
+                return Err(BlockError::Io);
+            }
+            channel.read_words(&mut buf[received..end]);
+            received = end;
+        }
+    }
+
+    fn read_capacity(&self) -> Result<u64, BlockError> {
+        let _guard = block::lock(&self.channel.lock);
+        let mut command = [0; 12];
+        command[0] = SCSI_READ_CAPACITY;
+        let mut response = [0; 8];
+        if self.packet(&command, &mut response)? != response.len() {
+            return Err(BlockError::Io);
+        }
+        // The address of the last block, big-endian.
+        let last = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+        Ok(last as u64 + 1)
+    }
+
+    fn read_packet_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = (buf.len() / ATAPI_SECTOR_SIZE) as u32;
+        let mut command = [0; 12];
+        command[0] = SCSI_READ_12;
+        command[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+        command[6..10].copy_from_slice(&count.to_be_bytes());
+        if self.packet(&command, buf)? != buf.len() {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        match self.kind {
+            DriveKind::Ata => SECTOR_SIZE,
+            DriveKind::Atapi => ATAPI_SECTOR_SIZE,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.kind == DriveKind::Atapi
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        let _guard = block::lock(&self.channel.lock);
+        let chunk_size = MAX_SECTORS_PER_COMMAND as usize * self.block_size();
+
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let lba = lba + index as u64 * MAX_SECTORS_PER_COMMAND;
+            match self.kind {
+                DriveKind::Ata => self.read_sectors(lba, chunk)?,
+                DriveKind::Atapi => self.read_packet_sectors(lba, chunk)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self, lba, buf.len())?;
+        let _guard = block::lock(&self.channel.lock);
+        let chunk_size = MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE;
+
+        for (index, chunk) in buf.chunks(chunk_size).enumerate() {
+            self.write_sectors(lba + index as u64 * MAX_SECTORS_PER_COMMAND, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let _guard = block::lock(&self.channel.lock);
+        let channel = self.channel;
+        channel.select(self.slave, 0xa0);
+        channel.poll()?;
+        channel.interrupted.store(false, Ordering::Release);
+        let command = if self.lba48 {
+            COMMAND_FLUSH_CACHE_EXT
+        } else {
+            COMMAND_FLUSH_CACHE
+        };
+        channel.write(REG_COMMAND, command);
+        channel.wait()?;
+        Ok(())
+    }
+}
+
+/// Called from the IRQ 14 and 15 handlers with the channel number.
+pub fn handle_interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    // Reading the status register acknowledges the interrupt at the drive.
+    channel.read(REG_STATUS);
+    channel.interrupted.store(true, Ordering::Release);
+}
+
+/// Detects the drives on both channels and registers them as `hda` to `hdd`.
+pub fn init() {
+    println!("[!] Probing ATA drives");
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        // A floating bus reads as all ones: there is no controller or no drive at all.
+        if channel.alt_status() == 0xff {
+            continue;
+        }
+        for slave in [false, true] {
+            let name = format!("hd{}", (b'a' + index as u8 * 2 + slave as u8) as char);
+            let drive = match AtaDrive::probe(name, channel, slave) {
+                Some(drive) => drive,
+                None => continue,
+            };
+            let size = drive.block_count() * drive.block_size() as u64;
+            println!(
+                "    [+] {}: {} {} ({})",
+                drive.name,
+                if drive.is_atapi() { "ATAPI" } else { "ATA" },
+                drive.model,
+                block::format_size(size)
+            );
+            block::add_disk(Arc::new(drive));
+        }
+        without_interrupts(|| unsafe { PICS.lock().unmask(channel.irq) });
+    }
+    println!("    [+] Done");
+}
@@ -0,0 +1,394 @@
+// Intel 82540EM (e1000) network cards, QEMU's default network card.
+//
+// The registers are memory mapped through BAR0. Frames are exchanged through two rings
+// of descriptors in DMA memory: the card fills receive buffers starting at the head and
+// the driver hands them back by moving the tail, while for transmitting the driver
+// fills descriptors at the tail and the card sends and marks them done. The MAC address
+// comes from the EEPROM. Interrupts use MSI when the card has it and the legacy line
+// otherwise; they acknowledge the card and bring the link back up after a change, while
+// frames are taken by polling.
+// Reference: https://wiki.osdev.org/Intel_Ethernet_i217
+// Reference: https://www.intel.com/content/dam/doc/manual/pci-pci-x-family-gbe-controllers-software-dev-manual.pdf
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::msi;
+use super::pci::{self, DeviceId, PciDevice, PciDriver};
+use crate::interrupts::apic;
+use crate::interrupts::interrupts::{register_irq_handler, without_interrupts};
+use crate::locks::mutex::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::net::{self, Counters, MacAddress, NetDevice, NetError, NetStats, MAX_FRAME_SIZE};
+use crate::println;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    // 82540EM desktop, as emulated by QEMU, and 82545EM copper.
+    ids: &[
+        DeviceId::Device(0x8086, 0x100e),
+        DeviceId::Device(0x8086, 0x100f),
+    ],
+    probe,
+};
+
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+// Interrupt causes.
+const ICR_LSC: u32 = 1 << 2;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the frame check sequence.
+const RCTL_SECRC: u32 = 1 << 26;
+// The buffer size field of 0 selects 2048 byte buffers.
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Recommended inter packet gap for IEEE 802.3.
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+// Descriptor status and command bits.
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const RX_END_OF_PACKET: u8 = 1 << 1;
+const TX_END_OF_PACKET: u8 = 1 << 0;
+const TX_INSERT_FCS: u8 = 1 << 1;
+const TX_REPORT_STATUS: u8 = 1 << 3;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const RX_DESCRIPTORS: usize = 32;
+const TX_DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+
+/// Register reads before a reset or EEPROM read is given up on.
+const POLL_LIMIT: u32 = 100_000;
+
+// A ring of descriptors and the buffers they point to, `buffers[i]` for descriptor i.
+struct Ring {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    /// Next descriptor to look at (receive) or fill (transmit).
+    next: usize,
+}
+
+impl Ring {
+    fn new(count: usize) -> Option<Ring> {
+        Some(Ring {
+            descriptors: DmaBuffer::new(count * DESCRIPTOR_SIZE)?,
+            buffers: DmaBuffer::new(count * BUFFER_SIZE)?,
+            next: 0,
+        })
+    }
+
+    fn buffer_address(&self, index: usize) -> u64 {
+        self.buffers.phys().as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    // Both kinds of descriptor have the buffer address first and the status in byte 12.
+    fn status(&self, index: usize) -> u8 {
+        unsafe {
+            self.descriptors
+                .ptr::<u8>(index * DESCRIPTOR_SIZE + 12)
+                .read_volatile()
+        }
+    }
+
+    fn write(&self, index: usize, address: u64, high: u64) {
+        unsafe {
+            self.descriptors
+                .ptr::<u64>(index * DESCRIPTOR_SIZE)
+                .write_volatile(address);
+            self.descriptors
+                .ptr::<u64>(index * DESCRIPTOR_SIZE + 8)
+                .write_volatile(high);
+        }
+    }
+}
+
+pub struct E1000 {
+    name: String,
+    registers: VirtAddr,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    counters: Counters,
+}
+
+/// Cards that raise interrupts, for the handlers.
+static CARDS: Mutex<Vec<Arc<E1000>>> = Mutex::new(Vec::new());
+
+impl E1000 {
+    fn read(&self, register: usize) -> u32 {
+        unsafe {
+            (self.registers + register as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe {
+            (self.registers + register as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    fn reset(&self) -> Result<(), &'static str> {
+        self.write(REG_IMC, u32::MAX);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_RST);
+        if !(0..POLL_LIMIT).any(|_| self.read(REG_CTRL) & CTRL_RST == 0) {
+            return Err("reset did not complete");
+        }
+        // Interrupts are enabled again by the reset.
+        self.write(REG_IMC, u32::MAX);
+        self.read(REG_ICR);
+        Ok(())
+    }
+
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(REG_EERD, EERD_START | (word as u32) << 8);
+        (0..POLL_LIMIT).find_map(|_| {
+            let value = self.read(REG_EERD);
+            (value & EERD_DONE != 0).then_some((value >> 16) as u16)
+        })
+    }
+
+    // The MAC address is in the first three EEPROM words. Without an EEPROM, whatever
+    // the firmware left in the first receive address register is used.
+    fn read_mac_address(&self) -> MacAddress {
+        let words: Option<Vec<u16>> = (0..3).map(|word| self.read_eeprom(word)).collect();
+        let mut mac = [0; 6];
+        match words {
+            Some(words) => {
+                for (index, word) in words.iter().enumerate() {
+                    mac[index * 2..index * 2 + 2].copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            None => {
+                mac[..4].copy_from_slice(&self.read(REG_RAL).to_le_bytes());
+                mac[4..].copy_from_slice(&self.read(REG_RAH).to_le_bytes()[..2]);
+            }
+        }
+        MacAddress(mac)
+    }
+
+    fn setup(&self) {
+        let [a, b, c, d, e, f] = self.mac.0;
+        self.write(REG_RAL, u32::from_le_bytes([a, b, c, d]));
+        // Bit 31 marks the address valid.
+        self.write(REG_RAH, u16::from_le_bytes([e, f]) as u32 | 1 << 31);
+        for index in 0..128 {
+            self.write(REG_MTA + index * 4, 0);
+        }
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        let rx = self.rx.lock();
+        for index in 0..RX_DESCRIPTORS {
+            rx.write(index, rx.buffer_address(index), 0);
+        }
+        let base = rx.descriptors.phys().as_u64();
+        self.write(REG_RDBAL, base as u32);
+        self.write(REG_RDBAH, (base >> 32) as u32);
+        self.write(REG_RDLEN, (RX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_RDH, 0);
+        // The card owns every descriptor but one: head equal to tail means the ring is
+        // empty.
+        self.write(REG_RDT, (RX_DESCRIPTORS - 1) as u32);
+        self.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx = self.tx.lock();
+        for index in 0..TX_DESCRIPTORS {
+            // Marked done so they count as free.
+            tx.write(
+                index,
+                tx.buffer_address(index),
+                (DESCRIPTOR_DONE as u64) << 32,
+            );
+        }
+        let base = tx.descriptors.phys().as_u64();
+        self.write(REG_TDBAL, base as u32);
+        self.write(REG_TDBAH, (base >> 32) as u32);
+        self.write(REG_TDLEN, (TX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_TDH, 0);
+        self.write(REG_TDT, 0);
+        self.write(REG_TIPG, TIPG_DEFAULT);
+        self.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    }
+
+    // Received frames are polled, so only link changes interrupt.
+    fn enable_interrupts(&self) {
+        self.write(REG_IMS, ICR_LSC);
+    }
+
+    // Acknowledges the interrupt causes of the card, reading ICR clears them. The line
+    // may be shared, so there may be none.
+    fn handle_interrupt(&self) {
+        let causes = self.read(REG_ICR);
+        if causes & ICR_LSC != 0 {
+            self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_SLU);
+        }
+    }
+}
+
+impl NetDevice for E1000 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        net::check_frame(frame)?;
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        if tx.status(index) & DESCRIPTOR_DONE == 0 {
+            self.counters.send_failed();
+            return Err(NetError::Busy);
+        }
+        tx.buffers.write(index * BUFFER_SIZE, frame);
+        let command = TX_END_OF_PACKET | TX_INSERT_FCS | TX_REPORT_STATUS;
+        // Length in bits 0-15, command in 24-31, status (cleared) in 32-39.
+        tx.write(
+            index,
+            tx.buffer_address(index),
+            frame.len() as u64 | (command as u64) << 24,
+        );
+        tx.next = (index + 1) % TX_DESCRIPTORS;
+        self.write(REG_TDT, tx.next as u32);
+        self.counters.sent(frame.len());
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let status = rx.status(index);
+            if status & DESCRIPTOR_DONE == 0 {
+                return None;
+            }
+            let (length, errors) = unsafe {
+                let descriptor = index * DESCRIPTOR_SIZE;
+                (
+                    rx.descriptors.ptr::<u16>(descriptor + 8).read_volatile() as usize,
+                    rx.descriptors.ptr::<u8>(descriptor + 13).read_volatile(),
+                )
+            };
+            // Frames that span buffers only happen beyond the maximum size.
+            let frame = match length {
+                1..=MAX_FRAME_SIZE if status & RX_END_OF_PACKET != 0 && errors == 0 => {
+                    let mut frame = vec![0; length];
+                    rx.buffers.read(index * BUFFER_SIZE, &mut frame);
+                    self.counters.received(length);
+                    Some(frame)
+                }
+                _ => {
+                    self.counters.dropped();
+                    None
+                }
+            };
+            rx.write(index, rx.buffer_address(index), 0);
+            rx.next = (index + 1) % RX_DESCRIPTORS;
+            // The descriptor is the card's again.
+            self.write(REG_RDT, index as u32);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+
+    fn stats(&self) -> NetStats {
+        self.counters.stats()
+    }
+}
+
+fn interrupt_handler(_vector: u8) {
+    for card in CARDS.lock().iter() {
+        card.handle_interrupt();
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    device.enable(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+    let (registers, _) = device.map_bar(0).ok_or("no register BAR")?;
+    let mut card = E1000 {
+        name: String::new(),
+        registers,
+        mac: MacAddress::ZERO,
+        rx: Mutex::new(Ring::new(RX_DESCRIPTORS).ok_or("out of memory for the rings")?),
+        tx: Mutex::new(Ring::new(TX_DESCRIPTORS).ok_or("out of memory for the rings")?),
+        counters: Counters::default(),
+    };
+    card.reset()?;
+    card.mac = card.read_mac_address();
+    card.setup();
+    card.name = net::next_ethernet_name();
+
+    let card = Arc::new(card);
+    without_interrupts(|| CARDS.lock().push(card.clone()));
+    let interrupt = match msi::enable(device, apic::id(), "e1000", interrupt_handler) {
+        Ok(vector) => alloc::format!("MSI vector {}", vector),
+        Err(_) if register_irq_handler(device.interrupt_line, "e1000", interrupt_handler) => {
+            alloc::format!("IRQ {}", device.interrupt_line)
+        }
+        Err(_) => String::from("no interrupt"),
+    };
+    card.enable_interrupts();
+    println!(
+        "    [+] {}: e1000 at {}, {}, {}, link {}",
+        card.name,
+        device.address,
+        card.mac,
+        interrupt,
+        if card.link_up() { "up" } else { "down" }
+    );
+    net::register(card);
+    Ok(())
+}
+
+/// Registers the driver with the PCI bus.
+pub fn init() {
+    println!("[!] Starting e1000 driver");
+    pci::register_driver(&DRIVER);
+    println!("    [+] Done");
+}
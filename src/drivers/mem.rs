@@ -0,0 +1,87 @@
+// The memory character devices: /dev/null, /dev/zero and /dev/random.
+//
+// Random numbers come from RDRAND when the CPU has it. Otherwise a xorshift generator
+// seeded from the time stamp counter stands in, which is fine for picking port numbers
+// and the like but nothing that has to be unpredictable.
+// Reference: https://www.felixcloutier.com/x86/rdrand
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::random::RdRand;
+
+use crate::fs::devfs::{self, Device};
+use crate::fs::FsError;
+
+/// Discards writes and reads nothing.
+struct Null;
+
+impl Device for Null {
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Discards writes and reads zeros.
+struct Zero;
+
+impl Device for Zero {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads random bytes. Writes are accepted and ignored.
+struct Random;
+
+impl Device for Random {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+static XORSHIFT_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns 64 random bits.
+pub fn random_u64() -> u64 {
+    if let Some(value) = RdRand::new().and_then(RdRand::get_u64) {
+        return value;
+    }
+    let mut state = XORSHIFT_STATE.load(Ordering::Relaxed);
+    if state == 0 {
+        // The state must never be zero.
+        state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    }
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    XORSHIFT_STATE.store(state, Ordering::Relaxed);
+    state
+}
+
+/// Publishes the memory devices.
+pub fn init() {
+    let devices: [(&str, Arc<dyn Device>); 3] = [
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+        ("random", Arc::new(Random)),
+    ];
+    for (name, device) in devices {
+        devfs::register(name, device).expect("memory device registered twice");
+    }
+}
@@ -0,0 +1,7 @@
+pub mod ata;
+pub mod e1000;
+pub mod mem;
+pub mod msi;
+pub mod pci;
+pub mod tty;
+pub mod virtio;
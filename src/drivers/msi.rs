@@ -0,0 +1,211 @@
+// Message signalled interrupts for PCI devices.
+//
+// Instead of pulling one of the few, shared legacy interrupt lines, a function that
+// supports MSI or MSI-X raises an interrupt by writing a value to an address: the
+// address names a local APIC and the value the vector. MSI has one such pair in its
+// capability in configuration space; MSI-X has a table of them in one of the BARs, one
+// entry per interrupt source, e.g. a queue. The vectors come from the runtime handler
+// registry, so every source gets a handler of its own.
+// Reference: https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::VirtAddr;
+
+use super::pci::{self, PciDevice};
+use crate::interrupts::apic;
+use crate::interrupts::interrupts::{register_handler, unregister_handler, Handler};
+
+// MSI capability.
+const MSI_CONTROL: u8 = 0x02;
+const MSI_ADDRESS: u8 = 0x04;
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_CONTROL_64_BIT: u16 = 1 << 7;
+
+// MSI-X capability.
+const MSIX_CONTROL: u8 = 0x02;
+const MSIX_TABLE: u8 = 0x04;
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_BIR: u32 = 0x7;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has no such capability, or there is no local APIC to deliver to.
+    NotSupported,
+    /// Every vector of the handler registry is taken.
+    NoFreeVectors,
+    /// More interrupts were asked for than the MSI-X table has entries.
+    TooManyVectors,
+    /// The MSI-X table is not in a memory BAR.
+    BadTable,
+}
+
+impl fmt::Display for MsiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MsiError::NotSupported => "message signalled interrupts not supported",
+            MsiError::NoFreeVectors => "no free interrupt vectors",
+            MsiError::TooManyVectors => "too many interrupt vectors",
+            MsiError::BadTable => "MSI-X table is not accessible",
+        })
+    }
+}
+
+// Allocates a vector for each handler, all or none.
+fn allocate(handlers: &[(&'static str, Handler)]) -> Result<Vec<u8>, MsiError> {
+    let mut vectors = Vec::new();
+    for (name, handler) in handlers {
+        match register_handler(name, *handler) {
+            Some(vector) => vectors.push(vector),
+            None => {
+                vectors.into_iter().for_each(unregister_handler);
+                return Err(MsiError::NoFreeVectors);
+            }
+        }
+    }
+    Ok(vectors)
+}
+
+/// Routes the interrupt of `device` to `handler` on the local APIC `apic_id` through its
+/// MSI capability, and returns the vector allocated for it. Legacy interrupts of the
+/// function are switched off.
+pub fn enable_msi(
+    device: &PciDevice,
+    apic_id: u8,
+    name: &'static str,
+    handler: Handler,
+) -> Result<u8, MsiError> {
+    let cap = device
+        .find_capability(pci::CAPABILITY_MSI)
+        .ok_or(MsiError::NotSupported)?;
+    if !apic::is_enabled() {
+        return Err(MsiError::NotSupported);
+    }
+    let vector = allocate(&[(name, handler)])?[0];
+
+    let control = device.read_u16(cap + MSI_CONTROL);
+    let data = match control & MSI_CONTROL_64_BIT {
+        0 => cap + 0x08,
+        _ => {
+            device.write_u32(cap + 0x08, 0);
+            cap + 0x0c
+        }
+    };
+    device.write_u32(cap + MSI_ADDRESS, apic::msi_address(apic_id));
+    // Edge triggered, fixed delivery.
+    device.write_u16(data, vector as u16);
+    // A single message, so the vector is not altered by the function.
+    let control = (control & !MSI_CONTROL_MULTIPLE_ENABLE) | MSI_CONTROL_ENABLE;
+    device.write_u16(cap + MSI_CONTROL, control);
+    device.enable(pci::COMMAND_INTERRUPT_DISABLE);
+    Ok(vector)
+}
+
+/// The MSI-X table of a function.
+pub struct MsiX {
+    table: VirtAddr,
+    size: u16,
+}
+
+impl MsiX {
+    fn entry(&self, index: u16, word: u64) -> *mut u32 {
+        (self.table + index as u64 * MSIX_ENTRY_SIZE + word * 4).as_mut_ptr()
+    }
+
+    /// Number of entries in the table.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Stops or resumes delivery of the interrupts of entry `index`.
+    pub fn set_masked(&self, index: u16, masked: bool) {
+        if index >= self.size {
+            return;
+        }
+        let control = self.entry(index, 3);
+        unsafe {
+            let value = control.read_volatile() & !MSIX_VECTOR_MASKED;
+            control.write_volatile(value | if masked { MSIX_VECTOR_MASKED } else { 0 });
+        }
+    }
+}
+
+/// Routes MSI-X table entries 0, 1, ... of `device` to `handlers`, each with a vector of
+/// its own, on the local APIC `apic_id`. Returns the table and the allocated vectors in
+/// order. Entries beyond the handlers stay masked.
+pub fn enable_msix(
+    device: &PciDevice,
+    apic_id: u8,
+    handlers: &[(&'static str, Handler)],
+) -> Result<(MsiX, Vec<u8>), MsiError> {
+    let cap = device
+        .find_capability(pci::CAPABILITY_MSI_X)
+        .ok_or(MsiError::NotSupported)?;
+    if !apic::is_enabled() {
+        return Err(MsiError::NotSupported);
+    }
+    let control = device.read_u16(cap + MSIX_CONTROL);
+    let size = (control & MSIX_CONTROL_TABLE_SIZE) + 1;
+    if handlers.len() > size as usize {
+        return Err(MsiError::TooManyVectors);
+    }
+
+    let table = device.read_u32(cap + MSIX_TABLE);
+    let offset = (table & !MSIX_TABLE_BIR) as u64;
+    let (bar, bar_size) = device
+        .map_bar((table & MSIX_TABLE_BIR) as usize)
+        .ok_or(MsiError::BadTable)?;
+    if offset + size as u64 * MSIX_ENTRY_SIZE > bar_size {
+        return Err(MsiError::BadTable);
+    }
+    let msix = MsiX {
+        table: bar + offset,
+        size,
+    };
+    let vectors = allocate(handlers)?;
+
+    // Enabled with the function mask set, so nothing fires while the table is half
+    // written.
+    device.enable(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_INTERRUPT_DISABLE);
+    device.write_u16(
+        cap + MSIX_CONTROL,
+        control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+    );
+    for index in 0..size {
+        msix.set_masked(index, true);
+    }
+    for (index, vector) in vectors.iter().enumerate() {
+        let index = index as u16;
+        unsafe {
+            msix.entry(index, 0)
+                .write_volatile(apic::msi_address(apic_id));
+            msix.entry(index, 1).write_volatile(0);
+            msix.entry(index, 2).write_volatile(*vector as u32);
+        }
+        msix.set_masked(index, false);
+    }
+    device.write_u16(
+        cap + MSIX_CONTROL,
+        (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK,
+    );
+    Ok((msix, vectors))
+}
+
+/// Routes the interrupt of `device` to `handler` on the local APIC `apic_id`, through
+/// MSI-X entry 0 if the function supports it and MSI otherwise.
+pub fn enable(
+    device: &PciDevice,
+    apic_id: u8,
+    name: &'static str,
+    handler: Handler,
+) -> Result<u8, MsiError> {
+    match enable_msix(device, apic_id, &[(name, handler)]) {
+        Ok((_, vectors)) => Ok(vectors[0]),
+        Err(MsiError::NotSupported) => enable_msi(device, apic_id, name, handler),
+        Err(error) => Err(error),
+    }
+}
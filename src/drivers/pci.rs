@@ -0,0 +1,619 @@
+// The PCI bus.
+//
+// Every function of every device has a 256 byte configuration space that tells what it
+// is and where its registers are. It is reached through the legacy mechanism, an
+// address written to port 0xCF8 and the data at 0xCFC, or through ECAM, where all of it
+// is mapped to memory at the address the ACPI MCFG table gives. ECAM is used when the
+// firmware provides it.
+//
+// At boot every bus, device and function is probed once and the functions found are
+// kept with their base address registers (BARs), sized by writing all ones to them, and
+// their capability lists. Drivers register with the IDs or classes they support and are
+// handed every matching function that no other driver claimed yet.
+// Reference: https://wiki.osdev.org/PCI
+// Reference: https://wiki.osdev.org/PCI_Express
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::instructions::{inl, outl};
+use crate::interrupts::interrupts::without_interrupts;
+use crate::locks::mutex::Mutex;
+use crate::memory::mmio;
+use crate::println;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Configuration space registers.
+const REG_VENDOR_ID: u8 = 0x00;
+const REG_DEVICE_ID: u8 = 0x02;
+const REG_COMMAND: u8 = 0x04;
+const REG_STATUS: u8 = 0x06;
+const REG_REVISION: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0e;
+const REG_BAR0: u8 = 0x10;
+const REG_SUBSYSTEM_VENDOR_ID: u8 = 0x2c;
+const REG_SUBSYSTEM_ID: u8 = 0x2e;
+const REG_CAPABILITIES: u8 = 0x34;
+const REG_INTERRUPT_LINE: u8 = 0x3c;
+const REG_INTERRUPT_PIN: u8 = 0x3d;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_GENERAL: u8 = 0;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+/// Size of the MCFG header up to the first allocation.
+const MCFG_ENTRIES: usize = 44;
+
+/// Where a function sits on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// How configuration space is reached.
+enum Access {
+    Legacy,
+    Ecam {
+        base: VirtAddr,
+        start_bus: u8,
+        end_bus: u8,
+    },
+}
+
+static ACCESS: Mutex<Access> = Mutex::new(Access::Legacy);
+
+// Returns the mapped configuration space of `address` if it is reachable through ECAM.
+fn ecam_address(address: Address, offset: u8) -> Option<VirtAddr> {
+    match *ACCESS.lock() {
+        Access::Ecam {
+            base,
+            start_bus,
+            end_bus,
+        } if (start_bus..=end_bus).contains(&address.bus) => {
+            let function = ((address.bus - start_bus) as u64) << 20
+                | (address.device as u64) << 15
+                | (address.function as u64) << 12;
+            Some(base + function + (offset & 0xfc) as u64)
+        }
+        _ => None,
+    }
+}
+
+fn legacy_address(address: Address, offset: u8) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xfc) as u32
+}
+
+/// Reads the aligned double word at `offset` of the configuration space of `address`.
+pub fn read_u32(address: Address, offset: u8) -> u32 {
+    if let Some(addr) = ecam_address(address, offset) {
+        return unsafe { addr.as_ptr::<u32>().read_volatile() };
+    }
+    // The address and data ports have to be used as a pair.
+    without_interrupts(|| {
+        let _access = ACCESS.lock();
+        unsafe {
+            outl(CONFIG_ADDRESS, legacy_address(address, offset));
+            inl(CONFIG_DATA)
+        }
+    })
+}
+
+/// Writes the aligned double word at `offset` of the configuration space of `address`.
+pub fn write_u32(address: Address, offset: u8, value: u32) {
+    if let Some(addr) = ecam_address(address, offset) {
+        return unsafe { addr.as_mut_ptr::<u32>().write_volatile(value) };
+    }
+    without_interrupts(|| {
+        let _access = ACCESS.lock();
+        unsafe {
+            outl(CONFIG_ADDRESS, legacy_address(address, offset));
+            outl(CONFIG_DATA, value);
+        }
+    })
+}
+
+pub fn read_u16(address: Address, offset: u8) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: Address, offset: u8) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Writes a word by reading and writing back the double word around it.
+pub fn write_u16(address: Address, offset: u8, value: u16) {
+    let shift = (offset & 2) * 8;
+    let old = read_u32(address, offset) & !(0xffff << shift);
+    write_u32(address, offset, old | (value as u32) << shift);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        wide: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                wide,
+            } => write!(
+                f,
+                "memory at {:#x} ({}-bit, {}prefetchable) [size={}]",
+                address,
+                if wide { 64 } else { 32 },
+                if prefetchable { "" } else { "non-" },
+                crate::block::format_size(size)
+            ),
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x} [size={}]", port, size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in configuration space.
+    pub offset: u8,
+}
+
+/// What a driver matches on.
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceId {
+    /// Vendor and device ID.
+    Device(u16, u16),
+    /// Class and subclass.
+    Class(u8, u8),
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [DeviceId],
+    /// Sets the device up. An error leaves the device to other drivers.
+    pub probe: fn(&Arc<PciDevice>) -> Result<(), &'static str>,
+}
+
+/// A function found on the bus.
+pub struct PciDevice {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// The legacy interrupt line the firmware routed the function to.
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 for none.
+    pub interrupt_pin: u8,
+    driver: Mutex<Option<&'static str>>,
+}
+
+impl PciDevice {
+    fn probe(address: Address) -> Option<PciDevice> {
+        let vendor_id = read_u16(address, REG_VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let class = read_u32(address, REG_REVISION);
+        let header_type = read_u8(address, REG_HEADER_TYPE) & HEADER_TYPE_MASK;
+        let general = header_type == HEADER_TYPE_GENERAL;
+
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: read_u16(address, REG_DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            subsystem_vendor_id: if general {
+                read_u16(address, REG_SUBSYSTEM_VENDOR_ID)
+            } else {
+                0
+            },
+            subsystem_id: if general {
+                read_u16(address, REG_SUBSYSTEM_ID)
+            } else {
+                0
+            },
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            interrupt_line: read_u8(address, REG_INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, REG_INTERRUPT_PIN),
+            driver: Mutex::new(None),
+        };
+        // Bridges only have two BARs.
+        device.size_bars(if general { 6 } else { 2 });
+        device.capabilities = device.read_capabilities();
+        Some(device)
+    }
+
+    // Finds the size of every BAR by writing all ones and seeing which bits stick.
+    // Decoding is switched off meanwhile, so the device does not answer at the bogus
+    // address.
+    fn size_bars(&mut self, count: usize) {
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(
+            REG_COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let mut index = 0;
+        while index < count {
+            let offset = REG_BAR0 + index as u8 * 4;
+            let original = self.read_u32(offset);
+            self.write_u32(offset, 0xffff_ffff);
+            let mask = self.read_u32(offset);
+            self.write_u32(offset, original);
+
+            if original & 1 == 1 {
+                let size = !(mask & 0xffff_fffc) & 0xffff;
+                if mask != 0 {
+                    self.bars[index] = Some(Bar::Io {
+                        port: (original & 0xffff_fffc) as u16,
+                        size: size + 1,
+                    });
+                }
+                index += 1;
+                continue;
+            }
+
+            let wide = original >> 1 & 3 == 2;
+            let mut address = (original & 0xffff_fff0) as u64;
+            let mut mask = (mask & 0xffff_fff0) as u64;
+            if wide && index + 1 < count {
+                let high = offset + 4;
+                let original_high = self.read_u32(high);
+                self.write_u32(high, 0xffff_ffff);
+                mask |= (self.read_u32(high) as u64) << 32;
+                self.write_u32(high, original_high);
+                address |= (original_high as u64) << 32;
+            } else {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+            if mask & 0xffff_fff0 != 0 {
+                self.bars[index] = Some(Bar::Memory {
+                    address,
+                    size: !mask + 1,
+                    prefetchable: original & 8 != 0,
+                    wide,
+                });
+            }
+            index += if wide { 2 } else { 1 };
+        }
+        self.write_u16(REG_COMMAND, command);
+    }
+
+    fn read_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = self.read_u8(REG_CAPABILITIES) & 0xfc;
+        // A broken list could loop; there is only room for 48 capabilities.
+        while offset >= 0x40 && capabilities.len() < 48 {
+            capabilities.push(Capability {
+                id: self.read_u8(offset),
+                offset,
+            });
+            offset = self.read_u8(offset + 1) & 0xfc;
+        }
+        capabilities
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        read_u32(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        read_u16(self.address, offset)
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        read_u8(self.address, offset)
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        write_u32(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        write_u16(self.address, offset, value)
+    }
+
+    /// Returns the offset of the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// Sets bits in the command register, e.g. to enable memory decoding and DMA.
+    pub fn enable(&self, bits: u16) {
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, command | bits);
+    }
+
+    /// Maps a memory BAR and returns its virtual address and size.
+    pub fn map_bar(&self, index: usize) -> Option<(VirtAddr, u64)> {
+        match self.bars.get(index)? {
+            Some(Bar::Memory { address, size, .. }) => {
+                Some((mmio::map(PhysAddr::new(*address), *size)?, *size))
+            }
+            _ => None,
+        }
+    }
+
+    /// The driver that claimed the function.
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+
+    fn matches(&self, id: &DeviceId) -> bool {
+        match *id {
+            DeviceId::Device(vendor, device) => {
+                self.vendor_id == vendor && self.device_id == device
+            }
+            DeviceId::Class(class, subclass) => self.class == class && self.subclass == subclass,
+        }
+    }
+
+    /// A short description of the class, e.g. "Ethernet controller".
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Audio device",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        (0xff, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+/// A short name for a capability ID.
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        CAPABILITY_MSI => "MSI",
+        CAPABILITY_VENDOR => "Vendor Specific",
+        CAPABILITY_EXPRESS => "Express",
+        CAPABILITY_MSI_X => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:04x}:{:04x}] (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<PciDevice>>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// All functions found at boot, in bus order.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    DEVICES.lock().clone()
+}
+
+// Offers `device` to `driver` if it matches and is still free.
+fn try_probe(driver: &PciDriver, device: &Arc<PciDevice>) {
+    if device.driver().is_some() || !driver.ids.iter().any(|id| device.matches(id)) {
+        return;
+    }
+    // Drivers announce the devices they set up themselves.
+    match (driver.probe)(device) {
+        Ok(()) => *device.driver.lock() = Some(driver.name),
+        Err(error) => println!("    [-] {}: {}: {}", driver.name, device.address, error),
+    }
+}
+
+/// Registers `driver` and probes it against the functions nobody claimed yet.
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        try_probe(driver, &device);
+    }
+}
+
+// Switches to ECAM if the MCFG table describes a usable configuration space for
+// segment 0.
+fn setup_ecam() -> Option<(PhysAddr, u8, u8)> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+    let entry = mcfg
+        .get(MCFG_ENTRIES..)?
+        .chunks_exact(16)
+        .find(|entry| entry[8..10] == [0, 0])?;
+    let base = PhysAddr::try_new(u64::from_le_bytes(entry[..8].try_into().unwrap())).ok()?;
+    let (start_bus, end_bus) = (entry[10], entry[11]);
+    let size = (end_bus.checked_sub(start_bus)? as u64 + 1) << 20;
+    let mapped = mmio::map(base, size)?;
+    *ACCESS.lock() = Access::Ecam {
+        base: mapped,
+        start_bus,
+        end_bus,
+    };
+    Some((base, start_bus, end_bus))
+}
+
+/// Scans all buses for functions.
+pub fn init() {
+    println!("[!] Scanning PCI buses");
+    match setup_ecam() {
+        Some((base, start, end)) => {
+            println!("    [+] ECAM at {:#x} for buses {}-{}", base, start, end)
+        }
+        None => println!("    [+] Using configuration ports"),
+    }
+
+    let mut found = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let address = Address {
+                bus,
+                device,
+                function: 0,
+            };
+            if read_u16(address, REG_VENDOR_ID) == 0xffff {
+                continue;
+            }
+            let functions = match read_u8(address, REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION {
+                0 => 1,
+                _ => 8,
+            };
+            for function in 0..functions {
+                if let Some(device) = PciDevice::probe(Address {
+                    function,
+                    ..address
+                }) {
+                    found.push(Arc::new(device));
+                }
+            }
+        }
+    }
+    let count = found.len();
+    *DEVICES.lock() = found;
+    println!("    [+] Found {} functions", count);
+    println!("    [+] Done");
+}
+
+/// One line per function, like `lspci`, plus the details with `verbose`.
+pub fn describe(device: &PciDevice, verbose: bool) -> String {
+    let mut text = format!("{}", device);
+    if let Some(driver) = device.driver() {
+        text += &format!(" [{}]", driver);
+    }
+    if !verbose {
+        return text;
+    }
+    text += &format!(
+        "\n\tSubsystem: {:04x}:{:04x}, class {:02x}{:02x}{:02x}",
+        device.subsystem_vendor_id,
+        device.subsystem_id,
+        device.class,
+        device.subclass,
+        device.prog_if
+    );
+    if device.interrupt_pin != 0 {
+        let pin = (b'A' + device.interrupt_pin - 1) as char;
+        text += &format!(
+            "\n\tInterrupt: pin {} routed to IRQ {}",
+            pin, device.interrupt_line
+        );
+    }
+    for (index, bar) in device.bars.iter().enumerate() {
+        if let Some(bar) = bar {
+            text += &format!("\n\tRegion {}: {}", index, bar);
+        }
+    }
+    for capability in &device.capabilities {
+        text += &format!(
+            "\n\tCapabilities: [{:02x}] {}",
+            capability.offset,
+            capability_name(capability.id)
+        );
+    }
+    text
+}
+
+#[test_case]
+fn test_pci_scan() {
+    let devices = devices();
+    let host = devices.iter().find(|device| {
+        device.address
+            == Address {
+                bus: 0,
+                device: 0,
+                function: 0,
+            }
+    });
+    assert_eq!(
+        host.map(|device| (device.class, device.subclass)),
+        Some((0x06, 0x00))
+    );
+    // Configuration space reads agree with what the scan recorded.
+    for device in &devices {
+        assert_eq!(read_u16(device.address, REG_VENDOR_ID), device.vendor_id);
+    }
+}
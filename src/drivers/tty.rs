@@ -0,0 +1,81 @@
+// Terminal devices: /dev/console for the keyboard and screen and /dev/ttyS0 for the
+// first serial port.
+//
+// Reading the console goes through its line discipline and returns a line at a time.
+// The serial port is read raw: a read waits for the first byte and then returns
+// whatever else has arrived, without waiting for more.
+// Reference: https://wiki.osdev.org/Serial_Ports
+use alloc::sync::Arc;
+
+use crate::console;
+use crate::fs::devfs::{self, Device};
+use crate::fs::FsError;
+use crate::instructions::inb;
+use crate::interrupts::interrupts::without_interrupts;
+use crate::process::scheduler;
+use crate::serial::SERIAL1;
+
+const COM1: u16 = 0x3f8;
+const REG_LINE_STATUS: u16 = 5;
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+
+struct Console;
+
+impl Device for Console {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(console::read_line(buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        console::write(buf);
+        Ok(buf.len())
+    }
+}
+
+struct Serial;
+
+impl Serial {
+    fn receive() -> Option<u8> {
+        without_interrupts(|| {
+            // Holding the port lock keeps writers away from the UART while we read it.
+            let _port = SERIAL1.lock();
+            unsafe {
+                if inb(COM1 + REG_LINE_STATUS) & LINE_STATUS_DATA_READY == 0 {
+                    return None;
+                }
+                Some(inb(COM1))
+            }
+        })
+    }
+}
+
+impl Device for Serial {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut count = 0;
+        while count < buf.len() {
+            match Serial::receive() {
+                Some(byte) => {
+                    buf[count] = byte;
+                    count += 1;
+                }
+                None if count > 0 => break,
+                None => scheduler::relax(),
+            }
+        }
+        Ok(count)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        without_interrupts(|| {
+            let mut port = SERIAL1.lock();
+            buf.iter().for_each(|byte| port.send(*byte));
+        });
+        Ok(buf.len())
+    }
+}
+
+/// Publishes the console and the serial port.
+pub fn init() {
+    devfs::register("console", Arc::new(Console)).expect("console registered twice");
+    devfs::register("ttyS0", Arc::new(Serial)).expect("ttyS0 registered twice");
+}
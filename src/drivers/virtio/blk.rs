@@ -0,0 +1,215 @@
+// Virtio block devices, e.g. QEMU's `-drive if=virtio`.
+//
+// Every request is a chain of three buffers on the single request queue: a header with
+// the operation and the first sector, the data, and a status byte the device writes
+// last. Requests go through a bounce buffer of DMA memory, since the buffers of callers
+// are not physically contiguous, and are sent one at a time.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2390002
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use super::queue::{Buffer, Virtqueue};
+use super::{wait_used, Transport, VENDOR_ID};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::drivers::pci::{DeviceId, PciDevice, PciDriver};
+use crate::locks::mutex::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::println;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    // Transitional and modern device IDs.
+    ids: &[
+        DeviceId::Device(VENDOR_ID, 0x1001),
+        DeviceId::Device(VENDOR_ID, 0x1042),
+    ],
+    probe,
+};
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+// Device configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const STATUS_OK: u8 = 0;
+
+const SECTOR_SIZE: usize = 512;
+/// Largest transfer of a single request.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+// Layout of the bounce buffer.
+const HEADER_OFFSET: usize = 0;
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const DATA_OFFSET: usize = 4096;
+
+static NEXT_INDEX: AtomicU8 = AtomicU8::new(0);
+
+struct Requests {
+    queue: Virtqueue,
+    buffer: DmaBuffer,
+}
+
+pub struct VirtioBlock {
+    name: String,
+    transport: Transport,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    requests: Mutex<Requests>,
+    /// Set once the device stopped answering; it is reset and every request fails.
+    failed: AtomicBool,
+}
+
+impl VirtioBlock {
+    // Sends one request and waits for it. `data` is the part of the bounce buffer the
+    // device reads or writes, if any.
+    fn request(
+        &self,
+        requests: &mut Requests,
+        kind: u32,
+        sector: u64,
+        data: usize,
+    ) -> Result<(), BlockError> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err(BlockError::Io);
+        }
+        let buffer = &requests.buffer;
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        buffer.write(HEADER_OFFSET, &header);
+        buffer.write(STATUS_OFFSET, &[0xff]);
+
+        let phys = buffer.phys();
+        let header = Buffer {
+            addr: phys + HEADER_OFFSET as u64,
+            len: HEADER_SIZE as u32,
+            writable: false,
+        };
+        let data = Buffer {
+            addr: phys + DATA_OFFSET as u64,
+            len: data as u32,
+            writable: kind == REQUEST_IN,
+        };
+        let status = Buffer {
+            addr: phys + STATUS_OFFSET as u64,
+            len: 1,
+            writable: true,
+        };
+        let added = match data.len {
+            0 => requests.queue.add(&[header, status]),
+            _ => requests.queue.add(&[header, data, status]),
+        };
+        let head = added.ok_or(BlockError::Io)?;
+        self.transport.notify(&requests.queue);
+
+        // The device may still write to the bounce buffer later, or already lost track
+        // of the queue. Only a reset makes it safe to use the memory again.
+        if wait_used(&mut requests.queue).map(|(used, _)| used) != Some(head) {
+            self.transport.reset();
+            self.failed.store(true, Ordering::Relaxed);
+            println!(
+                "[-] {}: the device does not answer requests, giving up",
+                self.name
+            );
+            return Err(BlockError::Io);
+        }
+        let mut status = [0];
+        requests.buffer.read(STATUS_OFFSET, &mut status);
+        match status[0] {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        let mut requests = block::lock(&self.requests);
+        for (index, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.request(&mut requests, REQUEST_IN, sector, chunk.len())?;
+            requests.buffer.read(DATA_OFFSET, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self, lba, buf.len())?;
+        let mut requests = block::lock(&self.requests);
+        for (index, chunk) in buf.chunks(MAX_TRANSFER).enumerate() {
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            requests.buffer.write(DATA_OFFSET, chunk);
+            self.request(&mut requests, REQUEST_OUT, sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush || self.read_only {
+            return Ok(());
+        }
+        let mut requests = block::lock(&self.requests);
+        self.request(&mut requests, REQUEST_FLUSH, 0, 0)
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let transport = Transport::new(device).ok_or("no usable registers")?;
+    let features = transport.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+    let queue = transport.setup_queue(0)?;
+    let buffer = DmaBuffer::new(DATA_OFFSET + MAX_TRANSFER).ok_or("out of memory for requests")?;
+    transport.finish_setup();
+
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    let disk = VirtioBlock {
+        name: format!("vd{}", (b'a' + index) as char),
+        sectors: transport.config_u64(CONFIG_CAPACITY),
+        read_only: features & FEATURE_READ_ONLY != 0,
+        flush: features & FEATURE_FLUSH != 0,
+        transport,
+        requests: Mutex::new(Requests { queue, buffer }),
+        failed: AtomicBool::new(false),
+    };
+    println!(
+        "    [+] {}: virtio {} at {} ({}{})",
+        disk.name,
+        if disk.transport.is_modern() {
+            "1.0"
+        } else {
+            "legacy"
+        },
+        device.address,
+        block::format_size(disk.sectors * SECTOR_SIZE as u64),
+        if disk.read_only { ", read-only" } else { "" }
+    );
+    block::add_disk(Arc::new(disk));
+    Ok(())
+}
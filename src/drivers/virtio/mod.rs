@@ -0,0 +1,365 @@
+// Virtio devices on the PCI bus.
+//
+// Virtio devices are the paravirtualized devices of QEMU and other hypervisors. They
+// all work the same way: the driver and the device agree on a set of feature bits, then
+// exchange buffers through virtqueues in guest memory, and the device is told about new
+// buffers by writing the queue number to a notification register. Only the device
+// specific configuration and the meaning of the buffers differ.
+//
+// Two PCI transports exist. Legacy (and transitional) devices have all registers in an
+// I/O port BAR. Modern (virtio 1.0) devices describe memory regions for the common,
+// notification and device configuration through vendor specific PCI capabilities. The
+// modern interface is used whenever the device offers it.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1090002
+pub mod blk;
+pub mod net;
+pub mod queue;
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::VirtAddr;
+
+use super::pci::{self, Bar, PciDevice};
+use crate::instructions::{inb, inl, interrupts_enabled, inw, outb, outl, outw};
+use crate::println;
+use crate::process::scheduler;
+use crate::time;
+use queue::Virtqueue;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// Device status bits.
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// The device follows virtio 1.0, required on the modern interface.
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, from the I/O BAR.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+/// Device configuration, as long as MSI-X is off.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Modern common configuration.
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Types of the vendor specific capabilities of modern devices.
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_DEVICE: u8 = 4;
+
+/// Largest queue set up, to keep the memory of a queue small.
+const MAX_QUEUE_SIZE: u16 = 256;
+
+/// How long a request may take before the device is given up on.
+const TIMEOUT_TICKS: u64 = 5 * time::TICKS_PER_SECOND;
+/// Checks of the used ring while interrupts are off and time stands still.
+const POLL_LIMIT: u32 = 10_000_000;
+
+/// How the registers of a device are reached.
+pub enum Transport {
+    Legacy {
+        port: u16,
+    },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        device: VirtAddr,
+    },
+}
+
+unsafe fn read_volatile<T>(base: VirtAddr, offset: usize) -> T {
+    (base + offset as u64).as_ptr::<T>().read_volatile()
+}
+
+unsafe fn write_volatile<T>(base: VirtAddr, offset: usize, value: T) {
+    (base + offset as u64)
+        .as_mut_ptr::<T>()
+        .write_volatile(value)
+}
+
+impl Transport {
+    /// Finds the registers of `device`, preferring the modern interface.
+    pub fn new(device: &PciDevice) -> Option<Transport> {
+        device.enable(pci::COMMAND_IO_SPACE | pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+        Transport::modern(device).or_else(|| match device.bars[0]? {
+            Bar::Io { port, .. } => Some(Transport::Legacy { port }),
+            Bar::Memory { .. } => None,
+        })
+    }
+
+    fn modern(device: &PciDevice) -> Option<Transport> {
+        let mut bars: [Option<VirtAddr>; 6] = [None; 6];
+        let (mut common, mut notify, mut config) = (None, None, None);
+        for capability in device
+            .capabilities
+            .iter()
+            .filter(|cap| cap.id == pci::CAPABILITY_VENDOR)
+        {
+            let kind = device.read_u8(capability.offset + 3);
+            let bar = device.read_u8(capability.offset + 4) as usize;
+            let offset = device.read_u32(capability.offset + 8) as u64;
+            if bar >= 6 || !matches!(kind, CAP_COMMON | CAP_NOTIFY | CAP_DEVICE) {
+                continue;
+            }
+            if bars[bar].is_none() {
+                bars[bar] = Some(device.map_bar(bar)?.0);
+            }
+            let address = bars[bar]? + offset;
+            match kind {
+                CAP_COMMON => common = Some(address),
+                CAP_NOTIFY => notify = Some((address, device.read_u32(capability.offset + 16))),
+                _ => config = Some(address),
+            }
+        }
+        let (notify, notify_multiplier) = notify?;
+        Some(Transport::Modern {
+            common: common?,
+            notify,
+            notify_multiplier,
+            device: config?,
+        })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port } => unsafe { inb(port + LEGACY_STATUS) },
+            Transport::Modern { common, .. } => unsafe { read_volatile(common, COMMON_STATUS) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { port } => unsafe { outb(port + LEGACY_STATUS, status) },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile(common, COMMON_STATUS, status)
+            },
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { port } => unsafe { inl(port + LEGACY_DEVICE_FEATURES) as u64 },
+            Transport::Modern { common, .. } => (0..2).fold(0, |features, select| unsafe {
+                write_volatile(common, COMMON_DEVICE_FEATURE_SELECT, select as u32);
+                let half: u32 = read_volatile(common, COMMON_DEVICE_FEATURE);
+                features | (half as u64) << (32 * select)
+            }),
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                outl(port + LEGACY_DRIVER_FEATURES, features as u32)
+            },
+            Transport::Modern { common, .. } => {
+                for select in 0..2 {
+                    unsafe {
+                        write_volatile(common, COMMON_DRIVER_FEATURE_SELECT, select as u32);
+                        write_volatile(
+                            common,
+                            COMMON_DRIVER_FEATURE,
+                            (features >> (32 * select)) as u32,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resets the device and agrees on the features in `supported` it offers, which are
+    /// returned. The queues have to be set up next, then `finish_setup` called.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let offered = self.device_features();
+        let mut features = offered & supported;
+        if self.is_modern() {
+            if offered & FEATURE_VERSION_1 == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err("device does not support virtio 1.0");
+            }
+            features |= FEATURE_VERSION_1;
+        }
+        self.set_driver_features(features);
+        // Legacy devices take the features as they are.
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err("device rejected the features");
+            }
+        }
+        Ok(features)
+    }
+
+    /// Creates queue `index` with as many entries as the device allows, up to a limit,
+    /// and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, &'static str> {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                outw(port + LEGACY_QUEUE_SELECT, index);
+                // Legacy devices dictate the size.
+                let size = inw(port + LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err("queue does not exist");
+                }
+                let queue = Virtqueue::new(index, size).ok_or("out of memory for the queue")?;
+                outl(
+                    port + LEGACY_QUEUE_ADDRESS,
+                    (queue.descriptor_address().as_u64() >> 12) as u32,
+                );
+                Ok(queue)
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile(common, COMMON_QUEUE_SELECT, index);
+                let max: u16 = read_volatile(common, COMMON_QUEUE_SIZE);
+                if max == 0 {
+                    return Err("queue does not exist");
+                }
+                // Any power of two up to the maximum will do.
+                let mut size = max.min(MAX_QUEUE_SIZE);
+                while !size.is_power_of_two() {
+                    size &= size - 1;
+                }
+                let queue = Virtqueue::new(index, size).ok_or("out of memory for the queue")?;
+                write_volatile(common, COMMON_QUEUE_SIZE, size);
+                write_volatile(
+                    common,
+                    COMMON_QUEUE_DESC,
+                    queue.descriptor_address().as_u64(),
+                );
+                write_volatile(
+                    common,
+                    COMMON_QUEUE_DRIVER,
+                    queue.available_address().as_u64(),
+                );
+                write_volatile(common, COMMON_QUEUE_DEVICE, queue.used_address().as_u64());
+                write_volatile(common, COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Tells the device the driver is ready.
+    pub fn finish_setup(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Resets the device, which makes it let go of every buffer it was given. It does
+    /// nothing more until it is set up again.
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                outw(port + LEGACY_QUEUE_NOTIFY, queue.index())
+            },
+            Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                ..
+            } => unsafe {
+                write_volatile(common, COMMON_QUEUE_SELECT, queue.index());
+                let offset: u16 = read_volatile(common, COMMON_QUEUE_NOTIFY_OFF);
+                write_volatile(
+                    notify,
+                    offset as usize * notify_multiplier as usize,
+                    queue.index(),
+                );
+            },
+        }
+    }
+
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                inb(port + LEGACY_DEVICE_CONFIG + offset as u16)
+            },
+            Transport::Modern { device, .. } => unsafe { read_volatile(device, offset) },
+        }
+    }
+
+    pub fn config_u16(&self, offset: usize) -> u16 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                inw(port + LEGACY_DEVICE_CONFIG + offset as u16)
+            },
+            Transport::Modern { device, .. } => unsafe { read_volatile(device, offset) },
+        }
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                inl(port + LEGACY_DEVICE_CONFIG + offset as u16)
+            },
+            Transport::Modern { device, .. } => unsafe { read_volatile(device, offset) },
+        }
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+}
+
+/// Waits until the device returns a chain on `queue` and takes it, see
+/// `Virtqueue::pop_used`. Other threads run meanwhile. Returns `None` on timeout.
+pub fn wait_used(queue: &mut Virtqueue) -> Option<(u16, u32)> {
+    if interrupts_enabled() {
+        let deadline = time::ticks() + TIMEOUT_TICKS;
+        while !queue.has_used() && time::ticks() < deadline {
+            scheduler::relax();
+        }
+    } else {
+        for _ in 0..POLL_LIMIT {
+            if queue.has_used() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    queue.pop_used()
+}
+
+/// Registers the virtio drivers with the PCI bus.
+pub fn init() {
+    println!("[!] Starting virtio drivers");
+    pci::register_driver(&blk::DRIVER);
+    pci::register_driver(&net::DRIVER);
+    println!("    [+] Done");
+}
@@ -0,0 +1,243 @@
+// Virtio network cards, e.g. QEMU's `-device virtio-net-pci`.
+//
+// Queue 0 receives and queue 1 transmits. Every frame is preceded by a small header
+// for checksum and segmentation offloading, which is all zeros here since neither is
+// negotiated. The receive queue is kept full of empty buffers the device writes frames
+// into; a buffer is put back as soon as its frame is copied out. Buffers of both queues
+// are fixed slots of DMA memory, one per descriptor chain.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1940001
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::queue::{Buffer, Virtqueue};
+use super::{Transport, VENDOR_ID};
+use crate::drivers::pci::{DeviceId, PciDevice, PciDriver};
+use crate::locks::mutex::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::net::{self, Counters, MacAddress, NetDevice, NetError, NetStats, MAX_FRAME_SIZE};
+use crate::println;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    // Transitional and modern device IDs.
+    ids: &[
+        DeviceId::Device(VENDOR_ID, 0x1000),
+        DeviceId::Device(VENDOR_ID, 0x1041),
+    ],
+    probe,
+};
+
+const FEATURE_MAC: u64 = 1 << 5;
+const FEATURE_STATUS: u64 = 1 << 16;
+
+// Device configuration.
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const STATUS_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The header without the buffer count, which modern devices always add.
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+const SLOT_SIZE: usize = 2048;
+/// Buffers per queue, at most.
+const MAX_SLOTS: u16 = 64;
+
+// The buffers of one queue: `slots[descriptor]` is the slot a chain in flight uses.
+struct Ring {
+    queue: Virtqueue,
+    memory: DmaBuffer,
+    slots: Vec<Option<usize>>,
+    free: Vec<usize>,
+}
+
+impl Ring {
+    fn new(queue: Virtqueue) -> Option<Ring> {
+        let count = queue.size().min(MAX_SLOTS) as usize;
+        Some(Ring {
+            memory: DmaBuffer::new(count * SLOT_SIZE)?,
+            slots: vec![None; queue.size() as usize],
+            free: (0..count).collect(),
+            queue,
+        })
+    }
+
+    fn slot_address(&self, slot: usize) -> x86_64::PhysAddr {
+        self.memory.phys() + (slot * SLOT_SIZE) as u64
+    }
+
+    // Takes back a chain the device is done with and returns its slot and length.
+    fn pop_used(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let slot = self.slots[head as usize].take()?;
+        Some((slot, len as usize))
+    }
+}
+
+pub struct VirtioNet {
+    name: String,
+    mac: MacAddress,
+    transport: Transport,
+    header_size: usize,
+    status: bool,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    counters: Counters,
+}
+
+impl VirtioNet {
+    // Offers every free receive slot to the device.
+    fn refill(&self, rx: &mut Ring) {
+        let mut added = false;
+        while let Some(slot) = rx.free.pop() {
+            let buffer = Buffer {
+                addr: rx.slot_address(slot),
+                len: SLOT_SIZE as u32,
+                writable: true,
+            };
+            match rx.queue.add(&[buffer]) {
+                Some(head) => rx.slots[head as usize] = Some(slot),
+                None => {
+                    rx.free.push(slot);
+                    break;
+                }
+            }
+            added = true;
+        }
+        if added {
+            self.transport.notify(&rx.queue);
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // Without the status feature the link is always up.
+        !self.status || self.transport.config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        net::check_frame(frame)?;
+        let mut tx = self.tx.lock();
+        // Frames the device sent free their slots.
+        while let Some((slot, _)) = tx.pop_used() {
+            tx.free.push(slot);
+        }
+        let Some(slot) = tx.free.pop() else {
+            self.counters.send_failed();
+            return Err(NetError::Busy);
+        };
+
+        let offset = slot * SLOT_SIZE;
+        tx.memory
+            .write(offset, &[0; HEADER_SIZE][..self.header_size]);
+        tx.memory.write(offset + self.header_size, frame);
+        let header = Buffer {
+            addr: tx.slot_address(slot),
+            len: self.header_size as u32,
+            writable: false,
+        };
+        let data = Buffer {
+            addr: header.addr + self.header_size as u64,
+            len: frame.len() as u32,
+            writable: false,
+        };
+        let Some(head) = tx.queue.add(&[header, data]) else {
+            tx.free.push(slot);
+            self.counters.send_failed();
+            return Err(NetError::Busy);
+        };
+        tx.slots[head as usize] = Some(slot);
+        self.transport.notify(&tx.queue);
+        self.counters.sent(frame.len());
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        // Bad frames are skipped rather than ending the caller's receive loop.
+        loop {
+            let (slot, len) = rx.pop_used()?;
+            let frame = match len.checked_sub(self.header_size) {
+                Some(size @ 1..=MAX_FRAME_SIZE) => {
+                    let mut frame = vec![0; size];
+                    rx.memory
+                        .read(slot * SLOT_SIZE + self.header_size, &mut frame);
+                    self.counters.received(size);
+                    Some(frame)
+                }
+                _ => {
+                    self.counters.dropped();
+                    None
+                }
+            };
+            rx.free.push(slot);
+            self.refill(&mut rx);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+
+    fn stats(&self) -> NetStats {
+        self.counters.stats()
+    }
+}
+
+fn probe(device: &Arc<PciDevice>) -> Result<(), &'static str> {
+    let transport = Transport::new(device).ok_or("no usable registers")?;
+    let features = transport.negotiate(FEATURE_MAC | FEATURE_STATUS)?;
+    if features & FEATURE_MAC == 0 {
+        return Err("device has no MAC address");
+    }
+    let rx = Ring::new(transport.setup_queue(RECEIVE_QUEUE)?).ok_or("out of memory for buffers")?;
+    let tx =
+        Ring::new(transport.setup_queue(TRANSMIT_QUEUE)?).ok_or("out of memory for buffers")?;
+    transport.finish_setup();
+
+    let mut mac = [0; 6];
+    for (index, byte) in mac.iter_mut().enumerate() {
+        *byte = transport.config_u8(CONFIG_MAC + index);
+    }
+    let card = VirtioNet {
+        name: net::next_ethernet_name(),
+        mac: MacAddress(mac),
+        header_size: if transport.is_modern() {
+            HEADER_SIZE
+        } else {
+            LEGACY_HEADER_SIZE
+        },
+        status: features & FEATURE_STATUS != 0,
+        transport,
+        rx: Mutex::new(rx),
+        tx: Mutex::new(tx),
+        counters: Counters::default(),
+    };
+    card.refill(&mut card.rx.lock());
+    println!(
+        "    [+] {}: virtio {} at {}, {}, link {}",
+        card.name,
+        if card.transport.is_modern() {
+            "1.0"
+        } else {
+            "legacy"
+        },
+        device.address,
+        card.mac,
+        if card.link_up() { "up" } else { "down" }
+    );
+    net::register(Arc::new(card));
+    Ok(())
+}
@@ -0,0 +1,293 @@
+// Split virtqueues.
+//
+// A queue is three rings in memory shared with the device: the descriptor table, where
+// each entry points to a buffer and may chain to the next one, the available ring, where
+// the driver puts the heads of chains it offers, and the used ring, where the device
+// returns them together with the number of bytes it wrote. Both indexes only ever grow
+// and wrap around at 2^16. The layout is the one legacy devices require, with the used
+// ring on its own page; modern devices are simply given the three addresses.
+// Reference: https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-230005
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+use crate::memory::dma::DmaBuffer;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const ALIGNMENT: usize = 4096;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+/// The device writes to the buffer rather than reading it.
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// Asks the device not to interrupt when it uses buffers.
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer of a chain, in device memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to it.
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    /// Unused descriptors, linked through their `next` fields.
+    free_head: u16,
+    free_count: u16,
+    /// Next index of the available ring to fill.
+    avail_index: u16,
+    /// Next index of the used ring to look at.
+    used_index: u16,
+    /// Length of the chain starting at each descriptor, for freeing it again.
+    chain_lengths: Vec<u16>,
+}
+
+impl Virtqueue {
+    /// Sets up queue `index` with `size` entries, a power of two. Returns `None` if no
+    /// contiguous memory is left.
+    pub fn new(index: u16, size: u16) -> Option<Virtqueue> {
+        let n = size as usize;
+        let avail_offset = n * DESCRIPTOR_SIZE;
+        let used_offset = (avail_offset + 6 + 2 * n).next_multiple_of(ALIGNMENT);
+        let memory = DmaBuffer::new(used_offset + 6 + 8 * n)?;
+
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            used_index: 0,
+            chain_lengths: alloc::vec![0; n],
+        };
+        for descriptor in 0..size {
+            queue.set_descriptor(
+                descriptor,
+                Descriptor {
+                    addr: 0,
+                    len: 0,
+                    flags: 0,
+                    next: descriptor + 1,
+                },
+            );
+        }
+        // The queue is polled.
+        unsafe {
+            queue
+                .memory
+                .ptr::<u16>(avail_offset)
+                .write_volatile(AVAIL_NO_INTERRUPT)
+        };
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.memory.phys() + self.avail_offset
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.memory.phys() + self.used_offset
+    }
+
+    /// Number of descriptors not in use.
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    fn descriptor(&self, index: u16) -> Descriptor {
+        unsafe {
+            self.memory
+                .ptr::<Descriptor>(index as usize * DESCRIPTOR_SIZE)
+                .read_volatile()
+        }
+    }
+
+    fn set_descriptor(&self, index: u16, descriptor: Descriptor) {
+        unsafe {
+            self.memory
+                .ptr::<Descriptor>(index as usize * DESCRIPTOR_SIZE)
+                .write_volatile(descriptor)
+        }
+    }
+
+    /// Offers a chain of buffers to the device, the ones it reads first. Returns the head
+    /// descriptor, which `pop_used` hands back once the device is done, or `None` if
+    /// there are not enough free descriptors. The device still has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut current = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let next = self.descriptor(current).next;
+            let mut flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 };
+            if position + 1 < buffers.len() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            self.set_descriptor(
+                current,
+                Descriptor {
+                    addr: buffer.addr.as_u64(),
+                    len: buffer.len,
+                    flags,
+                    next,
+                },
+            );
+            if position + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+        self.chain_lengths[head as usize] = buffers.len() as u16;
+
+        let slot = self.avail_offset + 4 + 2 * (self.avail_index % self.size) as usize;
+        unsafe { self.memory.ptr::<u16>(slot).write_volatile(head) };
+        // The device must see the descriptors and the ring entry before the new index.
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        unsafe {
+            self.memory
+                .ptr::<u16>(self.avail_offset + 2)
+                .write_volatile(self.avail_index)
+        };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Whether the device returned chains that `pop_used` has not taken yet.
+    pub fn has_used(&self) -> bool {
+        let device_index = unsafe { self.memory.ptr::<u16>(self.used_offset + 2).read_volatile() };
+        device_index != self.used_index
+    }
+
+    /// Takes the next chain the device is done with and frees its descriptors. Returns
+    /// its head descriptor and the number of bytes the device wrote. An entry that does
+    /// not name a chain the device was given is skipped, and `None` returned.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        // The ring entry is only valid once the index is seen.
+        fence(Ordering::SeqCst);
+        let slot = self.used_offset + 4 + 8 * (self.used_index % self.size) as usize;
+        let (head, len) = unsafe {
+            (
+                self.memory.ptr::<u32>(slot).read_volatile(),
+                self.memory.ptr::<u32>(slot + 4).read_volatile(),
+            )
+        };
+        self.used_index = self.used_index.wrapping_add(1);
+
+        let length = *self
+            .chain_lengths
+            .get(head as usize)
+            .filter(|&&length| length > 0)?;
+        let head = head as u16;
+        let mut last = head;
+        for _ in 1..length {
+            last = self.descriptor(last).next;
+        }
+        let mut descriptor = self.descriptor(last);
+        descriptor.next = self.free_head;
+        self.set_descriptor(last, descriptor);
+        self.free_head = head;
+        self.free_count += length;
+        self.chain_lengths[head as usize] = 0;
+        Some((head, len))
+    }
+}
+
+#[test_case]
+fn test_virtqueue_rings() {
+    let mut queue = Virtqueue::new(0, 8).unwrap();
+    let buffer = |addr: u64, writable| Buffer {
+        addr: PhysAddr::new(addr),
+        len: 16,
+        writable,
+    };
+    let first = queue
+        .add(&[buffer(0x1000, false), buffer(0x2000, true)])
+        .unwrap();
+    let second = queue.add(&[buffer(0x3000, true)]).unwrap();
+    assert_eq!(queue.free_count(), 5);
+    assert!(queue.add(&[buffer(0x4000, false); 6]).is_none());
+
+    let chained = queue.descriptor(first);
+    assert_eq!((chained.addr, chained.flags), (0x1000, DESCRIPTOR_NEXT));
+    assert_eq!(queue.descriptor(chained.next).flags, DESCRIPTOR_WRITE);
+    assert!(!queue.has_used());
+
+    // Play the device: return the second chain, then the first.
+    for (position, (head, len)) in [(second, 4), (first, 8)].into_iter().enumerate() {
+        let slot = queue.used_offset + 4 + 8 * position;
+        unsafe {
+            queue.memory.ptr::<u32>(slot).write_volatile(head as u32);
+            queue.memory.ptr::<u32>(slot + 4).write_volatile(len);
+        }
+    }
+    unsafe {
+        queue
+            .memory
+            .ptr::<u16>(queue.used_offset + 2)
+            .write_volatile(2)
+    };
+    assert_eq!(queue.pop_used(), Some((second, 4)));
+    assert_eq!(queue.pop_used(), Some((first, 8)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free_count(), 8);
+
+    // A chain returned twice, and one that does not exist.
+    for (position, head) in [(2, first as u32), (3, 8)] {
+        unsafe {
+            queue
+                .memory
+                .ptr::<u32>(queue.used_offset + 4 + 8 * position)
+                .write_volatile(head)
+        };
+    }
+    unsafe {
+        queue
+            .memory
+            .ptr::<u16>(queue.used_offset + 2)
+            .write_volatile(4)
+    };
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.pop_used(), None);
+    assert!(!queue.has_used());
+    assert_eq!(queue.free_count(), 8);
+    assert!(queue.add(&[buffer(0x4000, false); 8]).is_some());
+}
@@ -0,0 +1,150 @@
+// ELF64 executable parsing.
+//
+// Only what is needed to load statically linked x86_64 executables is decoded: the file
+// header and the program header table.
+// Reference: https://wiki.osdev.org/ELF
+// Reference: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Program header types.
+pub const PT_LOAD: u32 = 1;
+
+/// Program header flags.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    UnsupportedClass,
+    UnsupportedEndianness,
+    UnsupportedVersion,
+    UnsupportedType,
+    UnsupportedMachine,
+    BadProgramHeaders,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+/// A validated ELF64 executable.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+impl<'a> Elf<'a> {
+    /// Validates the file header and the location of the program header table.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedEndianness);
+        }
+        if data[6] != EV_CURRENT || read_u32(data, 20) != EV_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::UnsupportedType);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        let phoff = read_u64(data, 32);
+        let phentsize = read_u16(data, 54) as usize;
+        let phnum = read_u16(data, 56);
+        let table_end = (phnum as u64)
+            .checked_mul(PROGRAM_HEADER_SIZE as u64)
+            .and_then(|size| size.checked_add(phoff));
+        if phentsize != PROGRAM_HEADER_SIZE || table_end.map_or(true, |end| end > data.len() as u64)
+        {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        Ok(Elf {
+            data,
+            entry: read_u64(data, 24),
+            phoff,
+            phnum,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// File offset of the program header table.
+    pub fn program_header_offset(&self) -> u64 {
+        self.phoff
+    }
+
+    pub fn program_header_count(&self) -> u16 {
+        self.phnum
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let phoff = self.phoff as usize;
+        (0..self.phnum as usize).map(move |i| {
+            let offset = phoff + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(data, offset),
+                flags: read_u32(data, offset + 4),
+                offset: read_u64(data, offset + 8),
+                vaddr: read_u64(data, offset + 16),
+                file_size: read_u64(data, offset + 32),
+                mem_size: read_u64(data, offset + 40),
+                align: read_u64(data, offset + 48),
+            }
+        })
+    }
+}
@@ -0,0 +1,221 @@
+// A file system of device nodes, mounted at /dev.
+//
+// Drivers publish their devices here under a name by implementing `Device`, which
+// reads and writes at a byte offset like an inode does. The nodes live in a global
+// registry rather than in the mounted file system, so drivers can register them before
+// the file systems are up. The root directory is flat and cannot be modified through
+// the VFS.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, InodeRef, Metadata};
+use crate::locks::mutex::Mutex;
+use crate::time;
+
+const ROOT_INODE: u64 = 1;
+
+/// A device as seen through its node.
+pub trait Device: Send + Sync {
+    /// `CharDevice` or `BlockDevice`.
+    fn kind(&self) -> FileType {
+        FileType::CharDevice
+    }
+
+    /// Size in bytes for devices that have one.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Reads into `buf`; character devices ignore `offset`.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `buf`; character devices ignore `offset`.
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError>;
+}
+
+#[derive(Clone)]
+struct Node {
+    id: u64,
+    device: Arc<dyn Device>,
+    created: u64,
+}
+
+static NODES: Mutex<BTreeMap<String, Node>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(ROOT_INODE + 1);
+
+/// Publishes `device` as /dev/`name`.
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut nodes = NODES.lock();
+    if nodes.contains_key(name) {
+        return Err(FsError::AlreadyExists);
+    }
+    let node = Node {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        device,
+        created: time::now(),
+    };
+    nodes.insert(String::from(name), node);
+    Ok(())
+}
+
+/// Removes /dev/`name`. Files already open on it keep working.
+pub fn unregister(name: &str) -> Result<(), FsError> {
+    NODES
+        .lock()
+        .remove(name)
+        .map(|_| ())
+        .ok_or(FsError::NotFound)
+}
+
+pub struct DevFs;
+
+impl DevFs {
+    pub fn new() -> Arc<DevFs> {
+        Arc::new(DevFs)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(DevDirectory)
+    }
+}
+
+struct DevDirectory;
+
+impl Inode for DevDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: ROOT_INODE,
+            kind: FileType::Directory,
+            size: NODES.lock().len() as u64,
+            links: 2,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let node = NODES.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+        Ok(Arc::new(DevInode { node }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(NODES
+            .lock()
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                inode: node.id,
+                kind: node.device.kind(),
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &InodeRef,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+struct DevInode {
+    node: Node,
+}
+
+impl Inode for DevInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.node.id,
+            kind: self.node.device.kind(),
+            size: self.node.device.size(),
+            links: 1,
+            accessed: self.node.created,
+            modified: self.node.created,
+            changed: self.node.created,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.node.device.read(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        self.node.device.write(offset, buf)
+    }
+
+    // Opening a device for writing with truncation is common, e.g. for /dev/null, and
+    // means nothing to a device.
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_devfs_registration() {
+    struct Echo;
+
+    impl Device for Echo {
+        fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+            buf.fill(offset as u8);
+            Ok(buf.len())
+        }
+
+        fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+            Ok(buf.len())
+        }
+    }
+
+    register("test-echo", Arc::new(Echo)).unwrap();
+    assert_eq!(
+        register("test-echo", Arc::new(Echo)),
+        Err(FsError::AlreadyExists)
+    );
+    let root = DevFs::new().root();
+    assert!(root
+        .read_dir()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.name == "test-echo"));
+
+    let node = root.lookup("test-echo").unwrap();
+    assert_eq!(node.metadata().kind, FileType::CharDevice);
+    let mut buf = [0; 4];
+    assert_eq!(node.read_at(7, &mut buf), Ok(4));
+    assert_eq!(buf, [7; 4]);
+    assert_eq!(root.unlink("test-echo"), Err(FsError::NotSupported));
+
+    unregister("test-echo").unwrap();
+    assert_eq!(root.lookup("test-echo").err(), Some(FsError::NotFound));
+}
@@ -0,0 +1,1464 @@
+// The second extended file system.
+//
+// The volume is split into block groups, each with a bitmap of its used blocks, a
+// bitmap of its used inodes and a table of inodes; a table of group descriptors after
+// the superblock says where they are. An inode addresses its data through twelve direct
+// block pointers and single, double and triple indirect blocks full of further
+// pointers, where 0 stands for a hole. Directories are files of variable length
+// entries that map names to inode numbers.
+//
+// Inodes are not cached here: every operation reads the on-disk inode through the
+// buffer cache and writes it back when it changes. All operations of a volume are
+// serialized by its state lock. Volumes with features we do not know how to update are
+// mounted read-only, those we cannot even read are refused.
+// Reference: https://www.nongnu.org/ext2-doc/ext2.html
+// Reference: https://wiki.osdev.org/Ext2
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, InodeRef, Metadata};
+use crate::block::{self, cache::BufferCache, BlockDevice};
+use crate::locks::mutex::{Mutex, MutexGuard};
+use crate::time;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+/// Inode size and first usable inode of revision 0 volumes.
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+/// Directory entries carry the type of the file.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only changes where groups keep their metadata, which the descriptors tell us anyway.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+/// Hashed directory indexes hide in blocks that look empty to a linear reader.
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+const SUPPORTED_RO_COMPAT: u32 =
+    RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+/// Set on directories with a hash index, which we do not maintain.
+const FLAG_INDEX: u32 = 0x1000;
+
+const TYPE_REGULAR: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+
+const DIRECT_BLOCKS: usize = 12;
+const SINGLE_INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+/// Symbolic links with targets shorter than this keep them in the block pointers.
+const FAST_SYMLINK_SIZE: u64 = 60;
+
+/// Size of a directory entry header, in front of the name.
+const DIR_ENTRY_HEADER: usize = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Directory entries are padded to four bytes.
+fn entry_length(name_length: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_length + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_directories: u16,
+}
+
+struct Layout {
+    block_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    first_inode: u32,
+    group_table: u64,
+    file_type: bool,
+    large_file: bool,
+}
+
+struct State {
+    groups: Vec<Group>,
+    free_blocks: u32,
+    free_inodes: u32,
+}
+
+pub struct Ext2Fs {
+    cache: Arc<BufferCache>,
+    layout: Layout,
+    read_only: bool,
+    state: Mutex<State>,
+    this: Weak<Ext2Fs>,
+}
+
+// The fields of an on-disk inode we use. The others are left as they are.
+#[derive(Clone)]
+struct DiskInode {
+    mode: u16,
+    size: u64,
+    accessed: u32,
+    changed: u32,
+    modified: u32,
+    deleted: u32,
+    links: u16,
+    /// In 512 byte sectors, including indirect blocks.
+    sectors: u32,
+    flags: u32,
+    block: [u32; 15],
+}
+
+impl DiskInode {
+    fn new(mode: u16) -> DiskInode {
+        let now = time::now() as u32;
+        DiskInode {
+            mode,
+            size: 0,
+            accessed: now,
+            changed: now,
+            modified: now,
+            deleted: 0,
+            links: 1,
+            sectors: 0,
+            flags: 0,
+            block: [0; 15],
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    fn kind(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            // Symbolic links read as their target.
+            _ => FileType::Regular,
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = time::now() as u32;
+        self.modified = now;
+        self.changed = now;
+    }
+}
+
+// A directory entry as found in a directory block.
+struct RawEntry {
+    /// Byte offset of the entry in the directory.
+    offset: u64,
+    inode: u32,
+    record_length: usize,
+    name: String,
+    file_type: u8,
+}
+
+pub struct Ext2Inode {
+    fs: Arc<Ext2Fs>,
+    number: u32,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume on `cache`. Fails with `InvalidArgument` if the device does
+    /// not hold one, with `NotSupported` if it uses features we cannot read and with
+    /// `Corrupt` if its layout does not add up.
+    pub fn mount(cache: Arc<BufferCache>) -> Result<Arc<Ext2Fs>, FsError> {
+        let mut superblock = [0; 1024];
+        let device_size = cache.block_count() * cache.block_size() as u64;
+        if device_size < SUPERBLOCK_OFFSET * 2 {
+            return Err(FsError::InvalidArgument);
+        }
+        cache.read(SUPERBLOCK_OFFSET, &mut superblock)?;
+        if u16_at(&superblock, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let revision = u32_at(&superblock, 76);
+        let (inode_size, first_inode, incompat, ro_compat) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE, 0, 0),
+            _ => (
+                u16_at(&superblock, 88) as u64,
+                u32_at(&superblock, 84),
+                u32_at(&superblock, 96),
+                u32_at(&superblock, 100),
+            ),
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        let log_block_size = u32_at(&superblock, 24);
+        let layout = Layout {
+            block_size: 1024 << log_block_size.min(6),
+            blocks_count: u32_at(&superblock, 4),
+            inodes_count: u32_at(&superblock, 0),
+            first_data_block: u32_at(&superblock, 20),
+            blocks_per_group: u32_at(&superblock, 32),
+            inodes_per_group: u32_at(&superblock, 40),
+            inode_size,
+            first_inode,
+            group_table: 0,
+            file_type: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        };
+        let valid = log_block_size <= 6
+            && layout.blocks_per_group > 0
+            && layout.inodes_per_group > 0
+            && layout.inode_size >= GOOD_OLD_INODE_SIZE
+            && layout.inode_size <= layout.block_size
+            && layout.blocks_count as u64 * layout.block_size <= device_size;
+        if !valid {
+            return Err(FsError::InvalidArgument);
+        }
+        // Each group has one block of block bitmap and one of inode bitmap.
+        let bits_per_block = layout.block_size as u32 * 8;
+        if layout.first_data_block >= layout.blocks_count
+            || layout.blocks_per_group > bits_per_block
+            || layout.inodes_per_group > bits_per_block
+        {
+            return Err(FsError::Corrupt);
+        }
+        // The descriptors start in the block after the superblock.
+        let layout = Layout {
+            group_table: (layout.first_data_block as u64 + 1) * layout.block_size,
+            ..layout
+        };
+
+        let group_count = (layout.blocks_count - layout.first_data_block)
+            .div_ceil(layout.blocks_per_group) as u64;
+        let table_size = group_count * GROUP_DESCRIPTOR_SIZE;
+        if layout.group_table + table_size > device_size {
+            return Err(FsError::Corrupt);
+        }
+        // Still a lot of memory for a volume with tiny groups.
+        let mut table = Vec::new();
+        let mut groups = Vec::new();
+        table
+            .try_reserve_exact(table_size as usize)
+            .map_err(|_| FsError::NoSpace)?;
+        groups
+            .try_reserve_exact(group_count as usize)
+            .map_err(|_| FsError::NoSpace)?;
+        table.resize(table_size as usize, 0);
+        cache.read(layout.group_table, &mut table)?;
+        groups.extend(
+            table
+                .chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+                .map(|descriptor| Group {
+                    block_bitmap: u32_at(descriptor, 0),
+                    inode_bitmap: u32_at(descriptor, 4),
+                    inode_table: u32_at(descriptor, 8),
+                    free_blocks: u16_at(descriptor, 12),
+                    free_inodes: u16_at(descriptor, 14),
+                    used_directories: u16_at(descriptor, 16),
+                }),
+        );
+
+        let read_only = ro_compat & !SUPPORTED_RO_COMPAT != 0 || cache.is_read_only();
+        Ok(Arc::new_cyclic(|this| Ext2Fs {
+            state: Mutex::new(State {
+                groups,
+                free_blocks: u32_at(&superblock, 12),
+                free_inodes: u32_at(&superblock, 16),
+            }),
+            cache,
+            layout,
+            read_only,
+            this: this.clone(),
+        }))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        block::lock(&self.state)
+    }
+
+    fn lock_writable(&self) -> Result<MutexGuard<State>, FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(self.lock())
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.layout.block_size
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.layout.block_size / 4
+    }
+
+    fn inode_ref(&self, number: u32) -> Arc<Ext2Inode> {
+        Arc::new(Ext2Inode {
+            fs: self.this.upgrade().expect("file system dropped"),
+            number,
+        })
+    }
+
+    fn inode_offset(&self, state: &State, number: u32) -> Result<u64, FsError> {
+        if number == 0 || number > self.layout.inodes_count {
+            return Err(FsError::Io);
+        }
+        let index = number - 1;
+        let group = state
+            .groups
+            .get((index / self.layout.inodes_per_group) as usize)
+            .ok_or(FsError::Io)?;
+        let within = (index % self.layout.inodes_per_group) as u64;
+        Ok(self.block_offset(group.inode_table) + within * self.layout.inode_size)
+    }
+
+    fn read_inode(&self, state: &State, number: u32) -> Result<DiskInode, FsError> {
+        let mut data = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.cache
+            .read(self.inode_offset(state, number)?, &mut data)?;
+        let mode = u16_at(&data, 0);
+        let mut size = u32_at(&data, 4) as u64;
+        // For regular files the directory ACL field holds the upper half of the size.
+        if self.layout.large_file && mode & MODE_TYPE_MASK == MODE_REGULAR {
+            size |= (u32_at(&data, 108) as u64) << 32;
+        }
+        let mut block = [0; 15];
+        for (index, pointer) in block.iter_mut().enumerate() {
+            *pointer = u32_at(&data, 40 + index * 4);
+        }
+        Ok(DiskInode {
+            mode,
+            size,
+            accessed: u32_at(&data, 8),
+            changed: u32_at(&data, 12),
+            modified: u32_at(&data, 16),
+            deleted: u32_at(&data, 20),
+            links: u16_at(&data, 26),
+            sectors: u32_at(&data, 28),
+            flags: u32_at(&data, 32),
+            block,
+        })
+    }
+
+    fn write_inode(&self, state: &State, number: u32, inode: &DiskInode) -> Result<(), FsError> {
+        let offset = self.inode_offset(state, number)?;
+        let mut data = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.cache.read(offset, &mut data)?;
+        put_u16(&mut data, 0, inode.mode);
+        put_u32(&mut data, 4, inode.size as u32);
+        put_u32(&mut data, 8, inode.accessed);
+        put_u32(&mut data, 12, inode.changed);
+        put_u32(&mut data, 16, inode.modified);
+        put_u32(&mut data, 20, inode.deleted);
+        put_u16(&mut data, 26, inode.links);
+        put_u32(&mut data, 28, inode.sectors);
+        put_u32(&mut data, 32, inode.flags);
+        for (index, pointer) in inode.block.iter().enumerate() {
+            put_u32(&mut data, 40 + index * 4, *pointer);
+        }
+        if inode.mode & MODE_TYPE_MASK == MODE_REGULAR {
+            put_u32(&mut data, 108, (inode.size >> 32) as u32);
+        }
+        self.cache.write(offset, &data)?;
+        Ok(())
+    }
+
+    fn write_group(&self, state: &State, index: usize) -> Result<(), FsError> {
+        let group = &state.groups[index];
+        let offset = self.layout.group_table + index as u64 * GROUP_DESCRIPTOR_SIZE;
+        let mut data = [0; 6];
+        put_u16(&mut data, 0, group.free_blocks);
+        put_u16(&mut data, 2, group.free_inodes);
+        put_u16(&mut data, 4, group.used_directories);
+        self.cache.write(offset + 12, &data)?;
+        let mut counts = [0; 8];
+        put_u32(&mut counts, 0, state.free_blocks);
+        put_u32(&mut counts, 4, state.free_inodes);
+        self.cache.write(SUPERBLOCK_OFFSET + 12, &counts)?;
+        Ok(())
+    }
+
+    // Finds and sets a clear bit among the first `count` bits of a bitmap block.
+    fn take_bit(&self, bitmap: u32, first: u32, count: u32) -> Result<Option<u32>, FsError> {
+        let mut bytes = vec![0; ((count + 7) / 8) as usize];
+        self.cache.read(self.block_offset(bitmap), &mut bytes)?;
+        for (index, byte) in bytes.iter().enumerate().skip((first / 8) as usize) {
+            // Bits before `first` count as taken.
+            let skipped = if index as u32 == first / 8 {
+                (1u8 << (first % 8)) - 1
+            } else {
+                0
+            };
+            if byte | skipped == 0xff {
+                continue;
+            }
+            let bit = (byte | skipped).trailing_ones();
+            let number = index as u32 * 8 + bit;
+            if number >= count {
+                break;
+            }
+            self.cache
+                .write(self.block_offset(bitmap) + index as u64, &[byte | 1 << bit])?;
+            return Ok(Some(number));
+        }
+        Ok(None)
+    }
+
+    fn clear_bit(&self, bitmap: u32, number: u32) -> Result<(), FsError> {
+        let offset = self.block_offset(bitmap) + (number / 8) as u64;
+        let mut byte = [0];
+        self.cache.read(offset, &mut byte)?;
+        self.cache
+            .write(offset, &[byte[0] & !(1 << (number % 8))])?;
+        Ok(())
+    }
+
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let start = self.layout.first_data_block + group as u32 * self.layout.blocks_per_group;
+        (self.layout.blocks_count - start).min(self.layout.blocks_per_group)
+    }
+
+    // Allocates a zeroed block, preferably in group `near`.
+    fn allocate_block(&self, state: &mut State, near: usize) -> Result<u32, FsError> {
+        let count = state.groups.len();
+        for index in (0..count).map(|i| (near + i) % count) {
+            let group = state.groups[index];
+            if group.free_blocks == 0 {
+                continue;
+            }
+            if let Some(bit) = self.take_bit(group.block_bitmap, 0, self.blocks_in_group(index))? {
+                state.groups[index].free_blocks -= 1;
+                state.free_blocks = state.free_blocks.saturating_sub(1);
+                self.write_group(state, index)?;
+                let block = self.layout.first_data_block
+                    + index as u32 * self.layout.blocks_per_group
+                    + bit;
+                self.cache.write(
+                    self.block_offset(block),
+                    &vec![0; self.layout.block_size as usize],
+                )?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut State, block: u32) -> Result<(), FsError> {
+        if block < self.layout.first_data_block || block >= self.layout.blocks_count {
+            return Err(FsError::Io);
+        }
+        let index =
+            ((block - self.layout.first_data_block) / self.layout.blocks_per_group) as usize;
+        let bit = (block - self.layout.first_data_block) % self.layout.blocks_per_group;
+        self.clear_bit(state.groups[index].block_bitmap, bit)?;
+        state.groups[index].free_blocks += 1;
+        state.free_blocks += 1;
+        self.write_group(state, index)
+    }
+
+    fn allocate_inode(
+        &self,
+        state: &mut State,
+        near: usize,
+        directory: bool,
+    ) -> Result<u32, FsError> {
+        let count = state.groups.len();
+        for index in (0..count).map(|i| (near + i) % count) {
+            let group = state.groups[index];
+            if group.free_inodes == 0 {
+                continue;
+            }
+            // Reserved inodes are normally marked used already; never hand them out.
+            let first = self
+                .layout
+                .first_inode
+                .saturating_sub(1)
+                .saturating_sub(index as u32 * self.layout.inodes_per_group);
+            if let Some(bit) =
+                self.take_bit(group.inode_bitmap, first, self.layout.inodes_per_group)?
+            {
+                let number = index as u32 * self.layout.inodes_per_group + bit + 1;
+                let group = &mut state.groups[index];
+                group.free_inodes -= 1;
+                if directory {
+                    group.used_directories += 1;
+                }
+                state.free_inodes = state.free_inodes.saturating_sub(1);
+                self.write_group(state, index)?;
+                return Ok(number);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, state: &mut State, number: u32, directory: bool) -> Result<(), FsError> {
+        let index = ((number - 1) / self.layout.inodes_per_group) as usize;
+        self.clear_bit(
+            state.groups[index].inode_bitmap,
+            (number - 1) % self.layout.inodes_per_group,
+        )?;
+        let group = &mut state.groups[index];
+        group.free_inodes += 1;
+        if directory {
+            group.used_directories = group.used_directories.saturating_sub(1);
+        }
+        state.free_inodes += 1;
+        self.write_group(state, index)
+    }
+
+    fn group_of(&self, number: u32) -> usize {
+        ((number - 1) / self.layout.inodes_per_group) as usize
+    }
+
+    fn pointer(&self, block: u32, index: u64) -> Result<u32, FsError> {
+        let mut bytes = [0; 4];
+        self.cache
+            .read(self.block_offset(block) + index * 4, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn set_pointer(&self, block: u32, index: u64, value: u32) -> Result<(), FsError> {
+        self.cache
+            .write(self.block_offset(block) + index * 4, &value.to_le_bytes())?;
+        Ok(())
+    }
+
+    // The slot in the inode and the indexes into indirect blocks that lead to data block
+    // `index` of a file.
+    fn block_path(&self, index: u64) -> Result<(usize, Vec<u64>), FsError> {
+        let per_block = self.pointers_per_block();
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, Vec::new()));
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < per_block {
+            return Ok((SINGLE_INDIRECT, vec![index]));
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            return Ok((DOUBLE_INDIRECT, vec![index / per_block, index % per_block]));
+        }
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let path = vec![
+                index / (per_block * per_block),
+                index / per_block % per_block,
+                index % per_block,
+            ];
+            return Ok((TRIPLE_INDIRECT, path));
+        }
+        Err(FsError::NoSpace)
+    }
+
+    // Returns the block holding data block `index` of `inode`, 0 for a hole. With
+    // `allocate` set, missing data and indirect blocks are allocated.
+    fn map_block(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        index: u64,
+        allocate: bool,
+    ) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index)?;
+        let sectors_per_block = (self.layout.block_size / 512) as u32;
+        let group = self.group_of(number);
+
+        let mut block = inode.block[slot];
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+            block = self.allocate_block(state, group)?;
+            inode.block[slot] = block;
+            inode.sectors += sectors_per_block;
+        }
+        for index in path {
+            let mut next = self.pointer(block, index)?;
+            if next == 0 {
+                if !allocate {
+                    return Ok(0);
+                }
+                next = self.allocate_block(state, group)?;
+                self.set_pointer(block, index, next)?;
+                inode.sectors += sectors_per_block;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    // Frees the blocks of the tree below `block` from relative data block `start` on.
+    // `level` 0 is a data block. Returns whether `block` itself was freed.
+    fn free_tree(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        block: u32,
+        level: u32,
+        start: u64,
+    ) -> Result<bool, FsError> {
+        let sectors_per_block = (self.layout.block_size / 512) as u32;
+        if level > 0 {
+            let per_block = self.pointers_per_block();
+            let per_entry = per_block.pow(level - 1);
+            let mut empty = true;
+            for index in 0..per_block {
+                let child = self.pointer(block, index)?;
+                if child == 0 {
+                    continue;
+                }
+                let first = index * per_entry;
+                if first + per_entry <= start {
+                    // Entirely kept.
+                    empty = false;
+                    continue;
+                }
+                if self.free_tree(state, inode, child, level - 1, start.saturating_sub(first))? {
+                    self.set_pointer(block, index, 0)?;
+                } else {
+                    empty = false;
+                }
+            }
+            if !empty {
+                return Ok(false);
+            }
+        } else if start > 0 {
+            return Ok(false);
+        }
+        self.free_block(state, block)?;
+        inode.sectors = inode.sectors.saturating_sub(sectors_per_block);
+        Ok(true)
+    }
+
+    // Frees all data blocks from `keep` on.
+    fn free_blocks_from(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        keep: u64,
+    ) -> Result<(), FsError> {
+        for slot in (keep as usize).min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            if inode.block[slot] != 0 {
+                let block = inode.block[slot];
+                self.free_tree(state, inode, block, 0, 0)?;
+                inode.block[slot] = 0;
+            }
+        }
+        let per_block = self.pointers_per_block();
+        let mut base = DIRECT_BLOCKS as u64;
+        for (slot, level) in [
+            (SINGLE_INDIRECT, 1),
+            (DOUBLE_INDIRECT, 2),
+            (TRIPLE_INDIRECT, 3),
+        ] {
+            let span = per_block.pow(level);
+            let block = inode.block[slot];
+            if block != 0
+                && keep < base + span
+                && self.free_tree(state, inode, block, level, keep.saturating_sub(base))?
+            {
+                inode.block[slot] = 0;
+            }
+            base += span;
+        }
+        Ok(())
+    }
+
+    fn read_data(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        let block_size = self.layout.block_size;
+        let start = offset.min(inode.size);
+        let count = (buf.len() as u64).min(inode.size - start) as usize;
+
+        // Short symbolic link targets live in the block pointers.
+        if inode.mode & MODE_TYPE_MASK == MODE_SYMLINK
+            && inode.size < FAST_SYMLINK_SIZE
+            && inode.sectors == 0
+        {
+            let target: Vec<u8> = inode
+                .block
+                .iter()
+                .flat_map(|pointer| pointer.to_le_bytes())
+                .collect();
+            buf[..count].copy_from_slice(&target[start as usize..start as usize + count]);
+            return Ok(count);
+        }
+
+        let mut done = 0;
+        while done < count {
+            let position = start + done as u64;
+            let within = position % block_size;
+            let length = ((block_size - within) as usize).min(count - done);
+            match self.map_block(state, number, inode, position / block_size, false)? {
+                0 => buf[done..done + length].fill(0),
+                block => self.cache.read(
+                    self.block_offset(block) + within,
+                    &mut buf[done..done + length],
+                )?,
+            }
+            done += length;
+        }
+        Ok(count)
+    }
+
+    fn write_data(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let length = ((block_size - within) as usize).min(buf.len() - done);
+            let block = self.map_block(state, number, inode, position / block_size, true)?;
+            self.cache
+                .write(self.block_offset(block) + within, &buf[done..done + length])?;
+            done += length;
+            inode.size = inode.size.max(position + length as u64);
+        }
+        Ok(())
+    }
+
+    fn resize(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        size: u64,
+    ) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        if size < inode.size {
+            // Zero the tail of the last block, so growing the file again reads zeros.
+            let within = size % block_size;
+            if within != 0 {
+                let block = self.map_block(state, number, inode, size / block_size, false)?;
+                if block != 0 {
+                    let zeros = vec![0; (block_size - within) as usize];
+                    self.cache
+                        .write(self.block_offset(block) + within, &zeros)?;
+                }
+            }
+            self.free_blocks_from(state, inode, (size + block_size - 1) / block_size)?;
+        }
+        // Growing leaves a hole.
+        inode.size = size;
+        Ok(())
+    }
+
+    fn read_entries(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+    ) -> Result<Vec<RawEntry>, FsError> {
+        let block_size = self.layout.block_size as usize;
+        let mut entries = Vec::new();
+        let mut block = vec![0; block_size];
+
+        for index in 0..(inode.size + block_size as u64 - 1) / block_size as u64 {
+            let base = index * block_size as u64;
+            self.read_data(state, number, inode, base, &mut block)?;
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= block_size {
+                let entry = &block[offset..];
+                let record_length = u16_at(entry, 4) as usize;
+                let name_length = entry[6] as usize;
+                if record_length < DIR_ENTRY_HEADER
+                    || offset + record_length > block_size
+                    || DIR_ENTRY_HEADER + name_length > record_length
+                {
+                    return Err(FsError::Io);
+                }
+                entries.push(RawEntry {
+                    offset: base + offset as u64,
+                    inode: u32_at(entry, 0),
+                    record_length,
+                    name: String::from_utf8_lossy(
+                        &entry[DIR_ENTRY_HEADER..DIR_ENTRY_HEADER + name_length],
+                    )
+                    .into_owned(),
+                    file_type: entry[7],
+                });
+                offset += record_length;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+        name: &str,
+    ) -> Result<RawEntry, FsError> {
+        self.read_entries(state, number, inode)?
+            .into_iter()
+            .find(|entry| entry.inode != 0 && entry.name == name)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn write_entry(
+        &self,
+        state: &mut State,
+        number: u32,
+        directory: &mut DiskInode,
+        offset: u64,
+        entry: &RawEntry,
+    ) -> Result<(), FsError> {
+        let mut data = vec![0; DIR_ENTRY_HEADER + entry.name.len()];
+        put_u32(&mut data, 0, entry.inode);
+        put_u16(&mut data, 4, entry.record_length as u16);
+        data[6] = entry.name.len() as u8;
+        data[7] = if self.layout.file_type {
+            entry.file_type
+        } else {
+            0
+        };
+        data[DIR_ENTRY_HEADER..].copy_from_slice(entry.name.as_bytes());
+        self.write_data(state, number, directory, offset, &data)
+    }
+
+    // Adds `name` for inode `target` to the directory `number`, reusing free space in
+    // its blocks or appending a block.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        number: u32,
+        directory: &mut DiskInode,
+        name: &str,
+        target: u32,
+        file_type: u8,
+    ) -> Result<(), FsError> {
+        let needed = entry_length(name.len());
+        let block_size = self.layout.block_size as usize;
+
+        for entry in self.read_entries(state, number, directory)? {
+            let used = if entry.inode == 0 {
+                0
+            } else {
+                entry_length(entry.name.len())
+            };
+            if entry.record_length - used < needed {
+                continue;
+            }
+            // Either take over an unused entry or split off the slack of a used one.
+            let offset = if used == 0 {
+                entry.offset
+            } else {
+                let shrunk = RawEntry {
+                    record_length: used,
+                    ..entry
+                };
+                self.write_entry(state, number, directory, shrunk.offset, &shrunk)?;
+                shrunk.offset + used as u64
+            };
+            let record_length = if used == 0 {
+                entry.record_length
+            } else {
+                entry.record_length - used
+            };
+            let new = RawEntry {
+                offset,
+                inode: target,
+                record_length,
+                name: String::from(name),
+                file_type,
+            };
+            return self.write_entry(state, number, directory, offset, &new);
+        }
+
+        let offset = directory.size;
+        let new = RawEntry {
+            offset,
+            inode: target,
+            record_length: block_size,
+            name: String::from(name),
+            file_type,
+        };
+        directory.size += block_size as u64;
+        self.write_entry(state, number, directory, offset, &new)?;
+        // Record lengths may not cross blocks, so the new entry spans the whole block.
+        self.write_data(
+            state,
+            number,
+            directory,
+            offset + needed as u64,
+            &vec![0; block_size - needed],
+        )
+    }
+
+    // Removes the entry at `offset` by merging it into the one before it in the same
+    // block, or by clearing its inode if it is the first one.
+    fn remove_entry(
+        &self,
+        state: &mut State,
+        number: u32,
+        directory: &mut DiskInode,
+        offset: u64,
+    ) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        let entries = self.read_entries(state, number, directory)?;
+        let index = entries
+            .iter()
+            .position(|entry| entry.offset == offset)
+            .ok_or(FsError::Io)?;
+        let entry = &entries[index];
+        match index.checked_sub(1).map(|previous| &entries[previous]) {
+            Some(previous) if previous.offset / block_size == offset / block_size => {
+                let record_length = previous.record_length + entry.record_length;
+                let merged = RawEntry {
+                    record_length,
+                    name: previous.name.clone(),
+                    ..*previous
+                };
+                self.write_entry(state, number, directory, previous.offset, &merged)
+            }
+            _ => self.write_data(state, number, directory, offset, &0u32.to_le_bytes()),
+        }
+    }
+
+    fn is_empty_directory(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut DiskInode,
+    ) -> Result<bool, FsError> {
+        Ok(self
+            .read_entries(state, number, inode)?
+            .iter()
+            .all(|entry| entry.inode == 0 || entry.name == "." || entry.name == ".."))
+    }
+
+    // Drops one link to `number`, or two for a directory, which also loses its `.`.
+    // The inode and its blocks are freed when no link is left.
+    fn drop_link(&self, state: &mut State, number: u32) -> Result<(), FsError> {
+        let mut inode = self.read_inode(state, number)?;
+        let directory = inode.is_directory();
+        inode.links = inode.links.saturating_sub(if directory { 2 } else { 1 });
+        inode.changed = time::now() as u32;
+        if inode.links == 0 {
+            self.free_blocks_from(state, &mut inode, 0)?;
+            inode.size = 0;
+            inode.deleted = time::now() as u32;
+            self.write_inode(state, number, &inode)?;
+            return self.free_inode(state, number, directory);
+        }
+        self.write_inode(state, number, &inode)
+    }
+
+    // Makes a directory modification safe: we do not update hash indexes, so the
+    // directory has to stop claiming it has one.
+    fn prepare_directory_change(inode: &mut DiskInode) {
+        inode.flags &= !FLAG_INDEX;
+        inode.touch();
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.inode_ref(ROOT_INODE)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.cache.sync()?;
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains('/')
+        || name.len() > MAX_NAME_LENGTH
+    {
+        Err(FsError::InvalidPath)
+    } else {
+        Ok(())
+    }
+}
+
+impl Ext2Inode {
+    fn directory(&self, state: &State) -> Result<DiskInode, FsError> {
+        let inode = self.fs.read_inode(state, self.number)?;
+        if inode.links == 0 {
+            Err(FsError::NotFound)
+        } else if !inode.is_directory() {
+            Err(FsError::NotADirectory)
+        } else {
+            Ok(inode)
+        }
+    }
+
+    fn file(&self, state: &State) -> Result<DiskInode, FsError> {
+        let inode = self.fs.read_inode(state, self.number)?;
+        if inode.is_directory() {
+            Err(FsError::IsADirectory)
+        } else {
+            Ok(inode)
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let state = self.fs.lock();
+        let inode = self
+            .fs
+            .read_inode(&state, self.number)
+            .unwrap_or_else(|_| DiskInode::new(0));
+        Metadata {
+            inode: self.number as u64,
+            kind: inode.kind(),
+            size: inode.size,
+            links: inode.links as u32,
+            accessed: inode.accessed as u64,
+            modified: inode.modified as u64,
+            changed: inode.changed as u64,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut state = self.fs.lock();
+        let mut inode = self.file(&state)?;
+        self.fs
+            .read_data(&mut state, self.number, &mut inode, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.fs.lock_writable()?;
+        let mut inode = self.file(&state)?;
+        if !self.fs.layout.large_file && offset + buf.len() as u64 > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let result = self
+            .fs
+            .write_data(&mut state, self.number, &mut inode, offset, buf);
+        inode.touch();
+        // Record whatever was allocated, even if the write failed half way.
+        self.fs.write_inode(&state, self.number, &inode)?;
+        result.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut state = self.fs.lock_writable()?;
+        let mut inode = self.file(&state)?;
+        self.fs.resize(&mut state, self.number, &mut inode, size)?;
+        inode.touch();
+        self.fs.write_inode(&state, self.number, &inode)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let mut state = self.fs.lock();
+        let mut directory = self.directory(&state)?;
+        let entry = self
+            .fs
+            .find_entry(&mut state, self.number, &mut directory, name)?;
+        Ok(self.fs.inode_ref(entry.inode))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut state = self.fs.lock();
+        let mut directory = self.directory(&state)?;
+        let entries = self
+            .fs
+            .read_entries(&mut state, self.number, &mut directory)?;
+
+        let mut result = Vec::new();
+        for entry in entries {
+            if entry.inode == 0 || entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            // The type in the entry does not tell device files apart from each other.
+            let kind = if self.fs.layout.file_type && entry.file_type == TYPE_DIRECTORY {
+                FileType::Directory
+            } else {
+                self.fs.read_inode(&state, entry.inode)?.kind()
+            };
+            result.push(DirEntry {
+                name: entry.name,
+                inode: entry.inode as u64,
+                kind,
+            });
+        }
+        Ok(result)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsError> {
+        check_name(name)?;
+        let (mode, file_type) = match kind {
+            FileType::Regular => (MODE_REGULAR | 0o644, TYPE_REGULAR),
+            FileType::Directory => (MODE_DIRECTORY | 0o755, TYPE_DIRECTORY),
+            _ => return Err(FsError::NotSupported),
+        };
+        let mut state = self.fs.lock_writable()?;
+        let mut parent = self.directory(&state)?;
+        match self
+            .fs
+            .find_entry(&mut state, self.number, &mut parent, name)
+        {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let directory = kind == FileType::Directory;
+        let group = self.fs.group_of(self.number);
+        let number = self.fs.allocate_inode(&mut state, group, directory)?;
+        let mut inode = DiskInode::new(mode);
+        // Start from a clean record, a previous owner may have left fields behind.
+        let offset = self.fs.inode_offset(&state, number)?;
+        self.fs
+            .cache
+            .write(offset, &vec![0; self.fs.layout.inode_size as usize])?;
+
+        if directory {
+            inode.links = 2;
+            let dot = RawEntry {
+                offset: 0,
+                inode: number,
+                record_length: 12,
+                name: String::from("."),
+                file_type,
+            };
+            self.fs
+                .write_entry(&mut state, number, &mut inode, 0, &dot)?;
+            let block_size = self.fs.layout.block_size as usize;
+            let dot_dot = RawEntry {
+                offset: 12,
+                inode: self.number,
+                record_length: block_size - 12,
+                name: String::from(".."),
+                file_type,
+            };
+            self.fs
+                .write_entry(&mut state, number, &mut inode, 12, &dot_dot)?;
+            inode.size = block_size as u64;
+            parent.links += 1;
+        }
+        self.fs.write_inode(&state, number, &inode)?;
+
+        Ext2Fs::prepare_directory_change(&mut parent);
+        let added = self.fs.add_entry(
+            &mut state,
+            self.number,
+            &mut parent,
+            name,
+            number,
+            file_type,
+        );
+        self.fs.write_inode(&state, self.number, &parent)?;
+        if let Err(error) = added {
+            self.fs.drop_link(&mut state, number)?;
+            return Err(error);
+        }
+        Ok(self.fs.inode_ref(number))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut state = self.fs.lock_writable()?;
+        let mut parent = self.directory(&state)?;
+        let entry = self
+            .fs
+            .find_entry(&mut state, self.number, &mut parent, name)?;
+        let mut inode = self.fs.read_inode(&state, entry.inode)?;
+        if inode.is_directory() {
+            if !self
+                .fs
+                .is_empty_directory(&mut state, entry.inode, &mut inode)?
+            {
+                return Err(FsError::NotEmpty);
+            }
+            // The `..` of the removed directory pointed here.
+            parent.links = parent.links.saturating_sub(1);
+        }
+
+        Ext2Fs::prepare_directory_change(&mut parent);
+        self.fs
+            .remove_entry(&mut state, self.number, &mut parent, entry.offset)?;
+        self.fs.write_inode(&state, self.number, &parent)?;
+        self.fs.drop_link(&mut state, entry.inode)
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &InodeRef, new_name: &str) -> Result<(), FsError> {
+        check_name(new_name)?;
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &new_parent.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        let mut state = self.fs.lock_writable()?;
+        let same_directory = self.number == new_parent.number;
+
+        let mut old_directory = self.directory(&state)?;
+        let entry = self
+            .fs
+            .find_entry(&mut state, self.number, &mut old_directory, old_name)?;
+        let mut moved = self.fs.read_inode(&state, entry.inode)?;
+        let directory = moved.is_directory();
+
+        let mut new_directory = new_parent.directory(&state)?;
+        // Check what we are about to replace before changing anything.
+        match self
+            .fs
+            .find_entry(&mut state, new_parent.number, &mut new_directory, new_name)
+        {
+            Ok(replaced) if replaced.inode == entry.inode => return Ok(()),
+            Ok(replaced) => {
+                let mut replaced_inode = self.fs.read_inode(&state, replaced.inode)?;
+                match (directory, replaced_inode.is_directory()) {
+                    (true, true)
+                        if !self.fs.is_empty_directory(
+                            &mut state,
+                            replaced.inode,
+                            &mut replaced_inode,
+                        )? =>
+                    {
+                        return Err(FsError::NotEmpty)
+                    }
+                    (true, true) => new_directory.links = new_directory.links.saturating_sub(1),
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
+                Ext2Fs::prepare_directory_change(&mut new_directory);
+                self.fs.remove_entry(
+                    &mut state,
+                    new_parent.number,
+                    &mut new_directory,
+                    replaced.offset,
+                )?;
+                self.fs
+                    .write_inode(&state, new_parent.number, &new_directory)?;
+                self.fs.drop_link(&mut state, replaced.inode)?;
+            }
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        // Add the new entry before removing the old one, so a failure loses nothing.
+        let mut new_directory = new_parent.directory(&state)?;
+        Ext2Fs::prepare_directory_change(&mut new_directory);
+        let file_type = entry.file_type;
+        self.fs.add_entry(
+            &mut state,
+            new_parent.number,
+            &mut new_directory,
+            new_name,
+            entry.inode,
+            file_type,
+        )?;
+        if directory && !same_directory {
+            new_directory.links += 1;
+        }
+        self.fs
+            .write_inode(&state, new_parent.number, &new_directory)?;
+
+        let mut old_directory = self.directory(&state)?;
+        Ext2Fs::prepare_directory_change(&mut old_directory);
+        let entry = self
+            .fs
+            .find_entry(&mut state, self.number, &mut old_directory, old_name)?;
+        self.fs
+            .remove_entry(&mut state, self.number, &mut old_directory, entry.offset)?;
+        if directory && !same_directory {
+            old_directory.links = old_directory.links.saturating_sub(1);
+        }
+        self.fs.write_inode(&state, self.number, &old_directory)?;
+
+        if directory && !same_directory {
+            let dot_dot = self
+                .fs
+                .find_entry(&mut state, entry.inode, &mut moved, "..")?;
+            let updated = RawEntry {
+                inode: new_parent.number,
+                ..dot_dot
+            };
+            self.fs.write_entry(
+                &mut state,
+                entry.inode,
+                &mut moved,
+                updated.offset,
+                &updated,
+            )?;
+        }
+        moved.changed = time::now() as u32;
+        self.fs.write_inode(&state, entry.inode, &moved)
+    }
+}
+
+// Writes an empty volume with 1 KiB blocks and a single group to `disk`: the superblock
+// in block 1, then the descriptor table, the bitmaps, 8 blocks of inodes and the root
+// directory in block 13.
+#[cfg(test)]
+fn format_ext2(disk: &dyn BlockDevice) {
+    const BLOCKS: u32 = 512;
+    const INODES: u32 = 64;
+    const USED_BLOCKS: u32 = 13;
+    let write = |block: u64, data: &[u8]| disk.write_blocks(block * 2, data).unwrap();
+
+    let mut superblock = [0; 1024];
+    put_u32(&mut superblock, 0, INODES);
+    put_u32(&mut superblock, 4, BLOCKS);
+    put_u32(&mut superblock, 12, BLOCKS - 1 - USED_BLOCKS);
+    put_u32(&mut superblock, 16, INODES - 11);
+    put_u32(&mut superblock, 20, 1);
+    put_u32(&mut superblock, 32, 8192);
+    put_u32(&mut superblock, 40, INODES);
+    put_u16(&mut superblock, 56, MAGIC);
+    write(1, &superblock);
+
+    let mut descriptors = [0; 1024];
+    put_u32(&mut descriptors, 0, 3);
+    put_u32(&mut descriptors, 4, 4);
+    put_u32(&mut descriptors, 8, 5);
+    put_u16(&mut descriptors, 12, (BLOCKS - 1 - USED_BLOCKS) as u16);
+    put_u16(&mut descriptors, 14, (INODES - 11) as u16);
+    put_u16(&mut descriptors, 16, 1);
+    write(2, &descriptors);
+
+    // Bits past the end of the group count as used.
+    let mut bitmap = [0xff; 1024];
+    bitmap[..64].fill(0);
+    for bit in 0..USED_BLOCKS as usize {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+    bitmap[63] |= 0x80;
+    write(3, &bitmap);
+    let mut bitmap = [0xff; 1024];
+    bitmap[..8].copy_from_slice(&[0xff, 0x07, 0, 0, 0, 0, 0, 0]);
+    write(4, &bitmap);
+
+    let mut table = [0; 1024];
+    let root = &mut table[128..256];
+    put_u16(root, 0, MODE_DIRECTORY | 0o755);
+    put_u32(root, 4, 1024);
+    put_u16(root, 26, 2);
+    put_u32(root, 28, 2);
+    put_u32(root, 40, 13);
+    write(5, &table);
+
+    let mut directory = [0; 1024];
+    for (offset, name, length) in [(0, &b"."[..], 12), (12, &b".."[..], 1012)] {
+        put_u32(&mut directory, offset, ROOT_INODE);
+        put_u16(&mut directory, offset + 4, length);
+        directory[offset + 6] = name.len() as u8;
+        directory[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+    }
+    write(13, &directory);
+}
+
+#[test_case]
+fn test_ext2_operations() {
+    use crate::block::ram::RamDisk;
+
+    let disk = RamDisk::new("ram0", 512, 1024);
+    format_ext2(disk.as_ref());
+    let fs = Ext2Fs::mount(BufferCache::new(disk.clone(), 8)).unwrap();
+    let root = fs.root();
+
+    let dir = root.create("dir", FileType::Directory).unwrap();
+    let file = dir.create("file", FileType::Regular).unwrap();
+    // Past the direct blocks, so an indirect block is needed.
+    let data: Vec<u8> = (0..4000).map(|i| (i % 251) as u8).collect();
+    file.write_at(12 * 1024, &data).unwrap();
+    assert_eq!(file.metadata().size, 12 * 1024 + 4000);
+    assert_eq!(root.metadata().links, 3);
+    dir.rename("file", &root, "moved").unwrap();
+    fs.sync().unwrap();
+
+    let fs = Ext2Fs::mount(BufferCache::new(disk, 8)).unwrap();
+    let root = fs.root();
+    let moved = root.lookup("moved").unwrap();
+    let mut buf = vec![0xff; 12 * 1024 + 4000];
+    assert_eq!(moved.read_at(0, &mut buf).unwrap(), buf.len());
+    assert!(buf[..12 * 1024].iter().all(|&b| b == 0));
+    assert_eq!(&buf[12 * 1024..], &data[..]);
+
+    let free = fs.lock().free_blocks;
+    moved.truncate(0).unwrap();
+    // Four data blocks and the indirect block.
+    assert_eq!(fs.lock().free_blocks, free + 5);
+    root.rename("dir", &root, "renamed").unwrap();
+    root.unlink("moved").unwrap();
+    root.unlink("renamed").unwrap();
+    assert!(root.read_dir().unwrap().is_empty());
+    assert_eq!(root.metadata().links, 2);
+    assert_eq!(fs.lock().free_inodes, 64 - 11);
+}
+
+#[test_case]
+fn test_ext2_skips_reserved_inodes() {
+    use crate::block::ram::RamDisk;
+
+    let disk = RamDisk::new("ram0", 512, 1024);
+    format_ext2(disk.as_ref());
+    // Leave the reserved inodes other than the root unmarked in the bitmap.
+    let mut bitmap = [0xff; 1024];
+    bitmap[..8].copy_from_slice(&[0x02, 0, 0, 0, 0, 0, 0, 0]);
+    disk.write_blocks(8, &bitmap).unwrap();
+    let fs = Ext2Fs::mount(BufferCache::new(disk, 8)).unwrap();
+
+    let file = fs.root().create("file", FileType::Regular).unwrap();
+    assert_eq!(file.metadata().inode, 11);
+}
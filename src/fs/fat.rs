@@ -0,0 +1,1390 @@
+// The FAT file system, in its 12, 16 and 32 bit flavours.
+//
+// The volume starts with reserved sectors holding the BIOS parameter block, followed by
+// the file allocation tables and the data area, which is divided into clusters. The
+// table has one entry per cluster that links it to the next cluster of the same file,
+// so a file is its first cluster plus a chain through the table. Directories are files
+// of 32 byte entries; FAT12 and FAT16 keep the root directory in a fixed region in
+// front of the data area instead. Names longer than 8.3 are stored in extra long name
+// entries in front of the short entry, which still gets a unique 8.3 alias.
+//
+// All operations of a volume are serialized by its state lock. Inodes are shared: the
+// volume keeps a table of the live ones, keyed by the position of their directory entry.
+// Reference: https://wiki.osdev.org/FAT
+// Reference: https://en.wikipedia.org/wiki/Design_of_the_FAT_file_system
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, InodeRef, Metadata};
+use crate::block::{self, cache::BufferCache, BlockDevice};
+use crate::locks::mutex::{Mutex, MutexGuard};
+use crate::time;
+
+const ENTRY_SIZE: u64 = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Read-only, hidden, system and volume ID together mark a long name entry.
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// First name byte of the entry that ends a directory; all following ones are free.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands for 0xe5 as the first character of a name.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+/// Set on the sequence number of the last long name entry, which comes first.
+const LONG_NAME_LAST: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+/// Where the 13 UTF-16 characters of a long name entry are.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Flags in the reserved byte of short entries, used by Windows NT and Linux for names
+/// that are 8.3 apart from being lower case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// Stored as the free cluster count when it is unknown.
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+/// Clusters 2 up to 0x0fff_fff6, the last number below the FAT32 bad cluster mark.
+const MAX_CLUSTERS: u32 = 0x0fff_fff5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Table entries from this value up mark the end of a chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    /// The end of chain marker we write.
+    fn end_marker(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+// Positions are byte offsets on the device.
+struct Geometry {
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u64,
+    /// The fixed root directory of FAT12 and FAT16.
+    root_offset: u64,
+    root_entries: u64,
+    data_offset: u64,
+    cluster_count: u32,
+    /// The first cluster of the root directory on FAT32.
+    root_cluster: u32,
+    fs_info: Option<u64>,
+}
+
+struct State {
+    /// Where the search for a free cluster starts.
+    next_free: u32,
+    free_count: Option<u32>,
+    inodes: BTreeMap<u64, Weak<FatInode>>,
+}
+
+pub struct FatFs {
+    cache: Arc<BufferCache>,
+    geometry: Geometry,
+    state: Mutex<State>,
+    this: Weak<FatFs>,
+}
+
+#[derive(Clone)]
+struct Node {
+    /// Position of the short directory entry, 0 for the root directory.
+    entry: u64,
+    directory: bool,
+    /// 0 for empty files and the fixed root directory.
+    first_cluster: u32,
+    size: u32,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    /// Set once the entry is deleted; the clusters are gone.
+    removed: bool,
+}
+
+pub struct FatInode {
+    fs: Arc<FatFs>,
+    node: Mutex<Node>,
+}
+
+// A directory entry as read from disk.
+struct RawEntry {
+    /// The long name if there is one, otherwise the short one.
+    name: String,
+    short_name: String,
+    data: [u8; ENTRY_SIZE as usize],
+    position: u64,
+    /// Positions of the long name entries and the short entry.
+    slots: Vec<u64>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn entry_cluster(data: &[u8]) -> u32 {
+    (u16_at(data, 20) as u32) << 16 | u16_at(data, 26) as u32
+}
+
+fn set_entry_cluster(data: &mut [u8], cluster: u32) {
+    put_u16(data, 20, (cluster >> 16) as u16);
+    put_u16(data, 26, cluster as u16);
+}
+
+// Converts Unix time to a FAT (date, time) pair. FAT time starts in 1980 and has a
+// resolution of two seconds.
+fn encode_time(unix: u64) -> (u16, u16) {
+    let (year, month, day) = time::civil_from_days(unix / 86400);
+    if year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let seconds = unix % 86400;
+    let date = ((year - 1980).min(127) << 9 | month << 5 | day) as u16;
+    let time = (seconds / 3600 << 11 | seconds % 3600 / 60 << 5 | seconds % 60 / 2) as u16;
+    (date, time)
+}
+
+fn decode_time(date: u16, time: u16) -> u64 {
+    let (year, month, day) = (
+        1980 + (date >> 9) as u64,
+        (date >> 5 & 0xf) as u64,
+        (date & 0x1f) as u64,
+    );
+    if month == 0 || day == 0 {
+        return 0;
+    }
+    let (hours, minutes, seconds) = (
+        (time >> 11) as u64,
+        (time >> 5 & 0x3f) as u64,
+        (time & 0x1f) as u64 * 2,
+    );
+    time::days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+}
+
+fn new_entry(attr: u8, first_cluster: u32) -> [u8; ENTRY_SIZE as usize] {
+    let mut data = [0; ENTRY_SIZE as usize];
+    let (date, time) = encode_time(time::now());
+    data[11] = attr;
+    put_u16(&mut data, 14, time);
+    put_u16(&mut data, 16, date);
+    put_u16(&mut data, 18, date);
+    put_u16(&mut data, 22, time);
+    put_u16(&mut data, 24, date);
+    set_entry_cluster(&mut data, first_cluster);
+    data
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| (sum >> 1 | sum << 7).wrapping_add(byte))
+}
+
+fn display_short_name(data: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut part = String::new();
+        for (index, &byte) in bytes.iter().enumerate() {
+            let byte = if index == 0 && byte == ENTRY_KANJI_E5 {
+                ENTRY_DELETED
+            } else {
+                byte
+            };
+            part.push(if lower {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            } as char);
+        }
+        String::from(part.trim_end_matches(' '))
+    };
+    let mut name = part(&data[..8], data[12] & CASE_LOWER_BASE != 0);
+    let extension = part(&data[8..11], data[12] & CASE_LOWER_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+// Returns the short entry name and case flags if `name` is a valid 8.3 name, apart from
+// parts that are entirely lower case.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rfind('.') {
+        Some(index) => (&name[..index], &name[index + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, offset, flag) in [
+        (base, 0, CASE_LOWER_BASE),
+        (extension, 8, CASE_LOWER_EXTENSION),
+    ] {
+        let has_lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let has_upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            case |= flag;
+        }
+        for (index, byte) in part.bytes().enumerate() {
+            let byte = byte.to_ascii_uppercase();
+            if !is_short_name_char(byte) {
+                return None;
+            }
+            short[offset + index] = byte;
+        }
+    }
+    Some((short, case))
+}
+
+// The parts of a generated short name: the upper case name with invalid characters
+// replaced, cut to 8 and 3 characters.
+fn basis_name(name: &str) -> (Vec<u8>, Vec<u8>) {
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rfind('.') {
+        Some(index) => (&name[..index], &name[index + 1..]),
+        None => (name, ""),
+    };
+    let convert = |part: &str, length: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_short_name_char(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(length)
+            .collect()
+    };
+    let mut base = convert(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    (base, convert(extension, 3))
+}
+
+// Makes `BASE~N.EXT` out of a basis name.
+fn numbered_short_name(base: &[u8], extension: &[u8], number: u32) -> [u8; 11] {
+    let tail = format!("~{}", number);
+    let keep = base.len().min(8 - tail.len());
+    let mut short = [b' '; 11];
+    short[..keep].copy_from_slice(&base[..keep]);
+    short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension);
+    short
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    let invalid = |c: char| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c);
+    if name.is_empty() || name == "." || name == ".." || name.contains(invalid) {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LENGTH {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+// The long name entries for `name`, last one first, as they are stored.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE as usize]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS;
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut data = [0; ENTRY_SIZE as usize];
+            data[0] = index as u8 + 1
+                | if index + 1 == count {
+                    LONG_NAME_LAST
+                } else {
+                    0
+                };
+            data[11] = ATTR_LONG_NAME;
+            data[13] = checksum;
+            for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                // The name is terminated by a NUL if it does not fill the entry, the rest
+                // is padded with 0xffff.
+                let unit = match (index * LONG_NAME_CHARS + i).cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[index * LONG_NAME_CHARS + i],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                put_u16(&mut data, offset, unit);
+            }
+            data
+        })
+        .collect()
+}
+
+// Collects the parts of a long name while walking a directory.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the entry we expect next, counting down to 1.
+    next: u8,
+    slots: Vec<u64>,
+}
+
+impl LongName {
+    fn add(long_name: &mut Option<LongName>, data: &[u8], position: u64) {
+        let sequence = data[0] & 0x1f;
+        if data[0] & LONG_NAME_LAST != 0 && sequence > 0 {
+            *long_name = Some(LongName {
+                units: vec![0xffff; sequence as usize * LONG_NAME_CHARS],
+                checksum: data[13],
+                next: sequence,
+                slots: Vec::new(),
+            });
+        }
+        let valid = match long_name {
+            Some(name) => name.next == sequence && sequence > 0 && name.checksum == data[13],
+            None => false,
+        };
+        if !valid {
+            *long_name = None;
+            return;
+        }
+        let name = long_name.as_mut().unwrap();
+        let start = (sequence as usize - 1) * LONG_NAME_CHARS;
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            name.units[start + i] = u16_at(data, offset);
+        }
+        name.next -= 1;
+        name.slots.push(position);
+    }
+
+    // The name, if all parts were there and they belong to the short entry `data`.
+    fn finish(self, data: &[u8]) -> Option<(String, Vec<u64>)> {
+        if self.next != 0 || self.checksum != short_name_checksum(&data[..11]) {
+            return None;
+        }
+        let end = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0 || unit == 0xffff);
+        let units = &self.units[..end.unwrap_or(self.units.len())];
+        let name = char::decode_utf16(units.iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some((name, self.slots))
+    }
+}
+
+impl FatFs {
+    /// Mounts the FAT volume on `cache`. Fails with `InvalidArgument` if the device does
+    /// not hold one and with `Corrupt` if its FAT cannot describe its clusters.
+    pub fn mount(cache: Arc<BufferCache>) -> Result<Arc<FatFs>, FsError> {
+        let mut boot = [0; 512];
+        cache.read(0, &mut boot)?;
+
+        let bytes_per_sector = u16_at(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            count => count as u64,
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            count => count as u64,
+        };
+        let valid = boot[510..] == [0x55, 0xaa]
+            && [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            && fat_sectors > 0;
+        if !valid {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        if total_sectors <= data_sector
+            || total_sectors * bytes_per_sector > cache.block_count() * cache.block_size() as u64
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let cluster_count = ((total_sectors - data_sector) / sectors_per_cluster) as u32;
+        // The number of clusters alone decides the type.
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        // The FAT needs an entry for every cluster.
+        let fat_bytes = match fat_type {
+            FatType::Fat12 => ((cluster_count as u64 + 2) * 3).div_ceil(2),
+            FatType::Fat16 => (cluster_count as u64 + 2) * 2,
+            FatType::Fat32 => (cluster_count as u64 + 2) * 4,
+        };
+        if cluster_count == 0
+            || cluster_count > MAX_CLUSTERS
+            || fat_bytes > fat_sectors * bytes_per_sector
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        let geometry = Geometry {
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count,
+            root_offset: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_entries,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_count,
+            root_cluster: if fat_type == FatType::Fat32 {
+                u32_at(&boot, 44)
+            } else {
+                0
+            },
+            fs_info: match (fat_type, u16_at(&boot, 48)) {
+                (FatType::Fat32, sector) if sector != 0 && sector != 0xffff => {
+                    Some(sector as u64 * bytes_per_sector)
+                }
+                _ => None,
+            },
+        };
+
+        let mut state = State {
+            next_free: 2,
+            free_count: None,
+            inodes: BTreeMap::new(),
+        };
+        if let Some(offset) = geometry.fs_info {
+            let mut info = [0; 512];
+            cache.read(offset, &mut info)?;
+            if u32_at(&info, 0) == FS_INFO_LEAD_SIGNATURE
+                && u32_at(&info, 484) == FS_INFO_STRUCT_SIGNATURE
+            {
+                let free = u32_at(&info, 488);
+                let next = u32_at(&info, 492);
+                state.free_count = Some(free).filter(|&free| free <= cluster_count);
+                state.next_free = if (2..cluster_count + 2).contains(&next) {
+                    next
+                } else {
+                    2
+                };
+            }
+        }
+
+        let fs = Arc::new_cyclic(|this| FatFs {
+            cache,
+            geometry,
+            state: Mutex::new(state),
+            this: this.clone(),
+        });
+        if fs.geometry.fat_type == FatType::Fat32 && !fs.is_cluster(fs.geometry.root_cluster) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geometry.fat_type
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        block::lock(&self.state)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.geometry.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.geometry.data_offset + (cluster - 2) as u64 * self.geometry.cluster_size
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let g = &self.geometry;
+        Ok(match g.fat_type {
+            FatType::Fat12 => {
+                // Two entries share three bytes.
+                let mut bytes = [0; 2];
+                self.cache
+                    .read(g.fat_offset + (cluster + cluster / 2) as u64, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes);
+                (if cluster & 1 != 0 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }) as u32
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.cache
+                    .read(g.fat_offset + cluster as u64 * 2, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.cache
+                    .read(g.fat_offset + cluster as u64 * 4, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    // Updates the entry for `cluster` in every copy of the table.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let g = &self.geometry;
+        for copy in 0..g.fat_count {
+            let base = g.fat_offset + copy * g.fat_size;
+            match g.fat_type {
+                FatType::Fat12 => {
+                    let offset = base + (cluster + cluster / 2) as u64;
+                    let mut bytes = [0; 2];
+                    self.cache.read(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let value = value as u16 & 0xfff;
+                    let new = if cluster & 1 != 0 {
+                        old & 0x000f | value << 4
+                    } else {
+                        old & 0xf000 | value
+                    };
+                    self.cache.write(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    self.cache
+                        .write(base + cluster as u64 * 2, &(value as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    // The top four bits are reserved and have to be preserved.
+                    let offset = base + cluster as u64 * 4;
+                    let mut bytes = [0; 4];
+                    self.cache.read(offset, &mut bytes)?;
+                    let new = u32::from_le_bytes(bytes) & 0xf000_0000 | value & 0x0fff_ffff;
+                    self.cache.write(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The clusters of the chain starting at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // A free or bad cluster in a chain, or a loop, means the volume is damaged.
+            if !self.is_cluster(cluster) || clusters.len() >= self.geometry.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            cluster = if next >= self.geometry.fat_type.end_of_chain() {
+                0
+            } else {
+                next
+            };
+        }
+        Ok(clusters)
+    }
+
+    // Allocates a zeroed cluster and appends it to the chain ending in `last`.
+    fn allocate(&self, state: &mut State, last: Option<u32>) -> Result<u32, FsError> {
+        let count = self.geometry.cluster_count;
+        let start = state.next_free.clamp(2, count + 1);
+        let mut found = None;
+        for index in 0..count {
+            let cluster = 2 + (start - 2 + index) % count;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, self.geometry.fat_type.end_marker())?;
+        self.cache.write(
+            self.cluster_offset(cluster),
+            &vec![0; self.geometry.cluster_size as usize],
+        )?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+        state.next_free = cluster + 1;
+        state.free_count = state.free_count.map(|free| free.saturating_sub(1));
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+            state.free_count = state.free_count.map(|free| free + 1);
+        }
+        Ok(())
+    }
+
+    // Positions of all entry slots of the directory starting at `first_cluster`.
+    fn dir_slots(&self, first_cluster: u32) -> Result<Vec<u64>, FsError> {
+        let g = &self.geometry;
+        if first_cluster == 0 {
+            return Ok((0..g.root_entries)
+                .map(|index| g.root_offset + index * ENTRY_SIZE)
+                .collect());
+        }
+        let mut slots = Vec::new();
+        for cluster in self.chain(first_cluster)? {
+            let offset = self.cluster_offset(cluster);
+            slots.extend((0..g.cluster_size / ENTRY_SIZE).map(|index| offset + index * ENTRY_SIZE));
+        }
+        Ok(slots)
+    }
+
+    fn read_slot(&self, position: u64) -> Result<[u8; ENTRY_SIZE as usize], FsError> {
+        let mut data = [0; ENTRY_SIZE as usize];
+        self.cache.read(position, &mut data)?;
+        Ok(data)
+    }
+
+    // The entries of a directory, without `.`, `..` and the volume label.
+    fn read_entries(&self, first_cluster: u32) -> Result<Vec<RawEntry>, FsError> {
+        let mut entries = Vec::new();
+        let mut long_name = None;
+
+        for position in self.dir_slots(first_cluster)? {
+            let data = self.read_slot(position)?;
+            match data[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => {
+                    long_name = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if data[11] & 0x3f == ATTR_LONG_NAME {
+                LongName::add(&mut long_name, &data, position);
+                continue;
+            }
+            let long = long_name.take().and_then(|long| long.finish(&data));
+            if data[11] & ATTR_VOLUME_ID != 0 || data[0] == b'.' {
+                continue;
+            }
+
+            let short_name = display_short_name(&data);
+            let (name, mut slots) = long.unwrap_or_else(|| (short_name.clone(), Vec::new()));
+            slots.push(position);
+            entries.push(RawEntry {
+                name,
+                short_name,
+                data,
+                position,
+                slots,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&self, first_cluster: u32, name: &str) -> Result<RawEntry, FsError> {
+        self.read_entries(first_cluster)?
+            .into_iter()
+            .find(|entry| {
+                entry.name.eq_ignore_ascii_case(name) || entry.short_name.eq_ignore_ascii_case(name)
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    // Finds `count` consecutive free slots in a directory, growing it if needed.
+    fn free_slots(
+        &self,
+        state: &mut State,
+        first_cluster: u32,
+        count: usize,
+    ) -> Result<Vec<u64>, FsError> {
+        loop {
+            let mut run = Vec::new();
+            for position in self.dir_slots(first_cluster)? {
+                match self.read_slot(position)?[0] {
+                    ENTRY_END | ENTRY_DELETED => run.push(position),
+                    _ => run.clear(),
+                }
+                if run.len() == count {
+                    return Ok(run);
+                }
+            }
+            // The fixed root directory cannot grow.
+            if first_cluster == 0 {
+                return Err(FsError::NoSpace);
+            }
+            let last = *self.chain(first_cluster)?.last().ok_or(FsError::Io)?;
+            self.allocate(state, Some(last))?;
+        }
+    }
+
+    // Writes `entry` under `name` into a directory, preceded by long name entries if the
+    // name is not a plain 8.3 one. Returns the position of the short entry.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        directory: u32,
+        name: &str,
+        mut entry: [u8; ENTRY_SIZE as usize],
+    ) -> Result<u64, FsError> {
+        let mut long_entries = Vec::new();
+        match exact_short_name(name) {
+            Some((short, case)) => {
+                entry[..11].copy_from_slice(&short);
+                entry[12] = case;
+            }
+            None => {
+                let existing: Vec<[u8; ENTRY_SIZE as usize]> = self
+                    .read_entries(directory)?
+                    .into_iter()
+                    .map(|entry| entry.data)
+                    .collect();
+                let (base, extension) = basis_name(name);
+                let short = (1..1_000_000)
+                    .map(|number| numbered_short_name(&base, &extension, number))
+                    .find(|short| existing.iter().all(|data| data[..11] != short[..]))
+                    .ok_or(FsError::NoSpace)?;
+                entry[..11].copy_from_slice(&short);
+                entry[12] = 0;
+                long_entries = long_name_entries(name, short_name_checksum(&short));
+            }
+        }
+
+        let slots = self.free_slots(state, directory, long_entries.len() + 1)?;
+        for (position, data) in slots.iter().zip(long_entries.iter().chain([&entry])) {
+            self.cache.write(*position, data)?;
+        }
+        Ok(*slots.last().unwrap())
+    }
+
+    fn remove_entry(&self, entry: &RawEntry) -> Result<(), FsError> {
+        for &position in &entry.slots {
+            self.cache.write(position, &[ENTRY_DELETED])?;
+        }
+        Ok(())
+    }
+
+    // Writes the cluster, size and times of `node` back to its directory entry.
+    fn write_entry(&self, node: &Node) -> Result<(), FsError> {
+        if node.entry == 0 {
+            return Ok(());
+        }
+        let mut data = self.read_slot(node.entry)?;
+        set_entry_cluster(&mut data, node.first_cluster);
+        put_u32(&mut data, 28, if node.directory { 0 } else { node.size });
+        let (date, time) = encode_time(node.modified);
+        put_u16(&mut data, 22, time);
+        put_u16(&mut data, 24, date);
+        put_u16(&mut data, 18, encode_time(node.accessed).0);
+        self.cache.write(node.entry, &data)?;
+        Ok(())
+    }
+
+    fn node(entry: &RawEntry) -> Node {
+        let data = &entry.data;
+        Node {
+            entry: entry.position,
+            directory: data[11] & ATTR_DIRECTORY != 0,
+            first_cluster: entry_cluster(data),
+            size: u32_at(data, 28),
+            created: decode_time(u16_at(data, 16), u16_at(data, 14)),
+            modified: decode_time(u16_at(data, 24), u16_at(data, 22)),
+            accessed: decode_time(u16_at(data, 18), 0),
+            removed: false,
+        }
+    }
+
+    // Returns the live inode for `node.entry` or makes one.
+    fn inode(&self, state: &mut State, node: Node) -> Arc<FatInode> {
+        if let Some(inode) = state.inodes.get(&node.entry).and_then(Weak::upgrade) {
+            return inode;
+        }
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        let entry = node.entry;
+        let inode = Arc::new(FatInode {
+            fs: self.this.upgrade().expect("file system dropped"),
+            node: Mutex::new(node),
+        });
+        state.inodes.insert(entry, Arc::downgrade(&inode));
+        inode
+    }
+
+    // Marks the live inode of a deleted entry, if there is one.
+    fn forget(&self, state: &mut State, entry: u64) {
+        if let Some(inode) = state
+            .inodes
+            .remove(&entry)
+            .and_then(|inode| inode.upgrade())
+        {
+            let mut node = inode.node.lock();
+            node.removed = true;
+            node.first_cluster = 0;
+            node.size = 0;
+        }
+    }
+
+    fn is_empty_directory(&self, first_cluster: u32) -> Result<bool, FsError> {
+        Ok(self.read_entries(first_cluster)?.is_empty())
+    }
+
+    // Writes `buf` at `offset` of the file described by `node`, growing its chain.
+    fn write_data(
+        &self,
+        state: &mut State,
+        node: &mut Node,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), FsError> {
+        let cluster_size = self.geometry.cluster_size;
+        let end = offset + buf.len() as u64;
+        let mut chain = self.chain(node.first_cluster)?;
+        let needed = ((end + cluster_size - 1) / cluster_size) as usize;
+        while chain.len() < needed {
+            let cluster = self.allocate(state, chain.last().copied())?;
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % cluster_size;
+            let count = ((cluster_size - within) as usize).min(buf.len() - done);
+            let cluster = chain[(position / cluster_size) as usize];
+            self.cache.write(
+                self.cluster_offset(cluster) + within,
+                &buf[done..done + count],
+            )?;
+            done += count;
+        }
+        node.size = node.size.max(end as u32);
+        Ok(())
+    }
+
+    // Extends a file with zeros up to `size`.
+    fn zero_fill(&self, state: &mut State, node: &mut Node, size: u64) -> Result<(), FsError> {
+        let zeros = [0; 512];
+        while (node.size as u64) < size {
+            let count = (size - node.size as u64).min(zeros.len() as u64) as usize;
+            self.write_data(state, node, node.size as u64, &zeros[..count])?;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.geometry.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> InodeRef {
+        let node = Node {
+            entry: 0,
+            directory: true,
+            first_cluster: self.geometry.root_cluster,
+            size: 0,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+            removed: false,
+        };
+        let mut state = self.lock();
+        self.inode(&mut state, node)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let state = self.lock();
+        if let Some(offset) = self.geometry.fs_info {
+            let free = state.free_count.unwrap_or(FS_INFO_UNKNOWN);
+            self.cache.write(offset + 488, &free.to_le_bytes())?;
+            self.cache
+                .write(offset + 492, &state.next_free.to_le_bytes())?;
+        }
+        drop(state);
+        self.cache.sync()?;
+        Ok(())
+    }
+}
+
+impl FatInode {
+    fn node(&self) -> Node {
+        self.node.lock().clone()
+    }
+
+    // The node of this directory, if it still exists.
+    fn directory(&self) -> Result<Node, FsError> {
+        let node = self.node();
+        if node.removed {
+            Err(FsError::NotFound)
+        } else if !node.directory {
+            Err(FsError::NotADirectory)
+        } else {
+            Ok(node)
+        }
+    }
+
+    fn file(&self) -> Result<Node, FsError> {
+        let node = self.node();
+        if node.directory {
+            Err(FsError::IsADirectory)
+        } else {
+            Ok(node)
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node();
+        Metadata {
+            // Entry positions are unique; the root has none.
+            inode: if node.entry == 0 {
+                1
+            } else {
+                node.entry / ENTRY_SIZE
+            },
+            kind: if node.directory {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            size: node.size as u64,
+            links: if node.removed { 0 } else { 1 },
+            accessed: node.accessed,
+            modified: node.modified,
+            changed: node.modified.max(node.created),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.fs.lock();
+        let node = self.file()?;
+        let cluster_size = self.fs.geometry.cluster_size;
+        let start = offset.min(node.size as u64);
+        let count = (buf.len() as u64).min(node.size as u64 - start) as usize;
+        let chain = self.fs.chain(node.first_cluster)?;
+
+        let mut done = 0;
+        while done < count {
+            let position = start + done as u64;
+            let within = position % cluster_size;
+            let length = ((cluster_size - within) as usize).min(count - done);
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            self.fs.cache.read(
+                self.fs.cluster_offset(cluster) + within,
+                &mut buf[done..done + length],
+            )?;
+            done += length;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.fs.lock();
+        let mut node = self.file()?;
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        let result = self
+            .fs
+            .zero_fill(&mut state, &mut node, offset)
+            .and_then(|_| self.fs.write_data(&mut state, &mut node, offset, buf));
+        node.modified = time::now();
+        // Record whatever was allocated, even if the write failed half way.
+        self.fs.write_entry(&node)?;
+        *self.node.lock() = node;
+        result.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut state = self.fs.lock();
+        let mut node = self.file()?;
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        if size > node.size as u64 {
+            self.fs.zero_fill(&mut state, &mut node, size)?;
+        } else {
+            let cluster_size = self.fs.geometry.cluster_size;
+            let keep = ((size + cluster_size - 1) / cluster_size) as usize;
+            let chain = self.fs.chain(node.first_cluster)?;
+            if let Some(&first_freed) = chain.get(keep) {
+                if keep == 0 {
+                    node.first_cluster = 0;
+                } else {
+                    self.fs
+                        .set_fat_entry(chain[keep - 1], self.fs.geometry.fat_type.end_marker())?;
+                }
+                self.fs.free_chain(&mut state, first_freed)?;
+            }
+            node.size = size as u32;
+        }
+        node.modified = time::now();
+        self.fs.write_entry(&node)?;
+        *self.node.lock() = node;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let mut state = self.fs.lock();
+        let directory = self.directory()?;
+        let entry = self.fs.find_entry(directory.first_cluster, name)?;
+        Ok(self.fs.inode(&mut state, FatFs::node(&entry)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.fs.lock();
+        let directory = self.directory()?;
+        Ok(self
+            .fs
+            .read_entries(directory.first_cluster)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                inode: entry.position / ENTRY_SIZE,
+                kind: if entry.data[11] & ATTR_DIRECTORY != 0 {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsError> {
+        check_name(name)?;
+        if kind != FileType::Regular && kind != FileType::Directory {
+            return Err(FsError::NotSupported);
+        }
+        let mut state = self.fs.lock();
+        let parent = self.directory()?;
+        match self.fs.find_entry(parent.first_cluster, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let entry = if kind == FileType::Directory {
+            // A new directory gets a cluster with its `.` and `..` entries.
+            let cluster = self.fs.allocate(&mut state, None)?;
+            let mut dot = new_entry(ATTR_DIRECTORY, cluster);
+            dot[..11].copy_from_slice(b".          ");
+            // `..` refers to the root directory as cluster 0, even on FAT32.
+            let parent_cluster = if parent.entry == 0 {
+                0
+            } else {
+                parent.first_cluster
+            };
+            let mut dot_dot = new_entry(ATTR_DIRECTORY, parent_cluster);
+            dot_dot[..11].copy_from_slice(b"..         ");
+            let offset = self.fs.cluster_offset(cluster);
+            self.fs.cache.write(offset, &dot)?;
+            self.fs.cache.write(offset + ENTRY_SIZE, &dot_dot)?;
+            new_entry(ATTR_DIRECTORY, cluster)
+        } else {
+            new_entry(ATTR_ARCHIVE, 0)
+        };
+
+        let position = match self
+            .fs
+            .add_entry(&mut state, parent.first_cluster, name, entry)
+        {
+            Ok(position) => position,
+            Err(error) => {
+                if kind == FileType::Directory {
+                    self.fs.free_chain(&mut state, entry_cluster(&entry))?;
+                }
+                return Err(error);
+            }
+        };
+        let raw = RawEntry {
+            name: String::from(name),
+            short_name: String::new(),
+            data: self.fs.read_slot(position)?,
+            position,
+            slots: Vec::new(),
+        };
+        Ok(self.fs.inode(&mut state, FatFs::node(&raw)))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut state = self.fs.lock();
+        let parent = self.directory()?;
+        let entry = self.fs.find_entry(parent.first_cluster, name)?;
+        let node = FatFs::node(&entry);
+        if node.directory && !self.fs.is_empty_directory(node.first_cluster)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.fs.remove_entry(&entry)?;
+        self.fs.free_chain(&mut state, node.first_cluster)?;
+        self.fs.forget(&mut state, entry.position);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &InodeRef, new_name: &str) -> Result<(), FsError> {
+        check_name(new_name)?;
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &new_parent.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        let mut state = self.fs.lock();
+        let old_directory = self.directory()?;
+        let new_directory = new_parent.directory()?;
+        let entry = self.fs.find_entry(old_directory.first_cluster, old_name)?;
+        let node = FatFs::node(&entry);
+
+        // Check what we are about to replace before changing anything.
+        let replaced = match self.fs.find_entry(new_directory.first_cluster, new_name) {
+            // The same entry, maybe with a different case.
+            Ok(replaced) if replaced.position == entry.position => {
+                if replaced.name == new_name {
+                    return Ok(());
+                }
+                None
+            }
+            Ok(replaced) => {
+                let replaced_node = FatFs::node(&replaced);
+                match (node.directory, replaced_node.directory) {
+                    (true, true) if !self.fs.is_empty_directory(replaced_node.first_cluster)? => {
+                        return Err(FsError::NotEmpty)
+                    }
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
+                Some((replaced, replaced_node.first_cluster))
+            }
+            Err(FsError::NotFound) => None,
+            Err(error) => return Err(error),
+        };
+
+        // Add the new entry before removing the old one or the one it replaces, so a
+        // failure loses nothing.
+        let position = self.fs.add_entry(
+            &mut state,
+            new_directory.first_cluster,
+            new_name,
+            entry.data,
+        )?;
+        self.fs.remove_entry(&entry)?;
+        if let Some((replaced, first_cluster)) = replaced {
+            self.fs.remove_entry(&replaced)?;
+            self.fs.free_chain(&mut state, first_cluster)?;
+            self.fs.forget(&mut state, replaced.position);
+        }
+
+        if node.directory && old_directory.first_cluster != new_directory.first_cluster {
+            let parent_cluster = if new_directory.entry == 0 {
+                0
+            } else {
+                new_directory.first_cluster
+            };
+            let dot_dot = self.fs.cluster_offset(node.first_cluster) + ENTRY_SIZE;
+            let mut data = self.fs.read_slot(dot_dot)?;
+            set_entry_cluster(&mut data, parent_cluster);
+            self.fs.cache.write(dot_dot, &data)?;
+        }
+
+        if let Some(inode) = state
+            .inodes
+            .remove(&entry.position)
+            .and_then(|inode| inode.upgrade())
+        {
+            inode.node.lock().entry = position;
+            state.inodes.insert(position, Arc::downgrade(&inode));
+        }
+        Ok(())
+    }
+}
+
+// Writes an empty FAT12 file system with 512 byte sectors and clusters to `disk`.
+#[cfg(test)]
+fn format_fat12(disk: &dyn BlockDevice) {
+    let sectors = disk.block_count() as u16;
+    let mut boot = [0; 512];
+    boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(&mut boot, 11, 512);
+    boot[13] = 1;
+    put_u16(&mut boot, 14, 1);
+    boot[16] = 2;
+    put_u16(&mut boot, 17, 64);
+    put_u16(&mut boot, 19, sectors);
+    boot[21] = 0xf8;
+    put_u16(&mut boot, 22, 6);
+    boot[510..].copy_from_slice(&[0x55, 0xaa]);
+    disk.write_blocks(0, &boot).unwrap();
+
+    // The first two entries hold the media byte and an end of chain marker.
+    let mut fat = [0; 512];
+    fat[..3].copy_from_slice(&[0xf8, 0xff, 0xff]);
+    disk.write_blocks(1, &fat).unwrap();
+    disk.write_blocks(7, &fat).unwrap();
+}
+
+#[test_case]
+fn test_fat_operations() {
+    use crate::block::ram::RamDisk;
+
+    let disk = RamDisk::new("ram0", 512, 2048);
+    format_fat12(disk.as_ref());
+    // A tiny cache, so blocks get evicted and written back along the way.
+    let fs = FatFs::mount(BufferCache::new(disk.clone(), 8)).unwrap();
+    assert_eq!(fs.name(), "fat12");
+
+    let root = fs.root();
+    let docs = root.create("docs", FileType::Directory).unwrap();
+    let file = root
+        .create("A long file name.txt", FileType::Regular)
+        .unwrap();
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    assert_eq!(file.write_at(100, &data).unwrap(), 3000);
+    assert_eq!(file.metadata().size, 3100);
+    root.create("README", FileType::Regular).unwrap();
+    assert_eq!(
+        root.create("readme", FileType::Regular).err(),
+        Some(FsError::AlreadyExists)
+    );
+    root.rename("A long file name.txt", &docs, "moved file.txt")
+        .unwrap();
+    fs.sync().unwrap();
+
+    // A fresh mount only sees what reached the disk.
+    let fs = FatFs::mount(BufferCache::new(disk, 8)).unwrap();
+    let root = fs.root();
+    let mut names: Vec<String> = root
+        .read_dir()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["README", "docs"]);
+
+    let moved = root
+        .lookup("DOCS")
+        .unwrap()
+        .lookup("moved file.txt")
+        .unwrap();
+    let mut buf = vec![0xff; 3200];
+    assert_eq!(moved.read_at(0, &mut buf).unwrap(), 3100);
+    assert!(buf[..100].iter().all(|&b| b == 0));
+    assert_eq!(&buf[100..3100], &data[..]);
+
+    moved.truncate(10).unwrap();
+    assert_eq!(moved.metadata().size, 10);
+    assert_eq!(root.unlink("docs").err(), Some(FsError::NotEmpty));
+    root.lookup("docs")
+        .unwrap()
+        .unlink("moved file.txt")
+        .unwrap();
+    root.unlink("docs").unwrap();
+    assert_eq!(root.read_dir().unwrap().len(), 1);
+}
+
+#[test_case]
+fn test_fat_rejects_inconsistent_geometry() {
+    use crate::block::ram::RamDisk;
+
+    // One sector of FAT cannot hold the entries of 2041 clusters.
+    let disk = RamDisk::new("ram0", 512, 2048);
+    format_fat12(disk.as_ref());
+    let mut boot = [0; 512];
+    disk.read_blocks(0, &mut boot).unwrap();
+    put_u16(&mut boot, 22, 1);
+    disk.write_blocks(0, &boot).unwrap();
+    assert_eq!(
+        FatFs::mount(BufferCache::new(disk, 8)).err(),
+        Some(FsError::Corrupt)
+    );
+
+    // A single data sector does not make a cluster of two.
+    let disk = RamDisk::new("ram1", 512, 18);
+    format_fat12(disk.as_ref());
+    let mut boot = [0; 512];
+    disk.read_blocks(0, &mut boot).unwrap();
+    boot[13] = 2;
+    disk.write_blocks(0, &boot).unwrap();
+    assert_eq!(
+        FatFs::mount(BufferCache::new(disk, 8)).err(),
+        Some(FsError::Corrupt)
+    );
+}
@@ -0,0 +1,134 @@
+// The initial ramdisk.
+//
+// `build.rs` packs the `initrd/` directory into a USTAR archive that is embedded in the
+// kernel image. At boot its directories and regular files are recreated in the root
+// file system; other entry types (links, devices) are skipped.
+// Reference: https://wiki.osdev.org/USTAR
+use alloc::string::String;
+
+use super::{path, FileType, FsError, OpenFlags};
+
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// A header is damaged or a file extends past the end of the archive.
+    Corrupt,
+    Fs(FsError),
+}
+
+impl From<FsError> for InitrdError {
+    fn from(error: FsError) -> Self {
+        InitrdError::Fs(error)
+    }
+}
+
+struct Header<'a>(&'a [u8]);
+
+impl<'a> Header<'a> {
+    fn field(&self, start: usize, len: usize) -> &'a [u8] {
+        let field = &self.0[start..start + len];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        &field[..end]
+    }
+
+    fn octal(&self, start: usize, len: usize) -> Option<u64> {
+        let digits = core::str::from_utf8(self.field(start, len)).ok()?;
+        let digits = digits.trim_matches(|c| c == ' ');
+        if digits.is_empty() {
+            return Some(0);
+        }
+        u64::from_str_radix(digits, 8).ok()
+    }
+
+    fn is_valid(&self) -> bool {
+        let stored = self.octal(148, 8);
+        let sum: u64 = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u64
+                } else {
+                    b as u64
+                }
+            })
+            .sum();
+        self.field(257, 5) == b"ustar" && stored == Some(sum)
+    }
+
+    // USTAR splits long names into a prefix and a name.
+    fn name(&self) -> Option<String> {
+        let prefix = core::str::from_utf8(self.field(345, 155)).ok()?;
+        let name = core::str::from_utf8(self.field(0, 100)).ok()?;
+        let mut full = String::from(prefix);
+        if !full.is_empty() {
+            full.push('/');
+        }
+        full.push_str(name);
+        Some(full)
+    }
+}
+
+// Creates `path` and its missing parents like `mkdir -p`.
+fn create_directories(path: &str) -> Result<(), FsError> {
+    let mut current = String::new();
+    for component in path::components(path) {
+        current.push('/');
+        current.push_str(component);
+        match super::create(&current, FileType::Directory) {
+            Ok(_) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks a USTAR `archive` below the absolute directory `root` and returns the
+/// number of files created.
+pub fn unpack(archive: &[u8], root: &str) -> Result<usize, InitrdError> {
+    let mut files = 0;
+    let mut offset = 0;
+
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = Header(&archive[offset..offset + BLOCK_SIZE]);
+        // The archive ends with zero blocks.
+        if header.0.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !header.is_valid() {
+            return Err(InitrdError::Corrupt);
+        }
+
+        let name = header.name().ok_or(InitrdError::Corrupt)?;
+        let size = header.octal(124, 12).ok_or(InitrdError::Corrupt)? as usize;
+        let data_start = offset + BLOCK_SIZE;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(InitrdError::Corrupt)?;
+        let target = path::normalize(root, &name);
+
+        match header.0[156] {
+            b'5' => create_directories(&target)?,
+            b'0' | 0 => {
+                if let Some((parent, _)) = path::split(&target) {
+                    create_directories(parent)?;
+                }
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                super::open(&target, flags)?.write(data)?;
+                files += 1;
+            }
+            _ => {}
+        }
+        offset = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+    Ok(files)
+}
+
+/// Unpacks the archive built into the kernel into the root file system.
+pub fn load() -> Result<usize, InitrdError> {
+    unpack(INITRD, "/")
+}
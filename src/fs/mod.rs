@@ -0,0 +1,512 @@
+// The virtual file system.
+//
+// File systems implement `FileSystem` and hand out `Inode`s for the files and
+// directories they contain. The VFS keeps a table of mounted file systems and resolves
+// absolute paths by picking the mount with the longest matching prefix and walking the
+// remaining components from its root. Opening an inode yields a `File`, an open file
+// description that carries the offset and is shared by every descriptor referring to it.
+// Reference: https://wiki.osdev.org/VFS
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod path;
+pub mod procfs;
+pub mod tmpfs;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::ops::BitOr;
+
+use crate::block::{self, BlockError};
+use crate::locks::mutex::Mutex;
+use crate::println;
+
+pub type InodeRef = Arc<dyn Inode>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    InvalidPath,
+    InvalidArgument,
+    ReadOnly,
+    /// The file was not opened for the requested kind of access.
+    BadMode,
+    NotSupported,
+    NoSpace,
+    /// A rename between different file systems.
+    CrossDevice,
+    Busy,
+    /// The on-disk structures of the file system are inconsistent.
+    Corrupt,
+    Io,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::ReadOnly => "read-only file system",
+            FsError::BadMode => "bad file mode",
+            FsError::NotSupported => "operation not supported",
+            FsError::NoSpace => "no space left on device",
+            FsError::CrossDevice => "cross-device link",
+            FsError::Busy => "device or resource busy",
+            FsError::Corrupt => "file system is corrupted",
+            FsError::Io => "input/output error",
+        };
+        f.write_str(message)
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Unique within the file system.
+    pub inode: u64,
+    pub kind: FileType,
+    pub size: u64,
+    pub links: u32,
+    /// Unix timestamps in seconds, 0 if the file system does not keep them.
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// A file or directory. The defaults return the error a file system that does not
+/// support the operation would report.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Lets file systems get at their own inode type, e.g. for the target of `rename`.
+    fn as_any(&self) -> &dyn Any;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Finds `name` in this directory.
+    fn lookup(&self, _name: &str) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory called `name` in this directory.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Removes `name` from this directory. Directories have to be empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Moves `old_name` in this directory to `new_name` in `new_parent`, replacing
+    /// what was there. Both directories belong to the same file system.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &InodeRef,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotADirectory)
+    }
+}
+
+/// A mountable file system.
+pub trait FileSystem: Send + Sync {
+    /// Short name like "tmpfs", shown in the mount table.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> InodeRef;
+
+    /// Writes cached data back to the underlying device.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file description.
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError>;
+    fn seek(&self, pos: SeekFrom) -> Result<u64, FsError>;
+    fn metadata(&self) -> Metadata;
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it does not exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Cut the file to zero length when opening it for writing.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// The `File` for inodes: reads and writes at the current offset.
+pub struct OpenFile {
+    inode: InodeRef,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(inode: InodeRef, flags: OpenFlags) -> OpenFile {
+        OpenFile {
+            inode,
+            flags,
+            offset: Mutex::new(0),
+        }
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadMode);
+        }
+        // The offset is not locked during the I/O, which may wait for a disk.
+        let offset = *self.offset.lock();
+        let count = self.inode.read_at(offset, buf)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadMode);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.inode.metadata().size
+        } else {
+            *self.offset.lock()
+        };
+        let count = self.inode.write_at(offset, buf)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.metadata().size.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.inode.read_dir()
+    }
+}
+
+/// Mounts a tmpfs as the root file system, fills it from the initrd and mounts the
+/// device nodes at /dev and the kernel state at /proc.
+pub fn init() {
+    println!("[!] Mounting root file system");
+    mount("/", tmpfs::TmpFs::new()).expect("failed to mount the root file system");
+    match initrd::load() {
+        Ok(files) => println!("    [+] Unpacked initrd ({} files)", files),
+        Err(error) => println!("    [-] Failed to unpack initrd: {:?}", error),
+    }
+    let pseudo: [(&str, Arc<dyn FileSystem>); 2] = [
+        ("/dev", devfs::DevFs::new()),
+        ("/proc", procfs::ProcFs::new()),
+    ];
+    for (path, fs) in pseudo {
+        let name = fs.name();
+        let result = match create(path, FileType::Directory) {
+            Ok(_) | Err(FsError::AlreadyExists) => mount(path, fs),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            println!("    [-] Failed to mount {} at {}: {}", name, path, error);
+        }
+    }
+    println!("    [+] Done");
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mounts `fs` at the absolute `path`. Apart from the root, the mount point has to be an
+/// existing directory.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = path::normalize("/", path);
+    if path != "/" && lookup(&path)?.metadata().kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    if is_mount_point(&path) {
+        return Err(FsError::Busy);
+    }
+    MOUNTS.lock().push(Mount { path, fs });
+    Ok(())
+}
+
+/// Mounts the file system on the block device `device` at `path`. The type of the file
+/// system is detected from its superblock.
+pub fn mount_device(device: &str, path: &str) -> Result<(), FsError> {
+    let cache = block::cache::get(device).ok_or(FsError::NotFound)?;
+    let fs: Arc<dyn FileSystem> = match ext2::Ext2Fs::mount(cache.clone()) {
+        Ok(fs) => fs,
+        Err(FsError::InvalidArgument) => fat::FatFs::mount(cache)?,
+        Err(error) => return Err(error),
+    };
+    mount(path, fs)
+}
+
+/// Unmounts the file system at `path` after syncing it. File systems mounted below it
+/// have to be unmounted first.
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = path::normalize("/", path);
+    // Syncing is disk I/O, the mount table stays unlocked meanwhile so that path lookups
+    // do not wait for it.
+    let fs = {
+        let mounts = MOUNTS.lock();
+        mounts[unmountable(&mounts, &path)?].fs.clone()
+    };
+    fs.sync()?;
+
+    let mut mounts = MOUNTS.lock();
+    let index = unmountable(&mounts, &path)?;
+    // Unmounted and mounted again in the meantime.
+    if !Arc::ptr_eq(&mounts[index].fs, &fs) {
+        return Err(FsError::Busy);
+    }
+    mounts.remove(index);
+    Ok(())
+}
+
+// The index of the mount at `path`, if nothing is mounted below it.
+fn unmountable(mounts: &[Mount], path: &str) -> Result<usize, FsError> {
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::InvalidPath)?;
+    if mounts
+        .iter()
+        .any(|mount| mount.path != path && path::strip_prefix(&mount.path, path).is_some())
+    {
+        return Err(FsError::Busy);
+    }
+    Ok(index)
+}
+
+/// The mount table as (mount point, file system name) pairs.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.name()))
+        .collect()
+}
+
+/// Syncs every mounted file system.
+pub fn sync() -> Result<(), FsError> {
+    let file_systems: Vec<Arc<dyn FileSystem>> =
+        MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    file_systems.iter().try_for_each(|fs| fs.sync())
+}
+
+// Finds the file system responsible for `path` and the rest of the path inside it.
+fn find_mount(path: &str) -> Result<(Arc<dyn FileSystem>, String), FsError> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| Some((mount, path::strip_prefix(path, &mount.path)?)))
+        .max_by_key(|(mount, _)| mount.path.len())
+        .map(|(mount, rest)| (mount.fs.clone(), String::from(rest)))
+        .ok_or(FsError::NotFound)
+}
+
+/// Resolves the absolute `path` to an inode.
+pub fn lookup(path: &str) -> Result<InodeRef, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let path = path::normalize("/", path);
+    let (fs, rest) = find_mount(&path)?;
+
+    let mut inode = fs.root();
+    for name in path::components(&rest) {
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+// Resolves the parent directory of `path` and returns it with the last component.
+fn lookup_parent(path: &str) -> Result<(InodeRef, String), FsError> {
+    let path = path::normalize("/", path);
+    let (parent, name) = path::split(&path).ok_or(FsError::InvalidPath)?;
+    Ok((lookup(parent)?, String::from(name)))
+}
+
+/// Opens the file at the absolute `path`.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<dyn File>, FsError> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.create(&name, FileType::Regular)?
+        }
+        Err(error) => return Err(error),
+    };
+
+    let writable = flags.contains(OpenFlags::WRITE);
+    if writable && inode.metadata().kind == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    if writable && flags.contains(OpenFlags::TRUNCATE) {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(OpenFile::new(inode, flags)))
+}
+
+/// Lists the directory at the absolute `path`, including file systems mounted on it.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let path = path::normalize("/", path);
+    let mut entries = lookup(&path)?.read_dir()?;
+
+    for (mount_point, _) in mounts() {
+        let child = path::split(&mount_point).filter(|(parent, _)| *parent == path);
+        if let Some((_, name)) = child {
+            if !entries.iter().any(|entry| entry.name == name) {
+                entries.push(DirEntry {
+                    name: String::from(name),
+                    inode: 0,
+                    kind: FileType::Directory,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Creates an empty file or directory at the absolute `path`.
+pub fn create(path: &str, kind: FileType) -> Result<InodeRef, FsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, kind)
+}
+
+fn is_mount_point(path: &str) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.path == path)
+}
+
+/// Removes the file or empty directory at the absolute `path`.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let path = path::normalize("/", path);
+    if is_mount_point(&path) {
+        return Err(FsError::Busy);
+    }
+    let (parent, name) = lookup_parent(&path)?;
+    parent.unlink(&name)
+}
+
+/// Moves `old` to `new`. Both have to be on the same file system.
+pub fn rename(old: &str, new: &str) -> Result<(), FsError> {
+    let old = path::normalize("/", old);
+    let new = path::normalize("/", new);
+    if is_mount_point(&old) {
+        return Err(FsError::Busy);
+    }
+    // Moving a file onto itself changes nothing, as long as it exists.
+    if old == new {
+        return lookup(&old).map(|_| ());
+    }
+    // A directory cannot be moved into itself.
+    if path::strip_prefix(&new, &old).is_some() {
+        return Err(FsError::InvalidArgument);
+    }
+    let (old_fs, _) = find_mount(&old)?;
+    let (new_fs, _) = find_mount(&new)?;
+    if !Arc::ptr_eq(&old_fs, &new_fs) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let (old_parent, old_name) = lookup_parent(&old)?;
+    let (new_parent, new_name) = lookup_parent(&new)?;
+    old_parent.rename(&old_name, &new_parent, &new_name)
+}
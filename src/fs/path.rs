@@ -0,0 +1,77 @@
+// Path manipulation.
+//
+// Paths are resolved lexically: `.` is dropped and `..` removes the previous component,
+// which is correct as long as there are no symbolic links. The result is always an
+// absolute path without empty components, `.` or `..`.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Iterates over the non-empty components of `path`.
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+/// Turns `path` into a normalized absolute path, relative to `cwd` if it does not start
+/// with a slash. `..` at the root stays at the root.
+pub fn normalize(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    let relative = if path.starts_with('/') { "" } else { cwd };
+
+    for component in components(relative).chain(components(path)) {
+        match component {
+            "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+
+    let mut normalized = String::new();
+    for part in &parts {
+        normalized.push('/');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Splits a normalized path into its parent directory and last component. The root has
+/// no last component.
+pub fn split(path: &str) -> Option<(&str, &str)> {
+    let index = path.rfind('/')?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        return None;
+    }
+    Some((if index == 0 { "/" } else { &path[..index] }, name))
+}
+
+/// Returns the rest of `path` below `prefix`, if `path` is `prefix` or lies inside it.
+/// Both have to be normalized.
+pub fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+#[test_case]
+fn test_normalize() {
+    assert_eq!(normalize("/", "a/b/../c/./d"), "/a/c/d");
+    assert_eq!(normalize("/usr/bin", ".."), "/usr");
+    assert_eq!(normalize("/usr", "../../.."), "/");
+    assert_eq!(normalize("/usr", "/etc//motd/"), "/etc/motd");
+    assert_eq!(split("/etc/motd"), Some(("/etc", "motd")));
+    assert_eq!(split("/etc"), Some(("/", "etc")));
+    assert_eq!(split("/"), None);
+    assert_eq!(strip_prefix("/mnt/disk/a", "/mnt/disk"), Some("/a"));
+    assert_eq!(strip_prefix("/mnt/disk2", "/mnt/disk"), None);
+}
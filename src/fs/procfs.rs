@@ -0,0 +1,355 @@
+// A file system of kernel state, mounted at /proc.
+//
+// Every file is generated from the live kernel data structures when it is read, so
+// nothing here is stored and nothing can be written. Besides the global files there is
+// a directory for every process, named after its pid, and `self` for the reader's own.
+// Reference: https://man7.org/linux/man-pages/man5/proc.5.html
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, InodeRef, Metadata};
+use crate::cpu;
+use crate::interrupts::interrupts;
+use crate::memory::{self, address_space::COPY_ON_WRITE, heap};
+use crate::process::{self, Pid, ProcessState};
+use crate::time;
+use x86_64::structures::paging::PageTableFlags;
+
+const ROOT_INODE: u64 = 1;
+/// Inode numbers of the process directories and their files start here.
+const PROCESS_INODE_BASE: u64 = 0x1000;
+const FILES_PER_PROCESS: u64 = 16;
+
+/// Produces the contents of a global file.
+type Generator = fn() -> String;
+/// Produces the contents of a file in a process directory, `None` if the process is gone.
+type ProcessGenerator = fn(Pid) -> Option<String>;
+
+const FILES: [(&str, Generator); 4] = [
+    ("cpuinfo", cpuinfo),
+    ("interrupts", interrupt_counts),
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+];
+
+const PROCESS_FILES: [(&str, ProcessGenerator); 2] = [("maps", maps), ("status", status)];
+
+fn cpuinfo() -> String {
+    let info = cpu::info();
+    let mut text = String::new();
+    let _ = writeln!(text, "vendor_id\t: {}", info.vendor);
+    let _ = writeln!(text, "cpu family\t: {}", info.family);
+    let _ = writeln!(text, "model\t\t: {}", info.model);
+    let _ = writeln!(text, "model name\t: {}", info.model_name);
+    let _ = writeln!(text, "stepping\t: {}", info.stepping);
+    let _ = writeln!(text, "flags\t\t: {}", info.features.join(" "));
+    text
+}
+
+fn interrupt_counts() -> String {
+    let mut text = String::from("vector      count  use\n");
+    for (vector, count) in interrupts::counts() {
+        let _ = writeln!(
+            text,
+            "{:>6} {:>10}  {}",
+            vector,
+            count,
+            interrupts::vector_name(vector)
+        );
+    }
+    text
+}
+
+fn meminfo() -> String {
+    let (free, usable) = memory::with_memory(|memory| memory.frame_allocator.stats());
+    let (heap_used, heap_size) = heap::usage();
+    let mut text = String::new();
+    for (name, bytes) in [
+        ("MemTotal", usable * 4096),
+        ("MemFree", free * 4096),
+        ("MemUsed", (usable - free) * 4096),
+        ("HeapTotal", heap_size),
+        ("HeapUsed", heap_used),
+        ("HeapFree", heap_size - heap_used),
+    ] {
+        let _ = writeln!(text, "{:<12}{:>10} kB", format!("{}:", name), bytes / 1024);
+    }
+    text
+}
+
+fn uptime() -> String {
+    let ms = time::uptime_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+fn status(pid: Pid) -> Option<String> {
+    let info = process::list().into_iter().find(|info| info.pid == pid)?;
+    let state = match info.state {
+        ProcessState::Running => "running".to_string(),
+        ProcessState::Zombie(code) => format!("zombie (exit code {})", code),
+    };
+    let mut text = String::new();
+    let _ = writeln!(text, "Name:\t{}", info.name);
+    let _ = writeln!(text, "State:\t{}", state);
+    let _ = writeln!(text, "Pid:\t{}", info.pid);
+    let _ = writeln!(text, "PPid:\t{}", info.parent);
+    let _ = writeln!(text, "Threads:\t{}", info.threads);
+    Some(text)
+}
+
+// One line per region as `start-end permissions size`, where a `c` marks pages shared
+// copy-on-write after a fork and `p` private ones.
+fn maps(pid: Pid) -> Option<String> {
+    let mut text = String::new();
+    for region in process::regions(pid)? {
+        let flags = region.flags;
+        let writable = flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE);
+        let _ = writeln!(
+            text,
+            "{:016x}-{:016x} r{}{}{} {:>8} kB",
+            region.start.as_u64(),
+            region.end.as_u64(),
+            if writable { 'w' } else { '-' },
+            if flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+            if flags.contains(COPY_ON_WRITE) {
+                'c'
+            } else {
+                'p'
+            },
+            (region.end - region.start) / 1024
+        );
+    }
+    Some(text)
+}
+
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Arc<ProcFs> {
+        Arc::new(ProcFs)
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(ProcRoot)
+    }
+}
+
+fn directory_metadata(inode: u64) -> Metadata {
+    Metadata {
+        inode,
+        kind: FileType::Directory,
+        size: 0,
+        links: 2,
+        accessed: 0,
+        modified: 0,
+        changed: 0,
+    }
+}
+
+fn process_exists(pid: Pid) -> bool {
+    process::list().iter().any(|info| info.pid == pid)
+}
+
+fn process_inode(pid: Pid) -> u64 {
+    PROCESS_INODE_BASE + pid * FILES_PER_PROCESS
+}
+
+struct ProcRoot;
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> Metadata {
+        directory_metadata(ROOT_INODE)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        if let Some(index) = FILES.iter().position(|(file, _)| *file == name) {
+            let generate = FILES[index].1;
+            return Ok(Arc::new(ProcFile {
+                inode: ROOT_INODE + 1 + index as u64,
+                generate: Box::new(move || Some(generate())),
+            }));
+        }
+        let pid = match name {
+            "self" => process::current_pid(),
+            _ => name.parse().map_err(|_| FsError::NotFound)?,
+        };
+        if !process_exists(pid) {
+            return Err(FsError::NotFound);
+        }
+        Ok(Arc::new(ProcessDirectory { pid }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let files = FILES.iter().enumerate().map(|(index, (name, _))| DirEntry {
+            name: String::from(*name),
+            inode: ROOT_INODE + 1 + index as u64,
+            kind: FileType::Regular,
+        });
+        let processes = process::list().into_iter().map(|info| DirEntry {
+            name: info.pid.to_string(),
+            inode: process_inode(info.pid),
+            kind: FileType::Directory,
+        });
+        let current = DirEntry {
+            name: String::from("self"),
+            inode: process_inode(process::current_pid()),
+            kind: FileType::Directory,
+        };
+        Ok(files.chain(processes).chain([current]).collect())
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &InodeRef,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+struct ProcessDirectory {
+    pid: Pid,
+}
+
+impl Inode for ProcessDirectory {
+    fn metadata(&self) -> Metadata {
+        directory_metadata(process_inode(self.pid))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let index = PROCESS_FILES
+            .iter()
+            .position(|(file, _)| *file == name)
+            .ok_or(FsError::NotFound)?;
+        let (pid, generate) = (self.pid, PROCESS_FILES[index].1);
+        Ok(Arc::new(ProcFile {
+            inode: process_inode(pid) + 1 + index as u64,
+            generate: Box::new(move || generate(pid)),
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if !process_exists(self.pid) {
+            return Err(FsError::NotFound);
+        }
+        Ok(PROCESS_FILES
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| DirEntry {
+                name: String::from(*name),
+                inode: process_inode(self.pid) + 1 + index as u64,
+                kind: FileType::Regular,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &InodeRef,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+struct ProcFile {
+    inode: u64,
+    /// Produces the contents, `None` once the process the file describes is gone.
+    generate: Box<dyn Fn() -> Option<String> + Send + Sync>,
+}
+
+impl Inode for ProcFile {
+    // The size is only known by generating the contents, so like Linux we report 0.
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            kind: FileType::Regular,
+            size: 0,
+            links: 1,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let text = (self.generate)().ok_or(FsError::NotFound)?;
+        let bytes = text.as_bytes();
+        let start = (offset as usize).min(bytes.len());
+        let count = buf.len().min(bytes.len() - start);
+        buf[..count].copy_from_slice(&bytes[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+#[test_case]
+fn test_procfs_files() {
+    let root = ProcFs::new().root();
+    let read = |inode: InodeRef| {
+        let mut buf = [0; 512];
+        let count = inode.read_at(0, &mut buf).unwrap();
+        String::from_utf8(buf[..count].to_vec()).unwrap()
+    };
+
+    assert!(read(root.lookup("meminfo").unwrap()).starts_with("MemTotal:"));
+    let status = read(root.lookup("self").unwrap().lookup("status").unwrap());
+    assert!(status.contains(&format!("Pid:\t{}\n", process::current_pid())));
+    assert_eq!(
+        root.lookup("uptime").unwrap().write_at(0, b"0"),
+        Err(FsError::ReadOnly)
+    );
+    assert!(root.lookup("not-a-pid").is_err());
+}
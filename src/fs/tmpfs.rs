@@ -0,0 +1,360 @@
+// A file system that keeps everything in kernel memory.
+//
+// Directories map names to inodes, regular files keep their contents in a growable
+// buffer. Nothing is ever written anywhere, so the contents are gone after a reboot.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, InodeRef, Metadata};
+use crate::locks::mutex::Mutex;
+use crate::time;
+
+/// Files cannot grow beyond this, all of them live on the kernel heap.
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct InodeData {
+    content: Content,
+    links: u32,
+    accessed: u64,
+    modified: u64,
+    changed: u64,
+}
+
+pub struct TmpInode {
+    id: u64,
+    /// Shared by all inodes of the file system, hands out inode numbers.
+    next_id: Arc<AtomicU64>,
+    data: Mutex<InodeData>,
+}
+
+impl TmpInode {
+    fn new(id: u64, next_id: Arc<AtomicU64>, kind: FileType) -> Arc<TmpInode> {
+        let (content, links) = match kind {
+            FileType::Directory => (Content::Directory(BTreeMap::new()), 2),
+            _ => (Content::File(Vec::new()), 1),
+        };
+        let now = time::now();
+        Arc::new(TmpInode {
+            id,
+            next_id,
+            data: Mutex::new(InodeData {
+                content,
+                links,
+                accessed: now,
+                modified: now,
+                changed: now,
+            }),
+        })
+    }
+
+    fn kind(&self) -> FileType {
+        match self.data.lock().content {
+            Content::File(_) => FileType::Regular,
+            Content::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&self.data.lock().content, Content::Directory(entries) if entries.is_empty())
+    }
+}
+
+impl InodeData {
+    fn file(&mut self) -> Result<&mut Vec<u8>, FsError> {
+        match &mut self.content {
+            Content::File(data) => Ok(data),
+            Content::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn directory(&mut self) -> Result<&mut BTreeMap<String, Arc<TmpInode>>, FsError> {
+        match &mut self.content {
+            Content::Directory(entries) => Ok(entries),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = time::now();
+        self.modified = now;
+        self.changed = now;
+    }
+}
+
+// Grows or shrinks file contents to `size` bytes, failing rather than running the heap
+// out of memory.
+fn resize(bytes: &mut Vec<u8>, size: u64) -> Result<(), FsError> {
+    if size > MAX_FILE_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    let size = size as usize;
+    if size > bytes.len() {
+        bytes
+            .try_reserve(size - bytes.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    bytes.resize(size, 0);
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        Err(FsError::InvalidPath)
+    } else {
+        Ok(())
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let data = self.data.lock();
+        let (kind, size) = match &data.content {
+            Content::File(bytes) => (FileType::Regular, bytes.len() as u64),
+            Content::Directory(entries) => (FileType::Directory, entries.len() as u64),
+        };
+        Metadata {
+            inode: self.id,
+            kind,
+            size,
+            links: data.links,
+            accessed: data.accessed,
+            modified: data.modified,
+            changed: data.changed,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        data.accessed = time::now();
+        let bytes = data.file()?;
+
+        let start = (offset as usize).min(bytes.len());
+        let count = buf.len().min(bytes.len() - start);
+        buf[..count].copy_from_slice(&bytes[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let bytes = data.file()?;
+
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        if end > bytes.len() as u64 {
+            resize(bytes, end)?;
+        }
+        bytes[offset as usize..end as usize].copy_from_slice(buf);
+        data.touch();
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        resize(data.file()?, size)?;
+        data.touch();
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+        let mut data = self.data.lock();
+        let inode = data.directory()?.get(name).ok_or(FsError::NotFound)?;
+        Ok(inode.clone())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut data = self.data.lock();
+        data.accessed = time::now();
+        let entries = data.directory()?;
+        Ok(entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                inode: inode.id,
+                kind: inode.kind(),
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsError> {
+        check_name(name)?;
+        if kind != FileType::Regular && kind != FileType::Directory {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut data = self.data.lock();
+        let entries = data.directory()?;
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let inode = TmpInode::new(id, self.next_id.clone(), kind);
+        entries.insert(String::from(name), inode.clone());
+        if kind == FileType::Directory {
+            data.links += 1;
+        }
+        data.touch();
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let entries = data.directory()?;
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+
+        let is_directory = inode.kind() == FileType::Directory;
+        if is_directory && !inode.is_empty_directory() {
+            return Err(FsError::NotEmpty);
+        }
+        let inode = entries.remove(name).expect("entry vanished");
+        if is_directory {
+            data.links -= 1;
+        }
+        data.touch();
+
+        let mut removed = inode.data.lock();
+        removed.links = 0;
+        removed.changed = time::now();
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &InodeRef, new_name: &str) -> Result<(), FsError> {
+        check_name(new_name)?;
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<TmpInode>()
+            .ok_or(FsError::CrossDevice)?;
+
+        let inode = self
+            .data
+            .lock()
+            .directory()?
+            .get(old_name)
+            .ok_or(FsError::NotFound)?
+            .clone();
+        let kind = inode.kind();
+
+        // Check what we are about to replace before changing anything.
+        let replaced = new_parent.data.lock().directory()?.get(new_name).cloned();
+        if let Some(replaced) = &replaced {
+            if Arc::ptr_eq(replaced, &inode) {
+                return Ok(());
+            }
+            match (kind, replaced.kind()) {
+                (FileType::Directory, FileType::Directory) if !replaced.is_empty_directory() => {
+                    return Err(FsError::NotEmpty)
+                }
+                (FileType::Directory, FileType::Directory) => {}
+                (FileType::Directory, _) => return Err(FsError::NotADirectory),
+                (_, FileType::Directory) => return Err(FsError::IsADirectory),
+                _ => {}
+            }
+        }
+
+        let mut old_data = self.data.lock();
+        old_data.directory()?.remove(old_name);
+        if kind == FileType::Directory {
+            old_data.links -= 1;
+        }
+        old_data.touch();
+        drop(old_data);
+
+        let mut new_data = new_parent.data.lock();
+        let replaced_directory = replaced.map_or(false, |r| r.kind() == FileType::Directory);
+        new_data
+            .directory()?
+            .insert(String::from(new_name), inode.clone());
+        if kind == FileType::Directory && !replaced_directory {
+            new_data.links += 1;
+        }
+        new_data.touch();
+        drop(new_data);
+
+        inode.data.lock().changed = time::now();
+        Ok(())
+    }
+}
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<TmpFs> {
+        // Inode 1 is the root, like on most Unix file systems.
+        let next_id = Arc::new(AtomicU64::new(2));
+        Arc::new(TmpFs {
+            root: TmpInode::new(1, next_id, FileType::Directory),
+        })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+#[test_case]
+fn test_tmpfs_operations() {
+    let fs = TmpFs::new();
+    let root = fs.root();
+
+    let dir = root.create("dir", FileType::Directory).unwrap();
+    let file = dir.create("file", FileType::Regular).unwrap();
+    assert_eq!(file.write_at(4, b"data").unwrap(), 4);
+    assert_eq!(file.metadata().size, 8);
+
+    let mut buf = [0xff; 16];
+    assert_eq!(file.read_at(0, &mut buf).unwrap(), 8);
+    assert_eq!(&buf[..8], b"\0\0\0\0data");
+
+    file.truncate(2).unwrap();
+    assert_eq!(file.metadata().size, 2);
+    assert_eq!(file.truncate(1 << 40).err(), Some(FsError::NoSpace));
+    assert_eq!(
+        file.write_at(u64::MAX - 1, b"data").err(),
+        Some(FsError::InvalidArgument)
+    );
+    assert_eq!(
+        file.write_at(1 << 40, b"data").err(),
+        Some(FsError::NoSpace)
+    );
+    assert_eq!(file.metadata().size, 2);
+
+    dir.rename("file", &root, "moved").unwrap();
+    assert!(dir.lookup("file").is_err());
+    assert_eq!(
+        root.lookup("moved").unwrap().metadata().inode,
+        file.metadata().inode
+    );
+
+    assert_eq!(
+        root.create("moved", FileType::Regular).err(),
+        Some(FsError::AlreadyExists)
+    );
+    root.create("full", FileType::Directory)
+        .unwrap()
+        .create("x", FileType::Regular)
+        .unwrap();
+    assert_eq!(root.unlink("full").err(), Some(FsError::NotEmpty));
+    root.unlink("dir").unwrap();
+    assert_eq!(root.read_dir().unwrap().len(), 2);
+}
@@ -0,0 +1,125 @@
+// The local APIC.
+//
+// Every processor has a local APIC that accepts interrupts for it. Legacy interrupts
+// still arrive from the PICs through its LINT0 pin, which the firmware sets up for that,
+// but message signalled interrupts (MSI) from PCI devices are memory writes that name a
+// local APIC and a vector directly. The APIC only has to be switched on for those, and
+// their handlers acknowledge them here rather than at the PICs.
+// Reference: https://wiki.osdev.org/APIC
+// Reference: https://wiki.osdev.org/MADT
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::instructions::{cpuid, rdmsr, wrmsr};
+use crate::memory::mmio;
+use crate::println;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0xf_ffff_f000;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+/// Taken when an interrupt goes away before the processor accepts it. Needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Where MSI writes go; the destination APIC ID is added in bits 12 to 19.
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+const MADT_ENTRIES: usize = 44;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_ENABLED: u32 = 1 << 0;
+const MADT_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Virtual address of the registers, 0 until `init` mapped them.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn register(offset: usize) -> Option<*mut u32> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(VirtAddr::new(base + offset as u64).as_mut_ptr()),
+    }
+}
+
+fn read(offset: usize) -> u32 {
+    register(offset).map_or(0, |reg| unsafe { reg.read_volatile() })
+}
+
+fn write(offset: usize, value: u32) {
+    if let Some(reg) = register(offset) {
+        unsafe { reg.write_volatile(value) }
+    }
+}
+
+/// Whether the local APIC was enabled.
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// The ID of the local APIC of the running processor.
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+/// Acknowledges the interrupt being handled. Only for interrupts that came through the
+/// APIC itself, like MSI; the PICs acknowledge their own.
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// The address an MSI has to be written to so it reaches the local APIC `apic_id`.
+pub fn msi_address(apic_id: u8) -> u32 {
+    MSI_ADDRESS | (apic_id as u32) << 12
+}
+
+/// The APIC IDs of the usable processors the ACPI MADT lists.
+pub fn processors() -> Vec<u8> {
+    let Some(madt) = acpi::find_table(b"APIC") else {
+        return Vec::new();
+    };
+    let mut processors = Vec::new();
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let (kind, length) = (madt[offset], madt[offset + 1] as usize);
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+        if kind == MADT_LOCAL_APIC && length >= 8 {
+            let flags = u32::from_le_bytes(madt[offset + 4..offset + 8].try_into().unwrap());
+            if flags & (MADT_ENABLED | MADT_ONLINE_CAPABLE) != 0 {
+                processors.push(madt[offset + 3]);
+            }
+        }
+        offset += length;
+    }
+    processors
+}
+
+/// Maps and enables the local APIC of the boot processor, if it has one.
+pub fn init() {
+    println!("[!] Enabling local APIC");
+    // CPUID leaf 1 EDX bit 9.
+    if cpuid(1, 0)[3] & (1 << 9) == 0 {
+        return println!("    [-] No local APIC");
+    }
+    let msr = unsafe { rdmsr(IA32_APIC_BASE) };
+    let phys = PhysAddr::new(msr & APIC_BASE_ADDRESS);
+    let Some(base) = mmio::map(phys, 4096) else {
+        return println!("    [-] Cannot map the registers");
+    };
+    unsafe { wrmsr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE) };
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+
+    println!("    [+] APIC {} at {:#x}", id(), phys.as_u64());
+    let processors = processors();
+    if !processors.is_empty() {
+        println!("    [+] Processors: {:?}", processors);
+    }
+    println!("    [+] Done");
+}
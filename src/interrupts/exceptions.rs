@@ -1,40 +1,161 @@
 use super::idt::InterruptStackFrame;
+use super::interrupts;
+use crate::memory::address_space;
+use crate::memory::stack;
+use crate::symbols::Symbol;
+use crate::{println, process};
+use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
+
+// Signals a Unix kernel sends for these faults. A user process killed by a fault exits
+// with 128 plus the signal number, like a Unix shell reports it.
+const SIGILL: i64 = 4;
+const SIGFPE: i64 = 8;
+const SIGSEGV: i64 = 11;
 
 //CPU EXCEPTIONS HANDLERS
 // Reference: https://os.phil-opp.com/cpu-exceptions/#the-interrupt-calling-convention
 pub extern "x86-interrupt" fn div_error_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DIVISION ERROR\n{:#?}", stack_frame);
+    interrupts::count(0);
+    kill_user_process(&stack_frame, "division error", SIGFPE);
+    panic!(
+        "EXCEPTION: DIVISION ERROR at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
 pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+    interrupts::count(6);
+    kill_user_process(&stack_frame, "invalid opcode", SIGILL);
+    panic!(
+        "EXCEPTION: INVALID OPCODE at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
+}
+
+pub extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    interrupts::count(2);
+    panic!(
+        "EXCEPTION: NON-MASKABLE INTERRUPT at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    interrupts::count(3);
+    panic!(
+        "EXCEPTION: BREAKPOINT at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
 pub extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    interrupts::count(8);
+    // A page fault that cannot push its exception frame, because the stack ran into
+    // its guard page, escalates into a double fault. CR2 still holds the address, but
+    // may also be left over from an earlier fault, so the stack pointer has to be at
+    // the guard page as well.
+    let address = Cr2::read();
+    let stack_pointer = VirtAddr::new(stack_frame.stack_pointer);
+    report_stack_overflow(stack::overflowed_stack(address, stack_pointer), address);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
 pub extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
+    interrupts::count(13);
+    kill_user_process(&stack_frame, "general protection fault", SIGSEGV);
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
+// Page fault error code bits.
+const PAGE_PROTECTION_VIOLATION: u64 = 1 << 0;
+const PAGE_CAUSED_BY_WRITE: u64 = 1 << 1;
+
 pub extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: PAGE FAULT\n{:#?}", stack_frame);
+    error_code: u64,
+) {
+    interrupts::count(14);
+    let address = Cr2::read();
+
+    // A write to a present, read-only page may be the first write to a page shared
+    // copy-on-write by `fork`.
+    let write_to_present = PAGE_PROTECTION_VIOLATION | PAGE_CAUSED_BY_WRITE;
+    if error_code & write_to_present == write_to_present
+        && address_space::resolve_copy_on_write(address)
+    {
+        return;
+    }
+    kill_user_process(&stack_frame, "page fault", SIGSEGV);
+
+    report_stack_overflow(stack::guard_page_owner(address), address);
+
+    panic!(
+        "EXCEPTION: PAGE FAULT at {}\nAccessed address: {:?}\nError code: {:#x}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        address,
+        error_code,
+        stack_frame
+    );
+}
+
+// Panics with the name of the overflowed stack `owner`, if any, that `address` hit.
+fn report_stack_overflow(owner: Option<&'static str>, address: VirtAddr) {
+    if let Some(name) = owner {
+        panic!(
+            "EXCEPTION: kernel stack overflow on the \"{}\" stack (accessed {:?})",
+            name, address
+        );
+    }
+}
+
+// Ends the current process if the fault happened in user mode, the kernel itself is
+// fine then. Returns for faults in kernel mode, which the handlers panic on.
+fn kill_user_process(stack_frame: &InterruptStackFrame, fault: &str, signal: i64) {
+    // The lowest two bits of the interrupted code segment are its privilege level.
+    if stack_frame.code_segment & 3 != 3 {
+        return;
+    }
+    println!(
+        "[-] Process {} killed: {} at {:#x}",
+        process::current_pid(),
+        fault,
+        stack_frame.instruction_pointer
+    );
+    process::exit(128 + signal)
+}
+
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    interrupts::count(18);
+    panic!(
+        "EXCEPTION: MACHINE CHECK at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
 
 pub extern "x86-interrupt" fn generic_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: GENERIC\n{:#?}", stack_frame);
+    panic!(
+        "EXCEPTION: GENERIC at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer),
+        stack_frame
+    );
 }
@@ -1,55 +1,115 @@
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::stack;
 use crate::println;
 
+// Interrupt Stack Table slots. The CPU switches to these stacks unconditionally, so the
+// handlers still get a working stack when the fault was caused by a corrupted or
+// overflowed one.
+// Reference: https://wiki.osdev.org/Task_State_Segment
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+// (IST index, stack name, size in pages)
+pub const IST_STACKS: [(u16, &str, u64); 4] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault", 5),
+    (NMI_IST_INDEX, "NMI", 4),
+    (MACHINE_CHECK_IST_INDEX, "machine check", 4),
+    (PAGE_FAULT_IST_INDEX, "page fault", 5),
+];
+
+/// Returns whether the TSS has a stack installed for the given IST index.
+pub fn has_ist_stack(index: u16) -> bool {
+    IST_STACKS
+        .iter()
+        .any(|(ist_index, _, _)| *ist_index == index)
 }
 
+// Size of the stack the CPU switches to when an interrupt or exception arrives while
+// running in ring 3.
+const PRIVILEGE_STACK_PAGES: u64 = 8;
+
+// The TSS is filled in by `init` before the GDT is loaded. It is a plain static rather
+// than a lazy one because the ring 0 stack pointer gets updated at runtime, and the
+// system call entry reads that stack pointer straight from here.
+pub(crate) static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
+    // The user data segment has to come right before the user code segment, `sysret`
+    // derives both selectors from a single base in the STAR register.
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
-                code_selector,
+                kernel_code_selector,
+                kernel_data_selector,
+                user_data_selector,
+                user_code_selector,
                 tss_selector,
             },
         )
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub struct Selectors {
+    pub kernel_code_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+/// Returns the segment selectors of the loaded GDT.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3
+/// (`TSS.privilege_stack_table[0]`, also known as RSP0).
+///
+/// The caller has to make sure the stack stays mapped while it is installed.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    (*addr_of_mut!(TSS)).privilege_stack_table[0] = top;
+}
+
+fn init_tss() {
+    let tss = unsafe { &mut *addr_of_mut!(TSS) };
+    for (index, name, pages) in IST_STACKS {
+        tss.interrupt_stack_table[index as usize] = stack::allocate(name, pages)
+            .expect("failed to allocate an interrupt stack")
+            .top();
+    }
+    tss.privilege_stack_table[0] = stack::allocate("privilege level 0", PRIVILEGE_STACK_PAGES)
+        .expect("failed to allocate the privilege level 0 stack")
+        .top();
 }
 
+// The stacks are allocated from the virtual memory layer, so `memory::init` has to run
+// first.
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
     println!("[!] Loading GDT");
+    init_tss();
     GDT.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(GDT.1.kernel_code_selector);
+        SS::set_reg(GDT.1.kernel_data_selector);
+        DS::set_reg(GDT.1.kernel_data_selector);
+        ES::set_reg(GDT.1.kernel_data_selector);
         load_tss(GDT.1.tss_selector);
     }
     println!("    [+] Done")
@@ -1,4 +1,5 @@
 use super::exceptions;
+use super::gdt;
 use bit_field::BitField;
 use x86_64::registers::segmentation::Segment;
 
@@ -44,14 +45,56 @@ impl InterruptDescriptorTable {
         self
     }
 
+    /// Runs the handler of `int` on the Interrupt Stack Table stack `index`.
+    ///
+    /// Panics if `gdt` does not install a stack for that IST index.
+    pub fn with_stack(mut self, int: usize, index: u16) -> InterruptDescriptorTable {
+        assert!(
+            gdt::has_ist_stack(index),
+            "no IST stack with index {}",
+            index
+        );
+        // Safe because the index refers to a stack that the TSS sets up and that is
+        // reserved for exception handlers.
+        unsafe {
+            self.entries[int].set_stack_index(index);
+        }
+        self
+    }
+
+    /// Sets the Descriptor Privilege Level of the gate for `int`. Software interrupts
+    /// (`int n`) from a ring above the DPL raise a general protection fault, so gates
+    /// meant to be called from user mode need `PrivilegeLevel::Ring3`.
+    pub fn with_privilege_level(
+        mut self,
+        int: usize,
+        dpl: PrivilegeLevel,
+    ) -> InterruptDescriptorTable {
+        self.entries[int].options.set_privilege_level(dpl);
+        self
+    }
+
     //add exception handlers for various cpu exceptions
     pub fn add_exceptions(self) -> InterruptDescriptorTable {
         self.add(0x0, exceptions::div_error_handler as u64)
+            .add(0x2, exceptions::nmi_handler as u64)
             .add(0x3, exceptions::breakpoint_handler as u64)
             .add(0x6, exceptions::invalid_opcode_handler as u64)
             .add(0x8, exceptions::double_fault_handler as u64)
             .add(0xd, exceptions::general_protection_fault_handler as u64)
             .add(0xe, exceptions::page_fault_handler as u64)
+            .add(0x12, exceptions::machine_check_handler as u64)
+            .add_exception_stacks()
+    }
+
+    // Moves the exceptions that may be raised on a broken stack to their IST stacks.
+    // Note that a page fault inside the page fault handler reuses (and clobbers) the same
+    // stack, the handler must therefore not fault itself.
+    fn add_exception_stacks(self) -> InterruptDescriptorTable {
+        self.with_stack(0x2, gdt::NMI_IST_INDEX)
+            .with_stack(0x8, gdt::DOUBLE_FAULT_IST_INDEX)
+            .with_stack(0xe, gdt::PAGE_FAULT_IST_INDEX)
+            .with_stack(0x12, gdt::MACHINE_CHECK_IST_INDEX)
     }
 }
 
@@ -1,20 +1,47 @@
 use crate::{
+    console,
+    drivers::ata,
     instructions::{disable_interrupts, enable_interrupts, interrupts_enabled},
-    interrupts::idt::InterruptDescriptorTable,
+    interrupts::apic,
+    interrupts::idt::{InterruptDescriptorTable, PrivilegeLevel},
     locks::mutex::Mutex,
     println,
-    shell::shell::SHELL,
     pic::ChainedPics,
+    process::scheduler,
+    syscall, time,
 };
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use super::idt::InterruptStackFrame;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
-        InterruptDescriptorTable::new()
+        let mut idt = InterruptDescriptorTable::new()
             .add_exceptions()
             .add(PIC_1_OFFSET as usize, timer_interrupt_handler as u64)
             .add(33, keyboard_interrupt_handler as u64)
+            .add(
+                PIC_2_OFFSET as usize + 6,
+                primary_ata_interrupt_handler as u64,
+            )
+            .add(
+                PIC_2_OFFSET as usize + 7,
+                secondary_ata_interrupt_handler as u64,
+            )
+            .add(
+                apic::SPURIOUS_VECTOR as usize,
+                spurious_interrupt_handler as u64,
+            )
+            .add(syscall::SYSCALL_VECTOR, syscall::int80_entry as u64)
+            .with_privilege_level(syscall::SYSCALL_VECTOR, PrivilegeLevel::Ring3);
+        for (index, handler) in DYNAMIC_HANDLERS.iter().enumerate() {
+            idt = idt.add(FIRST_DYNAMIC_VECTOR as usize + index, *handler as u64);
+        }
+        for (irq, handler) in SHARED_IRQS.zip(SHARED_IRQ_HANDLERS) {
+            idt = idt.add((PIC_1_OFFSET + irq) as usize, handler as u64);
+        }
+        idt
     };
 }
 
@@ -23,14 +50,26 @@ pub fn init_idt() {
     println!("    [+] Setting up exceptions");
     println!("    [+] Setting up PIC interrupts");
     println!("    [+] Setting up keyboard interrupts");
+    println!("    [+] Setting up ATA interrupts");
+    println!("    [+] Setting up system call gate");
+    println!(
+        "    [+] Setting up IRQs {}-{} for drivers",
+        SHARED_IRQS.start(),
+        SHARED_IRQS.end()
+    );
+    println!(
+        "    [+] Setting up vectors {}-{} for drivers",
+        FIRST_DYNAMIC_VECTOR,
+        FIRST_DYNAMIC_VECTOR as usize + DYNAMIC_VECTORS - 1
+    );
     IDT.load();
     println!("    [+] Done")
 }
 
 // Ref: https://doc.rust-lang.org/rust-by-example/fn/closures/input_parameters.html
-pub fn without_interrupts<F>(f: F)
+pub fn without_interrupts<F, R>(f: F) -> R
 where
-    F: FnOnce(),
+    F: FnOnce() -> R,
 {
     let status = interrupts_enabled();
 
@@ -38,23 +77,71 @@ where
         disable_interrupts();
     }
 
-    f();
+    let result = f();
 
     if status {
         enable_interrupts();
     }
+
+    result
+}
+
+// How often each vector was taken, for /proc/interrupts.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Records that the interrupt `vector` was taken. Called by the handlers.
+pub fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The vectors that were taken at least once, with their counts.
+pub fn counts() -> Vec<(u8, u64)> {
+    (0..=255)
+        .map(|vector| (vector, COUNTS[vector as usize].load(Ordering::Relaxed)))
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
+/// What the given vector is used for.
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "division error",
+        2 => "nmi",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        8 => "double fault",
+        13 => "general protection",
+        14 => "page fault",
+        18 => "machine check",
+        PIC_1_OFFSET => "timer",
+        33 => "keyboard",
+        46 => "ata primary",
+        47 => "ata secondary",
+        0x80 => "system call",
+        0..=31 => "exception",
+        apic::SPURIOUS_VECTOR => "spurious",
+        PIC_1_OFFSET..=PIC_2_OFFSET_END => irq_handler_name(vector - PIC_1_OFFSET).unwrap_or(""),
+        _ => handler_name(vector).unwrap_or(""),
+    }
 }
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+const PIC_2_OFFSET_END: u8 = PIC_2_OFFSET + 7;
 
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-extern "x86-interrupt" fn timer_interrupt_handler(_: &mut InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(PIC_1_OFFSET);
+    time::tick();
+
     unsafe {
         PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET);
     }
+
+    // The lowest two bits of the interrupted code segment are its privilege level.
+    scheduler::preempt(stack_frame.code_segment & 3 == 3);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
@@ -66,6 +153,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
         );
     }
 
+    count(33);
     let mut keyboard = KEYBOARD.lock();
 
     let scancode: u8;
@@ -76,19 +164,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => {
-                    let mut shell = SHELL.lock();
-                    // Backspace
-                    if character == '\n' {
-                        shell.enter();
-                    } else if character == '\u{8}' {
-                        shell.backspace();
-                    } else {
-                        shell.add(character);
-                    }
-                    drop(shell);
-                }
-
+                DecodedKey::Unicode(character) => console::push(character),
                 _ => {}
             }
         }
@@ -98,3 +174,203 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptStackFrame) {
         PICS.lock().notify_end_of_interrupt(33);
     }
 }
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_: InterruptStackFrame) {
+    count(PIC_2_OFFSET + 6);
+    ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 6);
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_: InterruptStackFrame) {
+    count(PIC_2_OFFSET + 7);
+    ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 7);
+    }
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_: InterruptStackFrame) {
+    count(apic::SPURIOUS_VECTOR);
+}
+
+/// Handles an interrupt registered at runtime; gets the vector it was taken on.
+pub type Handler = fn(u8);
+/// A handler and the name it shows up under in /proc/interrupts.
+type Registration = (&'static str, Handler);
+
+/// Vectors from here on are handed out to drivers at runtime, e.g. for MSI.
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_2_OFFSET + 8;
+// Up to the system call vector.
+const DYNAMIC_VECTORS: usize = 80;
+
+static HANDLERS: Mutex<[Option<Registration>; DYNAMIC_VECTORS]> =
+    Mutex::new([None; DYNAMIC_VECTORS]);
+
+/// Allocates a free vector for `handler` and returns it, `None` if all are taken.
+/// `name` shows up in /proc/interrupts.
+pub fn register_handler(name: &'static str, handler: Handler) -> Option<u8> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(|entry| entry.is_none())?;
+        handlers[index] = Some((name, handler));
+        Some(FIRST_DYNAMIC_VECTOR + index as u8)
+    })
+}
+
+/// Frees a vector `register_handler` handed out. The device must not raise it anymore.
+pub fn unregister_handler(vector: u8) {
+    without_interrupts(|| {
+        if let Some(entry) = HANDLERS
+            .lock()
+            .get_mut(vector.wrapping_sub(FIRST_DYNAMIC_VECTOR) as usize)
+        {
+            *entry = None;
+        }
+    })
+}
+
+fn handler_name(vector: u8) -> Option<&'static str> {
+    let index = vector.checked_sub(FIRST_DYNAMIC_VECTOR)? as usize;
+    without_interrupts(|| {
+        HANDLERS
+            .lock()
+            .get(index)
+            .copied()
+            .flatten()
+            .map(|(name, _)| name)
+    })
+}
+
+// Runs the handler registered for `vector`, if any.
+fn dispatch(vector: u8) {
+    let index = (vector - FIRST_DYNAMIC_VECTOR) as usize;
+    // Copied out so the handler may register or free vectors itself.
+    let entry = HANDLERS.lock()[index];
+    if let Some((_, handler)) = entry {
+        handler(vector);
+    }
+}
+
+// One entry point per vector, since a handler cannot tell which vector it was called
+// for otherwise. Interrupts registered at runtime are message signalled ones that come
+// through the local APIC.
+extern "x86-interrupt" fn dynamic_handler<const VECTOR: u8>(_: InterruptStackFrame) {
+    count(VECTOR);
+    dispatch(VECTOR);
+    apic::end_of_interrupt();
+}
+
+macro_rules! dynamic_handlers {
+    ($($base:literal)*) => {
+        [$(
+            dynamic_handler::<{ $base }>, dynamic_handler::<{ $base + 1 }>,
+            dynamic_handler::<{ $base + 2 }>, dynamic_handler::<{ $base + 3 }>,
+            dynamic_handler::<{ $base + 4 }>, dynamic_handler::<{ $base + 5 }>,
+            dynamic_handler::<{ $base + 6 }>, dynamic_handler::<{ $base + 7 }>,
+            dynamic_handler::<{ $base + 8 }>, dynamic_handler::<{ $base + 9 }>,
+            dynamic_handler::<{ $base + 10 }>, dynamic_handler::<{ $base + 11 }>,
+            dynamic_handler::<{ $base + 12 }>, dynamic_handler::<{ $base + 13 }>,
+            dynamic_handler::<{ $base + 14 }>, dynamic_handler::<{ $base + 15 }>,
+        )*]
+    };
+}
+
+static DYNAMIC_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); DYNAMIC_VECTORS] =
+    dynamic_handlers!(48 64 80 96 112);
+
+/// Legacy IRQ lines drivers can attach to, the ones no fixed handler uses. IRQ 2 only
+/// cascades the second PIC.
+const SHARED_IRQS: core::ops::RangeInclusive<u8> = 3..=13;
+/// Devices that can share one line, as PCI devices do.
+const MAX_SHARED: usize = 4;
+
+static IRQ_HANDLERS: Mutex<[[Option<Registration>; MAX_SHARED]; 16]> =
+    Mutex::new([[None; MAX_SHARED]; 16]);
+
+/// Attaches `handler` to the legacy interrupt line `irq`, which may be shared with other
+/// devices, and unmasks the line. Every handler on the line runs for each interrupt and
+/// has to check whether its device raised it. Returns false if the line cannot be used.
+pub fn register_irq_handler(irq: u8, name: &'static str, handler: Handler) -> bool {
+    if !SHARED_IRQS.contains(&irq) {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let Some(entry) = handlers[irq as usize]
+            .iter_mut()
+            .find(|entry| entry.is_none())
+        else {
+            return false;
+        };
+        *entry = Some((name, handler));
+        unsafe { PICS.lock().unmask(irq) };
+        true
+    })
+}
+
+fn irq_handler_name(irq: u8) -> Option<&'static str> {
+    let handlers = without_interrupts(|| IRQ_HANDLERS.lock()[irq as usize & 15]);
+    handlers.iter().flatten().map(|(name, _)| *name).next()
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_: InterruptStackFrame) {
+    if IRQ == 7 && PICS.lock().is_spurious_irq7() {
+        return;
+    }
+    let vector = PIC_1_OFFSET + IRQ;
+    count(vector);
+    // Copied out so the handlers may take the lock themselves.
+    let handlers = IRQ_HANDLERS.lock()[IRQ as usize];
+    for (_, handler) in handlers.iter().flatten() {
+        handler(vector);
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+static SHARED_IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); 11] = [
+    irq_handler::<3>,
+    irq_handler::<4>,
+    irq_handler::<5>,
+    irq_handler::<6>,
+    irq_handler::<7>,
+    irq_handler::<8>,
+    irq_handler::<9>,
+    irq_handler::<10>,
+    irq_handler::<11>,
+    irq_handler::<12>,
+    irq_handler::<13>,
+];
+
+#[test_case]
+fn test_handler_registry() {
+    use core::sync::atomic::AtomicU8;
+
+    static TAKEN: AtomicU8 = AtomicU8::new(0);
+    fn handler(vector: u8) {
+        TAKEN.store(vector, Ordering::Relaxed);
+    }
+
+    let first = register_handler("test", handler).unwrap();
+    let second = register_handler("test", handler).unwrap();
+    assert_ne!(first, second);
+    assert!(
+        first >= FIRST_DYNAMIC_VECTOR
+            && (first as usize) < FIRST_DYNAMIC_VECTOR as usize + DYNAMIC_VECTORS
+    );
+    assert_eq!(vector_name(second), "test");
+
+    without_interrupts(|| dispatch(second));
+    assert_eq!(TAKEN.load(Ordering::Relaxed), second);
+
+    unregister_handler(first);
+    assert_eq!(vector_name(first), "");
+    assert_eq!(register_handler("test", handler), Some(first));
+    unregister_handler(first);
+    unregister_handler(second);
+}
@@ -1,3 +1,4 @@
+pub mod apic;
 pub mod gdt;
 pub mod idt;
 pub mod interrupts;
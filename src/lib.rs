@@ -6,15 +6,31 @@
 #![feature(abi_x86_interrupt)] //This error occurs because the x86-interrupt calling convention is still unstable. To use it anyway, we have to explicitly enable it by adding #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod block;
+pub mod console;
+pub mod cpu;
+pub mod drivers;
+pub mod elf;
+pub mod fs;
 pub mod instructions;
 pub mod interrupts;
+pub mod loader;
 pub mod locks;
 pub mod memory;
+pub mod net;
+pub mod process;
 pub mod serial;
 pub mod shell;
+pub mod symbols;
+pub mod syscall;
+pub mod time;
 pub mod vga_buffer;
 pub mod pic;
 
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
 use interrupts::gdt;
 use interrupts::interrupts as Interrupts;
@@ -25,11 +41,26 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
-pub fn init() {
+pub fn init(boot_info: &'static BootInfo) {
     println!("[!] Booting...");
+    memory::init(boot_info);
     gdt::init();
     Interrupts::init_idt();
     unsafe { Interrupts::PICS.lock().initialize() };
+    time::init();
+    syscall::init();
+    process::init();
+    block::init();
+    acpi::init();
+    interrupts::apic::init();
+    drivers::pci::init();
+    drivers::mem::init();
+    drivers::tty::init();
+    drivers::ata::init();
+    drivers::virtio::init();
+    drivers::e1000::init();
+    net::init();
+    fs::init();
     println!("[!] Enabling interrupts");
     instructions::enable_interrupts();
     println!("[!] MoonlightOS Initialized");
@@ -77,15 +108,6 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
-/// Entry point for `cargo test`
-#[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
-    test_main();
-    hlt_loop();
-}
-
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -93,16 +115,15 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 // Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    // like before
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop();
 }
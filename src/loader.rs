@@ -0,0 +1,265 @@
+// Loading ELF executables for user mode.
+//
+// Every program gets a fresh address space. Its `PT_LOAD` segments are mapped with the
+// permissions requested in the program headers, and a stack is set up the way the
+// System V ABI describes it, so the entry point finds argc, argv, envp and the
+// auxiliary vector at its stack pointer.
+// Reference: https://refspecs.linuxfoundation.org/elf/x86_64-abi-0.99.pdf (section 3.4)
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateError, TranslateResult};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::elf::{Elf, ElfError, ProgramHeader, PF_W, PF_X, PROGRAM_HEADER_SIZE, PT_LOAD};
+use crate::memory::address_space::{is_user_range, AddressSpace};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The user stack grows down from just below the end of user space.
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_PAGES: u64 = 16;
+
+/// Limits for the argument and environment vectors.
+const MAX_ARGS: usize = 32;
+
+// Auxiliary vector entry types.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// A segment lies outside user space or outside the file.
+    BadSegment,
+    /// The entry point is not inside an executable segment.
+    BadEntryPoint,
+    TooManyArguments,
+    Memory(MapToError<Size4KiB>),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        LoadError::Memory(error)
+    }
+}
+
+impl From<TranslateError> for LoadError {
+    // Only happens when writing to memory we did not map, i.e. for segments we rejected.
+    fn from(_: TranslateError) -> Self {
+        LoadError::BadSegment
+    }
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the executable `image` into a new address space and prepares its stack.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    if argv.len() > MAX_ARGS || envp.len() > MAX_ARGS {
+        return Err(LoadError::TooManyArguments);
+    }
+
+    let elf = Elf::parse(image)?;
+    let mut address_space = AddressSpace::new()?;
+
+    let mut entry_is_executable = false;
+    for segment in elf
+        .program_headers()
+        .filter(|header| header.kind == PT_LOAD)
+    {
+        load_segment(&mut address_space, &elf, &segment)?;
+        entry_is_executable |= segment.flags & PF_X != 0
+            && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&elf.entry());
+    }
+    if !entry_is_executable {
+        return Err(LoadError::BadEntryPoint);
+    }
+
+    let stack_pointer = setup_stack(&mut address_space, &elf, argv, envp)?;
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(elf.entry()),
+        stack_pointer,
+    })
+}
+
+fn segment_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn load_segment(
+    address_space: &mut AddressSpace,
+    elf: &Elf,
+    segment: &ProgramHeader,
+) -> Result<(), LoadError> {
+    let file_end = segment.offset.checked_add(segment.file_size);
+    if segment.file_size > segment.mem_size
+        || file_end.map_or(true, |end| end > elf.data().len() as u64)
+    {
+        return Err(LoadError::BadSegment);
+    }
+    if segment.mem_size == 0 {
+        return Ok(());
+    }
+    let start = VirtAddr::try_new(segment.vaddr).map_err(|_| LoadError::BadSegment)?;
+    if !is_user_range(start, segment.mem_size) {
+        return Err(LoadError::BadSegment);
+    }
+
+    let flags = segment_flags(segment);
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (segment.mem_size - 1));
+
+    for page in Page::range_inclusive(first, last) {
+        // Segments that are not page aligned may share a page with the previous one. The
+        // shared page gets the union of both permissions.
+        let existing = match address_space.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        };
+
+        match existing {
+            Some(existing) => {
+                let mut combined = existing | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    combined.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    address_space
+                        .mapper()
+                        .update_flags(page, combined)
+                        .map_err(|_| LoadError::BadSegment)?
+                        .ignore();
+                }
+            }
+            None => {
+                address_space.map_user_zeroed(page, flags)?;
+            }
+        }
+    }
+
+    let offset = segment.offset as usize;
+    address_space.write(
+        start,
+        &elf.data()[offset..offset + segment.file_size as usize],
+    )?;
+    Ok(())
+}
+
+// Writes strings and words downwards from `top`.
+struct StackWriter<'a> {
+    address_space: &'a mut AddressSpace,
+    sp: u64,
+}
+
+impl StackWriter<'_> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<u64, LoadError> {
+        self.sp -= bytes.len() as u64;
+        self.address_space.write(VirtAddr::new(self.sp), bytes)?;
+        Ok(self.sp)
+    }
+
+    fn push_str(&mut self, s: &str) -> Result<u64, LoadError> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(s.as_bytes())
+    }
+}
+
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    elf: &Elf,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, LoadError> {
+    let bottom =
+        Page::containing_address(VirtAddr::new(USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE));
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range(bottom, bottom + USER_STACK_PAGES) {
+        address_space.map_user_zeroed(page, flags)?;
+    }
+
+    let mut stack = StackWriter {
+        address_space,
+        sp: USER_STACK_TOP,
+    };
+
+    // The strings go on top of the stack, the pointers to them below.
+    let mut env_pointers = [0u64; MAX_ARGS];
+    for (pointer, var) in env_pointers.iter_mut().zip(envp) {
+        *pointer = stack.push_str(var)?;
+    }
+    let mut arg_pointers = [0u64; MAX_ARGS];
+    for (pointer, arg) in arg_pointers.iter_mut().zip(argv) {
+        *pointer = stack.push_str(arg)?;
+    }
+
+    let mut auxv = [(0u64, 0u64); 6];
+    let mut auxc = 0;
+    if let Some(phdr) = program_header_address(elf) {
+        auxv[auxc] = (AT_PHDR, phdr);
+        auxc += 1;
+    }
+    for entry in [
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_header_count() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry()),
+        (AT_NULL, 0),
+    ] {
+        auxv[auxc] = entry;
+        auxc += 1;
+    }
+
+    // argc, argv and its terminator, envp and its terminator, the auxiliary vector.
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * auxc;
+    stack.sp &= !0xf;
+    // The stack pointer has to be 16 byte aligned when it points at argc.
+    if words % 2 == 1 {
+        stack.sp -= 8;
+    }
+
+    for (key, value) in auxv[..auxc].iter().rev() {
+        stack.push_bytes(&value.to_le_bytes())?;
+        stack.push_bytes(&key.to_le_bytes())?;
+    }
+    stack.push_bytes(&0u64.to_le_bytes())?;
+    for pointer in env_pointers[..envp.len()].iter().rev() {
+        stack.push_bytes(&pointer.to_le_bytes())?;
+    }
+    stack.push_bytes(&0u64.to_le_bytes())?;
+    for pointer in arg_pointers[..argv.len()].iter().rev() {
+        stack.push_bytes(&pointer.to_le_bytes())?;
+    }
+    let sp = stack.push_bytes(&(argv.len() as u64).to_le_bytes())?;
+
+    Ok(VirtAddr::new(sp))
+}
+
+// Finds where the program header table ends up in memory, if a segment maps it.
+fn program_header_address(elf: &Elf) -> Option<u64> {
+    let phoff = elf.program_header_offset();
+    elf.program_headers()
+        .filter(|header| header.kind == PT_LOAD)
+        .find(|header| header.offset <= phoff && phoff < header.offset + header.file_size)
+        .map(|header| header.vaddr + (phoff - header.offset))
+}
@@ -25,6 +25,10 @@ impl<T: ?Sized> Mutex<T> {
             guard: self.mutex.lock(),
         }
     }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.mutex.try_lock().map(|guard| MutexGuard { guard })
+    }
 }
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
@@ -7,9 +7,8 @@
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use moonlight_os::memory;
-use moonlight_os::memory::BootInfoFrameAllocator;
 use moonlight_os::println;
-use moonlight_os::shell::shell::SHELL;
+use moonlight_os::shell::shell;
 use x86_64::{structures::paging::Page, VirtAddr};
 
 entry_point!(kernel_main);
@@ -17,14 +16,12 @@ entry_point!(kernel_main);
 #[no_mangle] // don't mangle the name of this function
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Moonlight OS{}", "!");
-    moonlight_os::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    moonlight_os::init(boot_info);
 
     let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    memory::with_memory(|memory| {
+        memory::create_example_mapping(page, &mut memory.mapper, &mut memory.frame_allocator)
+    });
 
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
@@ -34,8 +31,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("It did not crash");
     println!("[!] Entering shell...");
     print_info();
-    SHELL.lock().init();
-    moonlight_os::hlt_loop();
+    shell::run();
 }
 
 fn print_info() {
@@ -0,0 +1,430 @@
+// Per-process address spaces.
+//
+// Every address space gets its own level 4 page table. The lower half between
+// `USER_SPACE_START` and `USER_SPACE_END` belongs to the process and is mapped with
+// `USER_ACCESSIBLE`, every other level 4 entry is shared with the kernel's page table
+// and stays supervisor-only, so ring 3 code cannot touch kernel memory.
+//
+// `fork` shares the user pages of the parent with the child instead of copying them.
+// Writable pages are made read-only in both and marked `COPY_ON_WRITE`; the first
+// write to such a page faults and `resolve_copy_on_write` gives the writer a private
+// copy. The physical memory manager counts the references to every shared frame.
+// Reference: https://wiki.osdev.org/Paging#Page_Directory
+// Reference: https://en.wikipedia.org/wiki/Copy-on-write#In_virtual_memory_management
+use alloc::vec::Vec;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateError, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frame::PhysicalMemoryManager;
+use super::{kernel_level_4_frame, phys_to_virt, with_memory};
+
+/// Marks user pages that are shared read-only after `fork` but logically writable. Bit 9
+/// is one of the bits the CPU leaves to the operating system.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// First address available to user programs (level 4 entry 128).
+pub const USER_SPACE_START: u64 = 0x0000_4000_0000_0000;
+/// End of the user part of the address space (exclusive, level 4 entry 256).
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Returns whether the range `[start, start + size)` lies entirely in user space.
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    let start = start.as_u64();
+    start >= USER_SPACE_START
+        && start
+            .checked_add(size)
+            .map_or(false, |end| end <= USER_SPACE_END)
+}
+
+fn is_user_entry(index: usize) -> bool {
+    let start = usize::from(VirtAddr::new(USER_SPACE_START).p4_index());
+    (start..start + 128).contains(&index)
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+/// A run of consecutive user pages mapped with the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// Exclusive.
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half and the kernel mapped
+    /// supervisor-only.
+    pub fn new() -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let frame = with_memory(|memory| memory.frame_allocator.allocate_frame())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let kernel_table = unsafe { table(kernel_level_4_frame()) };
+        let table = unsafe { table(frame) };
+        table.zero();
+
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            assert!(
+                !is_user_entry(index),
+                "kernel memory mapped in the user part of the address space"
+            );
+            let flags = entry.flags() - PageTableFlags::USER_ACCESSIBLE;
+            table[PageTableIndex::new(index as u16)].set_addr(entry.addr(), flags);
+        }
+
+        Ok(AddressSpace {
+            level_4_frame: frame,
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns a mapper that edits this address space, whether it is active or not.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = phys_to_virt(PhysAddr::new(0));
+        unsafe { OffsetPageTable::new(table(self.level_4_frame), offset) }
+    }
+
+    /// Maps `page` to `frame` for user mode. `USER_ACCESSIBLE` is added to `flags` and
+    /// to every intermediate table on the way.
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_range(page.start_address(), page.size()),
+            "{:?} is not a user page",
+            page
+        );
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut mapper = self.mapper();
+        with_memory(|memory| unsafe {
+            mapper
+                .map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    table_flags,
+                    &mut memory.frame_allocator,
+                )
+                .map(|flush| flush.ignore())
+        })?;
+
+        if self.is_active() {
+            x86_64::instructions::tlb::flush(page.start_address());
+        }
+        Ok(())
+    }
+
+    /// Allocates a zeroed frame and maps it at `page` for user mode.
+    pub fn map_user_zeroed(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let frame = with_memory(|memory| memory.frame_allocator.allocate_frame())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { zero_frame(frame) };
+
+        if let Err(error) = self.map_user(page, frame, flags) {
+            with_memory(|memory| memory.frame_allocator.release(frame));
+            return Err(error);
+        }
+        Ok(frame)
+    }
+
+    /// Whether `page` is mapped in this address space.
+    pub fn is_mapped(&mut self, page: Page) -> bool {
+        self.mapper().translate_page(page).is_ok()
+    }
+
+    /// Unmaps the user page `page` and drops the reference to its frame. Does nothing if
+    /// the page is not mapped. Page tables that become empty are kept.
+    pub fn unmap_user(&mut self, page: Page) {
+        let Ok((frame, flush)) = self.mapper().unmap(page) else {
+            return;
+        };
+        flush.ignore();
+        with_memory(|memory| memory.frame_allocator.release(frame));
+        if self.is_active() {
+            tlb::flush(page.start_address());
+        }
+    }
+
+    /// Copies `data` to `addr` in this address space, whether it is active or not. The
+    /// memory is written through the physical memory mapping, so read-only user pages can
+    /// be filled as well. Fails if any page in the range is not mapped.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), TranslateError> {
+        let mapper = self.mapper();
+        let mut written = 0;
+
+        while written < data.len() {
+            let current = addr + written as u64;
+            let page = Page::<Size4KiB>::containing_address(current);
+            let frame = mapper.translate_page(page)?;
+
+            let offset = current - page.start_address();
+            let len = (page.size() - offset).min((data.len() - written) as u64) as usize;
+            let dest = phys_to_virt(frame.start_address() + offset).as_mut_ptr::<u8>();
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), dest, len);
+            }
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this address space for `fork`. The kernel part is shared as
+    /// usual. User pages are shared too, writable ones become copy-on-write in both
+    /// address spaces.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut copy = AddressSpace::new()?;
+        let mut result = Ok(());
+
+        for_each_user_entry(self.level_4_frame, |page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let mut flags = entry.flags();
+            if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+
+            let frame = entry.frame().expect("user page without a frame");
+            if !with_memory(|memory| memory.frame_allocator.retain(frame)) {
+                result = Err(MapToError::FrameAllocationFailed);
+                return;
+            }
+            result = copy.map_user(page, frame, flags);
+            if result.is_err() {
+                with_memory(|memory| memory.frame_allocator.release(frame));
+            }
+        });
+
+        // Our writable pages just became read-only.
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        result.map(|_| copy)
+    }
+
+    /// The mapped user pages, merged into regions, in address order.
+    pub fn regions(&self) -> Vec<Region> {
+        // Only the flags that tell regions apart; accessed and dirty bits do not.
+        let relevant = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | COPY_ON_WRITE;
+        let mut regions: Vec<Region> = Vec::new();
+        for_each_user_entry(self.level_4_frame, |page, entry| {
+            let flags = entry.flags() & relevant;
+            match regions.last_mut() {
+                Some(last) if last.end == page.start_address() && last.flags == flags => {
+                    last.end += page.size();
+                }
+                _ => regions.push(Region {
+                    start: page.start_address(),
+                    end: page.start_address() + page.size(),
+                    flags,
+                }),
+            }
+        });
+        regions
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches the CPU to this address space.
+    ///
+    /// The caller has to keep the address space alive while it is active.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the page tables of the user half and drops the references to the frames
+    /// mapped there.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let level_4_table = unsafe { table(self.level_4_frame) };
+        with_memory(|memory| {
+            for (index, entry) in level_4_table.iter().enumerate() {
+                if let (true, Ok(frame)) = (is_user_entry(index), entry.frame()) {
+                    free_table(&mut memory.frame_allocator, frame, 3);
+                }
+            }
+            memory.frame_allocator.release(self.level_4_frame);
+        });
+    }
+}
+
+// Releases the frames a user page table of the given level maps, and the tables below it.
+fn free_table(allocator: &mut PhysicalMemoryManager, frame: PhysFrame, level: u8) {
+    for entry in unsafe { table(frame) }.iter() {
+        if let Ok(next) = entry.frame() {
+            if level > 1 {
+                free_table(allocator, next, level - 1);
+            } else {
+                allocator.release(next);
+            }
+        }
+    }
+    allocator.release(frame);
+}
+
+// Calls `f` for every mapped user page of the address space with the given level 4 table.
+fn for_each_user_entry<F>(level_4_frame: PhysFrame, mut f: F)
+where
+    F: FnMut(Page, &mut PageTableEntry),
+{
+    let index = |i: usize| PageTableIndex::new(i as u16);
+    for (i4, e4) in unsafe { table(level_4_frame) }.iter().enumerate() {
+        let level_3_frame = match e4.frame() {
+            Ok(frame) if is_user_entry(i4) => frame,
+            _ => continue,
+        };
+        for (i3, e3) in unsafe { table(level_3_frame) }.iter().enumerate() {
+            let Ok(level_2_frame) = e3.frame() else {
+                continue;
+            };
+            for (i2, e2) in unsafe { table(level_2_frame) }.iter().enumerate() {
+                let Ok(level_1_frame) = e2.frame() else {
+                    continue;
+                };
+                for (i1, e1) in unsafe { table(level_1_frame) }.iter_mut().enumerate() {
+                    if e1.flags().contains(PageTableFlags::PRESENT) {
+                        let page = Page::from_page_table_indices(
+                            index(i4),
+                            index(i3),
+                            index(i2),
+                            index(i1),
+                        );
+                        f(page, e1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Walks the page tables to the level 1 entry of `addr`, if all tables on the way exist.
+unsafe fn leaf_entry(
+    level_4_frame: PhysFrame,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry> {
+    let mut frame = level_4_frame;
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        frame = table(frame)[index].frame().ok()?;
+    }
+    Some(&mut table(frame)[addr.p1_index()])
+}
+
+/// Handles a write to a copy-on-write page in the active address space by giving the
+/// page a private, writable frame. Returns `false` if `addr` is not on such a page, or
+/// if there is no memory left for the copy.
+pub fn resolve_copy_on_write(addr: VirtAddr) -> bool {
+    if !is_user_range(addr, 1) {
+        return false;
+    }
+    let entry = match unsafe { leaf_entry(Cr3::read().0, addr) } {
+        Some(entry)
+            if entry
+                .flags()
+                .contains(PageTableFlags::PRESENT | COPY_ON_WRITE) =>
+        {
+            entry
+        }
+        _ => return false,
+    };
+    let old = entry.frame().expect("copy-on-write page without a frame");
+
+    let frame = with_memory(|memory| {
+        // The other address spaces already copied the page or went away.
+        if memory.frame_allocator.ref_count(old) == 1 {
+            return Some(old);
+        }
+        let frame = memory.frame_allocator.allocate_frame()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(old.start_address()).as_ptr::<u8>(),
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                frame.size() as usize,
+            );
+        }
+        memory.frame_allocator.release(old);
+        Some(frame)
+    });
+
+    match frame {
+        Some(frame) => {
+            let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+            entry.set_addr(frame.start_address(), flags);
+            tlb::flush(addr);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns whether user mode may access `[start, start + size)` in the active address
+/// space: the range has to lie in user space and be mapped `USER_ACCESSIBLE`, and also
+/// `WRITABLE` (or `COPY_ON_WRITE`) if `write` is set.
+pub fn check_user_access(start: VirtAddr, size: u64, write: bool) -> bool {
+    if size == 0 {
+        return true;
+    }
+    if !is_user_range(start, size) {
+        return false;
+    }
+
+    let mapper = unsafe { super::init_offset_page_table(phys_to_virt(PhysAddr::new(0))) };
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+
+    Page::range_inclusive(first, last).all(|page| match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { flags, .. } => {
+            flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                && (!write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE))
+        }
+        _ => false,
+    })
+}
+
+/// Switches back to the kernel's own page table.
+pub fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+}
+
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Fills the given frame with zeros through the physical memory mapping.
+pub unsafe fn zero_frame(frame: PhysFrame) {
+    let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    core::ptr::write_bytes(ptr, 0, frame.size() as usize);
+}
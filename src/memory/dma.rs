@@ -0,0 +1,83 @@
+// Memory that devices read and write themselves (DMA).
+//
+// Devices see physical addresses only, so a buffer handed to them has to be physically
+// contiguous, unlike heap memory. A `DmaBuffer` takes whole frames straight from the
+// physical allocator and is accessed by the kernel through the physical memory mapping.
+// x86 keeps caches coherent with DMA, so the normal cached mapping is fine.
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{phys_to_virt, with_memory};
+
+const FRAME_SIZE: usize = 4096;
+
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates at least `size` bytes of zeroed, page aligned and physically contiguous
+    /// memory. Returns `None` if there is no run of free frames long enough.
+    pub fn new(size: usize) -> Option<DmaBuffer> {
+        let frames = size.max(1).div_ceil(FRAME_SIZE);
+        let start = with_memory(|memory| memory.frame_allocator.allocate_contiguous(frames))?;
+        let buffer = DmaBuffer { start, frames };
+        unsafe { core::ptr::write_bytes(buffer.virt().as_mut_ptr::<u8>(), 0, buffer.size()) };
+        Some(buffer)
+    }
+
+    /// The address to give to the device.
+    pub fn phys(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// The address the kernel accesses the memory at.
+    pub fn virt(&self) -> VirtAddr {
+        phys_to_virt(self.phys())
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames * FRAME_SIZE
+    }
+
+    /// A pointer to the `T` at `offset`, for structures shared with the device. Access
+    /// has to be volatile, the device may change them at any time.
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size(),
+            "DMA buffer access out of range"
+        );
+        (self.virt() + offset as u64).as_mut_ptr()
+    }
+
+    /// Copies `data` into the buffer at `offset`.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        assert!(
+            offset + data.len() <= self.size(),
+            "DMA buffer access out of range"
+        );
+        let target = (self.virt() + offset as u64).as_mut_ptr();
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), target, data.len()) };
+    }
+
+    /// Copies from the buffer at `offset` into `data`.
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        assert!(
+            offset + data.len() <= self.size(),
+            "DMA buffer access out of range"
+        );
+        let source = (self.virt() + offset as u64).as_ptr();
+        unsafe { core::ptr::copy_nonoverlapping(source, data.as_mut_ptr(), data.len()) };
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_memory(|memory| {
+            for index in 0..self.frames as u64 {
+                memory.frame_allocator.release(self.start + index);
+            }
+        });
+    }
+}
@@ -0,0 +1,167 @@
+// The physical memory manager.
+//
+// Every physical frame below the end of the highest usable region gets a 16 bit
+// reference count. Free frames have a count of 0, frames the bootloader did not report
+// as usable are pinned at `RESERVED` and never handed out. Frames shared between
+// address spaces, e.g. after a copy-on-write fork, are freed once the last mapping
+// releases them.
+// Reference: https://wiki.osdev.org/Page_Frame_Allocation
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = 4096;
+const RESERVED: u16 = u16::MAX;
+
+pub struct PhysicalMemoryManager {
+    /// Indexed by frame number.
+    ref_counts: &'static mut [u16],
+    /// Where the search for a free frame continues.
+    next: usize,
+    free: usize,
+    usable: usize,
+}
+
+impl PhysicalMemoryManager {
+    /// Builds the reference count table from the bootloader's memory map. The table
+    /// itself is placed in the first usable region large enough to hold it.
+    ///
+    /// The physical memory offset has to be set already, the table is accessed through
+    /// the physical memory mapping.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let frame_count = usable()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let table_size = (frame_count * 2) as u64;
+        let table_frames = (table_size + FRAME_SIZE - 1) / FRAME_SIZE;
+        let table_region = usable()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= table_frames)
+            .expect("no usable memory region can hold the frame table");
+        let table_start = table_region.range.start_frame_number;
+
+        let ptr = phys_to_virt(PhysAddr::new(table_start * FRAME_SIZE)).as_mut_ptr::<u16>();
+        let ref_counts = core::slice::from_raw_parts_mut(ptr, frame_count);
+        ref_counts.fill(RESERVED);
+
+        let mut manager = PhysicalMemoryManager {
+            ref_counts,
+            next: 0,
+            free: 0,
+            usable: 0,
+        };
+        for region in usable() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                // Frame 0 stays reserved so a null physical address is never valid.
+                let in_table = (table_start..table_start + table_frames).contains(&frame);
+                if frame != 0 && !in_table {
+                    manager.ref_counts[frame as usize] = 0;
+                    manager.free += 1;
+                }
+            }
+        }
+        manager.usable = manager.free;
+        manager
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Number of references to `frame`, 0 if it is free.
+    pub fn ref_count(&self, frame: PhysFrame) -> u16 {
+        match self.ref_counts.get(Self::index(frame)) {
+            Some(&RESERVED) | None => 1,
+            Some(&count) => count,
+        }
+    }
+
+    /// Adds a reference to an allocated frame. Fails if the frame has as many references
+    /// as the count can hold, the next one would turn it into `RESERVED`.
+    #[must_use]
+    pub fn retain(&mut self, frame: PhysFrame) -> bool {
+        if let Some(count) = self.ref_counts.get_mut(Self::index(frame)) {
+            assert!(*count != 0, "retaining free frame {:?}", frame);
+            if *count == RESERVED - 1 {
+                return false;
+            }
+            if *count != RESERVED {
+                *count += 1;
+            }
+        }
+        true
+    }
+
+    /// Drops a reference to `frame` and frees it when it was the last one. Returns
+    /// whether the frame was freed.
+    pub fn release(&mut self, frame: PhysFrame) -> bool {
+        let count = match self.ref_counts.get_mut(Self::index(frame)) {
+            Some(count) if *count != RESERVED => count,
+            // Memory the bootloader reserved, e.g. the VGA buffer, is never freed.
+            _ => return false,
+        };
+        assert!(*count != 0, "double free of {:?}", frame);
+        *count -= 1;
+        if *count == 0 {
+            self.free += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first, for devices
+    /// that access memory directly and do not go through the page tables.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut run = 0;
+        let end = (0..self.ref_counts.len()).find(|&index| {
+            run = if self.ref_counts[index] == 0 {
+                run + 1
+            } else {
+                0
+            };
+            run == count
+        })?;
+        let start = end + 1 - count;
+        self.ref_counts[start..=end].fill(1);
+        self.free -= count;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            start as u64 * FRAME_SIZE,
+        )))
+    }
+
+    /// Free and usable frames.
+    pub fn stats(&self) -> (usize, usize) {
+        (self.free, self.usable)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for PhysicalMemoryManager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let len = self.ref_counts.len();
+        let index = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&i| self.ref_counts[i] == 0)?;
+
+        self.ref_counts[index] = 1;
+        self.next = (index + 1) % len;
+        self.free -= 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * FRAME_SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PhysicalMemoryManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.release(frame);
+    }
+}
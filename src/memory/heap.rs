@@ -0,0 +1,262 @@
+// The kernel heap backing the `alloc` crate.
+//
+// A first-fit allocator over a free list that is kept sorted by address, so freed blocks
+// can be merged with their neighbours again. The heap starts small and grows by mapping
+// more pages at its end when an allocation does not fit, as long as enough physical
+// memory is left for everything else.
+// Reference: https://os.phil-opp.com/allocator-designs/#linked-list-allocator
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ptr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::mmio::MMIO_REGION_START;
+use super::with_memory;
+use crate::interrupts::interrupts::without_interrupts;
+use crate::locks::mutex::Mutex;
+
+// Lower half of P4 entry 509, which the bootloader leaves unused.
+pub const HEAP_START: u64 = 0xffff_fe80_0000_0000;
+const HEAP_END: u64 = MMIO_REGION_START;
+const INITIAL_HEAP_SIZE: u64 = 2 * 1024 * 1024;
+/// The heap grows by at least this much at once.
+const MIN_GROWTH: u64 = 256 * 1024;
+/// Frames the heap leaves to page tables, stacks and user processes when it grows.
+const MIN_FREE_FRAMES: usize = 1024;
+const PAGE_SIZE: u64 = 4096;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+
+pub struct LinkedListAllocator {
+    head: *mut FreeBlock,
+    used: usize,
+    size: usize,
+}
+
+unsafe impl Send for LinkedListAllocator {}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Every block has to be able to hold a `FreeBlock` once it is freed again.
+fn adjust(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(align_of::<FreeBlock>());
+    let size = align_up(layout.size().max(MIN_BLOCK_SIZE), align_of::<FreeBlock>());
+    (size, align)
+}
+
+impl LinkedListAllocator {
+    pub const fn empty() -> Self {
+        LinkedListAllocator {
+            head: ptr::null_mut(),
+            used: 0,
+            size: 0,
+        }
+    }
+
+    /// Hands the memory `[start, start + size)` to the allocator.
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        self.size = size;
+        self.insert(start, size);
+    }
+
+    /// Hands the memory `[start, start + size)` right after the managed memory to the
+    /// allocator as well.
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        self.size += size;
+        self.insert(start, size);
+    }
+
+    /// Bytes currently handed out and total bytes managed.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.used, self.size)
+    }
+
+    // Inserts a free block, merging it with adjacent free blocks.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = adjust(layout);
+
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        while !(*prev).is_null() {
+            let block = *prev;
+            let start = block as usize;
+            let end = start + (*block).size;
+
+            // A gap in front of the allocation must be big enough to stay a free block.
+            let mut alloc_start = align_up(start, align);
+            if alloc_start != start && alloc_start - start < MIN_BLOCK_SIZE {
+                alloc_start = align_up(start + MIN_BLOCK_SIZE, align);
+            }
+            let alloc_end = alloc_start + size;
+            let back = end.saturating_sub(alloc_end);
+
+            if alloc_end <= end && (back == 0 || back >= MIN_BLOCK_SIZE) {
+                *prev = (*block).next;
+                if back > 0 {
+                    self.insert(alloc_end, back);
+                }
+                if alloc_start > start {
+                    self.insert(start, alloc_start - start);
+                }
+                self.used += size;
+                return alloc_start as *mut u8;
+            }
+            prev = &mut (*block).next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = adjust(layout);
+        self.used -= size;
+        self.insert(ptr as usize, size);
+    }
+}
+
+pub struct Heap(Mutex<LinkedListAllocator>);
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut allocator = self.0.lock();
+            let ptr = allocator.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            // Enough for the block wherever its alignment puts it.
+            let (size, align) = adjust(layout);
+            let end = HEAP_START + allocator.size as u64;
+            let growth = align_up(size + align, PAGE_SIZE as usize) as u64;
+            drop(allocator);
+            match grow(end, growth.max(MIN_GROWTH)) {
+                Some(added) => {
+                    let mut allocator = self.0.lock();
+                    allocator.extend(end as usize, added as usize);
+                    allocator.allocate(layout)
+                }
+                None => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.lock().deallocate(ptr, layout))
+    }
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap(Mutex::new(LinkedListAllocator::empty()));
+
+/// Bytes of the heap in use and its total size.
+pub fn usage() -> (usize, usize) {
+    without_interrupts(|| HEAP.0.lock().usage())
+}
+
+/// Maps the initial heap and hands it to the allocator.
+pub fn init() {
+    let first = Page::containing_address(VirtAddr::new(HEAP_START));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    with_memory(|memory| {
+        for page in Page::range(first, first + INITIAL_HEAP_SIZE / PAGE_SIZE) {
+            let frame = memory
+                .frame_allocator
+                .allocate_frame()
+                .expect("out of memory while mapping the kernel heap");
+            unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+                    .expect("failed to map the kernel heap")
+                    .flush();
+            }
+        }
+    });
+
+    without_interrupts(|| unsafe {
+        HEAP.0
+            .lock()
+            .init(HEAP_START as usize, INITIAL_HEAP_SIZE as usize)
+    });
+}
+
+// Maps `size` more bytes at `end`, the current end of the heap, and returns how many it
+// mapped. Allocations fail instead if that would leave too little physical memory.
+fn grow(end: u64, size: u64) -> Option<u64> {
+    if size > HEAP_END - end {
+        return None;
+    }
+    // Code that allocates while it has the memory manager locked gets no more heap, the
+    // lock cannot be taken twice.
+    let mut guard = super::MEMORY.try_lock()?;
+    let memory = guard.as_mut()?;
+    let pages = size / PAGE_SIZE;
+    // The page tables for the new pages come out of the reserve as well.
+    if memory.frame_allocator.stats().0 < pages as usize + MIN_FREE_FRAMES {
+        return None;
+    }
+
+    let first = Page::containing_address(VirtAddr::new(end));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut mapped = 0;
+    for page in Page::range(first, first + pages) {
+        let Some(frame) = memory.frame_allocator.allocate_frame() else {
+            break;
+        };
+        match unsafe {
+            memory
+                .mapper
+                .map_to(page, frame, flags, &mut memory.frame_allocator)
+        } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                memory.frame_allocator.release(frame);
+                break;
+            }
+        }
+        mapped += 1;
+    }
+    // Whatever got mapped belongs to the heap now, even if it is not enough.
+    (mapped > 0).then_some(mapped * PAGE_SIZE)
+}
+
+#[test_case]
+fn test_heap_grows() {
+    let size = 2 * INITIAL_HEAP_SIZE as usize;
+    let block = alloc::vec![0xa5u8; size];
+    assert!(block.iter().all(|&byte| byte == 0xa5));
+    assert!(usage().1 >= size);
+}
@@ -0,0 +1,49 @@
+// Mappings of device memory, e.g. PCI configuration space and BARs.
+//
+// Device registers are mapped uncached into a region of their own. The region lives in
+// the same P4 entry as the heap, which every address space shares from the start, so
+// mappings added later are visible everywhere. Mappings are never removed.
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::with_memory;
+
+// Upper half of P4 entry 509, above the heap.
+pub const MMIO_REGION_START: u64 = 0xffff_fec0_0000_0000;
+const MMIO_REGION_END: u64 = 0xffff_ff00_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_REGION_START);
+
+/// Maps `size` bytes of device memory at `phys` and returns the virtual address of
+/// `phys`. Returns `None` if the region is exhausted or the page tables cannot be
+/// allocated.
+pub fn map(phys: PhysAddr, size: u64) -> Option<VirtAddr> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end = phys.as_u64().checked_add(size.max(1))?;
+    let pages = (end - first.start_address().as_u64() + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let start = NEXT_MMIO.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed);
+    if start + pages * PAGE_SIZE > MMIO_REGION_END {
+        return None;
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let mapped = with_memory(|memory| {
+        (0..pages).all(|i| unsafe {
+            memory
+                .mapper
+                .map_to(page + i, first + i, flags, &mut memory.frame_allocator)
+                .map(|flush| flush.flush())
+                .is_ok()
+        })
+    });
+    mapped.then(|| VirtAddr::new(start) + (phys - first.start_address()))
+}
@@ -1,3 +1,13 @@
+pub mod address_space;
+pub mod dma;
+pub mod frame;
+pub mod heap;
+pub mod mmio;
+pub mod stack;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::{
     structures::paging::{
         FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
@@ -5,38 +15,78 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
 
-// Initialize a new OffsetPageTable
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+use crate::interrupts::interrupts::without_interrupts;
+use crate::locks::mutex::Mutex;
+use crate::println;
+use frame::PhysicalMemoryManager;
+
+// The kernel's view of memory: the active page table and the physical frame allocator.
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: PhysicalMemoryManager,
 }
 
-// A FrameAllocator that returns usuable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+static MEMORY: Mutex<Option<MemoryManager>> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// Sets up the global memory manager from the information passed by the bootloader.
+// Has to run before anything that allocates memory, e.g. the GDT's interrupt stacks.
+pub fn init(boot_info: &'static BootInfo) {
+    println!("[!] Initializing memory");
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+
+    // Allow pages to be marked non-executable.
+    unsafe { Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE) };
+    // Make read-only pages read-only for the kernel too, so its writes to copy-on-write
+    // user pages fault like the user's own.
+    unsafe { Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT) };
+
+    let mapper = unsafe { init_offset_page_table(physical_memory_offset) };
+    let frame_allocator = unsafe { PhysicalMemoryManager::init(&boot_info.memory_map) };
+    *MEMORY.lock() = Some(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
+
+    println!("    [+] Mapping kernel heap");
+    heap::init();
+    println!("    [+] Done");
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-    // Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+// Runs `f` with exclusive access to the memory manager.
+pub fn with_memory<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        f(memory.as_mut().expect("memory manager is not initialized"))
+    })
+}
 
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+// Returns the virtual address at which the bootloader mapped the given physical address.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
 
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
+// Returns the frame of the kernel's level 4 page table, the one active at boot.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+// Initialize a new OffsetPageTable
+pub unsafe fn init_offset_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
 // Creates an example mapping for the given page to frame `0xb8000`.
@@ -58,14 +108,6 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
 // Returns a mutable reference to the active level 4 table.
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -0,0 +1,157 @@
+// Kernel stacks protected by guard pages.
+//
+// Stacks are carved out of a dedicated virtual region. Below every stack lies one page
+// that is left unmapped, so running off the end of a stack raises a page fault instead
+// of silently corrupting whatever is stored next to it. Every stack is registered with
+// a name, which lets the fault handlers tell which stack overflowed.
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::with_memory;
+use crate::locks::mutex::Mutex;
+
+// P4 entry 510, which the bootloader leaves unused.
+const STACK_REGION_START: u64 = 0xffff_ff00_0000_0000;
+const STACK_REGION_END: u64 = 0xffff_ff80_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_STACKS: usize = 64;
+
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_REGION_START);
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    name: &'static str,
+    guard: Page,
+    top: VirtAddr,
+}
+
+impl Stack {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The initial stack pointer, stacks grow down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// The lowest usable address of the stack, right above the guard page.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard.start_address() + PAGE_SIZE
+    }
+
+    fn is_guard_page(&self, addr: VirtAddr) -> bool {
+        Page::containing_address(addr) == self.guard
+    }
+}
+
+/// Allocates a kernel stack of `pages` mapped pages with an unmapped guard page beneath.
+/// Returns `None` when memory, the stack region or the registry runs out.
+pub fn allocate(name: &'static str, pages: u64) -> Option<Stack> {
+    let size = (pages + 1) * PAGE_SIZE;
+    let start = NEXT_STACK.fetch_add(size, Ordering::Relaxed);
+    if start + size > STACK_REGION_END {
+        return None;
+    }
+
+    let stack = Stack {
+        name,
+        guard: Page::containing_address(VirtAddr::new(start)),
+        top: VirtAddr::new(start + size),
+    };
+    register(stack)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mapped = with_memory(|memory| {
+        (1..=pages).all(|i| {
+            let frame = match memory.frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let result = unsafe {
+                memory
+                    .mapper
+                    .map_to(stack.guard + i, frame, flags, &mut memory.frame_allocator)
+            };
+            match result {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => {
+                    memory.frame_allocator.release(frame);
+                    false
+                }
+            }
+        })
+    });
+    if !mapped {
+        // Nothing runs on the stack yet, and `free` skips the pages that are not mapped.
+        unsafe { free(stack) };
+        return None;
+    }
+    Some(stack)
+}
+
+/// Unmaps a stack allocated with `allocate` and frees its frames. Its virtual range is
+/// not reused.
+///
+/// The caller has to make sure nothing runs on the stack anymore.
+pub unsafe fn free(stack: Stack) {
+    let pages = (stack.top - stack.bottom()) / PAGE_SIZE;
+    with_memory(|memory| {
+        for i in 1..=pages {
+            if let Ok((frame, flush)) = memory.mapper.unmap(stack.guard + i) {
+                flush.flush();
+                memory.frame_allocator.release(frame);
+            }
+        }
+    });
+
+    let mut stacks = STACKS.lock();
+    if let Some(slot) = stacks
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |s| s.guard == stack.guard))
+    {
+        *slot = None;
+    }
+}
+
+fn register(stack: Stack) -> Option<()> {
+    let mut stacks = STACKS.lock();
+    let slot = stacks.iter_mut().find(|slot| slot.is_none())?;
+    *slot = Some(stack);
+    Some(())
+}
+
+/// Returns the name of the stack whose guard page contains `addr`.
+///
+/// Called from fault handlers, so it gives up instead of spinning if the registry is
+/// locked by the code that faulted.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .find(|stack| stack.is_guard_page(addr))
+        .map(|stack| stack.name())
+}
+
+/// Like `guard_page_owner`, but only if `stack_pointer` is at that guard page too, in it
+/// or in the page right above it. CR2 keeps the address of the last page fault, so a
+/// guard page address in it alone does not mean the stack just overflowed.
+pub fn overflowed_stack(addr: VirtAddr, stack_pointer: VirtAddr) -> Option<&'static str> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .filter(|stack| stack.is_guard_page(addr))
+        .find(|stack| {
+            stack_pointer >= stack.guard.start_address()
+                && stack_pointer < stack.bottom() + PAGE_SIZE
+        })
+        .map(|stack| stack.name())
+}
@@ -0,0 +1,186 @@
+// The Address Resolution Protocol, which finds the MAC address of a neighbour with a
+// given IPv4 address.
+//
+// Packets to a neighbour whose MAC address is not known yet wait in its cache entry
+// while a request is out. The request is repeated every second, and after a few
+// unanswered ones the entry and its packets are dropped. Answers are remembered for a
+// few minutes. We also learn the address of everybody who asks for ours, as they are
+// about to talk to us.
+// Reference: https://www.rfc-editor.org/rfc/rfc826
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+use super::ethernet::{TYPE_ARP, TYPE_IPV4};
+use super::interface::{self, Interface};
+use super::ipv4::Ipv4Address;
+use super::{MacAddress, NetError};
+use crate::locks::mutex::Mutex;
+use crate::time::{self, TICKS_PER_SECOND};
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+/// How long an answer is remembered, in ticks.
+const CACHE_TIMEOUT: u64 = 300 * TICKS_PER_SECOND;
+const RETRY_INTERVAL: u64 = TICKS_PER_SECOND;
+const MAX_REQUESTS: u32 = 3;
+/// Packets waiting for one neighbour, at most.
+const MAX_PENDING: usize = 16;
+
+struct Entry {
+    interface: String,
+    /// `None` while the request is out.
+    mac: Option<MacAddress>,
+    /// When the entry expires or the request is repeated, in ticks.
+    deadline: u64,
+    requests: u32,
+    pending: Vec<Vec<u8>>,
+}
+
+static CACHE: Mutex<BTreeMap<Ipv4Address, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Sends an IPv4 packet to the neighbour `next_hop`, once its MAC address is known.
+pub fn send(interface: &Interface, next_hop: Ipv4Address, packet: Vec<u8>) -> Result<(), NetError> {
+    if next_hop == Ipv4Address::BROADCAST || next_hop == interface.broadcast() {
+        return interface.send(MacAddress::BROADCAST, TYPE_IPV4, &packet);
+    }
+    // Devices without a MAC address, like the loopback device, have nothing to resolve.
+    if interface.device.mac_address() == MacAddress::ZERO {
+        return interface.send(MacAddress::ZERO, TYPE_IPV4, &packet);
+    }
+
+    let mut cache = CACHE.lock();
+    match cache.get_mut(&next_hop) {
+        Some(Entry { mac: Some(mac), .. }) => {
+            let mac = *mac;
+            drop(cache);
+            interface.send(mac, TYPE_IPV4, &packet)
+        }
+        Some(entry) => {
+            if entry.pending.len() < MAX_PENDING {
+                entry.pending.push(packet);
+            }
+            Ok(())
+        }
+        None => {
+            let entry = Entry {
+                interface: interface.name().into(),
+                mac: None,
+                deadline: time::ticks() + RETRY_INTERVAL,
+                requests: 1,
+                pending: alloc::vec![packet],
+            };
+            cache.insert(next_hop, entry);
+            drop(cache);
+            request(interface, next_hop)
+        }
+    }
+}
+
+fn packet(
+    operation: u16,
+    interface: &Interface,
+    target_mac: MacAddress,
+    target: Ipv4Address,
+) -> [u8; PACKET_SIZE] {
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&TYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&interface.device.mac_address().0);
+    packet[14..18].copy_from_slice(&interface.address.0);
+    packet[18..24].copy_from_slice(&target_mac.0);
+    packet[24..28].copy_from_slice(&target.0);
+    packet
+}
+
+fn request(interface: &Interface, target: Ipv4Address) -> Result<(), NetError> {
+    let request = packet(OPERATION_REQUEST, interface, MacAddress::ZERO, target);
+    interface.send(MacAddress::BROADCAST, TYPE_ARP, &request)
+}
+
+/// Handles an ARP packet received on `interface`.
+pub fn handle(interface: &Interface, data: &[u8]) {
+    if data.len() < PACKET_SIZE || !interface.is_configured() {
+        return;
+    }
+    let field = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+    if field(0) != HARDWARE_ETHERNET || field(2) != TYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+        return;
+    }
+    let operation = field(6);
+    let sender_mac = MacAddress(data[8..14].try_into().unwrap());
+    let sender = Ipv4Address(data[14..18].try_into().unwrap());
+    let target = Ipv4Address(data[24..28].try_into().unwrap());
+    let for_us = target == interface.address;
+
+    let mut cache = CACHE.lock();
+    let deadline = time::ticks() + CACHE_TIMEOUT;
+    let pending = match cache.get_mut(&sender) {
+        Some(entry) => {
+            entry.mac = Some(sender_mac);
+            entry.deadline = deadline;
+            entry.requests = 0;
+            mem::take(&mut entry.pending)
+        }
+        None if for_us => {
+            let entry = Entry {
+                interface: interface.name().into(),
+                mac: Some(sender_mac),
+                deadline,
+                requests: 0,
+                pending: Vec::new(),
+            };
+            cache.insert(sender, entry);
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    drop(cache);
+
+    for packet in pending {
+        let _ = interface.send(sender_mac, TYPE_IPV4, &packet);
+    }
+    if for_us && operation == OPERATION_REQUEST {
+        let reply = packet(OPERATION_REPLY, interface, sender_mac, sender);
+        let _ = interface.send(sender_mac, TYPE_ARP, &reply);
+    }
+}
+
+/// The neighbours whose MAC address is known, with the interface they are on.
+pub fn entries() -> Vec<(Ipv4Address, MacAddress, String)> {
+    let cache = CACHE.lock();
+    let known = cache
+        .iter()
+        .filter_map(|(address, entry)| Some((*address, entry.mac?, entry.interface.clone())));
+    known.collect()
+}
+
+/// Repeats unanswered requests and forgets old answers.
+pub fn poll_timers() {
+    let now = time::ticks();
+    let mut requests = Vec::new();
+    CACHE.lock().retain(|address, entry| {
+        if now < entry.deadline {
+            return true;
+        }
+        if entry.mac.is_some() || entry.requests >= MAX_REQUESTS {
+            return false;
+        }
+        entry.requests += 1;
+        entry.deadline = now + RETRY_INTERVAL;
+        requests.push((entry.interface.clone(), *address));
+        true
+    });
+    for (name, address) in requests {
+        if let Some(interface) = interface::get(&name) {
+            let _ = request(&interface, address);
+        }
+    }
+}
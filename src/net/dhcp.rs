@@ -0,0 +1,547 @@
+// The Dynamic Host Configuration Protocol client, which gets the address of an
+// interface, the size of its network and the gateway from a DHCP server, e.g. the one
+// built into QEMU's user mode network.
+//
+// The client broadcasts a DISCOVER, takes the first OFFER and REQUESTs it from the
+// server that made it. The server's ACK starts the lease. Halfway through the lease
+// the client asks that server to renew it, after seven eighths any server. A NAK or a
+// lease that runs out takes the address away and the client starts over. Messages
+// without an answer are repeated with growing intervals.
+// Reference: https://www.rfc-editor.org/rfc/rfc2131
+// Reference: https://www.rfc-editor.org/rfc/rfc2132
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::interface::{self, Interface};
+use super::ipv4::{self, Ipv4Address, Route};
+use super::{udp, MacAddress, NetError};
+use crate::locks::mutex::Mutex;
+use crate::println;
+use crate::time::{self, TICKS_PER_SECOND};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OPERATION_REQUEST: u8 = 1;
+const OPERATION_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+/// Messages are at least as large as a BOOTP message.
+const MIN_MESSAGE_SIZE: usize = 300;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+// Intervals in ticks.
+const INITIAL_RETRY: u64 = 4 * TICKS_PER_SECOND;
+const MAX_RETRY: u64 = 64 * TICKS_PER_SECOND;
+const MIN_RENEW_RETRY: u64 = 60 * TICKS_PER_SECOND;
+/// REQUESTs for an offer before the client starts over.
+const MAX_REQUESTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            State::Selecting => "selecting",
+            State::Requesting => "requesting",
+            State::Bound => "bound",
+            State::Renewing => "renewing",
+            State::Rebinding => "rebinding",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Address>,
+    pub dns: Option<Ipv4Address>,
+    pub server: Ipv4Address,
+    /// Length of the lease in seconds.
+    pub duration: u32,
+    renewal: u32,
+    rebinding: u32,
+    /// When the lease runs out, in ticks.
+    pub expires: u64,
+}
+
+struct Message {
+    kind: u8,
+    xid: u32,
+    client: MacAddress,
+    your_address: Ipv4Address,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns: Option<Ipv4Address>,
+    server: Option<Ipv4Address>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Message> {
+        if data.len() < OPTIONS_OFFSET
+            || data[0] != OPERATION_REPLY
+            || data[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut message = Message {
+            kind: 0,
+            xid: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            client: MacAddress(data[28..34].try_into().ok()?),
+            your_address: Ipv4Address(data[16..20].try_into().ok()?),
+            subnet_mask: None,
+            router: None,
+            dns: None,
+            server: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        };
+
+        let mut options = &data[OPTIONS_OFFSET..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                OPTION_END => break,
+                OPTION_PAD => options = rest,
+                _ => {
+                    let length = *rest.first()? as usize;
+                    let value = rest.get(1..1 + length)?;
+                    let address = || Some(Ipv4Address(value.get(..4)?.try_into().ok()?));
+                    let seconds = || Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?));
+                    match *code {
+                        OPTION_MESSAGE_TYPE => message.kind = *value.first()?,
+                        OPTION_SUBNET_MASK => message.subnet_mask = address(),
+                        OPTION_ROUTER => message.router = address(),
+                        OPTION_DNS => message.dns = address(),
+                        OPTION_SERVER => message.server = address(),
+                        OPTION_LEASE_TIME => message.lease_time = seconds(),
+                        OPTION_RENEWAL_TIME => message.renewal_time = seconds(),
+                        OPTION_REBINDING_TIME => message.rebinding_time = seconds(),
+                        _ => {}
+                    }
+                    options = &rest[1 + length..];
+                }
+            }
+        }
+        Some(message)
+    }
+
+    // The lease a server offers or grants, if the message describes one.
+    fn lease(&self, source: Ipv4Address) -> Option<Lease> {
+        let duration = self.lease_time?;
+        let prefix_len = match self.subnet_mask {
+            Some(mask) => mask.prefix_len()?,
+            None => 24,
+        };
+        Some(Lease {
+            address: self.your_address,
+            prefix_len,
+            gateway: self.router,
+            dns: self.dns,
+            server: self.server.unwrap_or(source),
+            duration,
+            renewal: self.renewal_time.unwrap_or(duration / 2),
+            rebinding: self
+                .rebinding_time
+                .unwrap_or((duration as u64 * 7 / 8) as u32),
+            expires: 0,
+        })
+    }
+}
+
+struct Client {
+    interface: String,
+    mac: MacAddress,
+    xid: u32,
+    state: State,
+    /// The offer being requested, or the lease being renewed.
+    lease: Option<Lease>,
+    /// When the current message is sent again, in ticks.
+    retry_at: u64,
+    interval: u64,
+    requests: u32,
+    renew_at: u64,
+    rebind_at: u64,
+}
+
+impl Client {
+    fn new(interface: &Interface) -> Client {
+        let mut client = Client {
+            interface: interface.name().into(),
+            mac: interface.device.mac_address(),
+            xid: 0,
+            state: State::Selecting,
+            lease: None,
+            retry_at: 0,
+            interval: 0,
+            requests: 0,
+            renew_at: 0,
+            rebind_at: 0,
+        };
+        client.discover();
+        client
+    }
+
+    fn message(&self, kind: u8, client_address: Ipv4Address, options: &[u8]) -> Vec<u8> {
+        let mut message = alloc::vec![0; OPTIONS_OFFSET];
+        message[0] = OPERATION_REQUEST;
+        message[1] = HARDWARE_ETHERNET;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        // Without an address we cannot receive unicast replies.
+        if client_address == Ipv4Address::UNSPECIFIED {
+            message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        }
+        message[12..16].copy_from_slice(&client_address.0);
+        message[28..34].copy_from_slice(&self.mac.0);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+        message.extend_from_slice(options);
+        message.extend_from_slice(&[
+            OPTION_PARAMETERS,
+            5,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS,
+            OPTION_RENEWAL_TIME,
+            OPTION_REBINDING_TIME,
+            OPTION_END,
+        ]);
+        message.resize(message.len().max(MIN_MESSAGE_SIZE), OPTION_PAD);
+        message
+    }
+
+    // Broadcasts a message on the interface.
+    fn broadcast(&self, message: &[u8]) {
+        if let Some(interface) = interface::get(&self.interface) {
+            let _ = udp::send_via(
+                &interface,
+                CLIENT_PORT,
+                Ipv4Address::BROADCAST,
+                SERVER_PORT,
+                message,
+            );
+        }
+    }
+
+    fn send_discover(&self) {
+        self.broadcast(&self.message(DISCOVER, Ipv4Address::UNSPECIFIED, &[]));
+    }
+
+    // Asks for the offer while selecting, or for a longer lease.
+    fn send_request(&self) {
+        let Some(lease) = self.lease else {
+            return;
+        };
+        match self.state {
+            State::Requesting => {
+                let mut options = Vec::new();
+                options.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+                options.extend_from_slice(&lease.address.0);
+                options.extend_from_slice(&[OPTION_SERVER, 4]);
+                options.extend_from_slice(&lease.server.0);
+                self.broadcast(&self.message(REQUEST, Ipv4Address::UNSPECIFIED, &options));
+            }
+            State::Renewing => {
+                let message = self.message(REQUEST, lease.address, &[]);
+                let _ = udp::send(CLIENT_PORT, lease.server, SERVER_PORT, &message);
+            }
+            _ => self.broadcast(&self.message(REQUEST, lease.address, &[])),
+        }
+    }
+
+    fn discover(&mut self) {
+        self.xid = new_xid(self.mac);
+        self.state = State::Selecting;
+        self.lease = None;
+        self.interval = INITIAL_RETRY;
+        self.retry_at = time::ticks() + self.interval;
+        self.send_discover();
+    }
+
+    fn request(&mut self, offer: Lease) {
+        self.state = State::Requesting;
+        self.lease = Some(offer);
+        self.requests = 1;
+        self.interval = INITIAL_RETRY;
+        self.retry_at = time::ticks() + self.interval;
+        self.send_request();
+    }
+
+    // Takes the address away and starts over.
+    fn restart(&mut self) {
+        if matches!(
+            self.state,
+            State::Bound | State::Renewing | State::Rebinding
+        ) {
+            let _ = interface::configure(&self.interface, Ipv4Address::UNSPECIFIED, 0);
+        }
+        self.discover();
+    }
+
+    fn bind(&mut self, lease: Lease) {
+        let now = time::ticks();
+        let seconds = |seconds: u32| now + seconds as u64 * TICKS_PER_SECOND;
+        let lease = Lease {
+            expires: seconds(lease.duration),
+            ..lease
+        };
+        let current = interface::get(&self.interface)
+            .map(|interface| (interface.address, interface.prefix_len));
+        if current != Some((lease.address, lease.prefix_len)) {
+            let _ = interface::configure(&self.interface, lease.address, lease.prefix_len);
+        }
+        if let Some(gateway) = lease.gateway {
+            ipv4::add_route(Route::default_via(gateway, &self.interface));
+        }
+        if self.state == State::Requesting {
+            println!(
+                "[+] {}: {}/{}, gateway {}, leased from {} for {} s",
+                self.interface,
+                lease.address,
+                lease.prefix_len,
+                lease.gateway.unwrap_or(Ipv4Address::UNSPECIFIED),
+                lease.server,
+                lease.duration
+            );
+        }
+        self.state = State::Bound;
+        self.lease = Some(lease);
+        self.renew_at = seconds(lease.renewal);
+        self.rebind_at = seconds(lease.rebinding);
+    }
+
+    fn handle(&mut self, message: &Message, source: Ipv4Address) {
+        match (self.state, message.kind) {
+            (State::Selecting, OFFER) => {
+                if let Some(offer) = message.lease(source) {
+                    self.request(offer);
+                }
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, ACK) => {
+                if let Some(lease) = message.lease(source) {
+                    self.bind(lease);
+                }
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, NAK) => {
+                println!("[-] {}: DHCP server refused the lease", self.interface);
+                self.restart();
+            }
+            _ => {}
+        }
+    }
+
+    // Sends messages again and moves on as the lease gets older.
+    fn poll_timers(&mut self, now: u64) {
+        let expires = self.lease.map_or(0, |lease| lease.expires);
+        match self.state {
+            State::Selecting | State::Requesting if now >= self.retry_at => {
+                if self.state == State::Requesting && self.requests >= MAX_REQUESTS {
+                    return self.discover();
+                }
+                self.requests += 1;
+                self.interval = (self.interval * 2).min(MAX_RETRY);
+                self.retry_at = now + self.interval;
+                match self.state {
+                    State::Selecting => self.send_discover(),
+                    _ => self.send_request(),
+                }
+            }
+            State::Bound if now >= self.renew_at => {
+                self.state = State::Renewing;
+                self.retry_at = now + renew_retry(now, self.rebind_at);
+                self.send_request();
+            }
+            State::Renewing if now >= self.rebind_at => {
+                self.state = State::Rebinding;
+                self.retry_at = now + renew_retry(now, expires);
+                self.send_request();
+            }
+            State::Rebinding if now >= expires => {
+                println!("[-] {}: DHCP lease expired", self.interface);
+                self.restart();
+            }
+            State::Renewing | State::Rebinding if now >= self.retry_at => {
+                let deadline = if self.state == State::Renewing {
+                    self.rebind_at
+                } else {
+                    expires
+                };
+                self.retry_at = now + renew_retry(now, deadline);
+                self.send_request();
+            }
+            _ => {}
+        }
+    }
+}
+
+// While renewing, requests are repeated after half the time left, but not more often
+// than every minute.
+fn renew_retry(now: u64, deadline: u64) -> u64 {
+    (deadline.saturating_sub(now) / 2).max(MIN_RENEW_RETRY)
+}
+
+// Transaction IDs match replies to requests, so they differ between clients and
+// between attempts.
+fn new_xid(mac: MacAddress) -> u32 {
+    let [_, _, a, b, c, d] = mac.0;
+    u32::from_be_bytes([a, b, c, d]) ^ (time::ticks() as u32).wrapping_mul(2_654_435_761)
+}
+
+static CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+
+/// Starts configuring an interface over DHCP, replacing a client it already has.
+pub fn start(name: &str) -> Result<(), NetError> {
+    let interface = interface::get(name).ok_or(NetError::NoDevice)?;
+    let mut clients = CLIENTS.lock();
+    if clients.is_empty() {
+        udp::bind(CLIENT_PORT)?;
+    }
+    clients.retain(|client| client.interface != name);
+    clients.push(Client::new(&interface));
+    Ok(())
+}
+
+/// Stops configuring an interface over DHCP and gives its lease back. The interface
+/// keeps its address.
+pub fn stop(name: &str) {
+    let mut clients = CLIENTS.lock();
+    let Some(index) = clients.iter().position(|client| client.interface == name) else {
+        return;
+    };
+    let client = clients.remove(index);
+    if let (State::Bound | State::Renewing | State::Rebinding, Some(lease)) =
+        (client.state, client.lease)
+    {
+        let mut options = Vec::new();
+        options.extend_from_slice(&[OPTION_SERVER, 4]);
+        options.extend_from_slice(&lease.server.0);
+        let message = client.message(RELEASE, lease.address, &options);
+        let _ = udp::send(CLIENT_PORT, lease.server, SERVER_PORT, &message);
+    }
+    if clients.is_empty() {
+        udp::unbind(CLIENT_PORT);
+    }
+}
+
+/// The state of the DHCP client of an interface and its lease, if it has one.
+pub fn status(name: &str) -> Option<(State, Option<Lease>)> {
+    let clients = CLIENTS.lock();
+    let client = clients.iter().find(|client| client.interface == name)?;
+    let lease = client
+        .lease
+        .filter(|_| client.state != State::Selecting && client.state != State::Requesting);
+    Some((client.state, lease))
+}
+
+/// Handles replies from servers and runs the timers of the clients.
+pub fn poll() {
+    let mut clients = CLIENTS.lock();
+    if clients.is_empty() {
+        return;
+    }
+    while let Some(datagram) = udp::receive(CLIENT_PORT) {
+        let Some(message) = Message::parse(&datagram.data) else {
+            continue;
+        };
+        let client = clients
+            .iter_mut()
+            .find(|client| client.xid == message.xid && client.mac == message.client);
+        if let Some(client) = client {
+            client.handle(&message, datagram.source);
+        }
+    }
+    let now = time::ticks();
+    for client in clients.iter_mut() {
+        client.poll_timers(now);
+    }
+}
+
+#[test_case]
+fn test_parse_ack() {
+    let client = Client {
+        interface: "eth0".into(),
+        mac: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+        xid: 0x1234_5678,
+        state: State::Requesting,
+        lease: None,
+        retry_at: 0,
+        interval: 0,
+        requests: 0,
+        renew_at: 0,
+        rebind_at: 0,
+    };
+    // A request turned into the reply a server would send.
+    let mut ack = client.message(ACK, Ipv4Address::UNSPECIFIED, &[]);
+    ack[0] = OPERATION_REPLY;
+    ack[16..20].copy_from_slice(&[10, 0, 2, 15]);
+    ack.truncate(OPTIONS_OFFSET + 3);
+    ack.extend_from_slice(&[
+        OPTION_SUBNET_MASK,
+        4,
+        255,
+        255,
+        255,
+        0,
+        OPTION_ROUTER,
+        4,
+        10,
+        0,
+        2,
+        2,
+    ]);
+    ack.extend_from_slice(&[OPTION_LEASE_TIME, 4, 0, 1, 0x51, 0x80, OPTION_END]);
+
+    let message = Message::parse(&ack).unwrap();
+    assert_eq!(
+        (message.kind, message.xid, message.client),
+        (ACK, 0x1234_5678, client.mac)
+    );
+    let lease = message.lease(Ipv4Address([10, 0, 2, 2])).unwrap();
+    assert_eq!(
+        (lease.address, lease.prefix_len),
+        (Ipv4Address([10, 0, 2, 15]), 24)
+    );
+    assert_eq!(
+        (lease.gateway, lease.server),
+        (Some(Ipv4Address([10, 0, 2, 2])), Ipv4Address([10, 0, 2, 2]))
+    );
+    assert_eq!(
+        (lease.duration, lease.renewal, lease.rebinding),
+        (86400, 43200, 75600)
+    );
+}
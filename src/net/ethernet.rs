@@ -0,0 +1,71 @@
+// Ethernet II framing.
+//
+// A frame starts with the destination and source MAC addresses and the type of its
+// payload. Frames shorter than the Ethernet minimum are padded with zeros, so the
+// payload of a received frame can be longer than the packet inside it.
+// Reference: https://en.wikipedia.org/wiki/Ethernet_frame
+use alloc::vec::Vec;
+
+use super::interface::Interface;
+use super::{arp, ipv4, MacAddress, MAX_FRAME_SIZE};
+
+pub const HEADER_SIZE: usize = 14;
+/// Smallest frame without the frame check sequence.
+pub const MIN_FRAME_SIZE: usize = 60;
+/// Largest payload of a frame.
+pub const MTU: usize = MAX_FRAME_SIZE - HEADER_SIZE;
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+pub struct Frame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse(frame: &[u8]) -> Option<Frame<'_>> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+    Some(Frame {
+        destination: MacAddress(frame[0..6].try_into().ok()?),
+        source: MacAddress(frame[6..12].try_into().ok()?),
+        ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        payload: &frame[HEADER_SIZE..],
+    })
+}
+
+pub fn build(
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((HEADER_SIZE + payload.len()).max(MIN_FRAME_SIZE));
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&source.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_FRAME_SIZE), 0);
+    frame
+}
+
+/// Passes a received frame on to the protocol it carries.
+pub fn handle(interface: &Interface, frame: &[u8]) {
+    let Some(frame) = parse(frame) else {
+        return;
+    };
+    // Multicast is not supported.
+    if frame.destination != interface.device.mac_address()
+        && frame.destination != MacAddress::BROADCAST
+    {
+        return;
+    }
+    match frame.ethertype {
+        TYPE_ARP => arp::handle(interface, frame.payload),
+        TYPE_IPV4 => ipv4::handle(interface, frame.payload),
+        _ => {}
+    }
+}
@@ -0,0 +1,138 @@
+// The Internet Control Message Protocol: echo requests and replies, i.e. ping, and
+// destination unreachable messages for datagrams nobody is waiting for.
+//
+// Echo requests are answered right away. An echo we sent is matched to its reply by
+// its identifier and sequence number.
+// Reference: https://www.rfc-editor.org/rfc/rfc792
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::ipv4::{self, checksum, Ipv4Address, Packet, PROTOCOL_ICMP};
+use super::NetError;
+use crate::locks::mutex::Mutex;
+use crate::time;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const HEADER_SIZE: usize = 8;
+/// Bytes of the offending datagram quoted after its header in error messages.
+const QUOTED_PAYLOAD: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EchoReply {
+    pub source: Ipv4Address,
+    pub ttl: u8,
+    /// Size of the ICMP message, header included.
+    pub size: usize,
+    /// Round-trip time in milliseconds, with the resolution of one tick.
+    pub time_ms: u64,
+}
+
+struct Echo {
+    identifier: u16,
+    sequence: u16,
+    sent_ms: u64,
+    reply: Option<EchoReply>,
+}
+
+static ECHOES: Mutex<Vec<Echo>> = Mutex::new(Vec::new());
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+fn message(kind: u8, code: u8, rest: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(payload);
+    let sum = checksum(&[&message]);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+/// An identifier for a series of echo requests, different from the last one's.
+pub fn next_identifier() -> u16 {
+    NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Sends an echo request with `size` bytes of data and waits up to `timeout_ms`
+/// milliseconds for the reply.
+pub fn ping(
+    destination: Ipv4Address,
+    identifier: u16,
+    sequence: u16,
+    size: usize,
+    timeout_ms: u64,
+) -> Result<EchoReply, NetError> {
+    let payload: Vec<u8> = (0..size).map(|index| index as u8).collect();
+    let mut rest = [0; 4];
+    rest[..2].copy_from_slice(&identifier.to_be_bytes());
+    rest[2..].copy_from_slice(&sequence.to_be_bytes());
+    let request = message(TYPE_ECHO_REQUEST, 0, rest, &payload);
+
+    ECHOES.lock().push(Echo {
+        identifier,
+        sequence,
+        sent_ms: time::uptime_ms(),
+        reply: None,
+    });
+    let result = ipv4::send(destination, PROTOCOL_ICMP, &request).and_then(|()| {
+        super::wait(Some(timeout_ms), || {
+            let echoes = ECHOES.lock();
+            let echo = echoes
+                .iter()
+                .find(|echo| (echo.identifier, echo.sequence) == (identifier, sequence));
+            echo.and_then(|echo| echo.reply)
+        })
+    });
+    ECHOES
+        .lock()
+        .retain(|echo| (echo.identifier, echo.sequence) != (identifier, sequence));
+    result
+}
+
+/// Tells the sender of `packet` that it could not be delivered.
+pub fn send_unreachable(packet: &Packet, code: u8) {
+    let quoted = packet
+        .data
+        .len()
+        .min(packet.data.len() - packet.payload.len() + QUOTED_PAYLOAD);
+    let message = message(TYPE_UNREACHABLE, code, [0; 4], &packet.data[..quoted]);
+    let _ = ipv4::send(packet.source, PROTOCOL_ICMP, &message);
+}
+
+/// Handles an ICMP message.
+pub fn handle(packet: &Packet) {
+    let data = packet.payload;
+    if data.len() < HEADER_SIZE || checksum(&[data]) != 0 {
+        return;
+    }
+    match data[0] {
+        TYPE_ECHO_REQUEST => {
+            let reply = message(
+                TYPE_ECHO_REPLY,
+                0,
+                data[4..8].try_into().unwrap(),
+                &data[HEADER_SIZE..],
+            );
+            let _ = ipv4::send(packet.source, PROTOCOL_ICMP, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let identifier = u16::from_be_bytes([data[4], data[5]]);
+            let sequence = u16::from_be_bytes([data[6], data[7]]);
+            let mut echoes = ECHOES.lock();
+            let echo = echoes
+                .iter_mut()
+                .find(|echo| (echo.identifier, echo.sequence) == (identifier, sequence));
+            if let Some(echo) = echo.filter(|echo| echo.reply.is_none()) {
+                echo.reply = Some(EchoReply {
+                    source: packet.source,
+                    ttl: packet.ttl,
+                    size: data.len(),
+                    time_ms: time::uptime_ms() - echo.sent_ms,
+                });
+            }
+        }
+        _ => {}
+    }
+}
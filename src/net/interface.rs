@@ -0,0 +1,99 @@
+// Network interfaces: a device together with its IPv4 address.
+//
+// Every registered device gets an interface, which has no address until it is
+// configured. Configuring an address also replaces the routes through the interface
+// with one to its own network.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::ipv4::{self, Ipv4Address, Route};
+use super::{ethernet, MacAddress, NetDevice, NetError};
+use crate::locks::mutex::Mutex;
+
+#[derive(Clone)]
+pub struct Interface {
+    pub device: Arc<dyn NetDevice>,
+    /// `Ipv4Address::UNSPECIFIED` while the interface is not configured.
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.address != Ipv4Address::UNSPECIFIED
+    }
+
+    pub fn netmask(&self) -> Ipv4Address {
+        Ipv4Address::netmask(self.prefix_len)
+    }
+
+    /// The broadcast address of the interface's network.
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !self.netmask().to_u32())
+    }
+
+    /// Sends `payload` to a neighbour in an Ethernet frame.
+    pub fn send(
+        &self,
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let frame = ethernet::build(destination, self.device.mac_address(), ethertype, payload);
+        self.device.send(&frame)
+    }
+}
+
+static INTERFACES: Mutex<Vec<Interface>> = Mutex::new(Vec::new());
+
+/// Adds an unconfigured interface for `device`.
+pub fn add(device: Arc<dyn NetDevice>) {
+    INTERFACES.lock().push(Interface {
+        device,
+        address: Ipv4Address::UNSPECIFIED,
+        prefix_len: 0,
+    });
+}
+
+/// All interfaces in the order their devices were registered.
+pub fn all() -> Vec<Interface> {
+    INTERFACES.lock().clone()
+}
+
+pub fn get(name: &str) -> Option<Interface> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name() == name)
+        .cloned()
+}
+
+/// Sets the address of an interface, or removes it with `Ipv4Address::UNSPECIFIED`.
+/// Routes through the interface are replaced by one to the network of the address.
+pub fn configure(name: &str, address: Ipv4Address, prefix_len: u8) -> Result<(), NetError> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces
+        .iter_mut()
+        .find(|interface| interface.name() == name)
+        .ok_or(NetError::NoDevice)?;
+    interface.address = address;
+    interface.prefix_len = prefix_len.min(32);
+    let network = Ipv4Address::from_u32(address.to_u32() & interface.netmask().to_u32());
+    let prefix_len = interface.prefix_len;
+    drop(interfaces);
+
+    ipv4::remove_routes(name);
+    if address != Ipv4Address::UNSPECIFIED {
+        ipv4::add_route(Route {
+            destination: network,
+            prefix_len,
+            gateway: None,
+            interface: name.into(),
+        });
+    }
+    Ok(())
+}
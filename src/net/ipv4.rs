@@ -0,0 +1,389 @@
+// The Internet Protocol, version 4.
+//
+// Outgoing packets are routed by the longest matching prefix in the routing table,
+// which names the interface to send through and, for destinations outside the local
+// networks, the gateway that forwards them. Options in received headers are skipped;
+// fragmented packets are dropped since we never reassemble, and we never fragment.
+// Reference: https://www.rfc-editor.org/rfc/rfc791
+// Reference: https://www.rfc-editor.org/rfc/rfc1071
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::interface::{self, Interface};
+use super::{arp, ethernet, icmp, tcp, udp, NetError};
+use crate::locks::mutex::Mutex;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+/// Largest payload of a packet that fits into one Ethernet frame.
+pub const MAX_PAYLOAD: usize = ethernet::MTU - HEADER_SIZE;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+    pub const LOCALHOST: Ipv4Address = Ipv4Address([127, 0, 0, 1]);
+
+    /// Parses the dotted decimal notation, e.g. `10.0.2.2`.
+    pub fn parse(text: &str) -> Option<Ipv4Address> {
+        let mut address = [0; 4];
+        let mut parts = text.split('.');
+        for byte in address.iter_mut() {
+            let part = parts.next()?;
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            *byte = part.parse().ok()?;
+        }
+        match parts.next() {
+            None => Some(Ipv4Address(address)),
+            Some(_) => None,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Ipv4Address {
+        Ipv4Address(value.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// The netmask with the first `prefix_len` bits set.
+    pub fn netmask(prefix_len: u8) -> Ipv4Address {
+        Ipv4Address::from_u32(mask(prefix_len))
+    }
+
+    /// The prefix length of a netmask, if its bits are contiguous.
+    pub fn prefix_len(self) -> Option<u8> {
+        let mask = self.to_u32();
+        (mask.leading_ones() + mask.trailing_zeros() == 32).then_some(mask.leading_ones() as u8)
+    }
+
+    /// Whether the address is in the network `network/prefix_len`.
+    pub fn in_network(self, network: Ipv4Address, prefix_len: u8) -> bool {
+        (self.to_u32() ^ network.to_u32()) & mask(prefix_len) == 0
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// Parses an address with an optional prefix length, e.g. `10.0.2.15/24`.
+pub fn parse_cidr(text: &str) -> Option<(Ipv4Address, Option<u8>)> {
+    match text.split_once('/') {
+        Some((address, prefix_len)) => {
+            let prefix_len = prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= 32)?;
+            Some((Ipv4Address::parse(address)?, Some(prefix_len)))
+        }
+        None => Some((Ipv4Address::parse(text)?, None)),
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        _ => u32::MAX << (32 - prefix_len.min(32) as u32),
+    }
+}
+
+/// The Internet checksum of `parts` as if they were one buffer. Checking data with its
+/// checksum included gives zero.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    let mut high = None;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        match high.take() {
+            Some(high) => sum += u16::from_be_bytes([high, *byte]) as u64,
+            None => high = Some(*byte),
+        }
+    }
+    if let Some(high) = high {
+        sum += (high as u64) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The pseudo header UDP and TCP include in their checksums.
+pub fn pseudo_header(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    length: usize,
+) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&source.0);
+    header[4..8].copy_from_slice(&destination.0);
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&(length as u16).to_be_bytes());
+    header
+}
+
+pub struct Packet<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+    /// The whole packet, header included, without padding.
+    pub data: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+pub fn parse(data: &[u8]) -> Option<Packet<'_>> {
+    if data.len() < HEADER_SIZE || data[0] >> 4 != 4 {
+        return None;
+    }
+    let header_size = (data[0] & 0xf) as usize * 4;
+    let total = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if header_size < HEADER_SIZE || total < header_size || total > data.len() {
+        return None;
+    }
+    if checksum(&[&data[..header_size]]) != 0 {
+        return None;
+    }
+    let fragment = u16::from_be_bytes([data[6], data[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+        return None;
+    }
+    Some(Packet {
+        source: Ipv4Address(data[12..16].try_into().ok()?),
+        destination: Ipv4Address(data[16..20].try_into().ok()?),
+        protocol: data[9],
+        ttl: data[8],
+        data: &data[..total],
+        payload: &data[header_size..total],
+    })
+}
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+pub fn build(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&source.0);
+    packet.extend_from_slice(&destination.0);
+    let sum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Address,
+    pub prefix_len: u8,
+    /// Where packets are forwarded to; `None` if the destination is on the network of
+    /// the interface itself.
+    pub gateway: Option<Ipv4Address>,
+    pub interface: String,
+}
+
+impl Route {
+    /// The route for everything without a more specific one.
+    pub fn default_via(gateway: Ipv4Address, interface: &str) -> Route {
+        Route {
+            destination: Ipv4Address::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: Some(gateway),
+            interface: interface.into(),
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.prefix_len {
+            0 => write!(f, "default")?,
+            _ => write!(f, "{}/{}", self.destination, self.prefix_len)?,
+        }
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev {}", self.interface)
+    }
+}
+
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+/// Adds a route, replacing one for the same network.
+pub fn add_route(route: Route) {
+    let mut routes = ROUTES.lock();
+    routes.retain(|other| {
+        (other.destination, other.prefix_len) != (route.destination, route.prefix_len)
+    });
+    routes.push(route);
+}
+
+/// Removes the route for a network. Returns whether there was one.
+pub fn remove_route(destination: Ipv4Address, prefix_len: u8) -> bool {
+    let mut routes = ROUTES.lock();
+    let count = routes.len();
+    routes.retain(|route| (route.destination, route.prefix_len) != (destination, prefix_len));
+    routes.len() != count
+}
+
+/// Removes all routes through an interface.
+pub fn remove_routes(interface: &str) {
+    ROUTES.lock().retain(|route| route.interface != interface);
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+// The most specific of `routes` that covers `destination`.
+fn longest_match(routes: &[Route], destination: Ipv4Address) -> Option<&Route> {
+    routes
+        .iter()
+        .filter(|route| destination.in_network(route.destination, route.prefix_len))
+        .max_by_key(|route| route.prefix_len)
+}
+
+/// The interface to send packets for `destination` through, and the neighbour to hand
+/// them to.
+pub fn route(destination: Ipv4Address) -> Result<(Interface, Ipv4Address), NetError> {
+    let route = longest_match(&ROUTES.lock(), destination)
+        .cloned()
+        .ok_or(NetError::Unreachable)?;
+    let interface = interface::get(&route.interface)
+        .filter(|interface| interface.is_configured())
+        .ok_or(NetError::Unreachable)?;
+    Ok((interface, route.gateway.unwrap_or(destination)))
+}
+
+/// The address packets to `destination` are sent from.
+pub fn source_address(destination: Ipv4Address) -> Result<Ipv4Address, NetError> {
+    route(destination).map(|(interface, _)| interface.address)
+}
+
+/// Sends `payload` to `destination`, from the address of the interface it is routed
+/// through.
+pub fn send(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::MessageTooLong);
+    }
+    let (interface, next_hop) = route(destination)?;
+    let packet = build(interface.address, destination, protocol, payload);
+    arp::send(&interface, next_hop, packet)
+}
+
+/// Sends `payload` straight to `destination` on the network of `interface`, without
+/// routing. DHCP broadcasts this way while the interface has no address yet.
+pub fn send_via(
+    interface: &Interface,
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::MessageTooLong);
+    }
+    let packet = build(interface.address, destination, protocol, payload);
+    arp::send(interface, destination, packet)
+}
+
+/// Passes a packet received on `interface` on to the protocol it carries, if it is
+/// addressed to us. Interfaces without an address take everything, DHCP servers may
+/// send their offers to the address they are offering.
+pub fn handle(interface: &Interface, data: &[u8]) {
+    let Some(packet) = parse(data) else {
+        return;
+    };
+    // With a /32 address the broadcast address is our own.
+    let broadcast = packet.destination == Ipv4Address::BROADCAST
+        || (interface.is_configured()
+            && packet.destination == interface.broadcast()
+            && packet.destination != interface.address);
+    let for_us = !interface.is_configured() || packet.destination == interface.address || broadcast;
+    if !for_us {
+        return;
+    }
+    match packet.protocol {
+        PROTOCOL_ICMP => icmp::handle(&packet),
+        PROTOCOL_UDP => udp::handle(&packet, broadcast),
+        PROTOCOL_TCP => tcp::handle(&packet, broadcast),
+        _ => {}
+    }
+}
+
+#[test_case]
+fn test_checksum_and_header() {
+    // The example from RFC 1071.
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+    assert_eq!(checksum(&[&data]), !0xddf2);
+    assert_eq!(checksum(&[&data[..3], &data[3..]]), !0xddf2);
+
+    let source = Ipv4Address::parse("10.0.2.15").unwrap();
+    let packet = build(source, Ipv4Address([10, 0, 2, 2]), PROTOCOL_UDP, b"hello");
+    let parsed = parse(&packet).unwrap();
+    assert_eq!(
+        (parsed.source, parsed.protocol, parsed.payload),
+        (source, PROTOCOL_UDP, &b"hello"[..])
+    );
+    assert_eq!(alloc::format!("{}", parsed.destination), "10.0.2.2");
+    assert!(Ipv4Address::parse("10.0.2").is_none());
+    assert!(Ipv4Address::parse("10.0.2.256").is_none());
+}
+
+#[test_case]
+fn test_longest_prefix_match() {
+    let gateway = Ipv4Address([10, 0, 2, 2]);
+    let routes = [
+        Route::default_via(gateway, "eth0"),
+        Route {
+            destination: Ipv4Address([10, 0, 2, 0]),
+            prefix_len: 24,
+            gateway: None,
+            interface: "eth0".into(),
+        },
+        Route {
+            destination: Ipv4Address([10, 0, 0, 0]),
+            prefix_len: 8,
+            gateway: None,
+            interface: "eth1".into(),
+        },
+    ];
+    let found =
+        |address| longest_match(&routes, address).map(|route| (route.prefix_len, route.gateway));
+    assert_eq!(found(Ipv4Address([10, 0, 2, 99])), Some((24, None)));
+    assert_eq!(found(Ipv4Address([10, 9, 0, 1])), Some((8, None)));
+    assert_eq!(found(Ipv4Address([1, 1, 1, 1])), Some((0, Some(gateway))));
+    assert_eq!(Ipv4Address::netmask(24), Ipv4Address([255, 255, 255, 0]));
+    assert_eq!(Ipv4Address([255, 255, 240, 0]).prefix_len(), Some(20));
+    assert_eq!(Ipv4Address([255, 0, 255, 0]).prefix_len(), None);
+    assert_eq!(
+        parse_cidr("10.0.2.15/24"),
+        Some((Ipv4Address([10, 0, 2, 15]), Some(24)))
+    );
+    assert_eq!(parse_cidr("10.0.2.15/33"), None);
+}
@@ -0,0 +1,312 @@
+// Networking.
+//
+// Network card drivers implement `NetDevice`, which sends and receives whole Ethernet
+// frames, and register their cards here as `eth0`, `eth1`, ... Frames are received by
+// polling: `receive` hands out what the card got since the last call, without waiting.
+// Every device counts the frames and bytes it moved in both directions.
+//
+// On top of the devices sits an IPv4 stack: ARP, ICMP echo, UDP and TCP, used through
+// the sockets in `socket`, and a DHCP client that configures the interfaces. A kernel
+// thread polls the devices for frames and drives the timers of ARP and TCP as well as
+// the DHCP client; blocking socket calls poll as well while they wait.
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+
+use crate::locks::mutex::Mutex;
+use crate::process::{self, scheduler};
+use crate::{println, time};
+
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod loopback;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+use ipv4::Ipv4Address;
+
+/// Largest Ethernet frame without the frame check sequence: 14 byte header and 1500
+/// bytes of payload.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+    pub const ZERO: MacAddress = MacAddress([0; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame is empty or larger than `MAX_FRAME_SIZE`.
+    InvalidFrame,
+    /// The transmit ring is full; try again later.
+    Busy,
+    LinkDown,
+    /// The device reported an error or did not respond.
+    Io,
+    NoDevice,
+    /// There is no route to the destination or its neighbour does not answer.
+    Unreachable,
+    TimedOut,
+    AddressInUse,
+    ConnectionRefused,
+    ConnectionReset,
+    NotConnected,
+    /// The data does not fit into a single packet.
+    MessageTooLong,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            NetError::InvalidFrame => "invalid frame size",
+            NetError::Busy => "transmit queue full",
+            NetError::LinkDown => "link is down",
+            NetError::Io => "input/output error",
+            NetError::NoDevice => "no such device",
+            NetError::Unreachable => "network unreachable",
+            NetError::TimedOut => "timed out",
+            NetError::AddressInUse => "address in use",
+            NetError::ConnectionRefused => "connection refused",
+            NetError::ConnectionReset => "connection reset by peer",
+            NetError::NotConnected => "not connected",
+            NetError::MessageTooLong => "message too long",
+        };
+        f.write_str(message)
+    }
+}
+
+/// What a device sent and received since it was set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames the device received but had to throw away.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames that could not be sent.
+    pub tx_errors: u64,
+}
+
+/// The statistics of a device, updated by its driver.
+#[derive(Default)]
+pub struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+impl Counters {
+    pub fn received(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) {
+        self.tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub trait NetDevice: Send + Sync {
+    /// The name the device is registered under.
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    /// Whether a cable is plugged in, as far as the card can tell.
+    fn link_up(&self) -> bool;
+
+    /// Sends an Ethernet frame, from the destination address up to the end of the
+    /// payload. Returns once the frame is queued, not when it is on the wire.
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Takes the next received frame, if there is one.
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    fn stats(&self) -> NetStats;
+}
+
+/// Checks the size of a frame to send.
+pub fn check_frame(frame: &[u8]) -> Result<(), NetError> {
+    match frame.len() {
+        1..=MAX_FRAME_SIZE => Ok(()),
+        _ => Err(NetError::InvalidFrame),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn NetDevice>>> = Mutex::new(Vec::new());
+static NEXT_ETHERNET: AtomicUsize = AtomicUsize::new(0);
+
+/// The name for the next Ethernet card, `eth0`, `eth1`, ...
+pub fn next_ethernet_name() -> String {
+    format!("eth{}", NEXT_ETHERNET.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Makes `device` available under its name, as an interface without an address.
+pub fn register(device: Arc<dyn NetDevice>) {
+    DEVICES.lock().push(device.clone());
+    interface::add(device);
+}
+
+/// All registered devices in registration order.
+pub fn devices() -> Vec<Arc<dyn NetDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Frames taken from one device per poll, so a busy card cannot starve the others.
+const MAX_FRAMES_PER_POLL: usize = 64;
+
+/// Handles the frames the devices received since the last call and runs the timers
+/// that are due.
+pub fn poll() {
+    for interface in interface::all() {
+        for _ in 0..MAX_FRAMES_PER_POLL {
+            match interface.device.receive() {
+                Some(frame) => ethernet::handle(&interface, &frame),
+                None => break,
+            }
+        }
+    }
+    arp::poll_timers();
+    tcp::poll_timers();
+    dhcp::poll();
+}
+
+/// Polls the network until `ready` returns something or `timeout_ms` milliseconds have
+/// passed, letting other threads run in the meantime. `None` waits forever.
+pub fn wait<T>(
+    timeout_ms: Option<u64>,
+    mut ready: impl FnMut() -> Option<T>,
+) -> Result<T, NetError> {
+    let deadline = timeout_ms.map(time::deadline);
+    loop {
+        poll();
+        if let Some(value) = ready() {
+            return Ok(value);
+        }
+        if deadline.is_some_and(|deadline| time::ticks() >= deadline) {
+            return Err(NetError::TimedOut);
+        }
+        scheduler::relax();
+    }
+}
+
+/// Ports handed out to sockets that do not ask for a specific one.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+// Picks the next ephemeral port after `next` that `in_use` does not reject.
+fn ephemeral_port(next: &AtomicU16, in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    let count = EPHEMERAL_PORTS.len() as u16;
+    (0..count).find_map(|_| {
+        let offset = next.fetch_add(1, Ordering::Relaxed) % count;
+        let port = EPHEMERAL_PORTS.start() + offset;
+        (!in_use(port)).then_some(port)
+    })
+}
+
+fn network_thread() -> ! {
+    loop {
+        poll();
+        scheduler::relax();
+    }
+}
+
+/// Sets up the loopback device, starts polling the devices and configuring the first
+/// Ethernet card over DHCP. The lease is announced once the server granted it.
+pub fn init() {
+    println!("[!] Starting network stack");
+    register(Arc::new(loopback::Loopback::new()));
+    interface::configure("lo", Ipv4Address::LOCALHOST, 8).expect("loopback interface exists");
+    println!("    [+] lo: {}/8", Ipv4Address::LOCALHOST);
+
+    let ethernet = devices()
+        .into_iter()
+        .find(|device| device.mac_address() != MacAddress::ZERO);
+    match ethernet {
+        Some(device) => match dhcp::start(device.name()) {
+            Ok(()) => println!("    [+] {}: asking for an address over DHCP", device.name()),
+            Err(error) => println!("    [-] {}: DHCP: {}", device.name(), error),
+        },
+        None => println!("    [-] No network cards"),
+    }
+    process::spawn_kernel_thread(network_thread);
+    println!("    [+] Done");
+}
+
+#[test_case]
+fn test_counters_and_mac_address() {
+    let counters = Counters::default();
+    counters.received(60);
+    counters.received(1514);
+    counters.sent(42);
+    counters.dropped();
+    let stats = counters.stats();
+    assert_eq!(
+        (stats.rx_packets, stats.rx_bytes, stats.rx_dropped),
+        (2, 1574, 1)
+    );
+    assert_eq!(
+        (stats.tx_packets, stats.tx_bytes, stats.tx_errors),
+        (1, 42, 0)
+    );
+
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(format!("{}", mac), "52:54:00:12:34:56");
+    assert_eq!(
+        check_frame(&[0; MAX_FRAME_SIZE + 1]),
+        Err(NetError::InvalidFrame)
+    );
+}
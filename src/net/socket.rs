@@ -0,0 +1,178 @@
+// Sockets: the interface of UDP and TCP for the rest of the kernel.
+//
+// Calls that have to wait for the network block the calling thread, polling the
+// devices while other threads run. They wait forever unless a timeout is set.
+// Dropping a socket releases its port; a TCP connection is closed gracefully and
+// finishes in the background.
+use alloc::vec::Vec;
+
+use super::ipv4::Ipv4Address;
+use super::tcp::{self, ConnectionId, Endpoint, State};
+use super::{udp, wait, NetError};
+
+pub struct UdpSocket {
+    port: u16,
+    timeout_ms: Option<u64>,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        Ok(UdpSocket {
+            port: udp::bind(port)?,
+            timeout_ms: None,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// How long `recv_from` waits, `None` for forever.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn send_to(&self, data: &[u8], address: Ipv4Address, port: u16) -> Result<(), NetError> {
+        udp::send(self.port, address, port, data)
+    }
+
+    /// Waits for a datagram and copies it into `buf`, cutting off what does not fit.
+    /// Returns its size and where it came from.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Ipv4Address, u16), NetError> {
+        let datagram = wait(self.timeout_ms, || udp::receive(self.port))?;
+        let count = buf.len().min(datagram.data.len());
+        buf[..count].copy_from_slice(&datagram.data[..count]);
+        Ok((count, datagram.source, datagram.port))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        udp::unbind(self.port);
+    }
+}
+
+pub struct TcpListener {
+    port: u16,
+    timeout_ms: Option<u64>,
+}
+
+/// Connections a listener queues until they are accepted.
+const DEFAULT_BACKLOG: usize = 8;
+
+impl TcpListener {
+    /// Listens on `port`, or on a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        Ok(TcpListener {
+            port: tcp::listen(port, DEFAULT_BACKLOG)?,
+            timeout_ms: None,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// How long `accept` waits, `None` for forever.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Waits for the next connection.
+    pub fn accept(&self) -> Result<TcpStream, NetError> {
+        let id = wait(self.timeout_ms, || tcp::try_accept(self.port))?;
+        Ok(TcpStream {
+            id,
+            timeout_ms: None,
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        tcp::unlisten(self.port);
+    }
+}
+
+pub struct TcpStream {
+    id: ConnectionId,
+    timeout_ms: Option<u64>,
+}
+
+impl TcpStream {
+    /// Opens a connection to `port` on `address` and waits until it is established.
+    /// Gives up after the SYN was sent a few times without an answer.
+    pub fn connect(address: Ipv4Address, port: u16) -> Result<TcpStream, NetError> {
+        let stream = TcpStream {
+            id: tcp::connect(Endpoint { address, port })?,
+            timeout_ms: None,
+        };
+        wait(None, || tcp::try_connect(stream.id))??;
+        Ok(stream)
+    }
+
+    /// How long `read` and `write` wait, `None` for forever.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn state(&self) -> State {
+        tcp::info(self.id).map_or(State::Closed, |(state, _, _)| state)
+    }
+
+    pub fn local_address(&self) -> Option<Endpoint> {
+        tcp::info(self.id).map(|(_, local, _)| local)
+    }
+
+    pub fn peer_address(&self) -> Option<Endpoint> {
+        tcp::info(self.id).map(|(_, _, remote)| remote)
+    }
+
+    /// Waits for data and reads up to `buf.len()` bytes of it. Returns 0 once the peer
+    /// closed the connection and everything it sent was read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        wait(self.timeout_ms, || tcp::try_read(self.id, buf))?
+    }
+
+    /// Reads until the peer closes the connection.
+    pub fn read_to_end(&self, data: &mut Vec<u8>) -> Result<usize, NetError> {
+        let mut buf = [0; 1024];
+        let start = data.len();
+        loop {
+            match self.read(&mut buf)? {
+                0 => return Ok(data.len() - start),
+                count => data.extend_from_slice(&buf[..count]),
+            }
+        }
+    }
+
+    /// Queues all of `data` for sending, waiting while the send buffer is full.
+    pub fn write(&self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let count = wait(self.timeout_ms, || tcp::try_write(self.id, data))??;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Waits until the peer acknowledged everything written.
+    pub fn flush(&self) -> Result<(), NetError> {
+        wait(self.timeout_ms, || tcp::is_flushed(self.id).then_some(()))
+    }
+
+    /// Closes our side of the connection after the data written so far. The peer can
+    /// still send until it closes its side too.
+    pub fn shutdown(&self) {
+        tcp::close(self.id);
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        tcp::release(self.id);
+    }
+}
@@ -0,0 +1,987 @@
+// The Transmission Control Protocol.
+//
+// Every connection goes through the states of RFC 793. Outgoing data waits in the send
+// buffer until it is acknowledged, and no more is in flight than the peer's window
+// allows; the window we advertise is the free space of the receive buffer. Segments
+// are acknowledged as soon as they arrive. Out-of-order segments are dropped, the
+// peer sends them again.
+//
+// Unacknowledged data is sent again from the first unacknowledged byte when the
+// retransmission timer runs out, and the timeout doubles with every try. The timeout
+// itself follows the measured round-trip time, ignoring retransmitted segments. While
+// the peer's window is closed, the timer sends single bytes to probe it.
+// Reference: https://www.rfc-editor.org/rfc/rfc793
+// Reference: https://www.rfc-editor.org/rfc/rfc6298
+// Reference: https://www.rfc-editor.org/rfc/rfc9293
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+use super::ipv4::{self, checksum, pseudo_header, Ipv4Address, Packet, PROTOCOL_TCP};
+use super::NetError;
+use crate::locks::mutex::Mutex;
+use crate::time::{self, TICKS_PER_SECOND};
+
+const HEADER_SIZE: usize = 20;
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The largest segment we accept, which fills an Ethernet frame.
+const MAX_SEGMENT_SIZE: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+/// What the peer accepts if it does not say.
+const DEFAULT_SEGMENT_SIZE: usize = 536;
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+/// The largest window that fits into the header without window scaling.
+const RECEIVE_BUFFER_SIZE: usize = 0xffff;
+
+// Retransmission timeouts in ticks.
+const INITIAL_RTO: u64 = TICKS_PER_SECOND;
+const MIN_RTO: u64 = TICKS_PER_SECOND / 5;
+const MAX_RTO: u64 = 60 * TICKS_PER_SECOND;
+/// Retransmissions before a connection is given up.
+const MAX_RETRIES: u32 = 6;
+/// Twice the maximum segment lifetime, much shorter than the two minutes of RFC 793.
+const TIME_WAIT: u64 = 10 * TICKS_PER_SECOND;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+/// Identifies a connection for the socket that owns it.
+pub type ConnectionId = usize;
+
+// Sequence numbers wrap around, so they are compared by their distance.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn before_or_equal(a: u32, b: u32) -> bool {
+    !before(b, a)
+}
+
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(packet: &Packet<'a>) -> Option<Segment<'a>> {
+        let data = packet.payload;
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let pseudo = pseudo_header(packet.source, packet.destination, PROTOCOL_TCP, data.len());
+        if checksum(&[&pseudo, data]) != 0 {
+            return None;
+        }
+        let header_size = (data[12] >> 4) as usize * 4;
+        if header_size < HEADER_SIZE || header_size > data.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[HEADER_SIZE..header_size];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => options = rest,
+                _ => {
+                    let length = *rest.first()? as usize;
+                    if length < 2 || length > options.len() {
+                        return None;
+                    }
+                    // A maximum segment size of 0 would stall sending, so it counts as unset.
+                    if *kind == OPTION_MSS && length == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]))
+                            .filter(|&mss| mss > 0);
+                    }
+                    options = &options[length..];
+                }
+            }
+        }
+
+        let word = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+        Some(Segment {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            sequence: word(4),
+            acknowledgment: word(8),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[header_size..],
+        })
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // The sequence space the segment occupies: its data, SYN and FIN.
+    fn length(&self) -> u32 {
+        self.payload.len() as u32 + self.has(FLAG_SYN) as u32 + self.has(FLAG_FIN) as u32
+    }
+}
+
+fn build(
+    local: Endpoint,
+    remote: Endpoint,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    // SYNs tell the peer how large our segments may be.
+    let options: &[u8] = match flags & FLAG_SYN {
+        0 => &[],
+        _ => &[
+            OPTION_MSS,
+            4,
+            (MAX_SEGMENT_SIZE >> 8) as u8,
+            MAX_SEGMENT_SIZE as u8,
+        ],
+    };
+    let header_size = HEADER_SIZE + options.len();
+    let mut segment = Vec::with_capacity(header_size + data.len());
+    segment.extend_from_slice(&local.port.to_be_bytes());
+    segment.extend_from_slice(&remote.port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&acknowledgment.to_be_bytes());
+    segment.extend_from_slice(&[(header_size / 4) as u8 * 16, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0; 4]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(data);
+    let pseudo = pseudo_header(local.address, remote.address, PROTOCOL_TCP, segment.len());
+    let sum = checksum(&[&pseudo, &segment]);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+fn send(
+    local: Endpoint,
+    remote: Endpoint,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) {
+    let segment = build(local, remote, sequence, acknowledgment, flags, window, data);
+    // Lost segments are sent again by the retransmission timer.
+    let _ = ipv4::send(remote.address, PROTOCOL_TCP, &segment);
+}
+
+// Answers a segment that belongs to no connection.
+fn send_reset(local: Endpoint, remote: Endpoint, segment: &Segment) {
+    if segment.has(FLAG_RST) {
+        return;
+    }
+    match segment.has(FLAG_ACK) {
+        true => send(local, remote, segment.acknowledgment, 0, FLAG_RST, 0, &[]),
+        false => {
+            let acknowledgment = segment.sequence.wrapping_add(segment.length());
+            send(
+                local,
+                remote,
+                0,
+                acknowledgment,
+                FLAG_RST | FLAG_ACK,
+                0,
+                &[],
+            );
+        }
+    }
+}
+
+static ISN_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// Initial sequence numbers follow a clock, so a new connection between the same
+// endpoints does not start inside the sequence space of an old one.
+fn initial_sequence() -> u32 {
+    let clock = (time::ticks() as u32).wrapping_mul(40_000);
+    clock.wrapping_add(ISN_COUNTER.fetch_add(64_000, Ordering::Relaxed))
+}
+
+struct Connection {
+    state: State,
+    local: Endpoint,
+    remote: Endpoint,
+    /// The port of the listener the connection is accepted through.
+    listener: Option<u16>,
+    /// Set while no socket owns the connection; it is removed once closed.
+    detached: bool,
+    error: Option<NetError>,
+
+    initial_sequence: u32,
+    send_unacknowledged: u32,
+    send_next: u32,
+    /// The highest sequence number sent so far, `send_next` moves back on retransmits.
+    send_max: u32,
+    send_window: u32,
+    /// Data from `send_unacknowledged` on, sent or not.
+    send_buffer: VecDeque<u8>,
+    /// A FIN follows the data in the send buffer.
+    closing: bool,
+    segment_size: usize,
+
+    receive_next: u32,
+    receive_buffer: VecDeque<u8>,
+    /// The peer sent a FIN, no more data follows what is in the receive buffer.
+    remote_closed: bool,
+
+    rto: u64,
+    smoothed_rtt: Option<u64>,
+    rtt_variance: u64,
+    /// The sequence number whose acknowledgment is timed, and when it was sent.
+    rtt_probe: Option<(u32, u64)>,
+    /// When the retransmission timer or TIME-WAIT runs out, in ticks.
+    timer: Option<u64>,
+    retries: u32,
+}
+
+impl Connection {
+    fn new(state: State, local: Endpoint, remote: Endpoint) -> Connection {
+        let initial_sequence = initial_sequence();
+        Connection {
+            state,
+            local,
+            remote,
+            listener: None,
+            detached: false,
+            error: None,
+            initial_sequence,
+            send_unacknowledged: initial_sequence,
+            send_next: initial_sequence,
+            send_max: initial_sequence,
+            send_window: 0,
+            send_buffer: VecDeque::new(),
+            closing: false,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            receive_next: 0,
+            receive_buffer: VecDeque::new(),
+            remote_closed: false,
+            rto: INITIAL_RTO,
+            smoothed_rtt: None,
+            rtt_variance: 0,
+            rtt_probe: None,
+            timer: None,
+            retries: 0,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER_SIZE - self.receive_buffer.len()) as u16
+    }
+
+    fn send_segment(&self, flags: u8, sequence: u32, data: &[u8]) {
+        let acknowledgment = if flags & FLAG_ACK != 0 {
+            self.receive_next
+        } else {
+            0
+        };
+        send(
+            self.local,
+            self.remote,
+            sequence,
+            acknowledgment,
+            flags,
+            self.window(),
+            data,
+        );
+    }
+
+    fn send_ack(&self) {
+        self.send_segment(FLAG_ACK, self.send_next, &[]);
+    }
+
+    // Sends the SYN, or the SYN-ACK while the peer's SYN is in.
+    fn send_syn(&mut self, now: u64) {
+        let flags = match self.state {
+            State::SynReceived => FLAG_SYN | FLAG_ACK,
+            _ => FLAG_SYN,
+        };
+        self.send_segment(flags, self.initial_sequence, &[]);
+        self.send_next = self.initial_sequence.wrapping_add(1);
+        self.send_max = self.send_next;
+        if self.retries == 0 {
+            self.rtt_probe = Some((self.send_next, now));
+        }
+        self.timer = Some(now + self.rto);
+    }
+
+    // Sends the data and FIN the window allows, from `send_next` on. `probe` lets a
+    // byte through a closed window.
+    fn transmit(&mut self, now: u64, probe: bool) {
+        if !matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        ) {
+            return;
+        }
+        let window = match probe {
+            true => self.send_window.max(1) as usize,
+            false => self.send_window as usize,
+        };
+        let previous_max = self.send_max;
+        loop {
+            let in_flight = self.send_next.wrapping_sub(self.send_unacknowledged) as usize;
+            let available = self.send_buffer.len().saturating_sub(in_flight);
+            let size = available
+                .min(window.saturating_sub(in_flight))
+                .min(self.segment_size);
+            if size == 0 {
+                break;
+            }
+            let data: Vec<u8> = self
+                .send_buffer
+                .range(in_flight..in_flight + size)
+                .copied()
+                .collect();
+            self.send_segment(FLAG_ACK | FLAG_PSH, self.send_next, &data);
+            self.send_next = self.send_next.wrapping_add(size as u32);
+        }
+        let fin = self
+            .send_unacknowledged
+            .wrapping_add(self.send_buffer.len() as u32);
+        if self.closing && self.send_next == fin {
+            self.send_segment(FLAG_FIN | FLAG_ACK, fin, &[]);
+            self.send_next = fin.wrapping_add(1);
+        }
+
+        if before(self.send_max, self.send_next) {
+            self.send_max = self.send_next;
+            if self.rtt_probe.is_none() && self.retries == 0 {
+                self.rtt_probe = Some((self.send_next, now));
+            }
+        }
+        let unsent =
+            self.send_buffer.len() > self.send_max.wrapping_sub(self.send_unacknowledged) as usize;
+        let waiting =
+            self.send_max != self.send_unacknowledged || (unsent && self.send_window == 0);
+        if waiting && (self.timer.is_none() || previous_max == self.send_unacknowledged) {
+            self.timer = Some(now + self.rto);
+        }
+    }
+
+    fn update_rtt(&mut self, acknowledgment: u32, now: u64) {
+        let Some((sequence, sent)) = self.rtt_probe else {
+            return;
+        };
+        if before(acknowledgment, sequence) {
+            return;
+        }
+        self.rtt_probe = None;
+        let rtt = now - sent;
+        let smoothed = match self.smoothed_rtt {
+            None => {
+                self.rtt_variance = rtt / 2;
+                rtt
+            }
+            Some(smoothed) => {
+                self.rtt_variance = (3 * self.rtt_variance + smoothed.abs_diff(rtt)) / 4;
+                (7 * smoothed + rtt) / 8
+            }
+        };
+        self.smoothed_rtt = Some(smoothed);
+        self.rto = (smoothed + (4 * self.rtt_variance).max(1)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // Takes acknowledged data out of the send buffer. Returns whether our FIN is
+    // acknowledged.
+    fn acknowledge(&mut self, acknowledgment: u32, now: u64) -> bool {
+        let acked = acknowledgment.wrapping_sub(self.send_unacknowledged) as usize;
+        let data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.send_unacknowledged = acknowledgment;
+        if before(self.send_next, acknowledgment) {
+            self.send_next = acknowledgment;
+        }
+        self.update_rtt(acknowledgment, now);
+        self.retries = 0;
+        self.timer = None;
+        acked > data
+    }
+
+    fn fail(&mut self, error: NetError) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.timer = None;
+        self.send_buffer.clear();
+    }
+
+    // Whether a segment is within the receive window.
+    fn acceptable(&self, segment: &Segment) -> bool {
+        let window = self.window() as u32;
+        let in_window = |sequence: u32| sequence.wrapping_sub(self.receive_next) < window;
+        match (segment.length(), window) {
+            (0, 0) => segment.sequence == self.receive_next,
+            (0, _) => in_window(segment.sequence),
+            (_, 0) => false,
+            (length, _) => {
+                in_window(segment.sequence) || in_window(segment.sequence.wrapping_add(length - 1))
+            }
+        }
+    }
+
+    // Handles a segment in SYN-SENT, where the peer's SYN is expected.
+    fn receive_syn(&mut self, segment: &Segment, now: u64) {
+        let acknowledgment_valid = segment.acknowledgment == self.initial_sequence.wrapping_add(1);
+        if segment.has(FLAG_ACK) && !acknowledgment_valid {
+            send_reset(self.local, self.remote, segment);
+            return;
+        }
+        if segment.has(FLAG_RST) {
+            if segment.has(FLAG_ACK) {
+                self.fail(NetError::ConnectionRefused);
+            }
+            return;
+        }
+        if !segment.has(FLAG_SYN) {
+            return;
+        }
+        self.receive_next = segment.sequence.wrapping_add(1);
+        self.segment_size = segment
+            .mss
+            .map_or(DEFAULT_SEGMENT_SIZE, |mss| mss as usize)
+            .min(MAX_SEGMENT_SIZE);
+        self.send_window = segment.window as u32;
+        if segment.has(FLAG_ACK) {
+            self.acknowledge(segment.acknowledgment, now);
+            self.state = State::Established;
+            self.send_ack();
+        } else {
+            // Both sides opened at the same time.
+            self.state = State::SynReceived;
+            self.send_syn(now);
+        }
+    }
+
+    // Handles a segment in one of the synchronized states. Returns whether the
+    // connection got established.
+    fn receive(&mut self, segment: &Segment, now: u64) -> bool {
+        let in_window = self.acceptable(segment);
+        if !in_window {
+            if !segment.has(FLAG_RST) {
+                self.send_ack();
+            }
+            // Acknowledgments still count, the window may just be closed.
+            if !segment.has(FLAG_ACK) || segment.has(FLAG_RST) {
+                return false;
+            }
+        }
+        if segment.has(FLAG_RST) {
+            match self.state {
+                State::SynReceived if self.listener.is_some() => self.state = State::Closed,
+                State::TimeWait => self.state = State::Closed,
+                _ => self.fail(NetError::ConnectionReset),
+            }
+            return false;
+        }
+        // A SYN in a synchronized state is answered with an acknowledgment, which
+        // resets the peer if it lost track of the connection.
+        if segment.has(FLAG_SYN) {
+            self.send_ack();
+            return false;
+        }
+        if !segment.has(FLAG_ACK) {
+            return false;
+        }
+
+        let mut established = false;
+        if self.state == State::SynReceived {
+            if segment.acknowledgment != self.initial_sequence.wrapping_add(1) {
+                send_reset(self.local, self.remote, segment);
+                return false;
+            }
+            self.acknowledge(segment.acknowledgment, now);
+            self.state = State::Established;
+            established = true;
+        }
+        if before(self.send_max, segment.acknowledgment) {
+            self.send_ack();
+            return established;
+        }
+        let mut fin_acked = false;
+        if before(self.send_unacknowledged, segment.acknowledgment) {
+            fin_acked = self.acknowledge(segment.acknowledgment, now);
+        }
+        if before_or_equal(self.send_unacknowledged, segment.acknowledgment) {
+            self.send_window = segment.window as u32;
+            // The peer answers our window probes, it is still there.
+            if self.send_window == 0 {
+                self.retries = 0;
+            }
+        }
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => {
+                    self.state = State::Closed;
+                    return established;
+                }
+                _ => {}
+            }
+        }
+
+        let mut ack = false;
+        let mut all_data = true;
+        let receiving = matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        );
+        if in_window && receiving && !segment.payload.is_empty() {
+            if before(self.receive_next, segment.sequence) {
+                // Out of order: ask for what is missing.
+                self.send_ack();
+                return established;
+            }
+            let skip = self.receive_next.wrapping_sub(segment.sequence) as usize;
+            let data = &segment.payload[skip.min(segment.payload.len())..];
+            let room = RECEIVE_BUFFER_SIZE - self.receive_buffer.len();
+            let taken = data.len().min(room);
+            self.receive_buffer.extend(&data[..taken]);
+            self.receive_next = self.receive_next.wrapping_add(taken as u32);
+            all_data = taken == data.len();
+            ack = true;
+        }
+
+        let fin_sequence = segment.sequence.wrapping_add(segment.payload.len() as u32);
+        if in_window && segment.has(FLAG_FIN) && all_data && fin_sequence == self.receive_next {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.remote_closed = true;
+            ack = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 | State::TimeWait => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+        if ack {
+            self.send_ack();
+        }
+        self.transmit(now, false);
+        established
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.timer = Some(now + TIME_WAIT);
+    }
+
+    // The retransmission timer ran out.
+    fn timeout(&mut self, now: u64) {
+        self.timer = None;
+        if self.state == State::TimeWait {
+            self.state = State::Closed;
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            send(self.local, self.remote, self.send_next, 0, FLAG_RST, 0, &[]);
+            return self.fail(NetError::TimedOut);
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.rtt_probe = None;
+        self.send_next = self.send_unacknowledged;
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(now),
+            _ => self.transmit(now, true),
+        }
+    }
+}
+
+struct Listener {
+    backlog: usize,
+    /// Established connections waiting to be accepted.
+    ready: VecDeque<ConnectionId>,
+}
+
+struct Tcp {
+    connections: BTreeMap<ConnectionId, Connection>,
+    listeners: BTreeMap<u16, Listener>,
+    next_id: ConnectionId,
+}
+
+impl Tcp {
+    fn find(&self, local: Endpoint, remote: Endpoint) -> Option<ConnectionId> {
+        self.connections
+            .iter()
+            .find(|(_, connection)| {
+                connection.local == local
+                    && connection.remote == remote
+                    && connection.state != State::Closed
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn insert(&mut self, connection: Connection) -> ConnectionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(id, connection);
+        id
+    }
+
+    fn port_in_use(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port)
+            || self
+                .connections
+                .values()
+                .any(|connection| connection.local.port == port)
+    }
+
+    // A SYN for a listener starts a new connection.
+    fn accept_syn(&mut self, local: Endpoint, remote: Endpoint, segment: &Segment, now: u64) {
+        if !segment.has(FLAG_SYN) || segment.has(FLAG_ACK) || segment.has(FLAG_RST) {
+            return send_reset(local, remote, segment);
+        }
+        let Some(listener) = self.listeners.get(&local.port) else {
+            return send_reset(local, remote, segment);
+        };
+        let half_open = self
+            .connections
+            .values()
+            .filter(|connection| {
+                connection.listener == Some(local.port) && connection.state == State::SynReceived
+            })
+            .count();
+        if half_open + listener.ready.len() >= listener.backlog {
+            // The peer will try again.
+            return;
+        }
+
+        let mut connection = Connection::new(State::SynReceived, local, remote);
+        connection.listener = Some(local.port);
+        connection.detached = true;
+        connection.receive_next = segment.sequence.wrapping_add(1);
+        connection.segment_size = segment
+            .mss
+            .map_or(DEFAULT_SEGMENT_SIZE, |mss| mss as usize)
+            .min(MAX_SEGMENT_SIZE);
+        connection.send_window = segment.window as u32;
+        connection.send_syn(now);
+        self.insert(connection);
+    }
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    connections: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+    next_id: 0,
+});
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+/// Handles a TCP segment. Segments sent to a `broadcast` address are dropped without
+/// a reset: a connection is always between two hosts.
+pub fn handle(packet: &Packet, broadcast: bool) {
+    if broadcast {
+        return;
+    }
+    let Some(segment) = Segment::parse(packet) else {
+        return;
+    };
+    let local = Endpoint {
+        address: packet.destination,
+        port: segment.destination_port,
+    };
+    let remote = Endpoint {
+        address: packet.source,
+        port: segment.source_port,
+    };
+    let now = time::ticks();
+
+    let mut tcp = TCP.lock();
+    let Some(id) = tcp.find(local, remote) else {
+        return tcp.accept_syn(local, remote, &segment, now);
+    };
+    let connection = tcp.connections.get_mut(&id).unwrap();
+    let established = match connection.state {
+        State::SynSent => {
+            connection.receive_syn(&segment, now);
+            false
+        }
+        _ => connection.receive(&segment, now),
+    };
+    if let Some(port) = connection.listener.filter(|_| established) {
+        if let Some(listener) = tcp.listeners.get_mut(&port) {
+            listener.ready.push_back(id);
+        }
+    }
+}
+
+/// Runs the retransmission and TIME-WAIT timers and removes closed connections nobody
+/// owns anymore.
+pub fn poll_timers() {
+    let now = time::ticks();
+    let mut tcp = TCP.lock();
+    for connection in tcp.connections.values_mut() {
+        if connection.timer.is_some_and(|deadline| now >= deadline) {
+            connection.timeout(now);
+        }
+    }
+    tcp.connections
+        .retain(|_, connection| connection.state != State::Closed || !connection.detached);
+}
+
+/// Accepts connections on `port`, queueing at most `backlog` of them until they are
+/// accepted.
+pub fn listen(port: u16, backlog: usize) -> Result<u16, NetError> {
+    let mut tcp = TCP.lock();
+    let port = match port {
+        0 => super::ephemeral_port(&NEXT_EPHEMERAL, |port| tcp.port_in_use(port))
+            .ok_or(NetError::AddressInUse)?,
+        _ if tcp.listeners.contains_key(&port) => return Err(NetError::AddressInUse),
+        _ => port,
+    };
+    tcp.listeners.insert(
+        port,
+        Listener {
+            backlog: backlog.max(1),
+            ready: VecDeque::new(),
+        },
+    );
+    Ok(port)
+}
+
+/// Stops listening on `port` and resets the connections that were not accepted yet.
+pub fn unlisten(port: u16) {
+    let mut tcp = TCP.lock();
+    tcp.listeners.remove(&port);
+    for connection in tcp.connections.values_mut() {
+        if connection.listener == Some(port)
+            && connection.detached
+            && connection.state != State::Closed
+        {
+            send(
+                connection.local,
+                connection.remote,
+                connection.send_next,
+                0,
+                FLAG_RST,
+                0,
+                &[],
+            );
+            connection.state = State::Closed;
+        }
+    }
+}
+
+/// Takes the next established connection of the listener on `port`.
+pub fn try_accept(port: u16) -> Option<ConnectionId> {
+    let mut tcp = TCP.lock();
+    loop {
+        let id = tcp.listeners.get_mut(&port)?.ready.pop_front()?;
+        // Connections reset before they were accepted are gone already.
+        if let Some(connection) = tcp.connections.get_mut(&id) {
+            connection.detached = false;
+            return Some(id);
+        }
+    }
+}
+
+/// Starts opening a connection to `remote`.
+pub fn connect(remote: Endpoint) -> Result<ConnectionId, NetError> {
+    let address = ipv4::source_address(remote.address)?;
+    let mut tcp = TCP.lock();
+    let port = super::ephemeral_port(&NEXT_EPHEMERAL, |port| tcp.port_in_use(port))
+        .ok_or(NetError::AddressInUse)?;
+    let mut connection = Connection::new(State::SynSent, Endpoint { address, port }, remote);
+    connection.send_syn(time::ticks());
+    Ok(tcp.insert(connection))
+}
+
+/// Whether a connection being opened is established, `None` while it is not decided.
+pub fn try_connect(id: ConnectionId) -> Option<Result<(), NetError>> {
+    let tcp = TCP.lock();
+    let connection = tcp.connections.get(&id)?;
+    match connection.state {
+        State::SynSent | State::SynReceived => None,
+        State::Closed => Some(Err(connection.error.unwrap_or(NetError::NotConnected))),
+        _ => Some(Ok(())),
+    }
+}
+
+/// Reads received data into `buf`. Gives 0 once the peer closed its side and everything
+/// was read, and `None` if there is nothing to read yet.
+pub fn try_read(id: ConnectionId, buf: &mut [u8]) -> Option<Result<usize, NetError>> {
+    let mut tcp = TCP.lock();
+    let connection = tcp.connections.get_mut(&id)?;
+    if connection.receive_buffer.is_empty() {
+        return match (connection.state, connection.error) {
+            (_, Some(error)) => Some(Err(error)),
+            (State::Closed, None) => Some(Ok(0)),
+            _ if connection.remote_closed => Some(Ok(0)),
+            _ => None,
+        };
+    }
+    let window = connection.window() as usize;
+    let count = buf.len().min(connection.receive_buffer.len());
+    for (byte, received) in buf.iter_mut().zip(connection.receive_buffer.drain(..count)) {
+        *byte = received;
+    }
+    // Tell the peer when the window opens up again after it was nearly closed.
+    if window < connection.segment_size && connection.window() as usize >= connection.segment_size {
+        connection.send_ack();
+    }
+    Some(Ok(count))
+}
+
+/// Queues as much of `data` as fits into the send buffer and returns how much that was,
+/// or `None` if the buffer is full.
+pub fn try_write(id: ConnectionId, data: &[u8]) -> Option<Result<usize, NetError>> {
+    let mut tcp = TCP.lock();
+    let connection = tcp.connections.get_mut(&id)?;
+    if connection.closing || !matches!(connection.state, State::Established | State::CloseWait) {
+        return Some(Err(connection.error.unwrap_or(NetError::NotConnected)));
+    }
+    let count = data
+        .len()
+        .min(SEND_BUFFER_SIZE - connection.send_buffer.len());
+    if count == 0 && !data.is_empty() {
+        return None;
+    }
+    connection.send_buffer.extend(&data[..count]);
+    connection.transmit(time::ticks(), false);
+    Some(Ok(count))
+}
+
+/// Whether all data written to the connection was acknowledged.
+pub fn is_flushed(id: ConnectionId) -> bool {
+    let tcp = TCP.lock();
+    tcp.connections.get(&id).is_none_or(|connection| {
+        connection.send_buffer.is_empty() || connection.state == State::Closed
+    })
+}
+
+/// Closes our side of the connection: a FIN follows the data still to be sent.
+pub fn close(id: ConnectionId) {
+    let mut tcp = TCP.lock();
+    let Some(connection) = tcp.connections.get_mut(&id) else {
+        return;
+    };
+    match connection.state {
+        State::SynSent => connection.state = State::Closed,
+        State::SynReceived | State::Established => connection.state = State::FinWait1,
+        State::CloseWait => connection.state = State::LastAck,
+        _ => return,
+    }
+    connection.closing = true;
+    connection.transmit(time::ticks(), false);
+}
+
+/// Closes the connection and gives it up; it goes away once it is closed.
+pub fn release(id: ConnectionId) {
+    close(id);
+    if let Some(connection) = TCP.lock().connections.get_mut(&id) {
+        connection.detached = true;
+    }
+}
+
+/// The state and endpoints of a connection.
+pub fn info(id: ConnectionId) -> Option<(State, Endpoint, Endpoint)> {
+    let tcp = TCP.lock();
+    tcp.connections
+        .get(&id)
+        .map(|connection| (connection.state, connection.local, connection.remote))
+}
+
+#[test_case]
+fn test_segment_roundtrip() {
+    assert!(before(0xffff_fff0, 0x10));
+    assert!(!before(0x10, 0xffff_fff0));
+    assert!(before_or_equal(7, 7));
+
+    let local = Endpoint {
+        address: Ipv4Address([10, 0, 2, 15]),
+        port: 49152,
+    };
+    let remote = Endpoint {
+        address: Ipv4Address([10, 0, 2, 2]),
+        port: 80,
+    };
+    let data = build(local, remote, 1000, 0, FLAG_SYN, 4096, b"");
+    let packet = Packet {
+        source: local.address,
+        destination: remote.address,
+        protocol: PROTOCOL_TCP,
+        ttl: 64,
+        data: &data,
+        payload: &data,
+    };
+    let segment = Segment::parse(&packet).unwrap();
+    assert_eq!((segment.source_port, segment.destination_port), (49152, 80));
+    assert_eq!(
+        (segment.sequence, segment.window, segment.length()),
+        (1000, 4096, 1)
+    );
+    assert_eq!(segment.mss, Some(MAX_SEGMENT_SIZE as u16));
+
+    let data = build(local, remote, 1001, 5, FLAG_ACK | FLAG_FIN, 100, b"bye");
+    let packet = Packet {
+        payload: &data,
+        ..packet
+    };
+    let segment = Segment::parse(&packet).unwrap();
+    assert_eq!(
+        (segment.acknowledgment, segment.payload, segment.length()),
+        (5, &b"bye"[..], 4)
+    );
+
+    // A maximum segment size of 0 is ignored.
+    let mut data = build(local, remote, 1000, 0, FLAG_SYN, 4096, b"");
+    data[16..18].copy_from_slice(&[0, 0]);
+    data[22..24].copy_from_slice(&[0, 0]);
+    let sum = checksum(&[
+        &pseudo_header(local.address, remote.address, PROTOCOL_TCP, data.len()),
+        &data,
+    ]);
+    data[16..18].copy_from_slice(&sum.to_be_bytes());
+    let segment = Segment::parse(&Packet {
+        payload: &data,
+        ..packet
+    })
+    .unwrap();
+    assert_eq!(segment.mss, None);
+}
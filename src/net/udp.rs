@@ -0,0 +1,157 @@
+// The User Datagram Protocol.
+//
+// A socket is bound to a port on all interfaces and has a queue of the datagrams that
+// arrived for it. Datagrams arriving while the queue is full are dropped; those for a
+// port nobody is bound to are answered with an ICMP port unreachable message.
+// Reference: https://www.rfc-editor.org/rfc/rfc768
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU16;
+
+use super::icmp;
+use super::interface::Interface;
+use super::ipv4::{self, checksum, pseudo_header, Ipv4Address, Packet, PROTOCOL_UDP};
+use super::NetError;
+use crate::locks::mutex::Mutex;
+
+pub const HEADER_SIZE: usize = 8;
+/// Largest datagram that fits into one packet.
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+/// Datagrams queued for one socket, at most.
+const MAX_QUEUED: usize = 64;
+
+pub struct Datagram {
+    pub source: Ipv4Address,
+    pub port: u16,
+    pub data: Vec<u8>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, VecDeque<Datagram>>> = Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+/// Binds `port`, or a free ephemeral port if it is 0, and returns it.
+pub fn bind(port: u16) -> Result<u16, NetError> {
+    let mut sockets = SOCKETS.lock();
+    let port = match port {
+        0 => super::ephemeral_port(&NEXT_EPHEMERAL, |port| sockets.contains_key(&port))
+            .ok_or(NetError::AddressInUse)?,
+        _ if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+        _ => port,
+    };
+    sockets.insert(port, VecDeque::new());
+    Ok(port)
+}
+
+/// Releases a port and drops the datagrams still queued for it.
+pub fn unbind(port: u16) {
+    SOCKETS.lock().remove(&port);
+}
+
+/// Takes the next datagram that arrived for `port`.
+pub fn receive(port: u16) -> Option<Datagram> {
+    SOCKETS.lock().get_mut(&port)?.pop_front()
+}
+
+/// Sends `data` from `source_port` to `port` on `destination`.
+pub fn send(
+    source_port: u16,
+    destination: Ipv4Address,
+    port: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(NetError::MessageTooLong);
+    }
+    let source = ipv4::source_address(destination)?;
+    let datagram = build(source, source_port, destination, port, data);
+    ipv4::send(destination, PROTOCOL_UDP, &datagram)
+}
+
+/// Sends `data` from `source_port` straight to `port` on `destination`, through
+/// `interface` and from its address, which may still be unspecified.
+pub fn send_via(
+    interface: &Interface,
+    source_port: u16,
+    destination: Ipv4Address,
+    port: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(NetError::MessageTooLong);
+    }
+    let datagram = build(interface.address, source_port, destination, port, data);
+    ipv4::send_via(interface, destination, PROTOCOL_UDP, &datagram)
+}
+
+fn build(
+    source: Ipv4Address,
+    source_port: u16,
+    destination: Ipv4Address,
+    port: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    let length = HEADER_SIZE + data.len();
+    let mut datagram = Vec::with_capacity(length);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&port.to_be_bytes());
+    datagram.extend_from_slice(&(length as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let pseudo = pseudo_header(source, destination, PROTOCOL_UDP, length);
+    // A checksum of zero means there is none, so it is sent as all ones.
+    let sum = match checksum(&[&pseudo, &datagram]) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+/// Queues a received datagram for the socket bound to its port. Without a socket the
+/// sender learns that the port is unreachable, unless the datagram was a `broadcast`.
+pub fn handle(packet: &Packet, broadcast: bool) {
+    let data = packet.payload;
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+    let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if length < HEADER_SIZE || length > data.len() {
+        return;
+    }
+    let data = &data[..length];
+    let sum = u16::from_be_bytes([data[6], data[7]]);
+    let pseudo = pseudo_header(packet.source, packet.destination, PROTOCOL_UDP, length);
+    if sum != 0 && checksum(&[&pseudo, data]) != 0 {
+        return;
+    }
+
+    let source_port = u16::from_be_bytes([data[0], data[1]]);
+    let port = u16::from_be_bytes([data[2], data[3]]);
+    let mut sockets = SOCKETS.lock();
+    match sockets.get_mut(&port) {
+        Some(queue) if queue.len() < MAX_QUEUED => {
+            queue.push_back(Datagram {
+                source: packet.source,
+                port: source_port,
+                data: data[HEADER_SIZE..].into(),
+            });
+        }
+        Some(_) => {}
+        None => {
+            drop(sockets);
+            if !broadcast {
+                icmp::send_unreachable(packet, icmp::CODE_PORT_UNREACHABLE);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_datagram_checksum() {
+    let source = Ipv4Address([10, 0, 2, 15]);
+    let destination = Ipv4Address([10, 0, 2, 2]);
+    let datagram = build(source, 49152, destination, 7, b"odd");
+    let pseudo = pseudo_header(source, destination, PROTOCOL_UDP, datagram.len());
+    assert_eq!(checksum(&[&pseudo, &datagram]), 0);
+    assert_eq!(&datagram[..6], &[0xc0, 0x00, 0x00, 0x07, 0x00, 0x0b]);
+}
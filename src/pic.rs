@@ -25,6 +25,7 @@ use core::arch::asm;
 /// Constants for PIC initialization and control.
 const PIC_INIT: u8 = 0x11;
 const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0b;
 const MODE_8086: u8 = 0x01;
 
 /// PIC port addresses.
@@ -68,6 +69,17 @@ impl Pic {
         }
     }
 
+    /// Read the in-service register, the interrupts being handled.
+    unsafe fn read_isr(&mut self) -> u8 {
+        let isr: u8;
+        unsafe {
+            self.send(PIC_READ_ISR);
+            asm!("in al, dx", out("al") isr, in("dx") self.command as u16);
+        }
+
+        isr
+    }
+
     /// Send the given command to the PIC.
     unsafe fn send(&mut self, command: u8) {
         unsafe {
@@ -132,13 +144,35 @@ impl ChainedPics {
         self.master.handles_interrupt(interrupt_id) || self.slave.handles_interrupt(interrupt_id)
     }
 
-    /// Notify the PIC that an interrupt has been handled.
+    /// Enables IRQ line `irq` (0-15). Lines on the slave PIC also need the cascade line
+    /// on the master.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        if irq < 8 {
+            let mask = self.master.read_mask();
+            self.master.write_mask(mask & !(1 << irq));
+        } else {
+            let mask = self.slave.read_mask();
+            self.slave.write_mask(mask & !(1 << (irq - 8)));
+            let mask = self.master.read_mask();
+            self.master.write_mask(mask & !(1 << 2));
+        }
+    }
+
+    /// Whether IRQ 7 was raised although no interrupt is in service: the master PIC
+    /// does so when a request goes away before the CPU acknowledged it. Such an
+    /// interrupt must not be acknowledged.
+    pub fn is_spurious_irq7(&mut self) -> bool {
+        unsafe { self.master.read_isr() & 0x80 == 0 }
+    }
+
+    /// Notify the PIC that an interrupt has been handled. Interrupts of the slave also
+    /// went through the cascade line of the master.
     pub unsafe fn notify_end_of_interrupt(&mut self, interrupt_id: u8) {
         if self.handles_interrupt(interrupt_id) {
-            if self.master.handles_interrupt(interrupt_id) {
-                self.master.end_of_interrupt();
+            if self.slave.handles_interrupt(interrupt_id) {
+                self.slave.end_of_interrupt();
             }
-            self.slave.end_of_interrupt();
+            self.master.end_of_interrupt();
         }
     }
 }
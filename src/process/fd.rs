@@ -0,0 +1,64 @@
+// Per-process file descriptor tables.
+//
+// Descriptors refer to open file descriptions. Copying a table, as `fork` does, shares
+// the descriptions, so parent and child move the same file offset.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::File;
+
+/// What a file descriptor refers to.
+#[derive(Clone)]
+pub enum FileDescriptor {
+    /// The keyboard for reading and the screen for writing.
+    Console,
+    File(Arc<dyn File>),
+}
+
+#[derive(Clone)]
+pub struct FileDescriptorTable {
+    entries: Vec<Option<FileDescriptor>>,
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> Self {
+        FileDescriptorTable {
+            entries: Vec::new(),
+        }
+    }
+
+    /// A table with stdin, stdout and stderr connected to the console.
+    pub fn with_stdio() -> Self {
+        let mut table = FileDescriptorTable::new();
+        for _ in 0..3 {
+            table.insert(FileDescriptor::Console);
+        }
+        table
+    }
+
+    pub fn get(&self, fd: u64) -> Option<&FileDescriptor> {
+        self.entries.get(fd as usize)?.as_ref()
+    }
+
+    /// Installs `descriptor` at the lowest free number and returns that number.
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> u64 {
+        match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(fd) => {
+                self.entries[fd] = Some(descriptor);
+                fd as u64
+            }
+            None => {
+                self.entries.push(Some(descriptor));
+                (self.entries.len() - 1) as u64
+            }
+        }
+    }
+
+    pub fn close(&mut self, fd: u64) -> Option<FileDescriptor> {
+        self.entries.get_mut(fd as usize)?.take()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+}
@@ -0,0 +1,311 @@
+// Processes.
+//
+// A process owns an address space, a file descriptor table and one or more threads.
+// When its last thread exits the process becomes a zombie that keeps its exit code
+// until the parent collects it with `waitpid`. Process 0 is the kernel itself: the
+// shell and the idle thread run in it, and it adopts the children of processes that
+// exit before them. Nobody waits for those, so they leave no zombie behind.
+pub mod fd;
+pub mod scheduler;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+
+use crate::interrupts::interrupts::without_interrupts;
+use crate::loader::{self, LoadError};
+use crate::locks::mutex::Mutex;
+use crate::memory::address_space::{activate_kernel, AddressSpace, Region};
+use crate::println;
+use crate::syscall::SyscallFrame;
+use fd::FileDescriptorTable;
+use scheduler::ThreadId;
+
+pub type Pid = u64;
+
+pub const KERNEL_PID: Pid = 0;
+
+/// Anonymous mappings without an address hint are placed from here upwards.
+const MMAP_BASE: u64 = 0x0000_6000_0000_0000;
+
+/// RFLAGS for user code: interrupts enabled plus the always-one bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Exited with the given code, waiting for the parent to collect it.
+    Zombie(i64),
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    /// `None` for the kernel process, which uses the kernel page table, and for zombies.
+    pub address_space: Option<AddressSpace>,
+    pub fds: FileDescriptorTable,
+    pub threads: Vec<ThreadId>,
+    pub state: ProcessState,
+    /// Where the next `mmap` without an address hint goes.
+    pub mmap_next: u64,
+    /// Adopted by the kernel after the parent exited.
+    pub orphan: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The caller has no (matching) children.
+    NoChild,
+}
+
+/// A snapshot of a process table entry.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    pub state: ProcessState,
+    pub threads: usize,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+fn with_processes<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<Pid, Process>) -> R,
+{
+    without_interrupts(|| f(&mut PROCESSES.lock()))
+}
+
+/// Creates the kernel process and starts scheduling threads.
+pub fn init() {
+    println!("[!] Starting scheduler");
+    let (boot, idle) = scheduler::init(KERNEL_PID);
+    with_processes(|processes| {
+        processes.insert(
+            KERNEL_PID,
+            Process {
+                pid: KERNEL_PID,
+                parent: KERNEL_PID,
+                name: String::from("kernel"),
+                address_space: None,
+                fds: FileDescriptorTable::with_stdio(),
+                threads: alloc::vec![boot, idle],
+                state: ProcessState::Running,
+                mmap_next: MMAP_BASE,
+                orphan: false,
+            },
+        )
+    });
+    println!("    [+] Done");
+}
+
+/// Starts a thread of the kernel process that runs `entry`, e.g. a background worker.
+pub fn spawn_kernel_thread(entry: fn() -> !) -> ThreadId {
+    let thread = scheduler::spawn_kernel_thread(KERNEL_PID, entry);
+    with_processes(|processes| {
+        let kernel = processes.get_mut(&KERNEL_PID).expect("no kernel process");
+        kernel.threads.push(thread);
+    });
+    thread
+}
+
+/// The process of the running thread.
+pub fn current_pid() -> Pid {
+    scheduler::current_pid()
+}
+
+/// Runs `f` on the process of the running thread.
+pub fn with_current<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    let pid = current_pid();
+    with_processes(|processes| f(processes.get_mut(&pid).expect("running process is gone")))
+}
+
+/// Loads the executable `image` into a new process, a child of the caller, and starts
+/// its main thread.
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, LoadError> {
+    let program = loader::load(image, argv, envp)?;
+    let frame = SyscallFrame {
+        rip: program.entry.as_u64(),
+        rsp: program.stack_pointer.as_u64(),
+        rflags: USER_RFLAGS,
+        ..SyscallFrame::default()
+    };
+    let fds = with_current(|parent| parent.fds.clone());
+    Ok(start(
+        String::from(name),
+        program.address_space,
+        fds,
+        MMAP_BASE,
+        &frame,
+    )?)
+}
+
+/// Duplicates the calling user process, sharing its memory copy-on-write. The child
+/// resumes with the registers in `frame`, except that it sees 0 as the result of the
+/// system call.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, MapToError<Size4KiB>> {
+    let (name, address_space, fds, mmap_next) = with_current(|parent| {
+        let address_space = match &mut parent.address_space {
+            Some(address_space) => address_space.fork()?,
+            None => return Err(MapToError::FrameAllocationFailed),
+        };
+        Ok((
+            parent.name.clone(),
+            address_space,
+            parent.fds.clone(),
+            parent.mmap_next,
+        ))
+    })?;
+
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    start(name, address_space, fds, mmap_next, &child_frame)
+}
+
+// Fails if the main thread cannot get a kernel stack, the process is dropped again then.
+fn start(
+    name: String,
+    address_space: AddressSpace,
+    fds: FileDescriptorTable,
+    mmap_next: u64,
+    frame: &SyscallFrame,
+) -> Result<Pid, MapToError<Size4KiB>> {
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let parent = current_pid();
+    let level_4_frame = address_space.level_4_frame();
+
+    // Register the process before its thread can run and look itself up.
+    with_processes(|processes| {
+        processes.insert(
+            pid,
+            Process {
+                pid,
+                parent,
+                name,
+                address_space: Some(address_space),
+                fds,
+                threads: Vec::new(),
+                state: ProcessState::Running,
+                mmap_next,
+                orphan: false,
+            },
+        )
+    });
+    // The thread may run right away, so it is added to the process before it can exit.
+    without_interrupts(|| {
+        let thread = scheduler::spawn_user_thread(pid, level_4_frame, frame);
+        with_processes(|processes| match thread {
+            Some(thread) => {
+                if let Some(process) = processes.get_mut(&pid) {
+                    process.threads.push(thread);
+                }
+                Ok(pid)
+            }
+            None => {
+                processes.remove(&pid);
+                Err(MapToError::FrameAllocationFailed)
+            }
+        })
+    })
+}
+
+/// Terminates the calling process with `code`.
+pub fn exit(code: i64) -> ! {
+    let pid = current_pid();
+    assert!(pid != KERNEL_PID, "the kernel process cannot exit");
+
+    let (threads, address_space) = with_processes(|processes| {
+        // Orphans are adopted by the kernel, which does not wait for them. Zombies among
+        // them are dropped right away, the others when they exit.
+        processes.retain(|_, p| p.parent != pid || matches!(p.state, ProcessState::Running));
+        for process in processes.values_mut().filter(|p| p.parent == pid) {
+            process.parent = KERNEL_PID;
+            process.orphan = true;
+        }
+        let process = processes.get_mut(&pid).expect("running process is gone");
+        let threads = core::mem::take(&mut process.threads);
+        let address_space = process.address_space.take();
+        if process.orphan {
+            processes.remove(&pid);
+        } else {
+            process.state = ProcessState::Zombie(code);
+            process.fds = FileDescriptorTable::new();
+        }
+        (threads, address_space)
+    });
+
+    // The address space must not be active while it is freed, and none of the threads
+    // of the process runs again once they are marked as exited.
+    activate_kernel();
+    scheduler::exit_threads(&threads);
+    drop(address_space);
+    scheduler::exit_current()
+}
+
+/// Waits for a child of the caller to exit and returns its pid and exit code. `pid`
+/// selects a specific child, `None` takes whichever exits first.
+pub fn waitpid(pid: Option<Pid>) -> Result<(Pid, i64), WaitError> {
+    let parent = current_pid();
+    loop {
+        let result = with_processes(|processes| {
+            let children: Vec<(Pid, ProcessState)> = processes
+                .values()
+                .filter(|p| p.parent == parent && p.pid != parent)
+                .filter(|p| pid.map_or(true, |pid| p.pid == pid))
+                .map(|p| (p.pid, p.state))
+                .collect();
+            if children.is_empty() {
+                return Some(Err(WaitError::NoChild));
+            }
+            let (pid, code) = children.into_iter().find_map(|(pid, state)| match state {
+                ProcessState::Zombie(code) => Some((pid, code)),
+                ProcessState::Running => None,
+            })?;
+            processes.remove(&pid);
+            Some(Ok((pid, code)))
+        });
+        match result {
+            Some(result) => return result,
+            None => scheduler::relax(),
+        }
+    }
+}
+
+/// Returns a snapshot of the process table, ordered by pid.
+pub fn list() -> Vec<ProcessInfo> {
+    with_processes(|processes| {
+        processes
+            .values()
+            .map(|p| ProcessInfo {
+                pid: p.pid,
+                parent: p.parent,
+                name: p.name.clone(),
+                state: p.state,
+                threads: p.threads.len(),
+            })
+            .collect()
+    })
+}
+
+/// The user memory regions of process `pid`, `None` if there is no such process. The
+/// kernel process and zombies have none.
+pub fn regions(pid: Pid) -> Option<Vec<Region>> {
+    with_processes(|processes| {
+        let process = processes.get(&pid)?;
+        Some(
+            process
+                .address_space
+                .as_ref()
+                .map_or_else(Vec::new, |space| space.regions()),
+        )
+    })
+}
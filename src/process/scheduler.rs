@@ -0,0 +1,358 @@
+// A round-robin scheduler for kernel and user threads.
+//
+// Every thread except the boot thread runs on its own kernel stack. Switching threads
+// means saving the callee-saved registers on the old stack, storing its stack pointer
+// and loading the one of the next thread; everything else was already saved by the
+// caller. User threads are preempted by the timer, kernel threads only give up the CPU
+// when they wait for something, so they can never be interrupted while holding a lock.
+// Reference: https://wiki.osdev.org/Scheduling_Algorithms
+// Reference: https://wiki.osdev.org/Context_Switching
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ptr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+
+use super::Pid;
+use crate::instructions::{disable_interrupts, enable_interrupts_and_hlt, interrupts_enabled};
+use crate::interrupts::gdt;
+use crate::interrupts::interrupts::without_interrupts;
+use crate::locks::mutex::Mutex;
+use crate::memory::kernel_level_4_frame;
+use crate::memory::stack::{self, Stack};
+use crate::syscall::{self, SyscallFrame};
+
+pub type ThreadId = u64;
+
+const THREAD_STACK_PAGES: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Exited,
+}
+
+struct Thread {
+    pid: Pid,
+    /// `None` for the boot thread, which keeps running on the bootloader's stack.
+    stack: Option<Stack>,
+    /// Saved stack pointer while the thread is not running.
+    context: u64,
+    level_4_frame: PhysFrame,
+    state: ThreadState,
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: ThreadId,
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+extern "C" {
+    fn switch_context(old_context: *mut u64, new_context: u64);
+    fn kernel_thread_start();
+}
+
+// `switch_context` pushes the callee-saved registers, stores RSP in `*old_context`,
+// switches to `new_context` and pops the registers of the next thread. New threads
+// get a stack that looks like they called `switch_context` themselves, with the return
+// address pointing at their start routine.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global kernel_thread_start",
+    "kernel_thread_start:",
+    "pop rax",
+    "sti",
+    "call rax",
+    "ud2",
+);
+
+impl Scheduler {
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.threads.insert(id, Box::new(thread));
+        id
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread is gone")
+    }
+
+    fn next_ready(&mut self) -> Option<ThreadId> {
+        while let Some(id) = self.ready.pop_front() {
+            if self
+                .threads
+                .get(&id)
+                .map_or(false, |t| t.state == ThreadState::Ready)
+            {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    // Removes exited threads other than the running one and returns their stacks.
+    fn reap(&mut self) -> Vec<Stack> {
+        let current = self.current;
+        let exited: Vec<ThreadId> = self
+            .threads
+            .iter()
+            .filter(|(id, thread)| **id != current && thread.state == ThreadState::Exited)
+            .map(|(id, _)| *id)
+            .collect();
+        exited
+            .into_iter()
+            .filter_map(|id| self.threads.remove(&id).and_then(|thread| thread.stack))
+            .collect()
+    }
+}
+
+fn with_scheduler<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Scheduler) -> R,
+{
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        f(scheduler.as_mut().expect("scheduler is not initialized"))
+    })
+}
+
+/// Turns the running code into the boot thread of `pid` and starts the idle thread.
+pub fn init(pid: Pid) -> (ThreadId, ThreadId) {
+    let mut scheduler = Scheduler {
+        threads: BTreeMap::new(),
+        ready: VecDeque::new(),
+        current: 0,
+        idle: 0,
+        next_id: 0,
+    };
+    let boot = scheduler.add(Thread {
+        pid,
+        stack: None,
+        context: 0,
+        level_4_frame: kernel_level_4_frame(),
+        state: ThreadState::Running,
+    });
+    scheduler.current = boot;
+    without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+
+    let idle = spawn_kernel_thread(pid, idle_thread);
+    with_scheduler(|scheduler| {
+        // The idle thread only runs when nothing else is ready.
+        scheduler.ready.retain(|id| *id != idle);
+        scheduler.idle = idle;
+    });
+    (boot, idle)
+}
+
+fn idle_thread() -> ! {
+    loop {
+        if !yield_now() {
+            enable_interrupts_and_hlt();
+        }
+    }
+}
+
+// Pushes `words` onto the stack growing down from `sp` and returns the new stack pointer.
+unsafe fn push_words(mut sp: u64, words: &[u64]) -> u64 {
+    for word in words.iter().rev() {
+        sp -= 8;
+        ptr::write(sp as *mut u64, *word);
+    }
+    sp
+}
+
+/// Starts a thread of `pid` that runs `entry` in ring 0 on the kernel page table.
+pub fn spawn_kernel_thread(pid: Pid, entry: fn() -> !) -> ThreadId {
+    let stack = stack::allocate("kernel thread", THREAD_STACK_PAGES)
+        .expect("failed to allocate a kernel thread stack");
+    // Six callee-saved registers, the start routine and `entry` for it to pop, leaving
+    // the stack 16 byte aligned for the call.
+    let context = unsafe {
+        push_words(
+            stack.top().as_u64() - 16,
+            &[0, 0, 0, 0, 0, 0, kernel_thread_start as u64, entry as u64],
+        )
+    };
+    add_ready(Thread {
+        pid,
+        stack: Some(stack),
+        context,
+        level_4_frame: kernel_level_4_frame(),
+        state: ThreadState::Ready,
+    })
+}
+
+/// Starts a thread of `pid` that enters ring 3 with the registers in `frame`. Returns
+/// `None` if there is no kernel stack left for it.
+pub fn spawn_user_thread(
+    pid: Pid,
+    level_4_frame: PhysFrame,
+    frame: &SyscallFrame,
+) -> Option<ThreadId> {
+    let stack = stack::allocate("user thread", THREAD_STACK_PAGES)?;
+    let selectors = gdt::selectors();
+
+    // The thread starts in the system call exit path, which restores `frame` and
+    // returns to user mode through the interrupt frame above it.
+    let context = unsafe {
+        let iret_frame = [
+            frame.rip,
+            selectors.user_code_selector.0 as u64,
+            frame.rflags,
+            frame.rsp,
+            selectors.user_data_selector.0 as u64,
+        ];
+        let sp = push_words(stack.top().as_u64(), &iret_frame);
+        let sp = sp - core::mem::size_of::<SyscallFrame>() as u64;
+        ptr::write(sp as *mut SyscallFrame, frame.clone());
+        push_words(sp, &[0, 0, 0, 0, 0, 0, syscall::user_return as u64])
+    };
+    Some(add_ready(Thread {
+        pid,
+        stack: Some(stack),
+        context,
+        level_4_frame,
+        state: ThreadState::Ready,
+    }))
+}
+
+fn add_ready(thread: Thread) -> ThreadId {
+    with_scheduler(|scheduler| {
+        let id = scheduler.add(thread);
+        scheduler.ready.push_back(id);
+        id
+    })
+}
+
+pub fn current_thread() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+/// The process the running thread belongs to.
+pub fn current_pid() -> Pid {
+    with_scheduler(|scheduler| scheduler.current().pid)
+}
+
+/// Switches to the next ready thread. Returns `false` if there was none and the current
+/// thread simply keeps running.
+pub fn yield_now() -> bool {
+    without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        // Nothing to switch to before `init`.
+        let scheduler = match guard.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return false,
+        };
+
+        let runnable = scheduler.current().state != ThreadState::Exited;
+        let next = match scheduler.next_ready() {
+            Some(next) => next,
+            None if runnable => return false,
+            None => scheduler.idle,
+        };
+
+        let previous = scheduler.current;
+        if runnable && previous != scheduler.idle {
+            scheduler.current().state = ThreadState::Ready;
+            scheduler.ready.push_back(previous);
+        }
+
+        let old_context: *mut u64 = &mut scheduler.current().context;
+        scheduler.current = next;
+        let thread = scheduler.current();
+        thread.state = ThreadState::Running;
+        let new_context = thread.context;
+        if let Some(stack) = thread.stack {
+            unsafe { gdt::set_kernel_stack(stack.top()) };
+        }
+        let (active, flags) = Cr3::read();
+        if active != thread.level_4_frame {
+            unsafe { Cr3::write(thread.level_4_frame, flags) };
+        }
+
+        // The threads are boxed, so `old_context` stays valid after the lock is dropped.
+        drop(guard);
+        unsafe { switch_context(old_context, new_context) };
+
+        // Back on this thread. Free the stacks of threads that exited in the meantime,
+        // nobody is running on them anymore.
+        let stacks = with_scheduler(|scheduler| scheduler.reap());
+        for stack in stacks {
+            unsafe { stack::free(stack) };
+        }
+        true
+    })
+}
+
+/// Gives the CPU to other threads while waiting for something, or halts until the next
+/// interrupt if nothing else is ready.
+///
+/// Interrupts are enabled while waiting and restored to their previous state afterwards.
+pub fn relax() {
+    let status = interrupts_enabled();
+    if !yield_now() {
+        enable_interrupts_and_hlt();
+        if !status {
+            disable_interrupts();
+        }
+    }
+}
+
+/// Called from the timer interrupt. Threads are only preempted in user mode, where
+/// they cannot hold kernel locks, and the idle thread gives way as soon as work arrives.
+pub fn preempt(from_user: bool) {
+    let idle = SCHEDULER
+        .lock()
+        .as_ref()
+        .map_or(false, |scheduler| scheduler.current == scheduler.idle);
+    if from_user || idle {
+        yield_now();
+    }
+}
+
+/// Marks `threads` as exited. They are removed once they are no longer running.
+pub fn exit_threads(threads: &[ThreadId]) {
+    with_scheduler(|scheduler| {
+        for id in threads {
+            if let Some(thread) = scheduler.threads.get_mut(id) {
+                thread.state = ThreadState::Exited;
+            }
+        }
+    })
+}
+
+/// Terminates the running thread.
+pub fn exit_current() -> ! {
+    let current = current_thread();
+    exit_threads(&[current]);
+    yield_now();
+    unreachable!("exited thread {} was scheduled again", current);
+}
@@ -1,16 +1,48 @@
 use lazy_static::lazy_static;
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::block;
+use crate::console;
+use crate::drivers::pci;
+use crate::fs::{self, path, FileType, OpenFlags};
 use crate::locks::mutex::Mutex;
+use crate::net::interface::{self, Interface};
+use crate::net::ipv4::{self, Ipv4Address, Route};
+use crate::net::{arp, dhcp, ethernet, icmp};
+use crate::process::{self, ProcessState};
+use crate::symbols;
+use crate::time;
 use crate::vga_buffer::{Color, WRITER};
 use crate::{print, println};
 
 const PROMPT: &str = "MoonlightOS> ";
 const HELP: &'static str = "+-------------------------------------------+
 | Available commands:                       |
-| echo  --> prints any string               |
+| echo  --> prints text, > file writes it   |
 | help  --> lists available commands        |
 | clear --> clears the screen               |
 | osinfo --> prints OS information          |
+| sym   --> resolves an address to a symbol |
+| ps    --> lists processes                 |
+| lsblk --> lists block devices             |
+| lspci --> lists PCI devices, -v details   |
+| ls    --> lists a directory               |
+| cd    --> changes the current directory   |
+| pwd   --> prints the current directory    |
+| cat   --> prints a file                   |
+| mkdir --> creates a directory             |
+| touch --> creates an empty file           |
+| rm    --> removes a file or empty dir     |
+| mv    --> moves or renames a file         |
+| run   --> runs a program and waits for it |
+| mount --> mounts a disk or lists mounts   |
+| umount --> unmounts a file system         |
+| sync  --> writes cached data to disk      |
+| ping  --> sends ICMP echo requests        |
+| ifconfig --> shows or sets addresses      |
+| ip    --> lists addresses, routes, ARP    |
 +-------------------------------------------+
 ";
 
@@ -18,12 +50,27 @@ lazy_static! {
     pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell {
         buffer: ['\0'; 256],
         cursor: 0,
+        cwd: String::from("/"),
     });
 }
 
+/// Reads keyboard input and runs commands, forever.
+///
+/// Commands run here rather than in the keyboard interrupt handler, so they are free to
+/// wait for interrupts themselves, e.g. to run a user program that reads from stdin.
+pub fn run() -> ! {
+    SHELL.lock().init();
+    loop {
+        let c = console::read_char();
+        SHELL.lock().handle(c);
+    }
+}
+
 pub struct Shell {
     buffer: [char; 256],
     cursor: usize,
+    /// Relative paths in commands start here.
+    cwd: String,
 }
 
 impl Shell {
@@ -38,6 +85,15 @@ impl Shell {
         drop(writer);
     }
 
+    pub fn handle(&mut self, c: char) {
+        match c {
+            '\n' => self.enter(),
+            // Backspace
+            '\u{8}' => self.backspace(),
+            _ => self.add(c),
+        }
+    }
+
     pub fn add(&mut self, c: char) {
         self.buffer[self.cursor] = c;
         self.cursor += 1;
@@ -69,6 +125,26 @@ impl Shell {
             _b if self.is_command("osinfo") => self.osinfo(),
             _b if self.is_command("echo") => self.echo(),
             _b if self.is_command("clear") => self.clear(),
+            _b if self.is_command("sym") => self.sym(),
+            _b if self.is_command("ps") => self.ps(),
+            // Before `ls`, which is a prefix of them.
+            _b if self.is_command("lsblk") => self.lsblk(),
+            _b if self.is_command("lspci") => self.lspci(),
+            _b if self.is_command("ls") => self.ls(),
+            _b if self.is_command("cd") => self.cd(),
+            _b if self.is_command("pwd") => println!("{}", self.cwd),
+            _b if self.is_command("cat") => self.cat(),
+            _b if self.is_command("mkdir") => self.create(6, FileType::Directory),
+            _b if self.is_command("touch") => self.create(6, FileType::Regular),
+            _b if self.is_command("rm") => self.rm(),
+            _b if self.is_command("mv") => self.mv(),
+            _b if self.is_command("run") => self.run(),
+            _b if self.is_command("sync") => self.sync(),
+            _b if self.is_command("mount") => self.mount(),
+            _b if self.is_command("umount") => self.umount(),
+            _b if self.is_command("ping") => self.ping(),
+            _b if self.is_command("ifconfig") => self.ifconfig(),
+            _b if self.is_command("ip") => self.ip(),
             _ => println!("Unknown command!"),
         }
     }
@@ -82,8 +158,56 @@ impl Shell {
         true
     }
 
+    // Parses the argument starting at `skip` as a hexadecimal number, with or without
+    // a leading `0x`.
+    fn hex_argument(&self, skip: usize) -> Option<u64> {
+        let mut arg = &self.buffer[skip.min(self.cursor)..self.cursor];
+        while let [' ', rest @ ..] = arg {
+            arg = rest;
+        }
+        while let [rest @ .., ' '] = arg {
+            arg = rest;
+        }
+        if let ['0', 'x' | 'X', rest @ ..] = arg {
+            arg = rest;
+        }
+        if arg.is_empty() {
+            return None;
+        }
+
+        arg.iter().try_fold(0u64, |acc, c| {
+            acc.checked_mul(16)?.checked_add(c.to_digit(16)? as u64)
+        })
+    }
+
+    // Returns the text after the command name with surrounding spaces removed.
+    fn argument(&self, skip: usize) -> String {
+        let arg: String = self.buffer[skip.min(self.cursor)..self.cursor]
+            .iter()
+            .collect();
+        String::from(arg.trim())
+    }
+
+    // Resolves the argument starting at `skip` against the current directory. An empty
+    // argument means the current directory itself.
+    fn path_argument(&self, skip: usize) -> String {
+        path::normalize(&self.cwd, &self.argument(skip))
+    }
+
     //commands
     fn echo(&self) {
+        // `echo text > file` writes to a file, `>>` appends.
+        let arg: String = self.buffer[5.min(self.cursor)..self.cursor]
+            .iter()
+            .collect();
+        if let Some((text, target)) = arg.split_once('>') {
+            let (append, target) = match target.strip_prefix('>') {
+                Some(target) => (true, target),
+                None => (false, target),
+            };
+            return self.write_file(target.trim(), text.trim(), append);
+        }
+
         let mut writer = WRITER.lock();
         for c in self.buffer.iter().skip(5) {
             if *c == '\0' {
@@ -117,4 +241,451 @@ impl Shell {
         writer.write_string("OS Version: 0.1.0\n");
         drop(writer);
     }
+
+    fn sym(&self) {
+        match self.hex_argument(3) {
+            Some(addr) => match symbols::symbolize(addr) {
+                Some((name, offset)) => println!("{:#x} = {}+{:#x}", addr, name, offset),
+                None => println!("{:#x}: no symbol found", addr),
+            },
+            None => {
+                println!("Usage: sym <address>");
+                println!("{} kernel symbols loaded", symbols::count());
+            }
+        }
+    }
+
+    fn ps(&self) {
+        println!(
+            "{:>5} {:>5} {:<10} {:>7} NAME",
+            "PID", "PPID", "STATE", "THREADS"
+        );
+        for process in process::list() {
+            let state = match process.state {
+                ProcessState::Running => alloc::format!("running"),
+                ProcessState::Zombie(code) => alloc::format!("exit {}", code),
+            };
+            println!(
+                "{:>5} {:>5} {:<10} {:>7} {}",
+                process.pid, process.parent, state, process.threads, process.name
+            );
+        }
+    }
+
+    fn lsblk(&self) {
+        println!(
+            "{:<6} {:>6} {:>12} {:>10} RO",
+            "NAME", "BLOCK", "BLOCKS", "SIZE"
+        );
+        for device in block::devices() {
+            let size = device.block_count() * device.block_size() as u64;
+            println!(
+                "{:<6} {:>6} {:>12} {:>10} {}",
+                device.name(),
+                device.block_size(),
+                device.block_count(),
+                block::format_size(size),
+                if device.is_read_only() { "yes" } else { "no" }
+            );
+        }
+    }
+
+    fn lspci(&self) {
+        let verbose = match self.argument(5).as_str() {
+            "" => false,
+            "-v" => true,
+            _ => return println!("Usage: lspci [-v]"),
+        };
+        for device in pci::devices() {
+            println!("{}", pci::describe(&device, verbose));
+        }
+    }
+
+    fn sync(&self) {
+        if let Err(error) = fs::sync() {
+            println!("sync: {}", error);
+        }
+        if let Err(error) = block::cache::sync_all() {
+            println!("sync: {}", error);
+        }
+    }
+
+    fn ping(&self) {
+        const COUNT: u16 = 4;
+        const SIZE: usize = 56;
+        const TIMEOUT_MS: u64 = 1000;
+
+        let Some(address) = Ipv4Address::parse(&self.argument(4)) else {
+            return println!("Usage: ping <address>");
+        };
+        println!("PING {}: {} data bytes", address, SIZE);
+        let identifier = icmp::next_identifier();
+        let mut received = 0;
+        for sequence in 1..=COUNT {
+            match icmp::ping(address, identifier, sequence, SIZE, TIMEOUT_MS) {
+                Ok(reply) => {
+                    received += 1;
+                    println!(
+                        "{} bytes from {}: icmp_seq={} ttl={} time={} ms",
+                        reply.size, reply.source, sequence, reply.ttl, reply.time_ms
+                    );
+                    // One request a second, like ping(8).
+                    time::sleep(TIMEOUT_MS.saturating_sub(reply.time_ms));
+                }
+                Err(error) => println!("icmp_seq={}: {}", sequence, error),
+            }
+        }
+        let loss = (COUNT - received) * 100 / COUNT;
+        println!(
+            "{} packets transmitted, {} received, {}% packet loss",
+            COUNT, received, loss
+        );
+    }
+
+    fn ifconfig(&self) {
+        const USAGE: &str =
+            "Usage: ifconfig [<interface> [<address>[/<prefix>] [netmask <mask>] | dhcp]]";
+        let args = self.argument(8);
+        let args: Vec<&str> = args.split_whitespace().collect();
+        let Some(name) = args.first() else {
+            for interface in interface::all() {
+                print_interface(&interface);
+            }
+            return;
+        };
+        let Some(interface) = interface::get(name) else {
+            return println!("ifconfig: {}: {}", name, crate::net::NetError::NoDevice);
+        };
+
+        match args[1..] {
+            [] => print_interface(&interface),
+            ["dhcp"] => {
+                if let Err(error) = dhcp::start(name) {
+                    println!("ifconfig: {}: {}", name, error);
+                }
+            }
+            [address] | [address, "netmask", _] => {
+                let netmask = args
+                    .get(3)
+                    .map(|mask| Ipv4Address::parse(mask).and_then(Ipv4Address::prefix_len));
+                let (address, prefix_len) = match (ipv4::parse_cidr(address), netmask) {
+                    (Some((address, None)), Some(Some(prefix_len))) => (address, prefix_len),
+                    (Some((address, prefix_len)), None) => (address, prefix_len.unwrap_or(24)),
+                    _ => return println!("{}", USAGE),
+                };
+                set_address(name, address, prefix_len);
+            }
+            _ => println!("{}", USAGE),
+        }
+    }
+
+    fn ip(&self) {
+        const USAGE: &str = "Usage: ip [addr [add <address>/<prefix> dev <interface>] | route [add|del <network>/<prefix>|default [via <gateway>] [dev <interface>]] | neigh]";
+        let args = self.argument(2);
+        let args: Vec<&str> = args.split_whitespace().collect();
+        match args[..] {
+            [] | ["addr"] => {
+                for interface in interface::all() {
+                    let address = match interface.is_configured() {
+                        true => alloc::format!("{}/{}", interface.address, interface.prefix_len),
+                        false => String::from("-"),
+                    };
+                    let link = if interface.device.link_up() {
+                        "UP"
+                    } else {
+                        "DOWN"
+                    };
+                    println!(
+                        "{:<6} {:<5} {} {}",
+                        interface.name(),
+                        link,
+                        interface.device.mac_address(),
+                        address
+                    );
+                }
+            }
+            ["addr", "add", address, "dev", name] => match ipv4::parse_cidr(address) {
+                Some((address, prefix_len)) => set_address(name, address, prefix_len.unwrap_or(32)),
+                None => println!("{}", USAGE),
+            },
+            ["route"] => {
+                for route in ipv4::routes() {
+                    println!("{}", route);
+                }
+            }
+            ["route", "add" | "del", network, ref rest @ ..] => {
+                let (destination, prefix_len) = match network {
+                    "default" => (Ipv4Address::UNSPECIFIED, 0),
+                    _ => match ipv4::parse_cidr(network) {
+                        Some((address, prefix_len)) => (address, prefix_len.unwrap_or(32)),
+                        None => return println!("{}", USAGE),
+                    },
+                };
+                if args[1] == "del" {
+                    if !rest.is_empty() || !ipv4::remove_route(destination, prefix_len) {
+                        println!("ip: no such route");
+                    }
+                    return;
+                }
+                let (gateway, name) = match rest {
+                    ["via", gateway] => (Ipv4Address::parse(gateway), None),
+                    ["dev", name] => (None, Some(*name)),
+                    ["via", gateway, "dev", name] => (Ipv4Address::parse(gateway), Some(*name)),
+                    _ => return println!("{}", USAGE),
+                };
+                if rest.first() == Some(&"via") && gateway.is_none() {
+                    return println!("{}", USAGE);
+                }
+                // Without a device, the gateway has to be on the network of an interface.
+                let interface = match (name, gateway) {
+                    (Some(name), _) => interface::get(name),
+                    (None, Some(gateway)) => interface::all().into_iter().find(|interface| {
+                        interface.is_configured()
+                            && gateway.in_network(interface.address, interface.prefix_len)
+                    }),
+                    (None, None) => None,
+                };
+                match interface {
+                    Some(interface) => ipv4::add_route(Route {
+                        destination,
+                        prefix_len,
+                        gateway,
+                        interface: interface.name().into(),
+                    }),
+                    None => println!("ip: {}", crate::net::NetError::Unreachable),
+                }
+            }
+            ["neigh"] => {
+                for (address, mac, name) in arp::entries() {
+                    println!("{} dev {} lladdr {}", address, name, mac);
+                }
+            }
+            _ => println!("{}", USAGE),
+        }
+    }
+
+    fn ls(&self) {
+        let path = self.path_argument(2);
+        let mut entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(error) => return println!("ls: {}: {}", path, error),
+        };
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for entry in entries {
+            let child = path::normalize(&path, &entry.name);
+            let size = fs::lookup(&child).map_or(0, |inode| inode.metadata().size);
+            match entry.kind {
+                FileType::Directory => println!("{:>10}  {}/", "", entry.name),
+                _ => println!("{:>10}  {}", size, entry.name),
+            }
+        }
+    }
+
+    fn cd(&mut self) {
+        let arg = self.argument(2);
+        let path = path::normalize(&self.cwd, if arg.is_empty() { "/" } else { &arg });
+        match fs::lookup(&path).map(|inode| inode.metadata().kind) {
+            Ok(FileType::Directory) => self.cwd = path,
+            Ok(_) => println!("cd: {}: {}", path, fs::FsError::NotADirectory),
+            Err(error) => println!("cd: {}: {}", path, error),
+        }
+    }
+
+    fn cat(&self) {
+        if self.argument(3).is_empty() {
+            return println!("Usage: cat <file>");
+        }
+        let path = self.path_argument(3);
+        let file = match fs::open(&path, OpenFlags::READ) {
+            Ok(file) => file,
+            Err(error) => return println!("cat: {}: {}", path, error),
+        };
+
+        let mut buf = [0u8; 512];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => {
+                    let mut writer = WRITER.lock();
+                    for byte in &buf[..count] {
+                        match byte {
+                            b'\n' => writer.new_line(),
+                            _ => writer.write_byte(*byte),
+                        }
+                    }
+                }
+                Err(error) => return println!("cat: {}: {}", path, error),
+            }
+        }
+    }
+
+    fn write_file(&self, target: &str, text: &str, append: bool) {
+        let path = path::normalize(&self.cwd, target);
+        let mode = if append {
+            OpenFlags::APPEND
+        } else {
+            OpenFlags::TRUNCATE
+        };
+        let result = fs::open(&path, OpenFlags::WRITE | OpenFlags::CREATE | mode)
+            .and_then(|file| file.write(text.as_bytes()).and_then(|_| file.write(b"\n")));
+        if let Err(error) = result {
+            println!("echo: {}: {}", path, error);
+        }
+    }
+
+    fn create(&self, skip: usize, kind: FileType) {
+        if self.argument(skip).is_empty() {
+            return println!("Usage: mkdir <directory> / touch <file>");
+        }
+        let path = self.path_argument(skip);
+        match fs::create(&path, kind) {
+            Ok(_) => {}
+            // Like touch(1), touching an existing file is fine.
+            Err(fs::FsError::AlreadyExists) if kind == FileType::Regular => {}
+            Err(error) => println!("{}: {}", path, error),
+        }
+    }
+
+    fn rm(&self) {
+        if self.argument(2).is_empty() {
+            return println!("Usage: rm <path>");
+        }
+        let path = self.path_argument(2);
+        if let Err(error) = fs::unlink(&path) {
+            println!("rm: {}: {}", path, error);
+        }
+    }
+
+    fn mv(&self) {
+        let args = self.argument(2);
+        let mut args = args.split_whitespace();
+        let (from, to) = match (args.next(), args.next()) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return println!("Usage: mv <from> <to>"),
+        };
+        let from = path::normalize(&self.cwd, from);
+        let mut to = path::normalize(&self.cwd, to);
+        // Moving into a directory keeps the name.
+        if let Ok(FileType::Directory) = fs::lookup(&to).map(|inode| inode.metadata().kind) {
+            if let Some((_, name)) = path::split(&from) {
+                to = path::normalize(&to, name);
+            }
+        }
+        if let Err(error) = fs::rename(&from, &to) {
+            println!("mv: {}: {}", from, error);
+        }
+    }
+
+    fn mount(&self) {
+        let args = self.argument(6);
+        let mut args = args.split_whitespace();
+        let (device, target) = match (args.next(), args.next()) {
+            (Some(device), Some(target)) => (device, target),
+            (None, _) => {
+                for (path, name) in fs::mounts() {
+                    println!("{} on {}", name, path);
+                }
+                return;
+            }
+            _ => return println!("Usage: mount <device> <directory>"),
+        };
+        let target = path::normalize(&self.cwd, target);
+        if let Err(error) = fs::mount_device(device, &target) {
+            println!("mount: {}: {}", device, error);
+        }
+    }
+
+    fn umount(&self) {
+        if self.argument(7).is_empty() {
+            return println!("Usage: umount <directory>");
+        }
+        let path = self.path_argument(7);
+        if let Err(error) = fs::unmount(&path) {
+            println!("umount: {}: {}", path, error);
+        }
+    }
+
+    fn run(&self) {
+        let args = self.argument(3);
+        let argv: Vec<&str> = args.split_whitespace().collect();
+        if argv.is_empty() {
+            return println!("Usage: run <program> [arguments]");
+        }
+        let path = path::normalize(&self.cwd, argv[0]);
+
+        let mut image = Vec::new();
+        let result = fs::open(&path, OpenFlags::READ).and_then(|file| {
+            let mut buf = [0u8; 512];
+            loop {
+                match file.read(&mut buf)? {
+                    0 => return Ok(()),
+                    count => {
+                        // The file may be larger than the heap can grow.
+                        image.try_reserve(count).map_err(|_| fs::FsError::NoSpace)?;
+                        image.extend_from_slice(&buf[..count]);
+                    }
+                }
+            }
+        });
+        if let Err(error) = result {
+            return println!("run: {}: {}", path, error);
+        }
+
+        let name = path::split(&path).map_or("?", |(_, name)| name);
+        let pid = match process::spawn(name, &image, &argv, &[]) {
+            Ok(pid) => pid,
+            Err(error) => return println!("run: {}: {:?}", path, error),
+        };
+        match process::waitpid(Some(pid)) {
+            Ok((_, 0)) => {}
+            Ok((_, code)) => println!("{} exited with code {}", name, code),
+            Err(error) => println!("run: {:?}", error),
+        }
+    }
+}
+
+// Prints the configuration and statistics of an interface, like ifconfig(8).
+fn print_interface(interface: &Interface) {
+    let device = &interface.device;
+    let link = if device.link_up() { "up" } else { "down" };
+    println!("{}: link {}, mtu {}", interface.name(), link, ethernet::MTU);
+    println!("    ether {}", device.mac_address());
+    match interface.is_configured() {
+        true => println!(
+            "    inet {} netmask {} broadcast {}",
+            interface.address,
+            interface.netmask(),
+            interface.broadcast()
+        ),
+        false => println!("    inet not configured"),
+    }
+    match dhcp::status(interface.name()) {
+        Some((state, Some(lease))) => {
+            let left = lease.expires.saturating_sub(time::ticks()) / time::TICKS_PER_SECOND;
+            println!(
+                "    dhcp {} from {}, lease ends in {} s",
+                state, lease.server, left
+            );
+        }
+        Some((state, None)) => println!("    dhcp {}", state),
+        None => {}
+    }
+    let stats = device.stats();
+    println!(
+        "    RX packets {} bytes {} dropped {}",
+        stats.rx_packets, stats.rx_bytes, stats.rx_dropped
+    );
+    println!(
+        "    TX packets {} bytes {} errors {}",
+        stats.tx_packets, stats.tx_bytes, stats.tx_errors
+    );
+}
+
+// Sets an address by hand, which ends DHCP on the interface.
+fn set_address(name: &str, address: Ipv4Address, prefix_len: u8) {
+    dhcp::stop(name);
+    if let Err(error) = interface::configure(name, address, prefix_len) {
+        println!("{}: {}", name, error);
+    }
 }
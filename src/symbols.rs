@@ -0,0 +1,150 @@
+// Kernel symbol table used to turn raw addresses into `function+offset`.
+//
+// The table lives in its own `.ksymtab` section and is zero-filled at compile time.
+// After linking, `scripts/ksymtab.py` reads the ELF symbol table of the kernel, sorts
+// the function symbols by address and writes them into that section in place.
+//
+// Layout of the table (all integers little endian):
+//   header:  magic "KSYM" | count: u32 | strings offset: u32 | total size: u32
+//   entries: count * { address: u64 | size: u32 | name offset: u32 }  (sorted by address)
+//   strings: name length: u16 followed by the UTF-8 bytes of the name
+use core::fmt;
+
+/// Size reserved for the table. `scripts/ksymtab.py` refuses to patch the kernel if the
+/// generated table does not fit.
+pub const KSYMTAB_SIZE: usize = 512 * 1024;
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+#[repr(C, align(8))]
+struct SymbolTable([u8; KSYMTAB_SIZE]);
+
+#[used]
+#[link_section = ".ksymtab"]
+static KSYMTAB: SymbolTable = SymbolTable([0; KSYMTAB_SIZE]);
+
+/// Returns the raw table bytes.
+///
+/// The compiler only ever sees zeros in `KSYMTAB`, so the pointer is passed through
+/// `black_box` to stop it from constant folding reads of the patched section.
+fn table() -> &'static [u8] {
+    let ptr = core::hint::black_box(KSYMTAB.0.as_ptr());
+    unsafe { core::slice::from_raw_parts(ptr, KSYMTAB_SIZE) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+struct Entry {
+    address: u64,
+    size: u32,
+    name_offset: u32,
+}
+
+struct Table {
+    bytes: &'static [u8],
+    count: usize,
+    strings: usize,
+}
+
+impl Table {
+    /// Returns `None` when the kernel was not patched by `scripts/ksymtab.py`.
+    fn get() -> Option<Table> {
+        let bytes = table();
+        if bytes[0..4] != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(bytes, 4) as usize;
+        let strings = read_u32(bytes, 8) as usize;
+        let total = read_u32(bytes, 12) as usize;
+        if total > KSYMTAB_SIZE || HEADER_SIZE + count * ENTRY_SIZE > strings || strings > total {
+            return None;
+        }
+
+        Some(Table {
+            bytes,
+            count,
+            strings,
+        })
+    }
+
+    fn entry(&self, index: usize) -> Entry {
+        let offset = HEADER_SIZE + index * ENTRY_SIZE;
+        Entry {
+            address: read_u64(self.bytes, offset),
+            size: read_u32(self.bytes, offset + 8),
+            name_offset: read_u32(self.bytes, offset + 12),
+        }
+    }
+
+    fn name(&self, entry: &Entry) -> Option<&'static str> {
+        let start = self.strings + entry.name_offset as usize;
+        let len = self.bytes.get(start..start + 2)?;
+        let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+        core::str::from_utf8(self.bytes.get(start + 2..start + 2 + len)?).ok()
+    }
+
+    /// Binary search for the last entry whose address is less than or equal to `addr`.
+    fn lookup(&self, addr: u64) -> Option<Entry> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.entry(mid).address <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        if low == 0 {
+            return None;
+        }
+        Some(self.entry(low - 1))
+    }
+}
+
+/// Resolves `addr` to the name of the function containing it and the offset into that
+/// function.
+///
+/// Returns `None` if the symbol table was not embedded into the kernel image or the
+/// address does not belong to any known function.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let table = Table::get()?;
+    let entry = table.lookup(addr)?;
+
+    let offset = addr - entry.address;
+    if entry.size != 0 && offset >= entry.size as u64 {
+        return None;
+    }
+
+    Some((table.name(&entry)?, offset))
+}
+
+/// Returns the number of symbols in the embedded table.
+pub fn count() -> usize {
+    Table::get().map_or(0, |table| table.count)
+}
+
+/// Formats an address as `0x... <function+0x...>` for exception dumps.
+pub struct Symbol(pub u64);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match symbolize(self.0) {
+            Some((name, offset)) => write!(f, "{:#x} <{}+{:#x}>", self.0, name, offset),
+            None => write!(f, "{:#x} <unknown>", self.0),
+        }
+    }
+}
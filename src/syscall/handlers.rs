@@ -0,0 +1,251 @@
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{
+    fs_errno, SyscallFrame, EBADF, ECHILD, EEXIST, EFAULT, EINVAL, ENOMEM, ESPIPE, O_APPEND,
+    O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, PROT_EXEC, PROT_WRITE, SEEK_CUR, SEEK_END,
+    SEEK_SET,
+};
+use crate::console;
+use crate::fs::{self, OpenFlags, SeekFrom};
+use crate::memory::address_space::{check_user_access, is_user_range};
+use crate::process::fd::FileDescriptor;
+use crate::process::{self, scheduler, WaitError};
+use crate::time;
+
+const PAGE_SIZE: u64 = 4096;
+
+fn descriptor(fd: u64) -> Option<FileDescriptor> {
+    process::with_current(|process| process.fds.get(fd).cloned())
+}
+
+/// Checks that user mode may access the buffer and returns it.
+fn user_buffer(ptr: u64, len: u64, write: bool) -> Result<&'static mut [u8], i64> {
+    let start = VirtAddr::try_new(ptr).map_err(|_| -EFAULT)?;
+    if !check_user_access(start, len, write) {
+        return Err(-EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len as usize) })
+}
+
+/// read(fd, buf, len): reads from a file, or a line from the keyboard for the console.
+pub fn sys_read(frame: &SyscallFrame) -> i64 {
+    let args = frame.args();
+    let descriptor = match descriptor(args[0]) {
+        Some(descriptor) => descriptor,
+        None => return -EBADF,
+    };
+    let buf = match user_buffer(args[1], args[2], true) {
+        Ok(buf) => buf,
+        Err(error) => return error,
+    };
+    if let FileDescriptor::File(file) = descriptor {
+        return file
+            .read(buf)
+            .map_or_else(|error| -fs_errno(error), |count| count as i64);
+    }
+
+    console::read_line(buf) as i64
+}
+
+/// write(fd, buf, len): writes to a file, or prints to the screen for the console.
+pub fn sys_write(frame: &SyscallFrame) -> i64 {
+    let args = frame.args();
+    let descriptor = match descriptor(args[0]) {
+        Some(descriptor) => descriptor,
+        None => return -EBADF,
+    };
+    let buf = match user_buffer(args[1], args[2], false) {
+        Ok(buf) => buf,
+        Err(error) => return error,
+    };
+    if let FileDescriptor::File(file) = descriptor {
+        return file
+            .write(buf)
+            .map_or_else(|error| -fs_errno(error), |count| count as i64);
+    }
+
+    console::write(buf);
+    buf.len() as i64
+}
+
+/// exit(code): terminates the calling process.
+pub fn sys_exit(frame: &SyscallFrame) -> i64 {
+    process::exit(frame.rdi as i64)
+}
+
+/// yield(): lets other threads run.
+pub fn sys_yield(_frame: &SyscallFrame) -> i64 {
+    scheduler::yield_now();
+    0
+}
+
+/// sleep(ms): waits for at least `ms` milliseconds.
+pub fn sys_sleep(frame: &SyscallFrame) -> i64 {
+    time::sleep(frame.rdi);
+    0
+}
+
+/// getpid(): the id of the calling process.
+pub fn sys_getpid(_frame: &SyscallFrame) -> i64 {
+    process::current_pid() as i64
+}
+
+/// mmap(addr, len, prot): maps zeroed anonymous memory and returns its address.
+///
+/// `addr` is used as the address of the mapping if it is non-zero, otherwise the kernel
+/// picks one. Fails with `EEXIST` if the range overlaps an existing mapping, and maps
+/// nothing if it fails.
+pub fn sys_mmap(frame: &SyscallFrame) -> i64 {
+    let [addr, len, prot, ..] = frame.args();
+    if len == 0 || addr % PAGE_SIZE != 0 {
+        return -EINVAL;
+    }
+    let size = match len.checked_add(PAGE_SIZE - 1) {
+        Some(size) => size / PAGE_SIZE * PAGE_SIZE,
+        None => return -EINVAL,
+    };
+
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    process::with_current(|process| {
+        let start = if addr == 0 { process.mmap_next } else { addr };
+        let start = match VirtAddr::try_new(start) {
+            Ok(start) if is_user_range(start, size) => start,
+            _ => return -EINVAL,
+        };
+
+        let address_space = process
+            .address_space
+            .as_mut()
+            .expect("user process without an address space");
+        let first = Page::containing_address(start);
+        let pages = Page::range(first, first + size / PAGE_SIZE);
+        // Existing mappings are never replaced. Without a hint, a fixed mapping is in
+        // the way of the next free range.
+        if pages.clone().any(|page| address_space.is_mapped(page)) {
+            return if addr == 0 { -ENOMEM } else { -EEXIST };
+        }
+        for page in pages.clone() {
+            if address_space.map_user_zeroed(page, flags).is_err() {
+                for mapped in Page::range(first, page) {
+                    address_space.unmap_user(mapped);
+                }
+                return -ENOMEM;
+            }
+        }
+        if addr == 0 {
+            process.mmap_next += size;
+        }
+        start.as_u64() as i64
+    })
+}
+
+/// fork(): duplicates the calling process. Returns the child's pid in the parent and 0
+/// in the child.
+pub fn sys_fork(frame: &SyscallFrame) -> i64 {
+    match process::fork(frame) {
+        Ok(pid) => pid as i64,
+        Err(_) => -ENOMEM,
+    }
+}
+
+/// waitpid(pid, status): waits for the child `pid` to exit, or for any child if `pid`
+/// is -1. Stores its exit code in `*status` unless that is null and returns its pid.
+pub fn sys_waitpid(frame: &SyscallFrame) -> i64 {
+    let [pid, status, ..] = frame.args();
+    let pid = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(pid as u64),
+        _ => return -EINVAL,
+    };
+    let status = if status == 0 {
+        None
+    } else {
+        match user_buffer(status, 8, true) {
+            Ok(buf) => Some(buf),
+            Err(error) => return error,
+        }
+    };
+
+    match process::waitpid(pid) {
+        Ok((pid, code)) => {
+            if let Some(status) = status {
+                status.copy_from_slice(&code.to_le_bytes());
+            }
+            pid as i64
+        }
+        Err(WaitError::NoChild) => -ECHILD,
+    }
+}
+
+/// open(path, len, flags): opens the file at the absolute path of `len` bytes and returns
+/// the lowest free descriptor for it. `flags` is one of `O_RDONLY`, `O_WRONLY` and
+/// `O_RDWR`, plus any of `O_CREAT`, `O_TRUNC` and `O_APPEND`.
+pub fn sys_open(frame: &SyscallFrame) -> i64 {
+    let [path, len, flags, ..] = frame.args();
+    let path = match user_buffer(path, len, false) {
+        Ok(buf) => match core::str::from_utf8(buf) {
+            Ok(path) => path,
+            Err(_) => return -EINVAL,
+        },
+        Err(error) => return error,
+    };
+
+    let mut open_flags = match flags & 3 {
+        O_RDONLY => OpenFlags::READ,
+        O_WRONLY => OpenFlags::WRITE,
+        O_RDWR => OpenFlags::READ | OpenFlags::WRITE,
+        _ => return -EINVAL,
+    };
+    for (flag, open_flag) in [
+        (O_CREAT, OpenFlags::CREATE),
+        (O_TRUNC, OpenFlags::TRUNCATE),
+        (O_APPEND, OpenFlags::APPEND),
+    ] {
+        if flags & flag != 0 {
+            open_flags = open_flags | open_flag;
+        }
+    }
+
+    match fs::open(path, open_flags) {
+        Ok(file) => {
+            process::with_current(|process| process.fds.insert(FileDescriptor::File(file))) as i64
+        }
+        Err(error) => -fs_errno(error),
+    }
+}
+
+/// close(fd): releases the descriptor `fd`. The file stays open while other descriptors,
+/// e.g. copies made by `fork`, refer to it.
+pub fn sys_close(frame: &SyscallFrame) -> i64 {
+    match process::with_current(|process| process.fds.close(frame.rdi)) {
+        Some(_) => 0,
+        None => -EBADF,
+    }
+}
+
+/// lseek(fd, offset, whence): moves the file offset to `offset` from the start, the
+/// current offset or the end, as selected by `whence`, and returns the new offset.
+pub fn sys_lseek(frame: &SyscallFrame) -> i64 {
+    let [fd, offset, whence, ..] = frame.args();
+    let file = match descriptor(fd) {
+        Some(FileDescriptor::File(file)) => file,
+        Some(FileDescriptor::Console) => return -ESPIPE,
+        None => return -EBADF,
+    };
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return -EINVAL,
+    };
+    file.seek(pos)
+        .map_or_else(|error| -fs_errno(error), |offset| offset as i64)
+}
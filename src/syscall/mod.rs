@@ -0,0 +1,311 @@
+// System calls.
+//
+// User programs enter the kernel with the `syscall` instruction, or with `int 0x80`
+// as a fallback. Both follow the same convention: the system call number is passed in
+// RAX, up to six arguments in RDI, RSI, RDX, R10, R8 and R9, and the result comes back
+// in RAX. Errors are returned as negated error numbers. All other registers except
+// RCX and R11 (clobbered by `syscall` itself) are preserved.
+//
+// The entry stubs save every user register, so a system call can also switch to another
+// thread and come back later, and `fork` can hand a copy of the frame to the child.
+// Reference: https://wiki.osdev.org/SYSCALL
+mod handlers;
+
+use core::arch::global_asm;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::fs::FsError;
+use crate::interrupts::{gdt, interrupts};
+use crate::memory::address_space::USER_SPACE_END;
+use crate::println;
+use crate::process;
+
+/// Vector of the `int 0x80` gate.
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_YIELD: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_GETPID: u64 = 5;
+pub const SYS_MMAP: u64 = 6;
+pub const SYS_FORK: u64 = 7;
+pub const SYS_WAITPID: u64 = 8;
+pub const SYS_OPEN: u64 = 9;
+pub const SYS_CLOSE: u64 = 10;
+pub const SYS_LSEEK: u64 = 11;
+
+/// Protection flags for `mmap`.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Flags for `open`, one of the access modes combined with any of the others.
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+/// Where `lseek` counts the offset from.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const EXDEV: i64 = 18;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const ENOSPC: i64 = 28;
+pub const ESPIPE: i64 = 29;
+pub const EROFS: i64 = 30;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const EOPNOTSUPP: i64 = 95;
+
+/// The error number for a file system error.
+pub fn fs_errno(error: FsError) -> i64 {
+    match error {
+        FsError::NotFound => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::InvalidPath | FsError::InvalidArgument => EINVAL,
+        FsError::ReadOnly => EROFS,
+        FsError::BadMode => EBADF,
+        FsError::NotSupported => EOPNOTSUPP,
+        FsError::NoSpace => ENOSPC,
+        FsError::CrossDevice => EXDEV,
+        FsError::Busy => EBUSY,
+        FsError::Io | FsError::Corrupt => EIO,
+    }
+}
+
+type SyscallHandler = fn(&SyscallFrame) -> i64;
+
+/// Indexed by system call number.
+static SYSCALL_TABLE: [SyscallHandler; 12] = [
+    handlers::sys_read,
+    handlers::sys_write,
+    handlers::sys_exit,
+    handlers::sys_yield,
+    handlers::sys_sleep,
+    handlers::sys_getpid,
+    handlers::sys_mmap,
+    handlers::sys_fork,
+    handlers::sys_waitpid,
+    handlers::sys_open,
+    handlers::sys_close,
+    handlers::sys_lseek,
+];
+
+/// The complete user register state saved by the entry stubs, in the order they are
+/// pushed. It is enough to resume the thread later, or to start a copy of it.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r11: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The system call arguments.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// Scratch slot for the user stack pointer between entering the kernel and switching
+// to the kernel stack. Interrupts are masked on entry, so nothing else can use it.
+static mut USER_RSP: u64 = 0;
+
+extern "C" {
+    fn syscall_entry();
+    pub fn int80_entry();
+    /// Restores the `SyscallFrame` RSP points at and returns to user mode through the
+    /// interrupt frame above it. New user threads start here.
+    pub fn user_return();
+}
+
+// `syscall` leaves RSP pointing at the user stack, so the stub first switches to the
+// ring 0 stack stored in the TSS (`privilege_stack_table[0]` lives at offset 4), then
+// saves the user registers as a `SyscallFrame` and passes it to `syscall_dispatch`.
+// `syscall` stores the user RIP in RCX and RFLAGS in R11, and `sysret` takes them from
+// there again.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {tss} + 4]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push r11",
+    "push rcx",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push rbp",
+    "push rbx",
+    "push rcx",
+    "push r11",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "add rsp, 16",
+    "pop rbx",
+    "pop rbp",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "sysretq",
+    user_rsp = sym USER_RSP,
+    tss = sym gdt::TSS,
+    dispatch = sym syscall_dispatch,
+);
+
+// The CPU already switched stacks and pushed an interrupt frame (RIP, CS, RFLAGS, RSP,
+// SS). RIP, RFLAGS and RSP are copied into the `SyscallFrame` and written back before
+// `iretq`, so the handlers can change them. The frame leaves RSP 8 bytes off the 16
+// byte alignment required for the call.
+global_asm!(
+    ".global int80_entry",
+    "int80_entry:",
+    "push qword ptr [rsp + 24]",
+    "push qword ptr [rsp + 24]",
+    "push qword ptr [rsp + 16]",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push rbp",
+    "push rbx",
+    "push rcx",
+    "push r11",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "sub rsp, 8",
+    "call {dispatch}",
+    "add rsp, 8",
+    "",
+    ".global user_return",
+    "user_return:",
+    "mov rax, [rsp + 120]",
+    "mov [rsp + 144], rax",
+    "mov rax, [rsp + 128]",
+    "mov [rsp + 160], rax",
+    "mov rax, [rsp + 136]",
+    "mov [rsp + 168], rax",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop r11",
+    "pop rcx",
+    "pop rbx",
+    "pop rbp",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "add rsp, 24",
+    "iretq",
+    dispatch = sym int80_dispatch,
+);
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // `sysret` to a non-canonical RIP faults in ring 0, which can happen if the
+    // `syscall` instruction sits at the very end of user space.
+    if frame.rip >= USER_SPACE_END {
+        process::exit(-EFAULT);
+    }
+    frame.rax = dispatch(frame) as u64;
+}
+
+extern "C" fn int80_dispatch(frame: &mut SyscallFrame) {
+    interrupts::count(SYSCALL_VECTOR as u8);
+    frame.rax = dispatch(frame) as u64;
+}
+
+/// Runs the system call whose number and arguments are in `frame`.
+pub fn dispatch(frame: &SyscallFrame) -> i64 {
+    match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => -ENOSYS,
+    }
+}
+
+/// Enables the `syscall`/`sysret` instructions.
+pub fn init() {
+    println!("[!] Enabling system calls");
+    let selectors = gdt::selectors();
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    )
+    .expect("GDT layout does not match what syscall/sysret expect");
+    LStar::write(VirtAddr::new(syscall_entry as u64));
+    // Enter the kernel with interrupts disabled until we are on the kernel stack.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    println!("    [+] Done");
+}
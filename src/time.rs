@@ -0,0 +1,168 @@
+// Timekeeping based on the Programmable Interval Timer and the CMOS real-time clock.
+//
+// Channel 0 of the PIT is connected to IRQ 0. We reprogram it to fire `TICKS_PER_SECOND`
+// times a second (instead of the default ~18.2 Hz) and count the interrupts. The wall
+// clock time is read from the RTC once at boot and advanced with the tick count.
+// Reference: https://wiki.osdev.org/Programmable_Interval_Timer
+// Reference: https://wiki.osdev.org/CMOS#The_Real-Time_Clock
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::instructions::{inb, outb};
+use crate::process::scheduler;
+
+/// Frequency of the oscillator driving the PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+pub const TICKS_PER_SECOND: u64 = 100;
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+/// Channel 0, access mode lobyte/hibyte, mode 3 (square wave generator), binary mode.
+const PIT_SQUARE_WAVE: u8 = 0x36;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+/// Set in status register A while the RTC updates its registers.
+const RTC_UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Set in status register B if the values are binary rather than BCD.
+const RTC_BINARY: u8 = 0x04;
+/// Set in status register B if the hour is in 24 hour format.
+const RTC_24_HOUR: u8 = 0x02;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Unix time at boot.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    BOOT_TIME.store(read_rtc(), Ordering::Relaxed);
+
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    unsafe {
+        outb(PIT_COMMAND_PORT, PIT_SQUARE_WAVE);
+        outb(PIT_CHANNEL_0_PORT, divisor as u8);
+        outb(PIT_CHANNEL_0_PORT, (divisor >> 8) as u8);
+    }
+}
+
+/// Called by the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since boot, with the resolution of one tick.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICKS_PER_SECOND
+}
+
+/// The tick by which `ms` milliseconds from now have passed. Saturates, `ms` may come
+/// from user space.
+pub fn deadline(ms: u64) -> u64 {
+    ticks().saturating_add(ms.saturating_mul(TICKS_PER_SECOND).div_ceil(1000))
+}
+
+/// Waits for at least `ms` milliseconds, letting other threads run in the meantime.
+pub fn sleep(ms: u64) {
+    let target = deadline(ms);
+
+    while ticks() < target {
+        scheduler::relax();
+    }
+}
+
+/// The current time as seconds since the Unix epoch.
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + ticks() / TICKS_PER_SECOND
+}
+
+fn read_cmos(register: u8) -> u8 {
+    unsafe {
+        // Bit 7 of the address port would disable NMIs, leave it clear.
+        outb(CMOS_ADDRESS_PORT, register & 0x7f);
+        inb(CMOS_DATA_PORT)
+    }
+}
+
+// Reads (second, minute, hour, day, month, year) as stored in the RTC.
+fn read_rtc_registers() -> [u8; 6] {
+    while read_cmos(RTC_STATUS_A) & RTC_UPDATE_IN_PROGRESS != 0 {}
+    [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(read_cmos)
+}
+
+// Returns the RTC time as Unix time.
+fn read_rtc() -> u64 {
+    // Read until two reads agree, so we do not catch the clock in the middle of an
+    // update.
+    let mut values = read_rtc_registers();
+    loop {
+        let again = read_rtc_registers();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let status = read_cmos(RTC_STATUS_B);
+    let [second, minute, hour, day, month, year] = values;
+    let pm = hour & 0x80 != 0;
+    let decode = |value: u8| {
+        if status & RTC_BINARY != 0 {
+            value as u64
+        } else {
+            (value >> 4) as u64 * 10 + (value & 0xf) as u64
+        }
+    };
+
+    let mut hour = decode(hour & 0x7f);
+    if status & RTC_24_HOUR == 0 {
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    // The century register is not standardized, assume the 21st century.
+    let year = 2000 + decode(year);
+    let (month, day) = (decode(month), decode(day));
+    // A clock that was never set may hold garbage, start at the epoch then.
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return 0;
+    }
+
+    let days = days_from_civil(year, month, day);
+    days * 86400 + hour * 3600 + decode(minute) * 60 + decode(second)
+}
+
+/// Days between 1970-01-01 and the given date in the proleptic Gregorian calendar.
+// Reference: https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date `days` after 1970-01-01 as (year, month, day), the inverse of
+/// `days_from_civil`.
+// Reference: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moonlight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use moonlight_os::net::ipv4::Ipv4Address;
+use moonlight_os::net::socket::{TcpListener, TcpStream, UdpSocket};
+use moonlight_os::net::tcp::State;
+use moonlight_os::net::NetError;
+
+const LOCALHOST: Ipv4Address = Ipv4Address::LOCALHOST;
+/// Long enough for anything on loopback, short enough not to hang a failing test.
+const TIMEOUT_MS: Option<u64> = Some(2000);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    moonlight_os::init(boot_info);
+    test_main();
+    moonlight_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    moonlight_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_udp_round_trip() {
+    let mut server = UdpSocket::bind(7007).unwrap();
+    let mut client = UdpSocket::bind(0).unwrap();
+    server.set_timeout(TIMEOUT_MS);
+    client.set_timeout(TIMEOUT_MS);
+    assert_eq!(UdpSocket::bind(7007).err(), Some(NetError::AddressInUse));
+
+    client
+        .send_to(b"hello over loopback", LOCALHOST, 7007)
+        .unwrap();
+    let mut buf = [0; 64];
+    let (count, source, port) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..count], b"hello over loopback");
+    assert_eq!((source, port), (LOCALHOST, client.local_port()));
+
+    server.send_to(&buf[..count], source, port).unwrap();
+    let (count, _, port) = client.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..count], port), (&b"hello over loopback"[..], 7007));
+    assert_eq!(
+        client.recv_from(&mut buf).map(|_| ()),
+        Err(NetError::TimedOut)
+    );
+}
+
+#[test_case]
+fn test_tcp_echo() {
+    let mut listener = TcpListener::bind(7008).unwrap();
+    listener.set_timeout(TIMEOUT_MS);
+    let mut client = TcpStream::connect(LOCALHOST, 7008).unwrap();
+    client.set_timeout(TIMEOUT_MS);
+    let mut server = listener.accept().unwrap();
+    server.set_timeout(TIMEOUT_MS);
+    assert_eq!(
+        (client.state(), server.state()),
+        (State::Established, State::Established)
+    );
+    assert_eq!(server.peer_address(), client.local_address());
+
+    client.write(b"ping").unwrap();
+    let mut buf = [0; 16];
+    let count = server.read(&mut buf).unwrap();
+    assert_eq!(&buf[..count], b"ping");
+    server.write(b"pong").unwrap();
+    let count = client.read(&mut buf).unwrap();
+    assert_eq!(&buf[..count], b"pong");
+
+    // Closing one side ends the data the other side reads.
+    client.shutdown();
+    assert_eq!(server.read(&mut buf), Ok(0));
+    server.shutdown();
+    assert_eq!(client.read(&mut buf), Ok(0));
+}
+
+#[test_case]
+fn test_tcp_bulk_transfer() {
+    // More than the receive window, so the sender has to wait for the window to open
+    // again once the server reads.
+    let data: Vec<u8> = (0..100_000u32).map(|index| (index % 251) as u8).collect();
+    let mut listener = TcpListener::bind(0).unwrap();
+    listener.set_timeout(TIMEOUT_MS);
+    let mut client = TcpStream::connect(LOCALHOST, listener.local_port()).unwrap();
+    client.set_timeout(TIMEOUT_MS);
+    let mut server = listener.accept().unwrap();
+    server.set_timeout(TIMEOUT_MS);
+
+    client.write(&data).unwrap();
+    client.shutdown();
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    client.flush().unwrap();
+}
+
+#[test_case]
+fn test_tcp_connection_refused() {
+    assert_eq!(
+        TcpStream::connect(LOCALHOST, 7009).err(),
+        Some(NetError::ConnectionRefused)
+    );
+}
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use moonlight_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
 use lazy_static::lazy_static;
@@ -9,13 +10,9 @@ use moonlight_os::interrupts::idt::{InterruptDescriptorTable, InterruptStackFram
 
 lazy_static! {
     static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        unsafe {
-            idt.add(0x8, test_double_fault_handler as u64);
-            idt.entries[0x8].set_stack_index(moonlight_os::interrupts::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        idt
+        InterruptDescriptorTable::new()
+            .add(0x8, test_double_fault_handler as u64)
+            .with_stack(0x8, moonlight_os::interrupts::gdt::DOUBLE_FAULT_IST_INDEX)
     };
 }
 
@@ -32,10 +29,12 @@ extern "x86-interrupt" fn test_double_fault_handler(
     loop {}
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
+    moonlight_os::memory::init(boot_info);
     moonlight_os::interrupts::gdt::init();
     init_test_idt();
     stack_overflow();